@@ -0,0 +1,95 @@
+use std::fmt;
+use std::ops::Index;
+use std::slice::Iter;
+use std::vec::IntoIter;
+
+// Results of a batch operation, in the same order as the batch input
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchResult<T, E> {
+    results: Vec<Result<T, E>>,
+}
+
+impl<T, E> BatchResult<T, E> {
+    pub fn new(results: Vec<Result<T, E>>) -> Self {
+        Self { results }
+    }
+    pub fn len(&self) -> usize {
+        self.results.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+    pub fn get(&self, index: usize) -> Option<&Result<T, E>> {
+        self.results.get(index)
+    }
+    pub fn iter(&self) -> Iter<Result<T, E>> {
+        self.results.iter()
+    }
+    // Successful items with their positions in the batch
+    pub fn successes(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.results
+            .iter()
+            .enumerate()
+            .filter_map(|(i, r)| r.as_ref().ok().map(|v| (i, v)))
+    }
+    // Failed items with their positions in the batch
+    pub fn failures(&self) -> impl Iterator<Item = (usize, &E)> {
+        self.results
+            .iter()
+            .enumerate()
+            .filter_map(|(i, r)| r.as_ref().err().map(|e| (i, e)))
+    }
+    pub fn num_successes(&self) -> usize {
+        self.results.iter().filter(|r| r.is_ok()).count()
+    }
+    pub fn num_failures(&self) -> usize {
+        self.len() - self.num_successes()
+    }
+    pub fn all_ok(&self) -> bool {
+        self.results.iter().all(|r| r.is_ok())
+    }
+    pub fn into_inner(self) -> Vec<Result<T, E>> {
+        self.results
+    }
+}
+
+impl<T, E> From<Vec<Result<T, E>>> for BatchResult<T, E> {
+    fn from(results: Vec<Result<T, E>>) -> Self {
+        Self::new(results)
+    }
+}
+
+impl<T, E> Index<usize> for BatchResult<T, E> {
+    type Output = Result<T, E>;
+    fn index(&self, index: usize) -> &Self::Output {
+        &self.results[index]
+    }
+}
+
+impl<T, E> IntoIterator for BatchResult<T, E> {
+    type Item = Result<T, E>;
+    type IntoIter = IntoIter<Result<T, E>>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.results.into_iter()
+    }
+}
+
+impl<'a, T, E> IntoIterator for &'a BatchResult<T, E> {
+    type Item = &'a Result<T, E>;
+    type IntoIter = Iter<'a, Result<T, E>>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.results.iter()
+    }
+}
+
+impl<T, E> fmt::Display for BatchResult<T, E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} succeeded, {} failed",
+            self.num_successes(),
+            self.len(),
+            self.num_failures()
+        )
+    }
+}
@@ -0,0 +1,102 @@
+use crate::ram::clock;
+use crate::ram::types::{Id, RandValue};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+
+// Id generation for new cells.
+// The higher part of the id decides the server and the chunk of the cell, the lower part
+// identifies the cell in the chunk.
+pub trait IdStrategy: Send + Sync {
+    fn next_id(&self, partition: u64, key: &[u8]) -> Id;
+}
+
+// Randomly spread cells across the cluster, partition and key are ignored
+pub struct Random;
+
+// Bits of the sequence in lower ids of `Sequential`, and the random part of the seed above them
+const SEQ_BITS: u64 = 20;
+const SEQ_RAND_MASK: u64 = (1 << (32 - SEQ_BITS)) - 1;
+// Consecutive ids of a partition stay in the same higher id for runs of 2^10 ids
+const SEQ_RUN_BITS: u64 = 10;
+
+// Number of higher ids the ids of a partition are spread over by `Sequential::new`
+pub const DEFAULT_SEQUENTIAL_SPREAD: u64 = 16;
+
+// Monotonic ids within each partition for locality and range scans.
+// Runs of consecutive ids are spread over several higher ids of the partition, so a busy
+// partition is not bound to a single server and chunk. Ids are monotonic within each of them.
+// Counters start from the wall clock in seconds at the higher half of the lower ids, to avoid
+// reusing ids from previous sessions. Clients created in the same second tell apart by a random
+// part below it. Counters taking 2^20 ids are seeded again with a later second instead of running
+// into the ids of other clients.
+pub struct Sequential {
+    spread: u64,
+    counters: Mutex<HashMap<u64, SequentialCounter>>,
+}
+
+struct SequentialCounter {
+    seed: u64,
+    seq: u64,
+}
+
+// Deterministic ids from the natural key of the cell, partition is ignored
+pub struct Hashed;
+
+impl IdStrategy for Random {
+    fn next_id(&self, _partition: u64, _key: &[u8]) -> Id {
+        Id::rand()
+    }
+}
+
+impl Sequential {
+    pub fn new() -> Self {
+        Self::with_spread(DEFAULT_SEQUENTIAL_SPREAD)
+    }
+    // Spread of 1 keeps all ids of a partition in the partition itself
+    pub fn with_spread(spread: u64) -> Self {
+        Self {
+            spread: spread.max(1),
+            counters: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl SequentialCounter {
+    // Seconds of the seed never go back to the ones of the previous seed of the counter, even
+    // when the clock has not moved on yet
+    fn seeded_after(prev_seed: Option<u64>) -> Self {
+        let secs = (clock::now() as u64).max(prev_seed.map_or(0, |seed| (seed >> 32) + 1));
+        Self {
+            seed: (secs << 32) | ((Id::rand().lower & SEQ_RAND_MASK) << SEQ_BITS),
+            seq: 0,
+        }
+    }
+}
+
+impl IdStrategy for Sequential {
+    fn next_id(&self, partition: u64, _key: &[u8]) -> Id {
+        let mut counters = self.counters.lock();
+        let counter = counters
+            .entry(partition)
+            .or_insert_with(|| SequentialCounter::seeded_after(None));
+        if counter.seq >> SEQ_BITS != 0 {
+            *counter = SequentialCounter::seeded_after(Some(counter.seed));
+        }
+        let seq = counter.seq;
+        counter.seq += 1;
+        let higher = if self.spread == 1 {
+            partition
+        } else {
+            // Clients start from different runs by the random part of their seeds
+            let run = ((counter.seed >> SEQ_BITS) & SEQ_RAND_MASK) + (seq >> SEQ_RUN_BITS);
+            Id::from_obj(&(partition, run % self.spread)).higher
+        };
+        Id::new(higher, counter.seed | seq)
+    }
+}
+
+impl IdStrategy for Hashed {
+    fn next_id(&self, _partition: u64, key: &[u8]) -> Id {
+        Id::from_obj(&key)
+    }
+}
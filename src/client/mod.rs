@@ -1,31 +1,43 @@
 use bifrost::conshash::{CHError, ConsistentHashing};
 use bifrost::membership::client::ObserverClient;
 use bifrost::raft;
-use bifrost::raft::client::{ClientError, RaftClient};
+use bifrost::raft::client::{ClientError, RaftClient, SubscriptionError, SubscriptionReceipt};
 use bifrost::raft::state_machine::callback::server::NotifyError;
 use bifrost::raft::state_machine::master::ExecError;
 use bifrost::rpc::{RPCClient, RPCError, Server as RPCServer, DEFAULT_CLIENT_POOL};
 use futures::prelude::*;
 use futures::stream::FuturesUnordered;
 use futures::stream::StreamExt;
-use itertools::Itertools;
+use parking_lot::RwLock;
+use rand::seq::SliceRandom;
 use std::cell::Cell as StdCell;
 use std::collections::HashMap;
 use std::io;
-use std::mem;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
 
+use crate::ram::aggregate::{self, Aggregation, GroupedAggResult};
 use crate::ram::cell::{CellHeader, OwnedCell, ReadError, WriteError};
 use crate::ram::schema::sm::client::SMClient as SchemaClient;
 use crate::ram::schema::sm::generate_sm_id;
-use crate::ram::schema::Schema;
-use crate::ram::types::Id;
-use crate::server::{cell_rpc as plain_server, transactions as txn_server, CONS_HASH_ID};
+use crate::ram::schema::{Field, IndexType, Schema, SchemaError, SchemaFilter};
+use crate::ram::types::{Id, OwnedValue};
+use crate::server::{
+    capabilities, cell_rpc as plain_server, transactions as txn_server, Service, CONS_HASH_ID,
+};
+use crate::utils::env_or;
 
+use self::batch::BatchResult;
+use self::id_strategy::{IdStrategy, Random};
 use self::transaction::*;
 
 static TRANSACTION_MAX_RETRY: u32 = 1000;
+static SCHEMA_EVENTS_CAPACITY: usize = 64;
+pub const DEFAULT_BARRIER_TIMEOUT: Duration = Duration::from_secs(60);
 
+pub mod batch;
+pub mod id_strategy;
 #[cfg(test)]
 mod tests;
 pub mod transaction;
@@ -34,12 +46,62 @@ pub mod transaction;
 pub enum NebClientError {
     RaftClientError(ClientError),
     ConsistentHashtableError(CHError),
+    SchemaSubscriptionError(ExecError),
+    SubscriptionError(SubscriptionError),
+}
+
+#[derive(Debug)]
+pub enum CreateIndexError {
+    ExecError(ExecError),
+    SchemaError(SchemaError),
+    // The index is registered, but building it for existing cells failed
+    BackfillError(RPCError),
+}
+
+#[derive(Debug, Clone)]
+pub enum SchemaEvent {
+    Added(Schema),
+    // Deleted schemas are notified by name
+    Deleted(String),
 }
 
 pub struct AsyncClient {
     pub conshash: Arc<ConsistentHashing>,
     pub raft_client: Arc<RaftClient>,
     pub schema_client: SchemaClient,
+    pub id_strategy: Arc<dyn IdStrategy>,
+    // Subscribed to schema changes on the first watch
+    schema_events: tokio::sync::Mutex<Option<SchemaSubscription>>,
+    timeout: Option<Duration>,
+    // Services offered by servers by their addresses
+    capabilities: RwLock<HashMap<String, Vec<Service>>>,
+    // Cells larger than a frame in serialized form are read in multiple frames
+    read_frame_size: u32,
+    // Groups beyond the bound are aggregated into the overflow of grouped aggregations
+    max_agg_groups: u32,
+    // Transactions are retried after exponential backoff from the base delay, up to the cap
+    txn_backoff_base: Duration,
+    txn_backoff_cap: Duration,
+}
+
+fn default_read_frame_size() -> u32 {
+    env_or("NEB_READ_FRAME_SIZE", 1024 * 1024)
+}
+
+fn default_txn_backoff() -> (Duration, Duration) {
+    let base = env_or("NEB_TXN_BACKOFF_BASE_MS", 1);
+    let cap = env_or("NEB_TXN_BACKOFF_CAP_MS", 100);
+    (Duration::from_millis(base), Duration::from_millis(cap))
+}
+
+// Delay before the retry, between half and all of `base * 2^retried` bounded by the cap. The
+// jitter keeps conflicting clients from retrying at the same time again.
+fn txn_backoff(base: Duration, cap: Duration, retried: u32) -> Duration {
+    let backoff = base
+        .checked_mul(1 << retried.min(31))
+        .unwrap_or(cap)
+        .min(cap);
+    backoff / 2 + backoff.mul_f64(rand::random::<f64>() / 2.0)
 }
 
 pub fn client_by_rpc_client(rpc: &Arc<RPCClient>) -> Arc<plain_server::AsyncServiceClient> {
@@ -65,17 +127,64 @@ impl AsyncClient {
                 )
                 .await
                 {
-                    Ok(chash) => Ok(Self {
-                        conshash: chash,
-                        raft_client: raft_client.clone(),
-                        schema_client: SchemaClient::new(generate_sm_id(group), &raft_client),
-                    }),
+                    Ok(chash) => {
+                        let schema_client = SchemaClient::new(generate_sm_id(group), &raft_client);
+                        let (txn_backoff_base, txn_backoff_cap) = default_txn_backoff();
+                        Ok(Self {
+                            conshash: chash,
+                            raft_client: raft_client.clone(),
+                            schema_client,
+                            id_strategy: Arc::new(Random),
+                            schema_events: tokio::sync::Mutex::new(None),
+                            timeout: None,
+                            capabilities: RwLock::new(HashMap::new()),
+                            read_frame_size: default_read_frame_size(),
+                            max_agg_groups: aggregate::default_max_groups(),
+                            txn_backoff_base,
+                            txn_backoff_cap,
+                        })
+                    }
                     Err(err) => Err(NebClientError::ConsistentHashtableError(err)),
                 }
             }
             Err(err) => Err(NebClientError::RaftClientError(err)),
         }
     }
+    pub fn with_id_strategy(mut self, id_strategy: Arc<dyn IdStrategy>) -> Self {
+        self.id_strategy = id_strategy;
+        self
+    }
+    // Fail operations with a `TimedOut` IO error when their RPCs do not finish in time
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+    pub fn with_read_frame_size(mut self, frame_size: u32) -> Self {
+        self.read_frame_size = frame_size;
+        self
+    }
+    pub fn with_max_agg_groups(mut self, max_groups: u32) -> Self {
+        self.max_agg_groups = max_groups;
+        self
+    }
+    // Zero base delay retries transactions right away
+    pub fn with_txn_backoff(mut self, base: Duration, cap: Duration) -> Self {
+        self.txn_backoff_base = base;
+        self.txn_backoff_cap = cap;
+        self
+    }
+    pub fn new_id(&self, partition: u64, key: &[u8]) -> Id {
+        self.id_strategy.next_id(partition, key)
+    }
+    // Cells written with the unit id get ids of the id strategy, partitioned by their schemas and
+    // keyed by their data. Headers returned by the writes carry the ids.
+    fn assign_id(&self, cell: &mut OwnedCell) {
+        if cell.id().is_unit_id() {
+            let key = bincode::serialize(&cell.data).unwrap_or_default();
+            let id = self.new_id(cell.header.schema as u64, &key);
+            cell.header.set_id(&id);
+        }
+    }
     pub fn locate_server_id(&self, id: &Id) -> Result<u64, RPCError> {
         if id.is_unit_id() {
             return Ok(0);
@@ -100,6 +209,17 @@ impl AsyncClient {
             .map(|c| client_by_rpc_client(&c))
     }
 
+    // Ids of the servers currently online in the membership
+    async fn online_server_ids(&self) -> Result<Vec<u64>, RPCError> {
+        match self.conshash.membership().all_members(true).await {
+            Ok((members, _)) => Ok(members.into_iter().map(|m| m.id).collect()),
+            Err(e) => Err(RPCError::IOError(io::Error::new(
+                io::ErrorKind::Other,
+                format!("cannot list online servers, {:?}", e),
+            ))),
+        }
+    }
+
     pub async fn locate_plain_server(
         &self,
         id: Id,
@@ -110,165 +230,584 @@ impl AsyncClient {
     }
 
     pub async fn read_cell(&self, id: Id) -> Result<Result<OwnedCell, ReadError>, RPCError> {
+        timed(self.timeout, async move {
+            let client = self.locate_plain_server(id).await?;
+            client.read_cell(id).await
+        })
+        .await?
+    }
+    // Only the fields of the ids in the cell, values in the same order. Fields not in the schema
+    // are read as null.
+    pub async fn read_cell_selected(
+        &self,
+        id: Id,
+        fields: Vec<u64>,
+    ) -> Result<Result<Vec<OwnedValue>, ReadError>, RPCError> {
+        timed(self.timeout, async move {
+            let client = self.locate_plain_server(id).await?;
+            client.read_cell_selected(id, fields).await
+        })
+        .await?
+    }
+    // Bytes of the cell as stored on the server, for moving cells around without decoding them
+    pub async fn read_cell_raw(&self, id: Id) -> Result<Result<Vec<u8>, ReadError>, RPCError> {
+        timed(self.timeout, async move {
+            let client = self.locate_plain_server(id).await?;
+            client.read_cell_raw(id).await
+        })
+        .await?
+    }
+    // Read large cells without holding them in a single RPC message. The cell is read in frames
+    // of the serialized cell, starting over when it is changed in between.
+    pub async fn read_cell_stream(&self, id: Id) -> Result<Result<OwnedCell, ReadError>, RPCError> {
         let client = self.locate_plain_server(id).await?;
-        client.read_cell(id).await
+        'read: loop {
+            let mut data = vec![];
+            let mut reading_version = None;
+            loop {
+                let frame = timed(
+                    self.timeout,
+                    client.read_cell_frame(id, data.len() as u64, self.read_frame_size),
+                )
+                .await??;
+                match frame {
+                    Ok(plain_server::CellFrame::Whole(cell)) => return Ok(Ok(cell)),
+                    Ok(plain_server::CellFrame::Part {
+                        version,
+                        total,
+                        data: frame_data,
+                    }) => {
+                        if *reading_version.get_or_insert(version) != version {
+                            continue 'read;
+                        }
+                        // Reading on would never reach the end
+                        if frame_data.is_empty() {
+                            return Ok(Err(ReadError::InvalidFrameSize));
+                        }
+                        data.extend_from_slice(&frame_data);
+                        if data.len() as u64 >= total {
+                            return Ok(
+                                bincode::deserialize(&data).map_err(|_| ReadError::NetworkingError)
+                            );
+                        }
+                    }
+                    Err(e) => return Ok(Err(e)),
+                }
+            }
+        }
+    }
+    // Read the cell along with its version, to be used with `cas_cell`
+    pub async fn read_cell_versioned(
+        &self,
+        id: Id,
+    ) -> Result<Result<(OwnedCell, u64), ReadError>, RPCError> {
+        Ok(self.read_cell(id).await?.map(|cell| {
+            let version = cell.version();
+            (cell, version)
+        }))
     }
     pub async fn read_all_cells(
         &self,
         ids: Vec<Id>,
-    ) -> Result<Vec<Result<OwnedCell, ReadError>>, RPCError> {
-        let mut cells_by_client = ids
-            .iter()
-            .dedup()
-            .group_by(|id| self.locate_server_id(&id).unwrap())
-            .into_iter()
-            .map(|(server_id, ids)| (server_id, ids.map(|id| *id).collect_vec()))
-            .map(|(server_id, ids)| async move {
-                if server_id > 0 {
-                    let client = self.client_by_server_id(server_id).await.unwrap();
-                    (client.read_all_cells(ids.clone()).await, ids)
-                } else {
-                    (
-                        Ok(vec![Err(ReadError::CellIdIsUnitId)]),
-                        vec![Id::unit_id()],
-                    )
-                }
-            })
-            .collect::<FuturesUnordered<_>>();
-        let mut id_cell_map = HashMap::new();
-        while let Some((cells, ids)) = cells_by_client.next().await {
-            let cells = cells?;
-            for (id, cell) in ids.into_iter().zip(cells) {
-                id_cell_map.insert(id, Some(cell));
+    ) -> Result<BatchResult<OwnedCell, ReadError>, RPCError> {
+        self.batch_by_server(
+            ids,
+            |id| *id,
+            || ReadError::CellIdIsUnitId,
+            |client, ids| async move { client.read_all_cells(ids).await },
+        )
+        .await
+    }
+    pub async fn write_all_cells(
+        &self,
+        mut cells: Vec<OwnedCell>,
+    ) -> Result<BatchResult<CellHeader, WriteError>, RPCError> {
+        cells.iter_mut().for_each(|cell| self.assign_id(cell));
+        self.batch_by_server(
+            cells,
+            |cell| cell.id(),
+            || WriteError::ReadError(ReadError::CellIdIsUnitId),
+            |client, cells| async move { client.write_all_cells(cells).await },
+        )
+        .await
+    }
+    pub async fn upsert_all_cells(
+        &self,
+        cells: Vec<OwnedCell>,
+    ) -> Result<BatchResult<CellHeader, WriteError>, RPCError> {
+        self.batch_by_server(
+            cells,
+            |cell| cell.id(),
+            || WriteError::ReadError(ReadError::CellIdIsUnitId),
+            |client, cells| async move { client.upsert_all_cells(cells).await },
+        )
+        .await
+    }
+    pub async fn remove_all_cells(
+        &self,
+        ids: Vec<Id>,
+    ) -> Result<BatchResult<(), WriteError>, RPCError> {
+        self.batch_by_server(
+            ids,
+            |id| *id,
+            || WriteError::ReadError(ReadError::CellIdIsUnitId),
+            |client, ids| async move { client.remove_all_cells(ids).await },
+        )
+        .await
+    }
+    // Group batch items by their servers and put the results back in the order of the items
+    async fn batch_by_server<I, T, E, K, U, F, FR>(
+        &self,
+        items: Vec<I>,
+        key_of: K,
+        unit_id_error: U,
+        op: F,
+    ) -> Result<BatchResult<T, E>, RPCError>
+    where
+        K: Fn(&I) -> Id,
+        U: Fn() -> E,
+        F: Fn(Arc<plain_server::AsyncServiceClient>, Vec<I>) -> FR,
+        FR: Future<Output = Result<Vec<Result<T, E>>, RPCError>>,
+    {
+        timed(self.timeout, async move {
+            let num_items = items.len();
+            let mut items_by_server: HashMap<u64, Vec<(usize, I)>> = HashMap::new();
+            for (i, item) in items.into_iter().enumerate() {
+                let server_id = self.locate_server_id(&key_of(&item))?;
+                items_by_server
+                    .entry(server_id)
+                    .or_insert_with(|| vec![])
+                    .push((i, item));
             }
-        }
-        Ok(ids
-            .iter()
-            .map(|id| {
-                // Use mem::replace to avoid additional cost when hash map shriking by remove
-                let id_ref = id_cell_map.get_mut(id).unwrap();
-                if cfg!(debug_assertions) && id_ref.is_none() {
-                    let msg = format!("Cannot find {:?} for read_all_cells", id);
-                    error!("{}", msg);
-                    panic!(msg);
+            let op = &op;
+            let mut results_by_server = items_by_server
+                .into_iter()
+                .map(|(server_id, items)| async move {
+                    let (indices, items): (Vec<_>, Vec<_>) = items.into_iter().unzip();
+                    if server_id > 0 {
+                        let client = self.client_by_server_id(server_id).await?;
+                        Ok((indices, Some(op(client, items).await?)))
+                    } else {
+                        Ok((indices, None))
+                    }
+                })
+                .collect::<FuturesUnordered<_>>();
+            let mut results: Vec<Option<Result<T, E>>> = (0..num_items).map(|_| None).collect();
+            while let Some(server_results) = results_by_server.next().await {
+                let (indices, server_results): (Vec<usize>, Option<Vec<Result<T, E>>>) =
+                    server_results?;
+                match server_results {
+                    Some(server_results) => {
+                        debug_assert_eq!(indices.len(), server_results.len());
+                        for (i, res) in indices.into_iter().zip(server_results) {
+                            results[i] = Some(res);
+                        }
+                    }
+                    None => {
+                        for i in indices {
+                            results[i] = Some(Err(unit_id_error()));
+                        }
+                    }
                 }
-                mem::replace(id_ref, None).unwrap()
-            })
-            .collect())
+            }
+            Ok(BatchResult::new(
+                results.into_iter().map(|res| res.unwrap()).collect(),
+            ))
+        })
+        .await?
     }
     pub async fn write_cell(
         &self,
-        cell: OwnedCell,
+        mut cell: OwnedCell,
+    ) -> Result<Result<CellHeader, WriteError>, RPCError> {
+        self.assign_id(&mut cell);
+        timed(self.timeout, async move {
+            let client = self.locate_plain_server(cell.id()).await?;
+            client.write_cell(cell).await
+        })
+        .await?
+    }
+    // Write the cell, or get the header of an existing cell with identical content on its server
+    pub async fn write_cell_dedup(
+        &self,
+        mut cell: OwnedCell,
     ) -> Result<Result<CellHeader, WriteError>, RPCError> {
-        let client = self.locate_plain_server(cell.id()).await?;
-        client.write_cell(cell).await
+        self.assign_id(&mut cell);
+        timed(self.timeout, async move {
+            let client = self.locate_plain_server(cell.id()).await?;
+            client.write_cell_dedup(cell).await
+        })
+        .await?
     }
     pub async fn update_cell(
         &self,
         cell: OwnedCell,
     ) -> Result<Result<CellHeader, WriteError>, RPCError> {
-        let client = self.locate_plain_server(cell.id()).await?;
-        client.update_cell(cell).await
+        timed(self.timeout, async move {
+            let client = self.locate_plain_server(cell.id()).await?;
+            client.update_cell(cell).await
+        })
+        .await?
     }
     pub async fn upsert_cell(
         &self,
         cell: OwnedCell,
     ) -> Result<Result<CellHeader, WriteError>, RPCError> {
-        let client = self.locate_plain_server(cell.id()).await?;
-        client.upsert_cell(cell).await
+        timed(self.timeout, async move {
+            let client = self.locate_plain_server(cell.id()).await?;
+            client.upsert_cell(cell).await
+        })
+        .await?
+    }
+    // Update the cell only if it was not changed since `expected_version` was read
+    pub async fn cas_cell(
+        &self,
+        cell: OwnedCell,
+        expected_version: u64,
+    ) -> Result<Result<CellHeader, WriteError>, RPCError> {
+        timed(self.timeout, async move {
+            let client = self.locate_plain_server(cell.id()).await?;
+            client.cas_cell(cell, expected_version).await
+        })
+        .await?
     }
     pub async fn remove_cell(&self, id: Id) -> Result<Result<(), WriteError>, RPCError> {
-        let client = self.locate_plain_server(id).await?;
-        client.remove_cell(id).await
+        timed(self.timeout, async move {
+            let client = self.locate_plain_server(id).await?;
+            client.remove_cell(id).await
+        })
+        .await?
+    }
+    // Remove the cell only if it is still of the version read, like `cas_cell` for updates
+    pub async fn remove_cell_if_version(
+        &self,
+        id: Id,
+        expected_version: u64,
+    ) -> Result<Result<(), WriteError>, RPCError> {
+        timed(self.timeout, async move {
+            let client = self.locate_plain_server(id).await?;
+            client.remove_cell_if_version(id, expected_version).await
+        })
+        .await?
     }
     pub async fn count(&self) -> Result<u64, RPCError> {
-        let (members, _) = self.conshash.membership().all_members(true).await.unwrap();
-        let mut member_futs: FuturesUnordered<_> = members
-            .into_iter()
-            .map(|m| async move {
-                let client = self.client_by_server_id(m.id).await?;
-                Ok(client.count().await?)
-            })
-            .collect();
-        let mut sum = 0;
-        while let Some(res) = member_futs.next().await {
-            sum += res?;
-        }
-        Ok(sum)
+        timed(self.timeout, async move {
+            let members = self.online_server_ids().await?;
+            let mut member_futs: FuturesUnordered<_> = members
+                .into_iter()
+                .map(|server_id| async move {
+                    let client = self.client_by_server_id(server_id).await?;
+                    Ok(client.count().await?)
+                })
+                .collect();
+            let mut sum = 0;
+            while let Some(res) = member_futs.next().await {
+                sum += res?;
+            }
+            Ok(sum)
+        })
+        .await?
+    }
+    // Ids of all cells with the schema across the cluster, for maintenance that needs a full scan
+    pub async fn cell_ids_of_schema(&self, schema_id: u32) -> Result<Vec<Id>, RPCError> {
+        timed(self.timeout, async move {
+            let mut member_futs: FuturesUnordered<_> = self
+                .online_server_ids()
+                .await?
+                .into_iter()
+                .map(|server_id| async move {
+                    let client = self.client_by_server_id(server_id).await?;
+                    Ok(client.cell_ids_of_schema(schema_id).await?)
+                })
+                .collect();
+            let mut ids = vec![];
+            while let Some(res) = member_futs.next().await {
+                ids.append(&mut res?);
+            }
+            Ok(ids)
+        })
+        .await?
     }
+    // Server and the chunk on it holding the cell of the id, for diagnostics and locality
+    pub async fn locate_chunk(&self, id: Id) -> Result<(u64, usize), RPCError> {
+        timed(self.timeout, async move {
+            let server_id = self.locate_server_id(&id)?;
+            let client = self.client_by_server_id(server_id).await?;
+            let chunk_id = client.chunk_id_for(id).await?;
+            Ok((server_id, chunk_id as usize))
+        })
+        .await?
+    }
+    // Aggregate a top level field of cells of the schema by groups of another field's values.
+    // Servers aggregate their chunks in parallel and partial results are merged here.
+    pub async fn aggregate_grouped(
+        &self,
+        schema_id: u32,
+        group_by: u64,
+        agg_field: u64,
+        agg: Aggregation,
+    ) -> Result<GroupedAggResult, RPCError> {
+        let max_groups = self.max_agg_groups;
+        timed(self.timeout, async move {
+            let members = self.online_server_ids().await?;
+            let mut member_futs: FuturesUnordered<_> = members
+                .into_iter()
+                .map(|server_id| async move {
+                    let client = self.client_by_server_id(server_id).await?;
+                    client
+                        .aggregate_grouped(schema_id, group_by, agg_field, agg, max_groups)
+                        .await
+                })
+                .collect();
+            let mut res = GroupedAggResult::default();
+            while let Some(partial) = member_futs.next().await {
+                res.merge(partial?, max_groups as usize);
+            }
+            Ok(res)
+        })
+        .await?
+    }
+    // The closure is called again for every retry, it must build a fresh future from what it
+    // captured by reference or clone. Use `transaction_once` for closures that can only run once.
+    // `attempt` of the transaction tells retries from the first attempt, for backoff and metrics.
     pub async fn transaction<'a, TFN, TR, RF>(&self, func: TFN) -> Result<TR, TxnError>
     where
         TFN: Fn(Transaction) -> RF + 'a,
         RF: Future<Output = Result<TR, TxnError>> + 'a,
     {
-        let server_name = match self.conshash.rand_server() {
-            Some(name) => name,
-            None => return Err(TxnError::CannotFindAServer),
-        };
-        let txn_client = match txn_server::new_async_client(&server_name).await {
-            Ok(client) => client,
-            Err(e) => return Err(TxnError::IoError(e)),
-        };
-        let mut txn_id: txn_server::TxnId;
+        let mut txn_client = self.txn_client().await?;
         let mut retried = 0;
         while retried < TRANSACTION_MAX_RETRY {
-            txn_id = match txn_client.begin().await {
-                Ok(Ok(id)) => id,
-                _ => return Err(TxnError::CannotBegin),
-            };
-            let txn = Transaction {
-                tid: txn_id,
-                state: Arc::new(StdCell::new(txn_server::TxnState::Started)),
-                client: txn_client.clone(),
-            };
-            let exec_result = func(txn.clone()).await;
-            let mut exec_value = None;
-            let mut txn_result = Ok(());
-            match exec_result {
-                Ok(val) => {
-                    if txn.state.get() == txn_server::TxnState::Started {
-                        txn_result = txn.prepare().await;
-                        debug!("PREPARE STATE: {:?}", txn_result);
-                    }
-                    if txn_result.is_ok() && txn.state.get() == txn_server::TxnState::Prepared {
-                        txn_result = txn.commit().await;
-                        debug!("COMMIT STATE: {:?}", txn_result);
-                    }
-                    exec_value = Some(val);
-                }
-                Err(e) => txn_result = Err(e),
-            }
-            debug!("TXN CONCLUSION: {:?}", txn_result);
-            match txn_result {
-                Ok(()) => {
-                    return Ok(exec_value.unwrap());
-                }
+            // Timeouts surface as RPC errors, which are not retried
+            match self.attempt_txn(&txn_client, retried, &func).await {
                 Err(TxnError::NotRealizable) => {
-                    let abort_result = txn.abort().await; // continue the loop to retry
-                    debug!("TXN NOT REALIZABLE, ABORT: {:?}", abort_result);
+                    // Give the conflicting transactions time to finish before the next attempt
+                    let backoff = txn_backoff(self.txn_backoff_base, self.txn_backoff_cap, retried);
+                    if backoff > Duration::from_millis(0) {
+                        tokio::time::sleep(backoff).await;
+                    }
                 }
-                Err(e) => {
-                    // abort will always be an error to achieve early break
-                    let abort_result = txn.abort().await;
-                    debug!("TXN ERROR, ABORT: {:?}", abort_result);
-                    return Err(e);
+                Err(e) if e.is_transient() => {
+                    // The transaction manager may be unreachable, try another one
+                    debug!("Transient transaction error {:?}, will retry", e);
+                    txn_client = self.txn_client().await?;
                 }
+                res => return res,
             }
             retried += 1;
             debug!("Client retry transaction, {:?} times", retried);
         }
         Err(TxnError::TooManyRetry)
     }
+    // Run the transaction closure exactly once, for closures moving their captured data into the
+    // transaction. Conflicts are not retried and surface as `TxnError::NotRealizable`.
+    pub async fn transaction_once<'a, TFN, TR, RF>(&self, func: TFN) -> Result<TR, TxnError>
+    where
+        TFN: FnOnce(Transaction) -> RF + 'a,
+        RF: Future<Output = Result<TR, TxnError>> + 'a,
+    {
+        let txn_client = self.txn_client().await?;
+        self.attempt_txn(&txn_client, 0, func).await
+    }
+    // Wait until every transaction active at the time of the call on any server has committed or
+    // aborted, like before a schema migration. Bounded by `DEFAULT_BARRIER_TIMEOUT` for clients
+    // without a timeout.
+    pub async fn barrier(&self) -> Result<(), RPCError> {
+        let timeout = self.timeout.unwrap_or(DEFAULT_BARRIER_TIMEOUT);
+        timed(Some(timeout), async move {
+            let members = self.online_server_ids().await?;
+            let mut member_futs: FuturesUnordered<_> = members
+                .into_iter()
+                .map(|server_id| async move {
+                    let client = self.txn_client_by_server_id(server_id).await?;
+                    client.barrier().await
+                })
+                .collect();
+            while let Some(res) = member_futs.next().await {
+                res?;
+            }
+            Ok(())
+        })
+        .await?
+    }
+    async fn txn_client_by_server_id(
+        &self,
+        server_id: u64,
+    ) -> Result<Arc<txn_server::manager::AsyncServiceClient>, RPCError> {
+        DEFAULT_CLIENT_POOL
+            .get_by_id(server_id, move |sid| self.conshash.to_server_name(sid))
+            .await
+            .map_err(|e| RPCError::IOError(e))
+            .map(|c| {
+                txn_server::manager::AsyncServiceClient::new(
+                    txn_server::manager::DEFAULT_SERVICE_ID,
+                    &c,
+                )
+            })
+    }
+    // Transactions are managed by a random server. When it does not offer them, the other online
+    // servers are tried in random order.
+    async fn txn_client(
+        &self,
+    ) -> Result<Arc<txn_server::manager::AsyncServiceClient>, TxnError> {
+        let first_server = match self.conshash.rand_server() {
+            Some(name) => name,
+            None => return Err(TxnError::CannotFindAServer),
+        };
+        let res = self.txn_client_of(&first_server).await;
+        if !matches!(res, Err(TxnError::UnsupportedOperation(_))) {
+            return res;
+        }
+        let mut other_servers: Vec<_> = self
+            .online_server_ids()
+            .await
+            .map_err(TxnError::RPCError)?
+            .into_iter()
+            .map(|server_id| self.conshash.to_server_name(server_id))
+            .filter(|server_name| server_name != &first_server)
+            .collect();
+        other_servers.shuffle(&mut rand::thread_rng());
+        for server_name in other_servers {
+            let res = self.txn_client_of(&server_name).await;
+            if !matches!(res, Err(TxnError::UnsupportedOperation(_))) {
+                return res;
+            }
+        }
+        res
+    }
+    async fn txn_client_of(
+        &self,
+        server_name: &String,
+    ) -> Result<Arc<txn_server::manager::AsyncServiceClient>, TxnError> {
+        let client = match timed(self.timeout, txn_server::new_async_client(server_name)).await {
+            Ok(Ok(client)) => client,
+            Ok(Err(e)) => return Err(TxnError::IoError(e)),
+            Err(e) => return Err(TxnError::RPCError(e)),
+        };
+        let services = self
+            .capabilities(server_name)
+            .await
+            .map_err(TxnError::RPCError)?;
+        if !services.contains(&Service::Transaction) {
+            return Err(TxnError::UnsupportedOperation(Service::Transaction));
+        }
+        Ok(client)
+    }
+    // Services offered by the server at the address. Servers do not change their services while
+    // running, they are only asked once.
+    pub async fn capabilities(&self, server_addr: &String) -> Result<Vec<Service>, RPCError> {
+        if let Some(services) = self.capabilities.read().get(server_addr) {
+            return Ok(services.clone());
+        }
+        let services = timed(self.timeout, async move {
+            let rpc_client = DEFAULT_CLIENT_POOL
+                .get(server_addr)
+                .await
+                .map_err(RPCError::IOError)?;
+            capabilities::AsyncServiceClient::new(capabilities::DEFAULT_SERVICE_ID, &rpc_client)
+                .capabilities()
+                .await
+        })
+        .await??;
+        self.capabilities
+            .write()
+            .insert(server_addr.clone(), services.clone());
+        Ok(services)
+    }
+    // Begin, run and conclude the transaction once, each step within the client timeout. Timed
+    // out conclusions are aborted at best effort.
+    async fn attempt_txn<'a, TFN, TR, RF>(
+        &self,
+        txn_client: &Arc<txn_server::manager::AsyncServiceClient>,
+        attempt: u32,
+        func: TFN,
+    ) -> Result<TR, TxnError>
+    where
+        TFN: FnOnce(Transaction) -> RF + 'a,
+        RF: Future<Output = Result<TR, TxnError>> + 'a,
+    {
+        let txn = timed(self.timeout, Self::begin_txn(txn_client, attempt))
+            .await
+            .map_err(TxnError::RPCError)??;
+        let exec_result = timed(self.timeout, func(txn.clone()))
+            .await
+            .unwrap_or_else(|e| Err(TxnError::RPCError(e)));
+        match timed(self.timeout, Self::conclude_txn(txn.clone(), exec_result)).await {
+            Ok(res) => res,
+            Err(e) => {
+                // The manager may still hold the transaction and its locks
+                let abort_result = timed(self.timeout, txn.abort()).await;
+                debug!("TXN CONCLUSION TIMED OUT, ABORT: {:?}", abort_result);
+                Err(TxnError::RPCError(e))
+            }
+        }
+    }
+    async fn begin_txn(
+        txn_client: &Arc<txn_server::manager::AsyncServiceClient>,
+        attempt: u32,
+    ) -> Result<Transaction, TxnError> {
+        let txn_id = match txn_client.begin().await {
+            Ok(Ok(id)) => id,
+            _ => return Err(TxnError::CannotBegin),
+        };
+        Ok(Transaction {
+            tid: txn_id,
+            state: Arc::new(StdCell::new(txn_server::TxnState::Started)),
+            client: txn_client.clone(),
+            attempt,
+        })
+    }
+    // Prepare and commit the transaction after the closure, abort it on any error
+    async fn conclude_txn<TR>(
+        txn: Transaction,
+        exec_result: Result<TR, TxnError>,
+    ) -> Result<TR, TxnError> {
+        let mut exec_value = None;
+        let mut txn_result = Ok(());
+        match exec_result {
+            Ok(val) => {
+                if txn.state.get() == txn_server::TxnState::Started {
+                    txn_result = txn.prepare().await;
+                    debug!("PREPARE STATE: {:?}", txn_result);
+                }
+                if txn_result.is_ok() && txn.state.get() == txn_server::TxnState::Prepared {
+                    txn_result = txn.commit().await;
+                    debug!("COMMIT STATE: {:?}", txn_result);
+                }
+                exec_value = Some(val);
+            }
+            Err(e) => txn_result = Err(e),
+        }
+        debug!("TXN CONCLUSION: {:?}", txn_result);
+        match txn_result {
+            Ok(()) => Ok(exec_value.unwrap()),
+            Err(TxnError::NotRealizable) => {
+                let abort_result = txn.abort().await;
+                debug!("TXN NOT REALIZABLE, ABORT: {:?}", abort_result);
+                Err(TxnError::NotRealizable)
+            }
+            Err(e) => {
+                // abort will always be an error to achieve early break
+                let abort_result = txn.abort().await;
+                debug!("TXN ERROR, ABORT: {:?}", abort_result);
+                Err(e)
+            }
+        }
+    }
+    // Schemas that cells cannot be written in are rejected before being sent to the cluster
     pub async fn new_schema_with_id(
         &self,
         schema: Schema,
-    ) -> Result<Result<(), NotifyError>, ExecError> {
+    ) -> Result<Result<(), SchemaError>, ExecError> {
+        if let Err(e) = schema.check() {
+            return Ok(Err(e));
+        }
         self.schema_client.new_schema(&schema).await
     }
     pub async fn new_schema(
         &self,
         mut schema: Schema,
-    ) -> Result<(u32, Option<NotifyError>), ExecError> {
+    ) -> Result<(u32, Option<SchemaError>), ExecError> {
         let schema_id = self.schema_client.next_id().await?;
         schema.id = schema_id;
         self.new_schema_with_id(schema).await.map(|r| {
@@ -285,4 +824,183 @@ impl AsyncClient {
     pub async fn get_all_schema(&self) -> Result<Vec<Schema>, ExecError> {
         self.schema_client.get_all().await
     }
+    // All schemas of the cluster, to be imported to another one by `import_schemas`
+    pub async fn export_schemas(&self) -> Result<Vec<Schema>, ExecError> {
+        self.get_all_schema().await
+    }
+    // Register the schemas with their ids in a single command, either all of them or none when
+    // any conflicts. Returns ids of the schemas that were not registered before.
+    pub async fn import_schemas(
+        &self,
+        schemas: Vec<Schema>,
+    ) -> Result<Result<Vec<u32>, SchemaError>, ExecError> {
+        self.schema_client.import_schemas(&schemas).await
+    }
+    // Append a nullable top level field to the schema of the name. Cells are not rewritten, the
+    // ones written before read the field as null.
+    pub async fn alter_schema_add_field(
+        &self,
+        name: String,
+        field: Field,
+    ) -> Result<Result<Schema, SchemaError>, ExecError> {
+        self.schema_client.add_field(&name, &field).await
+    }
+    // Schemas are filtered on the schema state machine, only the matching ones are sent back
+    pub async fn find_schemas(&self, filter: SchemaFilter) -> Result<Vec<Schema>, ExecError> {
+        self.schema_client.find(&filter).await
+    }
+    // Register the index of the field in the cluster schema, so all servers index new writes, then
+    // build it for the existing cells on every server. Returns the number of cells backfilled.
+    pub async fn create_index(
+        &self,
+        schema_id: u32,
+        field_id: u64,
+        index: IndexType,
+    ) -> Result<u64, CreateIndexError> {
+        let schema = self
+            .schema_client
+            .add_index(&schema_id, &field_id, &index)
+            .await
+            .map_err(CreateIndexError::ExecError)?
+            .map_err(CreateIndexError::SchemaError)?;
+        let backfill = timed(self.timeout, async move {
+            let members = self.online_server_ids().await?;
+            let schema = &schema;
+            let mut member_futs: FuturesUnordered<_> = members
+                .into_iter()
+                .map(|server_id| async move {
+                    let client = self.client_by_server_id(server_id).await?;
+                    Ok(client
+                        .backfill_index(schema.clone(), field_id, index)
+                        .await?)
+                })
+                .collect();
+            let mut sum = 0;
+            while let Some(res) = member_futs.next().await {
+                sum += res?;
+            }
+            Ok(sum)
+        })
+        .await;
+        backfill
+            .and_then(|res| res)
+            .map_err(CreateIndexError::BackfillError)
+    }
+    // Schema changes in the cluster after the call. The client subscribes to them on the first
+    // watch and until it is dropped. Watchers falling behind skip missed events.
+    pub async fn watch_schemas(&self) -> Result<impl Stream<Item = SchemaEvent>, NebClientError> {
+        let mut subscription = self.schema_events.lock().await;
+        if subscription.is_none() {
+            *subscription =
+                Some(subscribe_schema_events(&self.schema_client, &self.raft_client).await?);
+        }
+        let receiver = subscription.as_ref().unwrap().sender.subscribe();
+        Ok(stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((event, receiver)),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!("Schema watcher lagged, missed {} events", missed);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }))
+    }
+}
+
+impl Drop for AsyncClient {
+    fn drop(&mut self) {
+        let subscription = match self.schema_events.get_mut().take() {
+            Some(subscription) => subscription,
+            None => return,
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => {
+                let raft_client = self.raft_client.clone();
+                runtime.spawn(async move {
+                    unsubscribe_schema_events(&raft_client, subscription.receipts).await
+                });
+            }
+            Err(_) => warn!("Cannot unsubscribe schema events without a runtime"),
+        }
+    }
+}
+
+// Await the future within the timeout, if there is one
+pub(crate) async fn timed<F: Future>(
+    timeout: Option<Duration>,
+    fut: F,
+) -> Result<F::Output, RPCError> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, fut).await.map_err(|_| {
+            RPCError::IOError(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("operation timed out after {:?}", timeout),
+            ))
+        }),
+        None => Ok(fut.await),
+    }
+}
+
+struct SchemaSubscription {
+    sender: broadcast::Sender<SchemaEvent>,
+    receipts: Vec<SubscriptionReceipt>,
+}
+
+fn subscribed(
+    res: Result<Result<SubscriptionReceipt, SubscriptionError>, ExecError>,
+) -> Result<SubscriptionReceipt, NebClientError> {
+    match res {
+        Ok(Ok(receipt)) => Ok(receipt),
+        Ok(Err(err)) => Err(NebClientError::SubscriptionError(err)),
+        Err(err) => Err(NebClientError::SchemaSubscriptionError(err)),
+    }
+}
+
+// Forward schema changes from the cluster to all watchers of the client
+async fn subscribe_schema_events(
+    schema_client: &SchemaClient,
+    raft_client: &Arc<RaftClient>,
+) -> Result<SchemaSubscription, NebClientError> {
+    let (sender, _) = broadcast::channel(SCHEMA_EVENTS_CAPACITY);
+    let added = sender.clone();
+    let deleted = sender.clone();
+    // Sending fails only when nobody is watching
+    let added_receipt = subscribed(
+        schema_client
+            .on_schema_added(move |schema| {
+                let _ = added.send(SchemaEvent::Added(schema));
+                future::ready(()).boxed()
+            })
+            .await,
+    )?;
+    let deleted_receipt = match subscribed(
+        schema_client
+            .on_schema_deleted(move |name| {
+                let _ = deleted.send(SchemaEvent::Deleted(name));
+                future::ready(()).boxed()
+            })
+            .await,
+    ) {
+        Ok(receipt) => receipt,
+        Err(err) => {
+            unsubscribe_schema_events(raft_client, vec![added_receipt]).await;
+            return Err(err);
+        }
+    };
+    Ok(SchemaSubscription {
+        sender,
+        receipts: vec![added_receipt, deleted_receipt],
+    })
+}
+
+async fn unsubscribe_schema_events(
+    raft_client: &Arc<RaftClient>,
+    receipts: Vec<SubscriptionReceipt>,
+) {
+    for receipt in receipts {
+        let res = raft_client.unsubscribe(receipt).await;
+        debug!("Unsubscribed schema events, {:?}", res);
+    }
 }
@@ -1,15 +1,18 @@
 use crate::client;
-use crate::client::transaction::TxnError;
+use crate::client::transaction::{self, TxnError};
+use crate::ram::aggregate::Aggregation;
 use crate::ram::cell::*;
 use crate::ram::schema::*;
-use crate::ram::tests::default_fields;
+use crate::ram::tests::{default_fields, simple_fields};
 use crate::ram::types;
 use crate::ram::types::*;
 use crate::server::*;
+use bifrost_hasher::hash_str;
 use parking_lot::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use super::*;
 
@@ -26,6 +29,10 @@ pub async fn general() {
             backup_storage: None,
             wal_storage: None,
             index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
             services: vec![Service::Cell, Service::Transaction],
         },
         &server_addr,
@@ -161,6 +168,10 @@ pub async fn multi_cell_update() {
             backup_storage: None,
             wal_storage: None,
             index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
             services: vec![Service::Cell, Service::Transaction],
         },
         &server_addr,
@@ -254,6 +265,10 @@ pub async fn write_skew() {
             backup_storage: None,
             wal_storage: None,
             index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
             services: vec![Service::Cell, Service::Transaction],
         },
         &server_addr,
@@ -364,6 +379,10 @@ pub async fn server_isolation() {
             backup_storage: None,
             wal_storage: None,
             index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
             services: vec![Service::Cell, Service::Transaction],
         },
         server_address_1,
@@ -388,6 +407,10 @@ pub async fn server_isolation() {
             backup_storage: None,
             wal_storage: None,
             index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
             services: vec![Service::Cell, Service::Transaction],
         },
         server_address_2,
@@ -539,3 +562,1472 @@ pub async fn server_isolation() {
         "-score"
     );
 }
+
+#[test]
+pub fn id_strategies() {
+    use crate::client::id_strategy::*;
+    let hashed = Hashed;
+    assert_eq!(hashed.next_id(0, b"jack"), hashed.next_id(1, b"jack"));
+    assert_ne!(hashed.next_id(0, b"jack"), hashed.next_id(0, b"john"));
+    let sequential = Sequential::with_spread(1);
+    let id_1 = sequential.next_id(1, &[]);
+    let id_2 = sequential.next_id(1, &[]);
+    let id_3 = sequential.next_id(2, &[]);
+    assert_eq!(id_1.higher, 1);
+    assert_eq!(id_3.higher, 2);
+    assert!(id_2.lower > id_1.lower);
+    // Counters running out of their sequence move on to later seeds
+    let mut last = id_2;
+    for _ in 2..(1 << 20) + 1 {
+        let id = sequential.next_id(1, &[]);
+        assert!(id.lower > last.lower);
+        last = id;
+    }
+    assert_eq!(last.lower & ((1 << 20) - 1), 0);
+    assert!(last.lower >> 32 > id_1.lower >> 32);
+    // Clients created at the same time start apart
+    let seeds = (0..16)
+        .map(|_| Sequential::new().next_id(1, &[]))
+        .collect::<std::collections::HashSet<_>>();
+    assert!(seeds.len() > 1);
+    // Busy partitions are spread over several higher ids
+    let sequential = Sequential::new();
+    let highers = (0..DEFAULT_SEQUENTIAL_SPREAD << 10)
+        .map(|_| sequential.next_id(1, &[]).higher)
+        .collect::<std::collections::HashSet<_>>();
+    assert_eq!(highers.len() as u64, DEFAULT_SEQUENTIAL_SPREAD);
+}
+
+#[test]
+pub fn batch_result() {
+    use crate::client::batch::BatchResult;
+    let res: BatchResult<u32, &str> = vec![Ok(1), Err("a"), Ok(3), Err("b")].into();
+    assert_eq!(res.len(), 4);
+    assert!(!res.all_ok());
+    assert_eq!(res.successes().collect::<Vec<_>>(), vec![(0, &1), (2, &3)]);
+    assert_eq!(
+        res.failures().collect::<Vec<_>>(),
+        vec![(1, &"a"), (3, &"b")]
+    );
+    assert_eq!(res[2], Ok(3));
+    assert_eq!(res.get(4), None);
+    assert_eq!(format!("{}", res), "2 of 4 succeeded, 2 failed");
+    let all_ok: BatchResult<u32, &str> = vec![Ok(1), Ok(2)].into();
+    assert!(all_ok.all_ok());
+    assert_eq!(all_ok.into_iter().collect::<Vec<_>>(), vec![Ok(1), Ok(2)]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn batch_ops() {
+    let _ = env_logger::try_init();
+    let server_group = "batch_ops_test";
+    let server_addr = String::from("127.0.0.1:5405");
+    let server = NebServer::new_from_opts(
+        &ServerOptions {
+            chunk_count: 1,
+            memory_size: 16 * 1024 * 1024,
+            backup_storage: None,
+            wal_storage: None,
+            index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
+            services: vec![Service::Cell],
+        },
+        &server_addr,
+        &server_group,
+    )
+    .await;
+    let schema = Schema::new_with_id(
+        1,
+        &String::from("test"),
+        None,
+        default_fields(),
+        false,
+        false,
+    );
+    let client = Arc::new(
+        client::AsyncClient::new(
+            &server.rpc,
+            &server.membership,
+            &vec![server_addr],
+            server_group,
+        )
+        .await
+        .unwrap(),
+    );
+    let schema_id = client.new_schema(schema).await.unwrap().0;
+    let cells = (0..10)
+        .map(|i| {
+            let mut data_map = OwnedMap::new();
+            data_map.insert(&String::from("id"), OwnedValue::I64(i));
+            data_map.insert(&String::from("score"), OwnedValue::U64(i as u64));
+            data_map.insert(
+                &String::from("name"),
+                OwnedValue::String(String::from("Jack")),
+            );
+            OwnedCell::new_with_id(schema_id, &Id::rand(), OwnedValue::Map(data_map))
+        })
+        .collect::<Vec<_>>();
+    let ids = cells.iter().map(|c| c.id()).collect::<Vec<_>>();
+    let written = client.write_all_cells(cells.clone()).await.unwrap();
+    assert!(written.all_ok(), "{}", written);
+    // writing again will fail for all cells
+    let rewritten = client.write_all_cells(cells.clone()).await.unwrap();
+    assert_eq!(rewritten.failures().count(), cells.len());
+    let read = client.read_all_cells(ids.clone()).await.unwrap();
+    assert!(read.all_ok());
+    for (i, cell) in read.successes() {
+        assert_eq!(cell.id(), ids[i]);
+        assert_eq!(cell.data["id"].i64().unwrap(), &(i as i64));
+    }
+    let removed = client.remove_all_cells(ids[..5].to_vec()).await.unwrap();
+    assert!(removed.all_ok());
+    let read = client.read_all_cells(ids.clone()).await.unwrap();
+    assert_eq!(read.num_failures(), 5);
+    assert_eq!(
+        read.failures().map(|(i, _)| i).collect::<Vec<_>>(),
+        (0..5).collect::<Vec<_>>()
+    );
+    // Cells without ids get them from the id strategy of the client
+    let mut unnamed = cells[..2].to_vec();
+    unnamed
+        .iter_mut()
+        .for_each(|cell| cell.header.set_id(&Id::unit_id()));
+    let written = client.write_all_cells(unnamed).await.unwrap();
+    assert!(written.all_ok(), "{}", written);
+    for (_, header) in written.successes() {
+        assert!(!header.id().is_unit_id());
+        assert!(client.read_cell(header.id()).await.unwrap().is_ok());
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn transaction_once() {
+    let _ = env_logger::try_init();
+    let server_group = "transaction_once_test";
+    let server_addr = String::from("127.0.0.1:5406");
+    let server = NebServer::new_from_opts(
+        &ServerOptions {
+            chunk_count: 1,
+            memory_size: 16 * 1024 * 1024,
+            backup_storage: None,
+            wal_storage: None,
+            index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
+            services: vec![Service::Cell, Service::Transaction],
+        },
+        &server_addr,
+        &server_group,
+    )
+    .await;
+    let schema = Schema::new_with_id(
+        1,
+        &String::from("test"),
+        None,
+        default_fields(),
+        false,
+        false,
+    );
+    let client = Arc::new(
+        client::AsyncClient::new(
+            &server.rpc,
+            &server.membership,
+            &vec![server_addr],
+            server_group,
+        )
+        .await
+        .unwrap(),
+    );
+    let schema_id = client.new_schema(schema).await.unwrap().0;
+    let mut data_map = OwnedMap::new();
+    data_map.insert(&String::from("id"), OwnedValue::I64(1));
+    data_map.insert(&String::from("score"), OwnedValue::U64(10));
+    data_map.insert(
+        &String::from("name"),
+        OwnedValue::String(String::from("Jack")),
+    );
+    let cell = OwnedCell::new_with_id(schema_id, &Id::rand(), OwnedValue::Map(data_map));
+    let cell_id = cell.id();
+    let calls = Arc::new(AtomicUsize::new(0));
+    // The cell is moved into the future on the first call, so the closure is only `FnOnce` and
+    // would not be accepted by `transaction`, which needs to call it again on retry
+    let calls_clone = calls.clone();
+    client
+        .transaction_once(move |txn| {
+            calls_clone.fetch_add(1, Ordering::Relaxed);
+            async move { txn.write(cell).await }
+        })
+        .await
+        .unwrap();
+    assert_eq!(calls.load(Ordering::Relaxed), 1);
+    let stored = client.read_cell(cell_id).await.unwrap().unwrap();
+    assert_eq!(stored.data["score"].u64().unwrap(), &10);
+    // Errors from a one shot transaction are not retried
+    let calls_clone = calls.clone();
+    let aborted = client
+        .transaction_once(move |txn| {
+            calls_clone.fetch_add(1, Ordering::Relaxed);
+            async move { txn.abort().await }
+        })
+        .await;
+    assert!(matches!(aborted, Err(TxnError::Aborted(_))), "{:?}", aborted);
+    assert_eq!(calls.load(Ordering::Relaxed), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn cas_cell() {
+    let _ = env_logger::try_init();
+    let server_group = "cas_cell_test";
+    let server_addr = String::from("127.0.0.1:5407");
+    let server = NebServer::new_from_opts(
+        &ServerOptions {
+            chunk_count: 1,
+            memory_size: 16 * 1024 * 1024,
+            backup_storage: None,
+            wal_storage: None,
+            index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
+            services: vec![Service::Cell],
+        },
+        &server_addr,
+        &server_group,
+    )
+    .await;
+    let schema = Schema::new_with_id(
+        1,
+        &String::from("test"),
+        None,
+        default_fields(),
+        false,
+        false,
+    );
+    let client = Arc::new(
+        client::AsyncClient::new(
+            &server.rpc,
+            &server.membership,
+            &vec![server_addr],
+            server_group,
+        )
+        .await
+        .unwrap(),
+    );
+    let schema_id = client.new_schema(schema).await.unwrap().0;
+    let mut data_map = OwnedMap::new();
+    data_map.insert(&String::from("id"), OwnedValue::I64(1));
+    data_map.insert(&String::from("score"), OwnedValue::U64(10));
+    data_map.insert(
+        &String::from("name"),
+        OwnedValue::String(String::from("Jack")),
+    );
+    let cell = OwnedCell::new_with_id(schema_id, &Id::rand(), OwnedValue::Map(data_map));
+    let cell_id = cell.id();
+    client.write_cell(cell).await.unwrap().unwrap();
+    let (mut cell, version) = client
+        .read_cell_versioned(cell_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(version, cell.version());
+    // Someone else updated the cell after we read it
+    cell.data["score"] = OwnedValue::U64(20);
+    let header = client.update_cell(cell.clone()).await.unwrap().unwrap();
+    assert!(header.version > version);
+    // So CAS with the version we read fails
+    cell.data["score"] = OwnedValue::U64(30);
+    let stale = client.cas_cell(cell.clone(), version).await.unwrap();
+    assert!(
+        matches!(stale, Err(WriteError::CellVersionMismatch(v)) if v == header.version),
+        "{:?}",
+        stale
+    );
+    let stored = client.read_cell(cell_id).await.unwrap().unwrap();
+    assert_eq!(stored.data["score"].u64().unwrap(), &20);
+    // CAS with the current version succeeds and bumps the version
+    let (_, version) = client
+        .read_cell_versioned(cell_id)
+        .await
+        .unwrap()
+        .unwrap();
+    let cas_header = client.cas_cell(cell, version).await.unwrap().unwrap();
+    assert!(cas_header.version > version);
+    let stored = client.read_cell(cell_id).await.unwrap().unwrap();
+    assert_eq!(stored.data["score"].u64().unwrap(), &30);
+    assert_eq!(stored.version(), cas_header.version);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn transaction_barrier() {
+    let _ = env_logger::try_init();
+    let server_group = "transaction_barrier_test";
+    let server_addr = String::from("127.0.0.1:5408");
+    let server = NebServer::new_from_opts(
+        &ServerOptions {
+            chunk_count: 1,
+            memory_size: 16 * 1024 * 1024,
+            backup_storage: None,
+            wal_storage: None,
+            index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
+            services: vec![Service::Cell, Service::Transaction],
+        },
+        &server_addr,
+        &server_group,
+    )
+    .await;
+    let schema = Schema::new_with_id(
+        1,
+        &String::from("test"),
+        None,
+        default_fields(),
+        false,
+        false,
+    );
+    let client = Arc::new(
+        client::AsyncClient::new(
+            &server.rpc,
+            &server.membership,
+            &vec![server_addr],
+            server_group,
+        )
+        .await
+        .unwrap(),
+    );
+    let schema_id = client.new_schema(schema).await.unwrap().0;
+    let mut data_map = OwnedMap::new();
+    data_map.insert(&String::from("id"), OwnedValue::I64(1));
+    data_map.insert(&String::from("score"), OwnedValue::U64(10));
+    data_map.insert(
+        &String::from("name"),
+        OwnedValue::String(String::from("Jack")),
+    );
+    let cell = OwnedCell::new_with_id(schema_id, &Id::rand(), OwnedValue::Map(data_map));
+    let cell_id = cell.id();
+    client.write_cell(cell).await.unwrap().unwrap();
+    // Nothing to wait for
+    tokio::time::timeout(Duration::from_secs(1), client.barrier())
+        .await
+        .unwrap()
+        .unwrap();
+    let began = Arc::new(AtomicUsize::new(0));
+    let began_c = began.clone();
+    let client_c = client.clone();
+    let txn = tokio::spawn(async move {
+        client_c
+            .transaction_once(move |txn| async move {
+                let mut cell = txn.read(cell_id).await?.unwrap();
+                began_c.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_secs(2)).await;
+                cell.data["score"] = OwnedValue::U64(20);
+                txn.update(cell).await
+            })
+            .await
+            .unwrap();
+    });
+    while began.load(Ordering::SeqCst) == 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let barrier_start = std::time::Instant::now();
+    client.barrier().await.unwrap();
+    // The barrier waited for the long transaction to commit
+    assert!(barrier_start.elapsed() >= Duration::from_secs(1));
+    let stored = client.read_cell(cell_id).await.unwrap().unwrap();
+    assert_eq!(stored.data["score"].u64().unwrap(), &20);
+    txn.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn watch_schemas() {
+    let _ = env_logger::try_init();
+    let server_group = "watch_schemas_test";
+    let server_addr = String::from("127.0.0.1:5409");
+    let server = NebServer::new_from_opts(
+        &ServerOptions {
+            chunk_count: 1,
+            memory_size: 16 * 1024 * 1024,
+            backup_storage: None,
+            wal_storage: None,
+            index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
+            services: vec![Service::Cell],
+        },
+        &server_addr,
+        &server_group,
+    )
+    .await;
+    let meta_servers = vec![server_addr];
+    let ddl_client =
+        client::AsyncClient::new(&server.rpc, &server.membership, &meta_servers, server_group)
+            .await
+            .unwrap();
+    let watching_client =
+        client::AsyncClient::new(&server.rpc, &server.membership, &meta_servers, server_group)
+            .await
+            .unwrap();
+    let mut watcher_1 = Box::pin(watching_client.watch_schemas().await.unwrap());
+    let mut watcher_2 = Box::pin(watching_client.watch_schemas().await.unwrap());
+    // Only clients watching schemas subscribe to their changes
+    assert!(ddl_client.schema_events.lock().await.is_none());
+    assert_eq!(
+        watching_client
+            .schema_events
+            .lock()
+            .await
+            .as_ref()
+            .unwrap()
+            .receipts
+            .len(),
+        2
+    );
+    let schema = Schema::new_with_id(
+        1,
+        &String::from("watched"),
+        None,
+        default_fields(),
+        false,
+        false,
+    );
+    let schema_id = ddl_client.new_schema(schema).await.unwrap().0;
+    for watcher in vec![&mut watcher_1, &mut watcher_2] {
+        let event = tokio::time::timeout(Duration::from_secs(10), watcher.next())
+            .await
+            .unwrap();
+        match event {
+            Some(SchemaEvent::Added(added)) => {
+                assert_eq!(added.id, schema_id);
+                assert_eq!(added.name, "watched");
+            }
+            e => panic!("Expect schema added event, got {:?}", e),
+        }
+    }
+    ddl_client
+        .del_schema(String::from("watched"))
+        .await
+        .unwrap()
+        .unwrap();
+    let event = tokio::time::timeout(Duration::from_secs(10), watcher_1.next())
+        .await
+        .unwrap();
+    assert!(
+        matches!(event, Some(SchemaEvent::Deleted(ref name)) if name == "watched"),
+        "{:?}",
+        event
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn client_timeout() {
+    let _ = env_logger::try_init();
+    // An endpoint accepting connections without ever responding
+    let unresponsive_addr = String::from("127.0.0.1:5410");
+    let _listener = std::net::TcpListener::bind(&unresponsive_addr).unwrap();
+    let rpc_client = DEFAULT_CLIENT_POOL.get(&unresponsive_addr).await.unwrap();
+    let cell_client = client_by_rpc_client(&rpc_client);
+    let started = Instant::now();
+    let res = timed(Some(Duration::from_millis(200)), cell_client.count()).await;
+    assert!(
+        matches!(res, Err(RPCError::IOError(ref e)) if e.kind() == io::ErrorKind::TimedOut),
+        "{:?}",
+        res
+    );
+    assert!(started.elapsed() < Duration::from_secs(2));
+
+    let server_group = "client_timeout_test";
+    let server_addr = String::from("127.0.0.1:5411");
+    let server = NebServer::new_from_opts(
+        &ServerOptions {
+            chunk_count: 1,
+            memory_size: 16 * 1024 * 1024,
+            backup_storage: None,
+            wal_storage: None,
+            index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
+            services: vec![Service::Cell, Service::Transaction],
+        },
+        &server_addr,
+        &server_group,
+    )
+    .await;
+    let meta_servers = vec![server_addr];
+    let client =
+        client::AsyncClient::new(&server.rpc, &server.membership, &meta_servers, server_group)
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_millis(500));
+    let schema = Schema::new_with_id(
+        1,
+        &String::from("test"),
+        None,
+        default_fields(),
+        false,
+        false,
+    );
+    let schema_id = client.new_schema(schema).await.unwrap().0;
+    let mut data_map = OwnedMap::new();
+    data_map.insert(&String::from("id"), OwnedValue::I64(1));
+    data_map.insert(&String::from("score"), OwnedValue::U64(10));
+    data_map.insert(
+        &String::from("name"),
+        OwnedValue::String(String::from("Jack")),
+    );
+    let cell = OwnedCell::new_with_id(schema_id, &Id::rand(), OwnedValue::Map(data_map));
+    let cell_id = cell.id();
+    // Responsive servers are not affected
+    client.write_cell(cell).await.unwrap().unwrap();
+    client.read_cell(cell_id).await.unwrap().unwrap();
+    // A transaction running out of time is aborted rather than retried
+    let calls = AtomicUsize::new(0);
+    let started = Instant::now();
+    let res = client
+        .transaction(|txn| {
+            calls.fetch_add(1, Ordering::Relaxed);
+            async move {
+                tokio::time::sleep(Duration::from_secs(2)).await;
+                txn.read(cell_id).await
+            }
+        })
+        .await;
+    assert!(
+        matches!(res, Err(TxnError::RPCError(RPCError::IOError(ref e))) if e.kind() == io::ErrorKind::TimedOut),
+        "{:?}",
+        res
+    );
+    assert_eq!(calls.load(Ordering::Relaxed), 1);
+    assert!(started.elapsed() < Duration::from_secs(2));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn transient_txn_errors() {
+    let _ = env_logger::try_init();
+    let server_group = "transient_txn_errors_test";
+    let server_addr = String::from("127.0.0.1:5412");
+    let server = NebServer::new_from_opts(
+        &ServerOptions {
+            chunk_count: 1,
+            memory_size: 16 * 1024 * 1024,
+            backup_storage: None,
+            wal_storage: None,
+            index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
+            services: vec![Service::Cell, Service::Transaction],
+        },
+        &server_addr,
+        &server_group,
+    )
+    .await;
+    let schema = Schema::new_with_id(
+        1,
+        &String::from("test"),
+        None,
+        default_fields(),
+        false,
+        false,
+    );
+    let client = Arc::new(
+        client::AsyncClient::new(
+            &server.rpc,
+            &server.membership,
+            &vec![server_addr],
+            server_group,
+        )
+        .await
+        .unwrap(),
+    );
+    let schema_id = client.new_schema(schema).await.unwrap().0;
+    let cell = OwnedCell::new_with_id(
+        schema_id,
+        &Id::rand(),
+        data_map_value! {
+            id: 1 as i64,
+            score: 10 as u64,
+            name: "Jack"
+        },
+    );
+    let cell_id = cell.id();
+    let io_error = |kind| TxnError::IoError(std::io::Error::new(kind, "injected"));
+    // The failed prepare surfaces as a retryable IO error, and nothing is written
+    let res = client
+        .transaction_once(|txn| {
+            let cell = cell.clone();
+            transaction::fail_prepare(&txn.tid);
+            async move { txn.write(cell).await }
+        })
+        .await;
+    match &res {
+        Err(e @ TxnError::IoError(_)) => assert!(e.is_transient()),
+        _ => panic!("{:?}", res),
+    }
+    assert!(client.read_cell(cell_id).await.unwrap().is_err());
+    // The prepare fails on the first run, after the write, and the transaction is run again
+    let calls = AtomicUsize::new(0);
+    client
+        .transaction(|txn| {
+            let cell = cell.clone();
+            if calls.fetch_add(1, Ordering::Relaxed) == 0 {
+                transaction::fail_prepare(&txn.tid);
+            }
+            async move { txn.write(cell).await }
+        })
+        .await
+        .unwrap();
+    assert_eq!(calls.load(Ordering::Relaxed), 2);
+    let stored = client.read_cell(cell_id).await.unwrap().unwrap();
+    assert_eq!(stored.data["score"].u64().unwrap(), &10);
+    // Other errors are returned without retrying
+    let calls = AtomicUsize::new(0);
+    let res = client
+        .transaction(|_txn| {
+            calls.fetch_add(1, Ordering::Relaxed);
+            future::ready(Err::<(), _>(io_error(std::io::ErrorKind::TimedOut)))
+        })
+        .await;
+    assert!(matches!(res, Err(TxnError::IoError(_))), "{:?}", res);
+    assert_eq!(calls.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn find_schemas() {
+    let _ = env_logger::try_init();
+    let server_group = "find_schemas_test";
+    let server_addr = String::from("127.0.0.1:5413");
+    let server = NebServer::new_from_opts(
+        &ServerOptions {
+            chunk_count: 1,
+            memory_size: 16 * 1024 * 1024,
+            backup_storage: None,
+            wal_storage: None,
+            index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
+            services: vec![Service::Cell],
+        },
+        &server_addr,
+        &server_group,
+    )
+    .await;
+    let client = client::AsyncClient::new(
+        &server.rpc,
+        &server.membership,
+        &vec![server_addr],
+        server_group,
+    )
+    .await
+    .unwrap();
+    let schemas = vec![
+        (1, "user_accounts", default_fields(), true),
+        (2, "user_sessions", default_fields(), false),
+        (3, "orders", default_fields(), true),
+        (4, "counters", simple_fields(), true),
+    ];
+    for (id, name, fields, is_scannable) in schemas {
+        let schema = Schema::new_with_id(id, name, None, fields, false, is_scannable);
+        client.new_schema_with_id(schema).await.unwrap().unwrap();
+    }
+    let names = |schemas: Vec<Schema>| {
+        let mut names = schemas.into_iter().map(|s| s.name).collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+    let by_prefix = client
+        .find_schemas(SchemaFilter {
+            name_prefix: Some("user_".to_string()),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(names(by_prefix), vec!["user_accounts", "user_sessions"]);
+    let scannable = client
+        .find_schemas(SchemaFilter {
+            is_scannable: Some(true),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(
+        names(scannable),
+        vec!["counters", "orders", "user_accounts"]
+    );
+    let scannable_users = client
+        .find_schemas(SchemaFilter {
+            name_prefix: Some("user_".to_string()),
+            is_scannable: Some(true),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(names(scannable_users), vec!["user_accounts"]);
+    let with_strings = client
+        .find_schemas(SchemaFilter {
+            field_type: Some(Type::String),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(with_strings.len(), 3);
+    // The root of simple fields is not a field of the schema
+    assert!(client
+        .find_schemas(SchemaFilter {
+            field_type: Some(Type::U64),
+            name_prefix: Some("counters".to_string()),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .is_empty());
+    // Schemas too large for cells are not registered, by the client or the schema state machine
+    let huge_fields = Field::new(
+        "*",
+        Type::Map,
+        false,
+        false,
+        Some(
+            (0..128 * 1024)
+                .map(|i| Field::new(&format!("f{}", i), Type::U64, false, false, None, vec![]))
+                .collect(),
+        ),
+        vec![],
+    );
+    let huge = Schema::new_with_id(5, "huge", None, huge_fields, false, false);
+    assert!(matches!(
+        client.new_schema_with_id(huge.clone()).await.unwrap(),
+        Err(SchemaError::CellTooLarge { .. })
+    ));
+    assert!(matches!(
+        client.schema_client.new_schema(&huge).await.unwrap(),
+        Err(SchemaError::CellTooLarge { .. })
+    ));
+    assert!(client
+        .find_schemas(SchemaFilter {
+            name_prefix: Some("huge".to_string()),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn unsupported_operations() {
+    let _ = env_logger::try_init();
+    let server_group = "unsupported_operations_test";
+    let server_addr = String::from("127.0.0.1:5414");
+    let server = NebServer::new_from_opts(
+        &ServerOptions {
+            chunk_count: 1,
+            memory_size: 16 * 1024 * 1024,
+            backup_storage: None,
+            wal_storage: None,
+            index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
+            services: vec![Service::Cell],
+        },
+        &server_addr,
+        &server_group,
+    )
+    .await;
+    let client = client::AsyncClient::new(
+        &server.rpc,
+        &server.membership,
+        &vec![server_addr.clone()],
+        server_group,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        client.capabilities(&server_addr).await.unwrap(),
+        vec![Service::Cell]
+    );
+    let schema_id = client
+        .new_schema(Schema::new("test", None, default_fields(), false, false))
+        .await
+        .unwrap()
+        .0;
+    // Cells are still served
+    let cell = OwnedCell::new_with_id(
+        schema_id,
+        &Id::rand(),
+        data_map_value! {
+            id: 1 as i64,
+            score: 10 as u64,
+            name: "Jack"
+        },
+    );
+    let cell_id = cell.id();
+    client.write_cell(cell).await.unwrap().unwrap();
+    let calls = AtomicUsize::new(0);
+    let res = client
+        .transaction(|txn| {
+            calls.fetch_add(1, Ordering::Relaxed);
+            async move { txn.read(cell_id).await }
+        })
+        .await;
+    assert!(
+        matches!(
+            res,
+            Err(TxnError::UnsupportedOperation(Service::Transaction))
+        ),
+        "{:?}",
+        res
+    );
+    // Not retried, and the closure never runs
+    assert_eq!(calls.load(Ordering::Relaxed), 0);
+    assert!(matches!(
+        client
+            .transaction_once(|txn| async move { txn.read(cell_id).await })
+            .await,
+        Err(TxnError::UnsupportedOperation(Service::Transaction))
+    ));
+    // Transactions go to the servers offering them once there are any
+    let txn_server_addr = String::from("127.0.0.1:5422");
+    let txn_server = NebServer::new_cluster_from_opts(
+        &ServerOptions {
+            chunk_count: 1,
+            memory_size: 16 * 1024 * 1024,
+            backup_storage: None,
+            wal_storage: None,
+            index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
+            services: vec![Service::Cell, Service::Transaction],
+        },
+        &txn_server_addr,
+        &vec![server_addr.clone()],
+        &server_group,
+    )
+    .await;
+    let client = client::AsyncClient::new(
+        &txn_server.rpc,
+        &txn_server.membership,
+        &vec![server_addr.clone()],
+        server_group,
+    )
+    .await
+    .unwrap();
+    for _ in 0..16 {
+        let read = client
+            .transaction(|txn| async move { txn.read(cell_id).await })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read.data["score"].u64().unwrap(), &10);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn read_cell_stream() {
+    let _ = env_logger::try_init();
+    let server_group = "read_cell_stream_test";
+    let server_addr = String::from("127.0.0.1:5415");
+    let server = NebServer::new_from_opts(
+        &ServerOptions {
+            chunk_count: 1,
+            memory_size: 16 * 1024 * 1024,
+            backup_storage: None,
+            wal_storage: None,
+            index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
+            services: vec![Service::Cell],
+        },
+        &server_addr,
+        &server_group,
+    )
+    .await;
+    let client = client::AsyncClient::new(
+        &server.rpc,
+        &server.membership,
+        &vec![server_addr.clone()],
+        server_group,
+    )
+    .await
+    .unwrap()
+    .with_read_frame_size(4096);
+    let schema_id = client
+        .new_schema(Schema::new("test", None, default_fields(), false, false))
+        .await
+        .unwrap()
+        .0;
+    let small = OwnedCell::new_with_id(
+        schema_id,
+        &Id::rand(),
+        data_map_value! {
+            id: 1 as i64,
+            score: 10 as u64,
+            name: "Jack"
+        },
+    );
+    let large_name = (0..100 * 1024)
+        .map(|i| (b'a' + (i % 26) as u8) as char)
+        .collect::<String>();
+    let large = OwnedCell::new_with_id(
+        schema_id,
+        &Id::rand(),
+        data_map_value! {
+            id: 2 as i64,
+            score: 20 as u64,
+            name: large_name.clone()
+        },
+    );
+    let large_id = large.id();
+    for cell in vec![small, large] {
+        let cell_id = cell.id();
+        client.write_cell(cell).await.unwrap().unwrap();
+        let expected = client.read_cell(cell_id).await.unwrap().unwrap();
+        let streamed = client.read_cell_stream(cell_id).await.unwrap().unwrap();
+        assert_eq!(streamed.id(), cell_id);
+        assert_eq!(streamed.header.version, expected.header.version);
+        for field in &["id", "score", "name"] {
+            assert_eq!(
+                bincode::serialize(&streamed.data[*field]).unwrap(),
+                bincode::serialize(&expected.data[*field]).unwrap()
+            );
+        }
+    }
+    let large = client.read_cell_stream(large_id).await.unwrap().unwrap();
+    assert_eq!(large.data["name"].string().unwrap(), &large_name);
+    assert!(matches!(
+        client.read_cell_stream(Id::rand()).await.unwrap(),
+        Err(ReadError::CellDoesNotExisted)
+    ));
+    let empty_frame_client = client::AsyncClient::new(
+        &server.rpc,
+        &server.membership,
+        &vec![server_addr.clone()],
+        server_group,
+    )
+    .await
+    .unwrap()
+    .with_read_frame_size(0);
+    assert!(matches!(
+        empty_frame_client.read_cell_stream(large_id).await.unwrap(),
+        Err(ReadError::InvalidFrameSize)
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn schema_export_import() {
+    let _ = env_logger::try_init();
+    let mut clients = vec![];
+    let mut servers = vec![];
+    for (server_group, server_addr) in &[
+        ("schema_export_test", "127.0.0.1:5416"),
+        ("schema_import_test", "127.0.0.1:5417"),
+    ] {
+        let server_addr = server_addr.to_string();
+        let server = NebServer::new_from_opts(
+            &ServerOptions {
+                chunk_count: 1,
+                memory_size: 16 * 1024 * 1024,
+                backup_storage: None,
+                wal_storage: None,
+                index_enabled: false,
+                cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+                ready_timeout: DEFAULT_READY_TIMEOUT,
+                admission: AdmissionConfig::default(),
+                cell_index_capacity: default_cell_index_capacity(),
+                services: vec![Service::Cell],
+            },
+            &server_addr,
+            server_group,
+        )
+        .await;
+        let client = client::AsyncClient::new(
+            &server.rpc,
+            &server.membership,
+            &vec![server_addr],
+            server_group,
+        )
+        .await
+        .unwrap();
+        servers.push(server);
+        clients.push(client);
+    }
+    let (source, target) = (&clients[0], &clients[1]);
+    for (id, name) in &[(11, "players"), (12, "teams"), (13, "matches")] {
+        source
+            .new_schema_with_id(Schema::new_with_id(
+                *id,
+                name,
+                None,
+                default_fields(),
+                false,
+                false,
+            ))
+            .await
+            .unwrap()
+            .unwrap();
+    }
+    let exported = source.export_schemas().await.unwrap();
+    assert_eq!(exported.len(), 3);
+    // Exported schemas can be carried over in a portable format
+    let json = serde_json::to_string(&exported).unwrap();
+    let schemas: Vec<Schema> = serde_json::from_str(&json).unwrap();
+    // A schema taking one of the ids and another taking one of the names fail the whole import
+    target
+        .new_schema_with_id(Schema::new_with_id(
+            12,
+            "other",
+            None,
+            default_fields(),
+            false,
+            false,
+        ))
+        .await
+        .unwrap()
+        .unwrap();
+    target
+        .new_schema_with_id(Schema::new_with_id(
+            20,
+            "matches",
+            None,
+            default_fields(),
+            false,
+            false,
+        ))
+        .await
+        .unwrap()
+        .unwrap();
+    match target.import_schemas(schemas.clone()).await.unwrap() {
+        Err(SchemaError::ImportConflicts(mut conflicts)) => {
+            conflicts.sort_by_key(|c| format!("{:?}", c));
+            assert_eq!(
+                conflicts,
+                vec![
+                    SchemaConflict::IdTaken {
+                        id: 12,
+                        name: "other".to_string()
+                    },
+                    SchemaConflict::NameTaken {
+                        name: "matches".to_string(),
+                        id: 20
+                    },
+                ]
+            );
+        }
+        res => panic!("Expected conflicts, got {:?}", res),
+    }
+    assert_eq!(target.get_all_schema().await.unwrap().len(), 2);
+    // Schemas cells cannot be written in fail the whole import as well
+    let duplicated = Field::new(
+        "*",
+        Type::Map,
+        false,
+        false,
+        Some(vec![
+            Field::new("id", Type::I64, false, false, None, vec![]),
+            Field::new("id", Type::U64, false, false, None, vec![]),
+        ]),
+        vec![],
+    );
+    let invalid = vec![
+        Schema::new_with_id(30, "valid", None, default_fields(), false, false),
+        Schema::new_with_id(31, "invalid", None, duplicated, false, false),
+    ];
+    assert!(matches!(
+        target.import_schemas(invalid).await.unwrap(),
+        Err(SchemaError::InvalidField { .. })
+    ));
+    assert_eq!(target.get_all_schema().await.unwrap().len(), 2);
+    target
+        .del_schema("other".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    target
+        .del_schema("matches".to_string())
+        .await
+        .unwrap()
+        .unwrap();
+    let mut imported = target
+        .import_schemas(schemas.clone())
+        .await
+        .unwrap()
+        .unwrap();
+    imported.sort();
+    assert_eq!(imported, vec![11, 12, 13]);
+    // Importing again is a no-op
+    assert_eq!(
+        target.import_schemas(schemas).await.unwrap().unwrap(),
+        Vec::<u32>::new()
+    );
+    let mut names = target
+        .export_schemas()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|s| (s.id, s.name))
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(
+        names,
+        vec![
+            (11, "players".to_string()),
+            (12, "teams".to_string()),
+            (13, "matches".to_string())
+        ]
+    );
+    // Servers of the target group can serve cells of the imported schemas
+    let cell = OwnedCell::new_with_id(
+        11,
+        &Id::rand(),
+        data_map_value! {
+            id: 1 as i64,
+            score: 10 as u64,
+            name: "Jack"
+        },
+    );
+    let cell_id = cell.id();
+    target.write_cell(cell).await.unwrap().unwrap();
+    assert_eq!(
+        target.read_cell(cell_id).await.unwrap().unwrap().data["name"]
+            .string()
+            .unwrap(),
+        "Jack"
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn aggregate_grouped() {
+    let _ = env_logger::try_init();
+    let server_group = "aggregate_grouped_test";
+    let server_addr = String::from("127.0.0.1:5418");
+    let server = NebServer::new_from_opts(
+        &ServerOptions {
+            chunk_count: 4,
+            memory_size: 64 * 1024 * 1024,
+            backup_storage: None,
+            wal_storage: None,
+            index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
+            services: vec![Service::Cell],
+        },
+        &server_addr,
+        &server_group,
+    )
+    .await;
+    let client = client::AsyncClient::new(
+        &server.rpc,
+        &server.membership,
+        &vec![server_addr.clone()],
+        server_group,
+    )
+    .await
+    .unwrap();
+    let fields = Field::new(
+        "*",
+        Type::Map,
+        false,
+        false,
+        Some(vec![
+            Field::new("category", Type::String, false, false, None, vec![]),
+            Field::new("amount", Type::U64, false, false, None, vec![]),
+        ]),
+        vec![],
+    );
+    let schema_id = client
+        .new_schema(Schema::new("orders", None, fields, false, false))
+        .await
+        .unwrap()
+        .0;
+    let categories = ["books", "games", "music"];
+    let num_cells = 600u64;
+    for n in 0..num_cells {
+        let cell = OwnedCell::new_with_id(
+            schema_id,
+            &Id::new(n % 4, n + 1),
+            data_map_value! {
+                category: categories[(n % 3) as usize],
+                amount: n
+            },
+        );
+        client.write_cell(cell).await.unwrap().unwrap();
+    }
+    let category_id = hash_str("category");
+    let amount_id = hash_str("amount");
+    let expected_sum = |i: u64| (0..num_cells).filter(|n| n % 3 == i).sum::<u64>() as f64;
+    let sums = client
+        .aggregate_grouped(schema_id, category_id, amount_id, Aggregation::Sum)
+        .await
+        .unwrap();
+    assert!(sums.overflow.is_none());
+    assert_eq!(sums.groups.len(), 3);
+    for (i, category) in categories.iter().enumerate() {
+        let res = &sums.groups[&OwnedValue::String(category.to_string())];
+        assert_eq!(res.count, num_cells / 3);
+        assert_eq!(res.value(), Some(expected_sum(i as u64)));
+    }
+    let maxes = client
+        .aggregate_grouped(schema_id, category_id, amount_id, Aggregation::Max)
+        .await
+        .unwrap();
+    assert_eq!(
+        maxes.groups[&OwnedValue::String("music".to_string())].value(),
+        Some((num_cells - 1) as f64)
+    );
+    // Groups beyond the bound are aggregated together
+    let client = client.with_max_agg_groups(2);
+    let counts = client
+        .aggregate_grouped(schema_id, category_id, amount_id, Aggregation::Count)
+        .await
+        .unwrap();
+    assert_eq!(counts.groups.len(), 2);
+    let overflow = counts.overflow.unwrap();
+    assert!(overflow.count > 0);
+    let total: u64 = counts.groups.values().map(|r| r.count).sum::<u64>() + overflow.count;
+    assert_eq!(total, num_cells);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn locate_chunk() {
+    let _ = env_logger::try_init();
+    let server_group = "locate_chunk_test";
+    let server_addr = String::from("127.0.0.1:5419");
+    let server = NebServer::new_from_opts(
+        &ServerOptions {
+            chunk_count: 4,
+            memory_size: 64 * 1024 * 1024,
+            backup_storage: None,
+            wal_storage: None,
+            index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
+            services: vec![Service::Cell],
+        },
+        &server_addr,
+        &server_group,
+    )
+    .await;
+    let client = client::AsyncClient::new(
+        &server.rpc,
+        &server.membership,
+        &vec![server_addr.clone()],
+        server_group,
+    )
+    .await
+    .unwrap();
+    let schema = Schema::new_with_id(1, "located", None, default_fields(), false, false);
+    client
+        .new_schema_with_id(schema.clone())
+        .await
+        .unwrap()
+        .unwrap();
+    for partition in 0..16 {
+        let id = Id::new(partition, partition + 1);
+        let cell = OwnedCell::new_with_id(
+            schema.id,
+            &id,
+            data_map_value! {
+                id: partition as i64,
+                score: partition,
+                name: "Jack"
+            },
+        );
+        client.write_cell(cell).await.unwrap().unwrap();
+        let (server_id, chunk_id) = client.locate_chunk(id).await.unwrap();
+        assert_eq!(server_id, server.server_id);
+        assert_eq!(server.get_server_id_by_id(&id), Some(server_id));
+        assert_eq!(chunk_id, server.chunks.chunk_id_for(&id));
+        // The cell is in the reported chunk and nowhere else
+        for (n, chunk) in server.chunks.list.iter().enumerate() {
+            assert_eq!(chunk.location_for_read(id.lower).is_ok(), n == chunk_id);
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn remove_cell_if_version() {
+    let _ = env_logger::try_init();
+    let server_group = "remove_if_version_test";
+    let server_addr = String::from("127.0.0.1:5420");
+    let server = NebServer::new_from_opts(
+        &ServerOptions {
+            chunk_count: 1,
+            memory_size: 16 * 1024 * 1024,
+            backup_storage: None,
+            wal_storage: None,
+            index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
+            services: vec![Service::Cell],
+        },
+        &server_addr,
+        &server_group,
+    )
+    .await;
+    let client = client::AsyncClient::new(
+        &server.rpc,
+        &server.membership,
+        &vec![server_addr],
+        server_group,
+    )
+    .await
+    .unwrap();
+    let schema = Schema::new_with_id(1, "removing", None, default_fields(), false, false);
+    let schema_id = client.new_schema(schema).await.unwrap().0;
+    let cell = OwnedCell::new_with_id(
+        schema_id,
+        &Id::rand(),
+        data_map_value! {
+            id: 1 as i64,
+            score: 10 as u64,
+            name: "Jack"
+        },
+    );
+    let cell_id = cell.id();
+    client.write_cell(cell).await.unwrap().unwrap();
+    let (mut cell, version) = client.read_cell_versioned(cell_id).await.unwrap().unwrap();
+    // Someone else updated the cell after we read it
+    cell.data["score"] = OwnedValue::U64(20);
+    let header = client.update_cell(cell).await.unwrap().unwrap();
+    assert!(header.version > version);
+    // So the delete with the version we read fails and the update is kept
+    let stale = client
+        .remove_cell_if_version(cell_id, version)
+        .await
+        .unwrap();
+    assert!(
+        matches!(stale, Err(WriteError::CellVersionMismatch(v)) if v == header.version),
+        "{:?}",
+        stale
+    );
+    let stored = client.read_cell(cell_id).await.unwrap().unwrap();
+    assert_eq!(stored.data["score"].u64().unwrap(), &20);
+    // Delete with the current version succeeds
+    client
+        .remove_cell_if_version(cell_id, header.version)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(
+        client.read_cell(cell_id).await.unwrap(),
+        Err(ReadError::CellDoesNotExisted)
+    ));
+    assert!(matches!(
+        client
+            .remove_cell_if_version(cell_id, header.version)
+            .await
+            .unwrap(),
+        Err(WriteError::CellDoesNotExisted)
+    ));
+}
+
+#[test]
+pub fn txn_backoff_bounds() {
+    let base = Duration::from_millis(2);
+    let cap = Duration::from_millis(50);
+    for retried in 0..64 {
+        let expected = (base * 2u32.pow(retried.min(20))).min(cap);
+        let backoff = txn_backoff(base, cap, retried);
+        assert!(backoff >= expected / 2, "{} {:?}", retried, backoff);
+        assert!(backoff <= expected, "{} {:?}", retried, backoff);
+    }
+    assert_eq!(
+        txn_backoff(Duration::from_millis(0), cap, 10),
+        Duration::from_millis(0)
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn read_cell_selected() {
+    let _ = env_logger::try_init();
+    let server_group = "read_cell_selected_test";
+    let server_addr = String::from("127.0.0.1:5421");
+    let server = NebServer::new_from_opts(
+        &ServerOptions {
+            chunk_count: 1,
+            memory_size: 16 * 1024 * 1024,
+            backup_storage: None,
+            wal_storage: None,
+            index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
+            services: vec![Service::Cell],
+        },
+        &server_addr,
+        &server_group,
+    )
+    .await;
+    let client = client::AsyncClient::new(
+        &server.rpc,
+        &server.membership,
+        &vec![server_addr.clone()],
+        server_group,
+    )
+    .await
+    .unwrap();
+    let schema_id = client
+        .new_schema(Schema::new("test", None, default_fields(), false, false))
+        .await
+        .unwrap()
+        .0;
+    let cell = OwnedCell::new_with_id(
+        schema_id,
+        &Id::rand(),
+        data_map_value! {
+            id: 1 as i64,
+            score: 10 as u64,
+            name: "Jack"
+        },
+    );
+    let cell_id = cell.id();
+    client.write_cell(cell).await.unwrap().unwrap();
+    let values = client
+        .read_cell_selected(cell_id, vec![hash_str("name"), hash_str("score")])
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(values.len(), 2);
+    assert_eq!(values[0].string().unwrap(), "Jack");
+    assert_eq!(values[1].u64().unwrap(), &10);
+    let values = client
+        .read_cell_selected(cell_id, vec![hash_str("id")])
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(values.len(), 1);
+    assert_eq!(values[0].i64().unwrap(), &1);
+    assert!(matches!(
+        client
+            .read_cell_selected(Id::rand(), vec![hash_str("id")])
+            .await
+            .unwrap(),
+        Err(ReadError::CellDoesNotExisted)
+    ));
+}
@@ -2,6 +2,7 @@ use crate::ram::cell::{Cell, CellHeader, OwnedCell, ReadError, WriteError};
 use crate::ram::types::{Id, Value};
 use crate::server::transactions::TxnId;
 use crate::server::transactions::*;
+use crate::server::Service;
 use std::cell::Cell as StdCell;
 use std::io;
 use std::sync::Arc;
@@ -25,6 +26,50 @@ pub enum TxnError {
     PrepareError(TMPrepareResult),
     CommitError(EndResult),
     AbortError(AbortResult),
+    // The server picked for the transaction does not offer the service
+    UnsupportedOperation(Service),
+}
+
+impl TxnError {
+    // Connection failures of the prepare that may go away when the transaction is run again, like
+    // a server being restarted. Time outs and failures of other steps are not transient.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            TxnError::IoError(e) => match e.kind() {
+                io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::UnexpectedEof => true,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+// Transactions whose prepare fails like the manager went unreachable, consumed by the prepare
+#[cfg(test)]
+lazy_static! {
+    static ref FAILING_PREPARES: parking_lot::Mutex<Vec<TxnId>> = parking_lot::Mutex::new(vec![]);
+}
+
+// Fail the next prepare of the transaction with a connection reset, before it reaches the manager
+#[cfg(test)]
+pub fn fail_prepare(tid: &TxnId) {
+    FAILING_PREPARES.lock().push(tid.to_owned());
+}
+
+#[cfg(test)]
+fn injected_prepare_failure(tid: &TxnId) -> Option<RPCError> {
+    let mut failing = FAILING_PREPARES.lock();
+    let pos = failing.iter().position(|failing_tid| failing_tid == tid)?;
+    failing.remove(pos);
+    Some(RPCError::IOError(io::Error::new(
+        io::ErrorKind::ConnectionReset,
+        "injected prepare failure",
+    )))
 }
 
 #[derive(Clone)]
@@ -32,6 +77,8 @@ pub struct Transaction {
     pub tid: TxnId,
     pub state: Arc<StdCell<TxnState>>,
     pub client: Arc<manager::AsyncServiceClient>,
+    // Times the transaction closure has been retried before this attempt, starts from 0
+    pub attempt: u32,
 }
 
 unsafe impl Send for Transaction {}
@@ -118,7 +165,18 @@ impl Transaction {
     }
     pub async fn prepare(&self) -> Result<(), TxnError> {
         self.state.set(TxnState::Prepared);
-        match self.client.prepare(self.tid.to_owned()).await {
+        #[cfg(test)]
+        {
+            if let Some(e) = injected_prepare_failure(&self.tid) {
+                return Self::prepare_result(Err(e));
+            }
+        }
+        Self::prepare_result(self.client.prepare(self.tid.to_owned()).await)
+    }
+    fn prepare_result(
+        res: Result<Result<TMPrepareResult, TMError>, RPCError>,
+    ) -> Result<(), TxnError> {
+        match res {
             Ok(Ok(TMPrepareResult::Success)) => return Ok(()),
             Ok(Ok(TMPrepareResult::DMPrepareError(DMPrepareResult::NotRealizable))) => {
                 Err(TxnError::NotRealizable)
@@ -128,6 +186,8 @@ impl Transaction {
             }
             Ok(Ok(rpr)) => Err(TxnError::PrepareError(rpr)),
             Ok(Err(tme)) => Err(TxnError::ManagerError(tme)),
+            // Nothing is committed yet, the transaction can be run again
+            Err(RPCError::IOError(e)) => Err(TxnError::IoError(e)),
             Err(e) => Err(TxnError::RPCError(e)),
         }
     }
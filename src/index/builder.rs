@@ -1,6 +1,6 @@
-use super::{EntryKey, Feature, IndexerClients};
+use super::{EntryKey, Feature, IndexerClients, FEATURE_SIZE};
 use crate::ram::cell::{OwnedCell, SharedCell};
-use crate::ram::types::{Id, Value};
+use crate::ram::types::{Id, OwnedValue, Value};
 use crate::ram::{
     cell::Cell,
     schema::{Field, IndexType, Schema},
@@ -54,6 +54,7 @@ pub enum IndexMeta {
 
 pub enum IndexComps {
     Ranged(Feature),
+    RangedDesc(Feature),
     Hashed(Feature),
     Vectorized(Feature, u8),
 }
@@ -228,11 +229,129 @@ impl IndexBuilder {
     }
 }
 
+// Descending index keys carry the bitwise inverted feature so they sort in reverse.
+// The inversion is its own inverse, use it to both encode and decode the feature.
+#[inline]
+pub fn desc_feature(feature: &Feature) -> Feature {
+    let mut res = *feature;
+    res.iter_mut().for_each(|b| *b = !*b);
+    res
+}
+
+// Field id of the descending index in its keys, apart from the ascending index of the field
+pub fn desc_index_id(field_id: u64) -> u64 {
+    hash_str(&format!("{},desc", field_id))
+}
+
+// Key to seek the descending index of the field from the feature, forward scans from it go down
+// from the feature
+pub fn desc_seek_key(schema_id: u32, field_id: u64, from: &Feature) -> EntryKey {
+    EntryKey::from_props(
+        &Id::new(0, 0),
+        &desc_feature(from),
+        desc_index_id(field_id),
+        schema_id,
+    )
+}
+
+// Enum fields are indexed by their ordinals, ranges follow the order of the declared values
+fn enum_ordinal(schema: &Schema, field_id: u64, value: &dyn Value) -> Option<OwnedValue> {
+    let values = schema.field_by_id(field_id)?.enum_values.as_ref()?;
+    let hash = value.hash();
+    values
+        .iter()
+        .position(|v| {
+            let v: &dyn Value = &OwnedValue::String(v.clone());
+            v.hash() == hash
+        })
+        .map(|ordinal| OwnedValue::U16(ordinal as u16))
+}
+
+// Field id of the composite index in its keys, in place of the id of a single field
+pub fn composite_index_id(field_ids: &[u64]) -> u64 {
+    let ids = field_ids
+        .iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<_>>();
+    hash_str(&ids.join(","))
+}
+
+// Keys have room for a single feature, so fields of a composite index share it. Every field takes
+// an equal part of the leading bytes of its feature in the declared order, the first fields take
+// the bytes left over. Fields missing from `features`, like for prefix seeks on the leading
+// fields, are filled with `fill`.
+pub fn compose_feature(features: &[Feature], num_fields: usize, fill: u8) -> Feature {
+    let num_fields = num_fields.max(1).min(FEATURE_SIZE);
+    let width = FEATURE_SIZE / num_fields;
+    let rem = FEATURE_SIZE % num_fields;
+    let mut res = [fill; FEATURE_SIZE];
+    let mut pos = 0;
+    for (i, feature) in features.iter().take(num_fields).enumerate() {
+        let len = width + if i < rem { 1 } else { 0 };
+        res[pos..pos + len].copy_from_slice(&feature[..len]);
+        pos += len;
+    }
+    res
+}
+
+// Smallest and largest keys of the composite index with the leading fields at the features, seek
+// from the lower key and stop after the upper one to match the prefix
+pub fn composite_prefix_range(
+    schema_id: u32,
+    field_ids: &[u64],
+    leading: &[Feature],
+) -> (EntryKey, EntryKey) {
+    let index_id = composite_index_id(field_ids);
+    let num_fields = field_ids.len();
+    let lower = compose_feature(leading, num_fields, 0);
+    let upper = compose_feature(leading, num_fields, u8::MAX);
+    (
+        EntryKey::from_props(&Id::new(0, 0), &lower, index_id, schema_id),
+        EntryKey::from_props(&Id::new(u64::MAX, u64::MAX), &upper, index_id, schema_id),
+    )
+}
+
+fn probe_composite_indices(cell: &dyn Cell, schema: &Schema) -> Vec<IndexRes> {
+    let cell_id = cell.id();
+    schema
+        .composite_indices
+        .iter()
+        .filter_map(|field_ids| {
+            let features = field_ids
+                .iter()
+                .map(|field_id| {
+                    let id_path = schema.id_index.get(field_id)?;
+                    let value = cell.data().get_in_by_ids(id_path);
+                    if value.prim_array_data_size().is_some() {
+                        return None;
+                    }
+                    let ordinal = enum_ordinal(schema, *field_id, value);
+                    Some(ordinal.as_ref().map_or(value.feature(), |o| o.feature()))
+                })
+                .collect::<Option<Vec<_>>>()?;
+            // Cells without the leading field are not indexed, like for single fields
+            if features[0] == UNSETTLED {
+                return None;
+            }
+            let feature = compose_feature(&features, field_ids.len(), 0);
+            let index_id = composite_index_id(field_ids);
+            let key = EntryKey::from_props(&cell_id, &feature, index_id, schema.id);
+            Some(IndexRes {
+                meta: vec![IndexMeta::Ranged(RangedIndexMeta { key })],
+            })
+        })
+        .collect()
+}
+
 pub fn probe_cell_indices(cell: &dyn Cell, schema: &Schema) -> Vec<IndexRes> {
     let mut res = vec![];
     schema.index_fields.iter().for_each(|(field_id, indices)| {
         if let Some(id_path) = schema.id_index.get(field_id) {
             let value = cell.data().get_in_by_ids(id_path);
+            let ordinal = enum_ordinal(schema, *field_id, value);
+            let value = ordinal
+                .as_ref()
+                .map_or(value, |ordinal| ordinal as &dyn Value);
             let mut components = vec![];
             if let Some(array_data_size) = value.prim_array_data_size() {
                 for index in indices {
@@ -244,6 +363,13 @@ pub fn probe_cell_indices(cell: &dyn Cell, schema: &Schema) -> Vec<IndexRes> {
                                 .map(|vec| IndexComps::Ranged(vec))
                                 .collect(),
                         ),
+                        &IndexType::RangedDesc => components.append(
+                            &mut value
+                                .features()
+                                .into_iter()
+                                .map(|vec| IndexComps::RangedDesc(vec))
+                                .collect(),
+                        ),
                         &IndexType::Hashed => components.append(
                             &mut value
                                 .hashes()
@@ -265,6 +391,9 @@ pub fn probe_cell_indices(cell: &dyn Cell, schema: &Schema) -> Vec<IndexRes> {
                 for index in indices {
                     match index {
                         &IndexType::Ranged => components.push(IndexComps::Ranged(value.feature())),
+                        &IndexType::RangedDesc => {
+                            components.push(IndexComps::RangedDesc(value.feature()))
+                        }
                         &IndexType::Hashed => components.push(IndexComps::Hashed(value.hash())),
                         &IndexType::Vectorized => components.push(IndexComps::Vectorized(
                             value.feature(),
@@ -292,6 +421,15 @@ pub fn probe_cell_indices(cell: &dyn Cell, schema: &Schema) -> Vec<IndexRes> {
                         let key = EntryKey::from_props(&cell_id, &feat, *field_id, schema.id);
                         metas.push(IndexMeta::Ranged(RangedIndexMeta { key }));
                     }
+                    IndexComps::RangedDesc(feat) => {
+                        if feat == UNSETTLED {
+                            continue;
+                        }
+                        let desc_feat = desc_feature(&feat);
+                        let index_id = desc_index_id(*field_id);
+                        let key = EntryKey::from_props(&cell_id, &desc_feat, index_id, schema.id);
+                        metas.push(IndexMeta::Ranged(RangedIndexMeta { key }));
+                    }
                     IndexComps::Vectorized(feat, size) => {
                         if feat == UNSETTLED {
                             continue;
@@ -310,5 +448,177 @@ pub fn probe_cell_indices(cell: &dyn Cell, schema: &Schema) -> Vec<IndexRes> {
             });
         }
     });
+    res.append(&mut probe_composite_indices(cell, schema));
     res
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ram::cell::CellHeader;
+    use crate::ram::types::Type;
+
+    #[test]
+    fn ranged_desc() {
+        let fields = Field::new(
+            "*",
+            Type::Map,
+            false,
+            false,
+            Some(vec![Field::new(
+                "score",
+                Type::U64,
+                false,
+                false,
+                None,
+                vec![IndexType::Ranged, IndexType::RangedDesc],
+            )]),
+            vec![],
+        );
+        let schema = Schema::new_with_id(1, "desc", None, fields, false, false);
+        let scores = vec![5u64, 300, 1, 70000, 42];
+        let mut keys = scores
+            .iter()
+            .enumerate()
+            .flat_map(|(i, score)| {
+                let id = Id::new(1, i as u64);
+                let cell = OwnedCell {
+                    header: CellHeader::new(schema.id, &id),
+                    data: data_map_value! { score: *score },
+                };
+                probe_cell_indices(&cell, &schema)
+                    .into_iter()
+                    .flat_map(|res| res.meta)
+                    .map(|meta| match meta {
+                        IndexMeta::Ranged(meta) => (meta.key, *score),
+                        _ => panic!("Expecting ranged index"),
+                    })
+            })
+            .collect::<Vec<_>>();
+        // Keys of both indices of the field are apart
+        assert_eq!(keys.len(), scores.len() * 2);
+        keys.sort_by(|(a, _), (b, _)| a.cmp(b));
+        keys.dedup_by(|(a, _), (b, _)| a == b);
+        assert_eq!(keys.len(), scores.len() * 2);
+        // Forward order of the keys is the order of a forward scan in the descending index
+        let field_id = hash_str("score");
+        let lower = desc_seek_key(schema.id, field_id, &[u8::MAX; FEATURE_SIZE]);
+        let upper = desc_seek_key(schema.id, field_id, &[0; FEATURE_SIZE]);
+        let scanned = keys
+            .iter()
+            .filter(|(key, _)| key >= &lower && key < &upper)
+            .map(|(_, score)| *score)
+            .collect::<Vec<_>>();
+        assert_eq!(scanned, vec![70000, 300, 42, 5, 1]);
+    }
+
+    #[test]
+    fn composite_keys() {
+        let fields = Field::new(
+            "*",
+            Type::Map,
+            false,
+            false,
+            Some(vec![
+                Field::new("country", Type::String, false, false, None, vec![]),
+                Field::new("city", Type::String, false, false, None, vec![]),
+            ]),
+            vec![],
+        );
+        let schema = Schema::new_with_id(1, "composite", None, fields, false, false)
+            .with_composite_index(&["country", "city"])
+            .unwrap();
+        let written = vec![("us", "nyc"), ("cn", "bj"), ("us", "la"), ("cn", "sh")];
+        let mut keys = written
+            .iter()
+            .enumerate()
+            .flat_map(|(i, (country, city))| {
+                let id = Id::new(1, i as u64);
+                let cell = OwnedCell {
+                    header: CellHeader::new(schema.id, &id),
+                    data: data_map_value! { country: *country, city: *city },
+                };
+                probe_cell_indices(&cell, &schema)
+                    .into_iter()
+                    .flat_map(|res| res.meta)
+                    .map(|meta| match meta {
+                        IndexMeta::Ranged(meta) => (meta.key, (*country, *city)),
+                        _ => panic!("Expecting ranged index"),
+                    })
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(keys.len(), written.len());
+        // Ordered by the leading field, then the next one
+        keys.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let scanned = keys.iter().map(|(_, pair)| *pair).collect::<Vec<_>>();
+        assert_eq!(
+            scanned,
+            vec![("cn", "bj"), ("cn", "sh"), ("us", "la"), ("us", "nyc")]
+        );
+        // Prefix of the leading field
+        let country = OwnedValue::String("us".to_string()).feature();
+        let (lower, upper) =
+            composite_prefix_range(schema.id, &schema.composite_indices[0], &[country]);
+        let matched = keys
+            .iter()
+            .filter(|(key, _)| key >= &lower && key <= &upper)
+            .map(|(_, pair)| *pair)
+            .collect::<Vec<_>>();
+        assert_eq!(matched, vec![("us", "la"), ("us", "nyc")]);
+    }
+
+    #[test]
+    fn enum_ordinals() {
+        let statuses = ["pending", "active", "closed"];
+        let fields = Field::new(
+            "*",
+            Type::Map,
+            false,
+            false,
+            Some(vec![Field::new(
+                "status",
+                Type::String,
+                false,
+                false,
+                None,
+                vec![IndexType::Ranged],
+            )
+            .with_enum_values(&statuses)]),
+            vec![],
+        );
+        let schema = Schema::new_with_id(1, "enum", None, fields, false, false);
+        let written = vec!["closed", "pending", "active", "closed", "pending"];
+        let mut keys = written
+            .iter()
+            .enumerate()
+            .flat_map(|(i, status)| {
+                let id = Id::new(1, i as u64);
+                let cell = OwnedCell {
+                    header: CellHeader::new(schema.id, &id),
+                    data: data_map_value! { status: *status },
+                };
+                probe_cell_indices(&cell, &schema)
+                    .into_iter()
+                    .flat_map(|res| res.meta)
+                    .map(|meta| match meta {
+                        IndexMeta::Ranged(meta) => (meta.key, *status),
+                        _ => panic!("Expecting ranged index"),
+                    })
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(keys.len(), written.len());
+        // Scans follow the declared order of the values rather than the lexical one
+        keys.sort_by(|(a, _), (b, _)| a.cmp(b));
+        let scanned = keys.iter().map(|(_, status)| *status).collect::<Vec<_>>();
+        assert_eq!(
+            scanned,
+            vec!["pending", "pending", "active", "closed", "closed"]
+        );
+        for (key, status) in &keys {
+            let ordinal = statuses.iter().position(|s| s == status).unwrap() as u16;
+            let mut feat = Feature::default();
+            feat.copy_from_slice(&key.as_slice()[8..16]);
+            assert_eq!(feat, OwnedValue::U16(ordinal).feature());
+        }
+    }
+}
@@ -97,6 +97,10 @@ impl EntryKey {
         key.set_id(id);
         key
     }
+    // Whether the key have the same schema, field and feature as the prefix, regardless of ids
+    pub fn key_prefixed(&self, prefix: &EntryKey) -> bool {
+        self.slice[..KEY_SIZE - ID_SIZE] == prefix.slice[..KEY_SIZE - ID_SIZE]
+    }
 }
 
 impl Default for EntryKey {
@@ -1,5 +1,6 @@
 use super::super::lsm::btree::Ordering;
 use super::super::lsm::service::*;
+use crate::client::timed;
 use crate::index::ranged::{
     client::RangedQueryClient,
     trees::{max_entry_key, min_entry_key},
@@ -18,6 +19,8 @@ pub struct ClientCursor {
     tree_key: EntryKey,
     pub pos: usize,
     buffer_size: u16,
+    // Keys out of the lower and upper bounds are not taken
+    bounds: (Option<EntryKey>, Option<EntryKey>),
 }
 
 impl ClientCursor {
@@ -27,6 +30,7 @@ impl ClientCursor {
         tree_key: EntryKey,
         query_client: Arc<RangedQueryClient>,
         buffer_size: u16,
+        bounds: (Option<EntryKey>, Option<EntryKey>),
     ) -> Result<Self, RPCError> {
         trace!(
             "Client cursor created with buffer next {:?}, tree key {:?}, block keys {:?}",
@@ -44,6 +48,7 @@ impl ClientCursor {
             next,
             buffer_size,
             pos: 0,
+            bounds,
         })
     }
 
@@ -76,11 +81,13 @@ impl ClientCursor {
             current_key,
             next_key.id()
         );
-        let next_cursor = RangedQueryClient::seek(
+        let next_cursor = RangedQueryClient::seek_bounded(
             &self.query_client,
             next_key,
             self.ordering,
             self.buffer_size,
+            self.bounds.0.clone(),
+            self.bounds.1.clone(),
         )
         .await?;
         if let Some(cursor) = next_cursor {
@@ -91,6 +98,27 @@ impl ClientCursor {
         return Ok(res);
     }
 
+    // Take up to `n` ids, fewer if the cursor is exhausted before. Buffered ids are taken at once,
+    // and blocks are only fetched from the servers when the buffer runs out.
+    pub async fn collect_n(&mut self, n: usize) -> Result<Vec<Id>, RPCError> {
+        let mut res = Vec::with_capacity(n);
+        while res.len() < n {
+            let buffered = self.ids.len().saturating_sub(self.pos);
+            if buffered > 1 {
+                // The last buffered id is left to `next` for it to fetch the next block
+                let take = (n - res.len()).min(buffered - 1);
+                res.extend_from_slice(&self.ids[self.pos..self.pos + take]);
+                self.pos += take;
+            } else {
+                match self.next().await? {
+                    Some(id) => res.push(id),
+                    None => break,
+                }
+            }
+        }
+        Ok(res)
+    }
+
     pub fn current(&self) -> Option<&Id> {
         match self.ids.get(self.pos) {
             Some(id) => Some(id),
@@ -114,21 +142,32 @@ impl ClientCursor {
                     "Next tree for {:?} returns {:?}, lower key {:?}, ordering {:?}",
                     self.tree_key, tree, tree_key, self.ordering
                 );
+                // Keys of the trees past the bound are all out of it
+                let past_bound = match (self.ordering, &self.bounds) {
+                    (Ordering::Forward, (_, Some(upper))) => &tree_key > upper,
+                    (Ordering::Backward, (Some(lower), _)) => &self.tree_key <= lower,
+                    _ => false,
+                };
+                if past_bound {
+                    self.ids.clear();
+                    return Ok(());
+                }
                 let tree_client =
                     locate_tree_server_from_conshash(&tree.id, &self.query_client.conshash).await?;
                 let seek_key = match self.ordering {
                     Ordering::Forward => min_entry_key(),
                     Ordering::Backward => max_entry_key(),
                 };
-                let seek_res = tree_client
-                    .seek(
-                        tree.id,
-                        seek_key,
-                        self.ordering,
-                        self.buffer_size,
-                        tree.epoch,
-                    )
-                    .await?;
+                let seek_fut = tree_client.seek(
+                    tree.id,
+                    seek_key,
+                    self.ordering,
+                    self.buffer_size,
+                    tree.epoch,
+                    self.bounds.0.clone(),
+                    self.bounds.1.clone(),
+                );
+                let seek_res = timed(self.query_client.timeout, seek_fut).await??;
                 match seek_res {
                     OpResult::Successful(block) => {
                         if block.buffer.is_empty() {
@@ -146,6 +185,7 @@ impl ClientCursor {
                                 tree_key,
                                 self.query_client.clone(),
                                 self.buffer_size,
+                                self.bounds.clone(),
                             )
                             .await?;
                         }
@@ -154,7 +194,7 @@ impl ClientCursor {
                     OpResult::Migrating => {
                         tokio::time::sleep(Duration::from_millis(500)).await;
                     }
-                    OpResult::OutOfBound | OpResult::NotFound => unreachable!(),
+                    OpResult::OutOfBound | OpResult::NotFound | OpResult::Busy => unreachable!(),
                     OpResult::EpochMissMatch(expect, actual) => {
                         debug!(
                             "Epoch mismatch on refill, expected {}, actual {}",
@@ -1,8 +1,12 @@
 use super::lsm::service::*;
 use super::sm::client::SMClient;
-use super::{lsm::btree::Ordering, sm::TreePlacement};
-use crate::client::AsyncClient;
-use crate::index::EntryKey;
+use super::{
+    lsm::btree::Ordering,
+    sm::{TreeInfo, TreePlacement},
+};
+use crate::client::{timed, AsyncClient};
+use crate::index::builder::{composite_prefix_range, desc_seek_key};
+use crate::index::{EntryKey, Feature, FEATURE_SIZE};
 use crate::ram::types::Id;
 use bifrost::raft::client::RaftClient;
 use bifrost::rpc::RPCError;
@@ -18,10 +22,21 @@ use std::time::Duration;
 
 pub mod cursor;
 
+const BUSY_RETRY_MS: u64 = 50;
+
+#[derive(Debug)]
+pub enum VerifyIndexError {
+    ExecError(ExecError),
+    RPCError(RPCError),
+    // The tree is gone from the server, like after being split, verify again to cover its keys
+    TreeNotFound(Id),
+}
+
 pub struct RangedQueryClient {
     conshash: Arc<ConsistentHashing>,
     sm: Arc<SMClient>,
     placement: RwLock<BTreeMap<EntryKey, (TreePlacement, EntryKey)>>,
+    timeout: Option<Duration>,
 }
 
 impl RangedQueryClient {
@@ -31,45 +46,65 @@ impl RangedQueryClient {
             conshash: conshash.clone(),
             sm: Arc::new(sm),
             placement: RwLock::new(BTreeMap::new()),
+            timeout: None,
         }
     }
 
+    // Bound every RPC to the tree servers, expired ones fail with a `TimedOut` IO error
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    // Returns `None` promptly when there is no key from `key` in the ordering of the tree
     pub async fn seek(
         self_ref: &Arc<Self>,
         key: &EntryKey,
         ordering: Ordering,
         buffer_size: u16,
     ) -> Result<Option<cursor::ClientCursor>, RPCError> {
+        Self::seek_bounded(self_ref, key, ordering, buffer_size, None, None).await
+    }
+
+    // Seek like `seek`, the cursor stops at the keys out of the bounds, both inclusive
+    pub async fn seek_bounded(
+        self_ref: &Arc<Self>,
+        key: &EntryKey,
+        ordering: Ordering,
+        buffer_size: u16,
+        lower: Option<EntryKey>,
+        upper: Option<EntryKey>,
+    ) -> Result<Option<cursor::ClientCursor>, RPCError> {
+        let (seek_lower, seek_upper) = (&lower, &upper);
         self_ref
             .run_on_destinated_tree(
                 key,
                 |key, client, tree_id, epoch| {
+                    let (lower, upper) = (seek_lower.clone(), seek_upper.clone());
                     async move {
                         client
-                            .seek(tree_id, key, ordering, buffer_size, epoch)
+                            .seek(tree_id, key, ordering, buffer_size, epoch, lower, upper)
                             .await
                     }
                     .boxed()
                 },
-                |action_res, _tree_client, lower, _upper| {
+                |block, _tree_client, lower, _upper| {
+                    let bounds = (seek_lower.clone(), seek_upper.clone());
                     async move {
-                        if let Some(block) = action_res {
-                            if block.buffer.is_empty() {
-                                return Ok(Some(None));
-                            } else {
-                                let client_cursor = cursor::ClientCursor::new(
-                                    ordering,
-                                    block,
-                                    lower,
-                                    self_ref.clone(),
-                                    buffer_size,
-                                )
-                                .await?;
-                                return Ok(Some(Some(client_cursor)));
-                            }
-                        } else {
-                            unreachable!()
+                        if block.buffer.is_empty() {
+                            // Nothing to seek, this is a result rather than a reason to retry
+                            return Ok(Some(None));
                         }
+                        let client_cursor = cursor::ClientCursor::new(
+                            ordering,
+                            block,
+                            lower,
+                            self_ref.clone(),
+                            buffer_size,
+                            bounds,
+                        )
+                        .await?;
+                        Ok(Some(Some(client_cursor)))
                     }
                     .boxed()
                 },
@@ -77,6 +112,40 @@ impl RangedQueryClient {
             .await
     }
 
+    // Seek the descending index of the field from the feature, the cursor goes down from it in the
+    // forward ordering of the keys
+    pub async fn seek_desc(
+        self_ref: &Arc<Self>,
+        schema_id: u32,
+        field_id: u64,
+        from: &Feature,
+        buffer_size: u16,
+    ) -> Result<Option<cursor::ClientCursor>, RPCError> {
+        let key = desc_seek_key(schema_id, field_id, from);
+        Self::seek(self_ref, &key, Ordering::Forward, buffer_size).await
+    }
+
+    // Seek the composite index of the fields for the cells with the leading fields at the
+    // features, ordered by the rest of the fields
+    pub async fn seek_composite(
+        self_ref: &Arc<Self>,
+        schema_id: u32,
+        field_ids: &[u64],
+        leading: &[Feature],
+        buffer_size: u16,
+    ) -> Result<Option<cursor::ClientCursor>, RPCError> {
+        let (lower, upper) = composite_prefix_range(schema_id, field_ids, leading);
+        Self::seek_bounded(
+            self_ref,
+            &lower,
+            Ordering::Forward,
+            buffer_size,
+            Some(lower.clone()),
+            Some(upper),
+        )
+        .await
+    }
+
     pub async fn delete(&self, key: &EntryKey) -> Result<bool, RPCError> {
         self.run_on_destinated_tree(
             key,
@@ -129,7 +198,7 @@ impl RangedQueryClient {
             u64,
         ) -> BoxFuture<'a, Result<OpResult<AR>, RPCError>>,
         P: Fn(
-            Option<AR>,
+            AR,
             Arc<AsyncServiceClient>,
             EntryKey,
             EntryKey,
@@ -147,22 +216,26 @@ impl RangedQueryClient {
             }
             let (placement, tree_client, lower, upper) =
                 self.locate_key_server(&key, ensure_updated).await?;
-            match action(
+            let action_fut = action(
                 key.clone(),
                 tree_client.clone(),
                 placement.id,
                 placement.epoch,
-            )
-            .await?
-            {
+            );
+            match timed(self.timeout, action_fut).await?? {
                 OpResult::Successful(res) => {
-                    if let Some(proc_res) = proc(Some(res), tree_client, lower, upper).await? {
+                    if let Some(proc_res) = proc(res, tree_client, lower, upper).await? {
                         return Ok(proc_res);
                     }
                 }
                 OpResult::Migrating => {
                     tokio::time::sleep(Duration::from_millis(500)).await;
                 }
+                // Wait for merges of the tree to catch up
+                OpResult::Busy => {
+                    tokio::time::sleep(Duration::from_millis(BUSY_RETRY_MS)).await;
+                }
+                // Only stale placements are worth refreshing
                 OpResult::OutOfBound | OpResult::NotFound => {
                     ensure_updated = true;
                 }
@@ -203,6 +276,68 @@ impl RangedQueryClient {
         Ok((tree_placement, tree_client, lower, upper))
     }
 
+    // Remove index entries of the field referring to cells that no longer exist, like after a
+    // crash between removing a cell and updating its index. Returns the number of entries removed.
+    pub async fn verify_index(
+        &self,
+        schema_id: u32,
+        field_id: u64,
+    ) -> Result<u64, VerifyIndexError> {
+        let lower = EntryKey::from_props(&Id::new(0, 0), &[0; FEATURE_SIZE], field_id, schema_id);
+        let upper = EntryKey::from_props(
+            &Id::new(u64::MAX, u64::MAX),
+            &[u8::MAX; FEATURE_SIZE],
+            field_id,
+            schema_id,
+        );
+        let trees = self
+            .owners_for_range(&lower, &upper)
+            .await
+            .map_err(VerifyIndexError::ExecError)?;
+        let mut repaired = 0;
+        for tree in trees {
+            let tree_id = tree.placement.id;
+            loop {
+                let tree_client = locate_tree_server_from_conshash(&tree_id, &self.conshash)
+                    .await
+                    .map_err(VerifyIndexError::RPCError)?;
+                let verify_fut = tree_client.verify(tree_id, lower.clone(), upper.clone());
+                match timed(self.timeout, verify_fut)
+                    .await
+                    .and_then(|res| res)
+                    .map_err(VerifyIndexError::RPCError)?
+                {
+                    OpResult::Successful(num) => {
+                        repaired += num;
+                        break;
+                    }
+                    OpResult::Migrating => {
+                        tokio::time::sleep(Duration::from_millis(500)).await;
+                    }
+                    _ => return Err(VerifyIndexError::TreeNotFound(tree_id)),
+                }
+            }
+        }
+        Ok(repaired)
+    }
+
+    // Trees owning any key from `lower` to `upper`, so a range query can be sent to exactly them
+    pub async fn owners_for_range(
+        &self,
+        lower: &EntryKey,
+        upper: &EntryKey,
+    ) -> Result<Vec<TreeInfo>, ExecError> {
+        let trees = self.sm.trees_in_range(lower, upper).await?;
+        let mut placement = self.placement.write();
+        for tree in &trees {
+            placement.insert(
+                tree.lower.clone(),
+                (tree.placement.clone(), tree.upper.clone()),
+            );
+        }
+        Ok(trees)
+    }
+
     async fn refresh_key_mapping(
         &self,
         key: &EntryKey,
@@ -1,4 +1,5 @@
 use super::*;
+use crate::ram::cell::OwnedCell;
 use futures::prelude::*;
 use futures::FutureExt;
 use mem::forget;
@@ -127,6 +128,13 @@ impl NodeCellRef {
         future::ready(()).boxed()
     }
 
+    pub fn persist_cell(&self, deletion: &Arc<DeletionSet>) -> Option<OwnedCell> {
+        if self.is_default() {
+            return None;
+        }
+        unsafe { self.inner.as_ref().unwrap().obj.persist_cell(self, deletion) }
+    }
+
     pub fn ptr_eq(&self, other: &Self) -> bool {
         self.inner == other.inner
     }
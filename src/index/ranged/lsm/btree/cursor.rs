@@ -4,6 +4,7 @@ use super::*;
 // It hold a copy of the containing page next page lock guard
 // These lock guards are preventing the node and their neighbourhoods been changed externally
 // Ordering are specified that can also change lock pattern
+// Positions shift under concurrent inserts into the page, `StableCursor` tolerates them
 pub struct RTCursor<KS, PS>
 where
     KS: Slice<EntryKey> + Debug + 'static,
@@ -14,6 +15,8 @@ where
     pub page: Option<NodeCellRef>,
     pub marker: PhantomData<(KS, PS)>,
     pub current: Option<EntryKey>,
+    // Keys beyond it in the ordering are out of the range of the cursor
+    pub stop: Bound<EntryKey>,
 }
 
 impl<KS, PS> RTCursor<KS, PS>
@@ -28,6 +31,7 @@ where
             page: Some(page.clone()),
             marker: PhantomData,
             current: None,
+            stop: Bound::Unbounded,
         };
         match ordering {
             Ordering::Forward
@@ -48,6 +52,30 @@ where
         cursor
     }
 
+    // Up to `n` keys from the current one, as `collect_n` would take, without moving this cursor.
+    // Pages are only read in memory along their links.
+    pub fn peek_n(&self, n: usize) -> Vec<EntryKey> {
+        let mut ahead = RTCursor::<KS, PS> {
+            index: self.index,
+            ordering: self.ordering,
+            page: self.page.clone(),
+            marker: PhantomData,
+            current: self.current.clone(),
+            stop: self.stop.clone(),
+        };
+        ahead.collect_n(n)
+    }
+
+    fn crossed(&self, key: &EntryKey) -> bool {
+        match (&self.stop, self.ordering) {
+            (Bound::Unbounded, _) => false,
+            (Bound::Included(stop), Ordering::Forward) => key > stop,
+            (Bound::Excluded(stop), Ordering::Forward) => key >= stop,
+            (Bound::Included(stop), Ordering::Backward) => key < stop,
+            (Bound::Excluded(stop), Ordering::Backward) => key <= stop,
+        }
+    }
+
     fn read_current(node: &NodeCellRef, pos: usize) -> Option<EntryKey> {
         read_node(node, |node: &NodeReadHandler<KS, PS>| {
             // node can be empty only if the node have been changed in the middle
@@ -60,6 +88,41 @@ where
         })
     }
 
+    // Move up to `n` keys along the ordering within the current page, without leaving it or going
+    // beyond the stop bound. Returns the number of keys moved.
+    fn jump_in_page(&mut self, n: usize) -> usize {
+        if n == 0 || self.current.is_none() {
+            return 0;
+        }
+        let page = match &self.page {
+            Some(page) => page.clone(),
+            None => return 0,
+        };
+        let (index, ordering) = (self.index, self.ordering);
+        let target = read_node(&page, |node: &NodeReadHandler<KS, PS>| {
+            if node.is_empty_node() || node.is_empty() || index >= node.len() {
+                return None;
+            }
+            let pos = match ordering {
+                Ordering::Forward => index + n.min(node.len() - 1 - index),
+                Ordering::Backward => index - n.min(index),
+            };
+            Some((pos, node.extnode().keys.as_slice_immute()[pos].clone()))
+        });
+        match target {
+            Some((pos, key)) if pos != index && !self.crossed(&key) => {
+                self.index = pos;
+                self.current = Some(key);
+                if pos > index {
+                    pos - index
+                } else {
+                    index - pos
+                }
+            }
+            _ => 0,
+        }
+    }
+
     fn next_candidate(&mut self) -> Option<EntryKey> {
         loop {
             let search_result = if self.page.is_some() {
@@ -80,7 +143,12 @@ where
                                             self.page = None;
                                             self.current = None;
                                             return Some(None);
+                                        } else if next_node.is_empty_node() {
+                                            return None;
                                         } else if next_node.is_empty() {
+                                            // External node without keys, skip over it
+                                            self.index = 0;
+                                            self.page = Some(next_node_ref.clone());
                                             return None;
                                         } else if next_node.is_ext() {
                                             self.index = 0;
@@ -110,7 +178,12 @@ where
                                             self.page = None;
                                             self.current = None;
                                             return Some(None);
+                                        } else if prev_node.is_empty_node() {
+                                            return None;
                                         } else if prev_node.is_empty() {
+                                            // External node without keys, skip over it
+                                            self.index = 0;
+                                            self.page = Some(prev_node_ref.clone());
                                             return None;
                                         } else if prev_node.is_ext() {
                                             self.index = prev_node.len() - 1;
@@ -152,6 +225,9 @@ where
 {
     // TODO: Copy current after next
     fn next(&mut self) -> Option<EntryKey> {
+        if self.current.as_ref().map_or(false, |key| self.crossed(key)) {
+            return None;
+        }
         if let Some(swapped_old_candidate) = self.next_candidate() {
             return Some(swapped_old_candidate);
         } else {
@@ -160,6 +236,113 @@ where
     }
 
     // TODO: Use copied key reference
+    fn current(&self) -> Option<&EntryKey> {
+        self.current.as_ref().filter(|key| !self.crossed(key))
+    }
+
+    // Keys in the page are jumped over at once, the leaf chain is only followed on overruns
+    fn skip(&mut self, n: usize) -> usize {
+        let mut skipped = 0;
+        while skipped < n {
+            // Land on the last key to pass in the page, `next` passes it and leaves the page
+            skipped += self.jump_in_page(n - skipped - 1);
+            if self.next().is_none() {
+                break;
+            }
+            skipped += 1;
+        }
+        skipped
+    }
+}
+
+// Forward cursor for scans running along with insertions.
+// `RTCursor` keeps a position in the page, which is shifted by inserts and splits into the same
+// page. This one resumes from the last key it yielded instead, searching for the key following it
+// in the page, or in the pages at its right when the page has been split. Pages are read under
+// their versions by `read_node`, a read racing with a latched or changed page is taken again.
+// The isolation is read committed rather than snapshot: keys present when the scan started are
+// yielded exactly once and in order unless removed, keys inserted meanwhile may be yielded or not.
+pub struct StableCursor<KS, PS>
+where
+    KS: Slice<EntryKey> + Debug + 'static,
+    PS: Slice<NodeCellRef> + 'static,
+{
+    page: Option<NodeCellRef>,
+    current: Option<EntryKey>,
+    marker: PhantomData<(KS, PS)>,
+}
+
+enum StableStep {
+    Found(EntryKey),
+    Right(NodeCellRef),
+    Retry,
+    End,
+}
+
+impl<KS, PS> StableCursor<KS, PS>
+where
+    KS: Slice<EntryKey> + Debug + 'static,
+    PS: Slice<NodeCellRef> + 'static,
+{
+    pub fn new(start: &EntryKey, page: Option<NodeCellRef>) -> Self {
+        let mut cursor = StableCursor {
+            page,
+            current: None,
+            marker: PhantomData,
+        };
+        cursor.current = cursor.seek_from(start, true);
+        cursor
+    }
+
+    // First key from the bound at the page or its right siblings
+    fn seek_from(&mut self, bound: &EntryKey, inclusive: bool) -> Option<EntryKey> {
+        let backoff = crossbeam::utils::Backoff::new();
+        loop {
+            let page = self.page.clone()?;
+            let step = read_node(&page, |node: &NodeReadHandler<KS, PS>| match &**node {
+                &NodeData::External(ref n) => {
+                    let mut pos = match n.search_unwindable(bound) {
+                        Ok(pos) => pos,
+                        Err(_) => return StableStep::Retry,
+                    };
+                    let keys = n.keys.as_slice_immute();
+                    if !inclusive && pos < n.len && &keys[pos] == bound {
+                        pos += 1;
+                    }
+                    if pos < n.len {
+                        StableStep::Found(keys[pos].clone())
+                    } else {
+                        StableStep::Right(n.next.clone())
+                    }
+                }
+                &NodeData::Empty(ref n) => StableStep::Right(n.right.clone()),
+                &NodeData::None => StableStep::End,
+                &NodeData::Internal(_) => unreachable!(),
+            });
+            match step {
+                StableStep::Found(key) => return Some(key),
+                StableStep::Right(right) => self.page = Some(right),
+                StableStep::Retry => backoff.spin(),
+                StableStep::End => {
+                    self.page = None;
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+impl<KS, PS> Cursor for StableCursor<KS, PS>
+where
+    KS: Slice<EntryKey> + Debug + 'static,
+    PS: Slice<NodeCellRef> + 'static,
+{
+    fn next(&mut self) -> Option<EntryKey> {
+        let current = self.current.take()?;
+        self.current = self.seek_from(&current, false);
+        Some(current)
+    }
+
     fn current(&self) -> Option<&EntryKey> {
         self.current.as_ref()
     }
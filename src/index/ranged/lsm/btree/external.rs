@@ -160,7 +160,9 @@ where
         // cached.dump();
         let keys_1 = &mut self.keys;
         let pivot = self.len / 2;
-        let new_page_id = BPlusTree::<KS, PS>::new_page_id();
+        // The new page is linked before orphan pages of the tree are collected again
+        let _split_guard = tree.split_lock.read();
+        let new_page_id = tree.new_page_id();
         let mut keys_2 = keys_1.split_at_pivot(pivot, self.len);
         let mut keys_1_len = pivot;
         let mut keys_2_len = self.len - pivot;
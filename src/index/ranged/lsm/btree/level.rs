@@ -57,30 +57,7 @@ where
                     boundary
                 );
                 debug!("Selected {} pages to merge", nodes.len());
-                // Update deleted key set
-                nodes
-                    .iter()
-                    .map(|node| {
-                        node.keys()
-                            .iter()
-                            .filter(|k| src_tree.deletion.contains(k))
-                            .cloned()
-                    })
-                    .flatten()
-                    .for_each(|k| {
-                        deleted.insert(k);
-                    });
-                // Collect keys to merge to next level
-                let merging_keys = nodes
-                    .iter()
-                    .map(|node| {
-                        node.keys()
-                            .iter()
-                            .filter(|k| !src_tree.deletion.contains(k))
-                            .map(|k| k.clone())
-                    })
-                    .flatten()
-                    .collect_vec();
+                let merging_keys = collect_merging_keys(&nodes, src_tree, deleted);
                 let num_keys_merged = merging_keys.len();
                 debug!(
                     "Collected {} keys at level {}, merging to destination tree",
@@ -92,15 +69,22 @@ where
                     debug!("Pruning source tree for {} external pages", nodes.len());
                     debug_assert!(!new_first.node_ref().is_default());
                     for node in nodes.into_iter() {
+                        let node_id = node.ext_id();
                         let node_ref = node.node_ref().clone();
                         drop(node); // unlock read
                         let node = write_node::<KS, PS>(&node_ref);
                         clear_node(node, new_first.node_ref());
+                        if node_id != head_id {
+                            src_tree.release_page_id(&node_id);
+                        }
                     }
                     let new_first_ref = new_first.node_ref().clone();
                     drop(new_first);
                     let mut new_first = write_node::<KS, PS>(&new_first_ref);
-                    new_first.extnode_mut(src_tree).id = head_id;
+                    // The first page takes over the head id, its own id is free
+                    let first_id =
+                        std::mem::replace(&mut new_first.extnode_mut(src_tree).id, head_id);
+                    src_tree.release_page_id(&first_id);
                     *new_first.left_ref_mut().unwrap() = NodeCellRef::default();
                     src_tree.len.fetch_sub(num_keys_merged, Release);
                     debug!("Source tree external nodes pruned with head {:?}", head_id);
@@ -219,6 +203,42 @@ where
     }
 }
 
+// Keys of the pages to merge to next level, keys in the deletion set are collected to `deleted`
+fn collect_merging_keys<KS, PS>(
+    nodes: &Vec<NodeReadHandler<KS, PS>>,
+    src_tree: &BPlusTree<KS, PS>,
+    deleted: &mut HashSet<EntryKey>,
+) -> Vec<EntryKey>
+where
+    KS: Slice<EntryKey> + Debug + 'static,
+    PS: Slice<NodeCellRef> + 'static,
+{
+    // Update deleted key set
+    nodes
+        .iter()
+        .map(|node| {
+            node.keys()
+                .iter()
+                .filter(|k| src_tree.deletion.contains(k))
+                .cloned()
+        })
+        .flatten()
+        .for_each(|k| {
+            deleted.insert(k);
+        });
+    // Collect keys to merge to next level
+    nodes
+        .iter()
+        .map(|node| {
+            node.keys()
+                .iter()
+                .filter(|k| !src_tree.deletion.contains(k))
+                .map(|k| k.clone())
+        })
+        .flatten()
+        .collect_vec()
+}
+
 fn clear_node<KS, PS>(mut node: NodeWriteGuard<KS, PS>, right_ref: &NodeCellRef)
 where
     KS: Slice<EntryKey> + Debug + 'static,
@@ -327,6 +347,43 @@ where
     }
 }
 
+// Keys bounding the band of pages to merge from the one covering `start`, the band holds keys
+// above the lower key and up to the upper key. Both are keys of the highest node with keys, so
+// nodes of every level below are cut along them. The band takes about half of the keys in the
+// node as the one from the left edge does, and never reaches its last pointer.
+// None when the band starts from the left edge of the tree.
+pub fn select_from<KS, PS>(node: &NodeCellRef, start: &EntryKey) -> Option<(EntryKey, EntryKey)>
+where
+    KS: Slice<EntryKey> + Debug + 'static,
+    PS: Slice<NodeCellRef> + 'static,
+{
+    let res = read_node(node, |node: &NodeReadHandler<KS, PS>| {
+        if node.len() < 1 {
+            if node.is_ext() {
+                return Ok(None);
+            } else {
+                return Err(node.ptrs()[0].clone());
+            }
+        }
+        let node_keys = node.keys();
+        let node_len = node_keys.len();
+        let num_lower = node_keys.iter().take_while(|key| *key < start).count();
+        if node.is_ext() || node_len < 2 || num_lower == 0 {
+            return Ok(None);
+        }
+        let lower_idx = (num_lower - 1).min(node_len - 2);
+        let upper_idx = (lower_idx + node_len / 2 + 1).min(node_len - 1);
+        Ok(Some((
+            node_keys[lower_idx].clone(),
+            node_keys[upper_idx].clone(),
+        )))
+    });
+    match res {
+        Ok(band) => band,
+        Err(r) => select_from::<KS, PS>(&r, start),
+    }
+}
+
 pub fn level_merge<KS, PS>(
     level: usize,
     src_tree: &BPlusTree<KS, PS>,
@@ -338,10 +395,170 @@ where
     KS: Slice<EntryKey> + Debug + 'static,
     PS: Slice<NodeCellRef> + 'static,
 {
-    debug!("Merging LSM tree level {}", level);
+    level_merge_from(
+        level,
+        src_tree,
+        dest_tree,
+        &EntryKey::default(),
+        deleted,
+        prune,
+    )
+}
+
+// Merge the band selected by `select_from`, for the region of the tree that actually grew
+pub fn level_merge_from<KS, PS>(
+    level: usize,
+    src_tree: &BPlusTree<KS, PS>,
+    dest_tree: &dyn LevelTree,
+    start: &EntryKey,
+    deleted: &mut HashSet<EntryKey>,
+    prune: bool,
+) -> usize
+where
+    KS: Slice<EntryKey> + Debug + 'static,
+    PS: Slice<NodeCellRef> + 'static,
+{
+    debug!("Merging LSM tree level {} from {:?}", level, start);
     let root = src_tree.get_root();
-    let key_boundary = select_merge_boundary::<KS, PS>(&root).unwrap();
-    merge_with_boundary(level, src_tree, dest_tree, &key_boundary, deleted, prune)
+    match select_from::<KS, PS>(&root, start) {
+        Some((lower, upper)) => {
+            merge_band(level, src_tree, dest_tree, &lower, &upper, deleted, prune)
+        }
+        None => {
+            let key_boundary = select_merge_boundary::<KS, PS>(&root).unwrap();
+            merge_with_boundary(level, src_tree, dest_tree, &key_boundary, deleted, prune)
+        }
+    }
+}
+
+// Nodes of a level at the right of `left`, up to the one bounded by `upper`
+fn select_band_nodes<KS, PS>(left: &NodeCellRef, upper: &EntryKey) -> Vec<NodeReadHandler<KS, PS>>
+where
+    KS: Slice<EntryKey> + Debug + 'static,
+    PS: Slice<NodeCellRef> + 'static,
+{
+    let mut nodes = vec![];
+    let mut node = read_unchecked::<KS, PS>(read_unchecked::<KS, PS>(left).right_ref().unwrap());
+    while node.right_bound() <= upper {
+        let next_node = read_unchecked::<KS, PS>(node.right_ref().unwrap());
+        nodes.push(node);
+        node = next_node;
+    }
+    nodes
+}
+
+// Merge keys in the band of `select_from`. Unlike bands from the left edge, nodes at both sides
+// of the band stay, so the highest node with keys drops the pointers into the band and nodes of
+// each level below are linked over it.
+fn merge_band<KS, PS>(
+    level: usize,
+    src_tree: &BPlusTree<KS, PS>,
+    dest_tree: &dyn LevelTree,
+    lower: &EntryKey,
+    upper: &EntryKey,
+    deleted: &mut HashSet<EntryKey>,
+    prune: bool,
+) -> usize
+where
+    KS: Slice<EntryKey> + Debug + 'static,
+    PS: Slice<NodeCellRef> + 'static,
+{
+    debug!(
+        "Level merge level {} with band {:?} to {:?}",
+        level, lower, upper
+    );
+    let mut band_root = src_tree.get_root();
+    loop {
+        let node = read_unchecked::<KS, PS>(&band_root);
+        if node.len() > 0 {
+            break;
+        }
+        let next_node = node.ptrs()[0].clone();
+        drop(node);
+        band_root = next_node;
+    }
+    let (lower_pos, upper_pos, first_sides) = {
+        let node = read_unchecked::<KS, PS>(&band_root);
+        let lower_pos = node.keys().binary_search(lower).unwrap();
+        let upper_pos = node.keys().binary_search(upper).unwrap();
+        let sides = (
+            node.ptrs()[lower_pos].clone(),
+            node.ptrs()[upper_pos + 1].clone(),
+        );
+        (lower_pos, upper_pos, sides)
+    };
+    // Nodes at the left and the right of the band, from the level below the band root to pages
+    let mut sides = vec![first_sides];
+    loop {
+        let next_sides = {
+            let (left, right) = sides.last().unwrap();
+            let left_node = read_unchecked::<KS, PS>(left);
+            debug_assert_eq!(left_node.right_bound(), lower);
+            if left_node.is_ext() {
+                break;
+            }
+            let right_node = read_unchecked::<KS, PS>(right);
+            (
+                left_node.ptrs()[left_node.len()].clone(),
+                right_node.ptrs()[0].clone(),
+            )
+        };
+        sides.push(next_sides);
+    }
+    let (left_page, right_page) = sides.pop().unwrap();
+    let pages = select_band_nodes::<KS, PS>(&left_page, upper);
+    debug!("Selected {} pages to merge", pages.len());
+    let merging_keys = collect_merging_keys(&pages, src_tree, deleted);
+    let num_keys_merged = merging_keys.len();
+    dest_tree.merge_with_keys(merging_keys);
+    debug!("Merged {} keys, pruning: {}", num_keys_merged, prune);
+    if !prune {
+        return num_keys_merged;
+    }
+    // Searches from the root stop entering the band, then each level is linked over it
+    {
+        let mut node = write_node::<KS, PS>(&band_root);
+        let (node_keys, node_ptrs) = (node.keys(), node.ptrs());
+        let num_keys = node_keys.len() - (upper_pos - lower_pos);
+        let mut new_keys = KS::init();
+        let mut new_ptrs = PS::init();
+        let kept_keys = node_keys[..=lower_pos]
+            .iter()
+            .chain(&node_keys[upper_pos + 1..]);
+        for (i, k) in kept_keys.enumerate() {
+            new_keys.as_slice()[i] = k.clone();
+        }
+        let kept_ptrs = node_ptrs[..=lower_pos]
+            .iter()
+            .chain(&node_ptrs[upper_pos + 1..]);
+        for (i, p) in kept_ptrs.enumerate() {
+            new_ptrs.as_slice()[i] = p.clone();
+        }
+        let innode = node.innode_mut();
+        innode.keys = new_keys;
+        innode.ptrs = new_ptrs;
+        innode.len = num_keys;
+    }
+    for (left, right) in sides.iter() {
+        let nodes = select_band_nodes::<KS, PS>(left, upper);
+        *write_node::<KS, PS>(left).right_ref_mut().unwrap() = right.clone();
+        clear_nodes(nodes, right);
+    }
+    write_node::<KS, PS>(&left_page).extnode_mut(src_tree).next = right_page.clone();
+    write_node::<KS, PS>(&right_page).extnode_mut(src_tree).prev = left_page.clone();
+    for page in pages.into_iter() {
+        let page_id = page.ext_id();
+        let page_ref = page.node_ref().clone();
+        drop(page); // unlock read
+        clear_node(write_node::<KS, PS>(&page_ref), &right_page);
+        external::make_deleted::<KS, PS>(&page_id);
+        src_tree.release_page_id(&page_id);
+    }
+    src_tree.len.fetch_sub(num_keys_merged, Release);
+    debug!("Post-merge verification at level {}", level);
+    debug_assert!(verification::tree_has_no_empty_node(&src_tree));
+    debug_assert!(verification::is_tree_in_order(&src_tree, level));
+    num_keys_merged
 }
 
 pub fn merge_with_boundary<KS, PS>(
@@ -15,7 +15,7 @@ use itertools::Itertools;
 use level::LEVEL_TREE_DEPTH;
 use merge::*;
 pub use node::*;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use search::*;
 use std::any::Any;
 use std::cell::UnsafeCell;
@@ -24,6 +24,7 @@ use std::collections::HashSet;
 use std::fmt::Debug;
 use std::marker::PhantomData;
 use std::mem;
+use std::ops::Bound;
 use std::ops::Deref;
 use std::ops::DerefMut;
 use std::sync::atomic::{AtomicUsize, Ordering::*};
@@ -49,8 +50,7 @@ pub mod marco;
 
 // Items can be added in real-time
 // It is not supposed to hold a lot of items when it is actually feasible
-// There will be a limit for maximum items in ths data structure, when the limit exceeds, higher ordering
-// items with number of one page will be merged to next level
+// Items beyond the capacity of the tree make it full, full trees are merged to their next levels
 pub struct BPlusTree<KS, PS>
 where
     KS: Slice<EntryKey> + Debug + 'static,
@@ -60,6 +60,16 @@ where
     root_versioning: NodeCellRef,
     head_page_id: Id,
     len: AtomicUsize,
+    capacity: usize,
+    // Higher part of ids of new pages, pages are placed by ids randomly without it
+    partition: Option<u64>,
+    // Higher 32 bits of lower parts of page ids in the partition, telling pages of this tree
+    // from the ones of other trees in the partition
+    tag: u64,
+    // Lower parts of ids of the pages alive in the partition
+    page_ids: Mutex<HashSet<u64>>,
+    // Held by splits to link new pages, and exclusively when collecting orphan pages
+    split_lock: RwLock<()>,
     pub deletion: Arc<DeletionSet>,
     marker: PhantomData<(KS, PS)>,
 }
@@ -90,16 +100,30 @@ where
     PS: Slice<NodeCellRef> + 'static,
 {
     pub fn new(deletion: &Arc<DeletionSet>) -> BPlusTree<KS, PS> {
+        Self::new_with_partition(deletion, None)
+    }
+
+    // Pages of the tree are kept in the same chunk, so scans along its leaves hit one server
+    pub fn new_in_partition(deletion: &Arc<DeletionSet>, partition: u64) -> BPlusTree<KS, PS> {
+        Self::new_with_partition(deletion, Some(partition))
+    }
+
+    fn new_with_partition(deletion: &Arc<DeletionSet>, partition: Option<u64>) -> Self {
         trace!("Creating B+ Tree, with capacity {}", KS::slice_len());
         let mut tree = BPlusTree {
             root: RwLock::new(NodeCellRef::new(Node::<KS, PS>::new(NodeData::None))),
             root_versioning: NodeCellRef::new(Node::<KS, PS>::new(NodeData::None)),
             head_page_id: Id::unit_id(),
             len: AtomicUsize::new(0),
+            capacity: ideal_capacity_from_node_size(KS::slice_len()),
+            partition,
+            tag: Id::rand().lower >> 32,
+            page_ids: Mutex::new(HashSet::new()),
+            split_lock: RwLock::new(()),
             marker: PhantomData,
             deletion: deletion.clone(),
         };
-        let root_id = Self::new_page_id();
+        let root_id = tree.new_page_id();
         let max_key = max_entry_key();
         debug!("Created B-tree with {:?}", root_id);
         let root_inner = Node::<KS, PS>::new_external(root_id, max_key);
@@ -109,6 +133,12 @@ where
         return tree;
     }
 
+    // Number of items the tree holds before it is full, sized by its pages by default
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
     // Non-atomic
     pub fn clear(&self) {
         let new_node = NodeCellRef::new(Node::<KS, PS>::new_external(
@@ -117,6 +147,8 @@ where
         ));
         let old_node = mem::replace(&mut *self.root.write(), new_node);
         self.len.store(0, Release);
+        let head_lower = self.head_page_id.lower;
+        self.page_ids.lock().retain(|lower| *lower == head_lower);
         clear::clear_by_node::<KS, PS>(&old_node);
     }
 
@@ -134,6 +166,47 @@ where
         reconstruct::reconstruct_from_head_id(*head_id, neb, deletion, level).await
     }
 
+    // Remove persisted pages of this tree not linked from it. Pages of other trees, including
+    // the ones in the same partition, are left alone. Trees without a partition cannot tell
+    // their pages apart and collect nothing.
+    pub async fn gc_orphan_pages(
+        &self,
+        neb: &AsyncClient,
+    ) -> Result<usize, bifrost::rpc::RPCError> {
+        let partition = match self.partition {
+            Some(partition) => partition,
+            None => {
+                debug!("Tree {:?} has no partition to collect", self.head_page_id);
+                return Ok(0);
+            }
+        };
+        let tag = self.tag;
+        storage::gc_orphan_pages(
+            neb,
+            move |id: &Id| id.higher == partition && id.lower >> 32 == tag,
+            || self.linked_page_ids(),
+        )
+        .await
+    }
+
+    // Ids of pages along the leaves, with splits held off
+    fn linked_page_ids(&self) -> HashSet<Id> {
+        let _split_guard = self.split_lock.write();
+        let mut ids = HashSet::new();
+        let mut page = self.seek(&*MIN_ENTRY_KEY, Ordering::Forward).page;
+        while let Some(page_ref) = page {
+            let node = read_unchecked::<KS, PS>(&page_ref);
+            if node.is_none() {
+                break;
+            }
+            if node.is_ext() {
+                ids.insert(node.ext_id());
+            }
+            page = node.right_ref().cloned();
+        }
+        ids
+    }
+
     pub fn from_root(
         root: NodeCellRef,
         head_id: Id,
@@ -145,6 +218,12 @@ where
             root_versioning: NodeCellRef::default(),
             head_page_id: head_id,
             len: AtomicUsize::new(len),
+            capacity: ideal_capacity_from_node_size(KS::slice_len()),
+            // New pages stay with the head page, like trees created in a partition
+            partition: Some(head_id.higher),
+            tag: head_id.lower >> 32,
+            page_ids: Mutex::new(HashSet::new()),
+            split_lock: RwLock::new(()),
             marker: PhantomData,
             deletion: deletion.clone(),
         }
@@ -158,6 +237,41 @@ where
         search_node(&self.get_root(), key, ordering)
     }
 
+    // Cursor within the bounds in the ordering, it ends once the bound ahead is crossed
+    pub fn seek_range(
+        &self,
+        low: Bound<&EntryKey>,
+        high: Bound<&EntryKey>,
+        ordering: Ordering,
+    ) -> RTCursor<KS, PS> {
+        let (start, stop) = match ordering {
+            Ordering::Forward => (low, high),
+            Ordering::Backward => (high, low),
+        };
+        let mut cursor = match (start, ordering) {
+            (Bound::Included(key), _) | (Bound::Excluded(key), _) => self.seek(key, ordering),
+            (Bound::Unbounded, Ordering::Forward) => self.seek(&*MIN_ENTRY_KEY, ordering),
+            (Bound::Unbounded, Ordering::Backward) => self.seek(&*MAX_ENTRY_KEY, ordering),
+        };
+        if let Bound::Excluded(key) = start {
+            if cursor.current() == Some(key) {
+                cursor.next();
+            }
+        }
+        cursor.stop = match stop {
+            Bound::Included(key) => Bound::Included(key.clone()),
+            Bound::Excluded(key) => Bound::Excluded(key.clone()),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        cursor
+    }
+
+    // Forward scan along with insertions and splits, see `StableCursor` for the isolation
+    pub fn scan(&self, start: &EntryKey) -> StableCursor<KS, PS> {
+        let page = search_node::<KS, PS>(&self.get_root(), start, Ordering::Forward).page;
+        StableCursor::new(start, page)
+    }
+
     pub fn insert(&self, key: &EntryKey) -> bool {
         match insert_to_tree_node(&self, &self.get_root(), &self.root_versioning, &key, 0) {
             Some(Some(split)) => {
@@ -178,6 +292,69 @@ where
         return true;
     }
 
+    // Insert keys in ascending order, returns the number of keys inserted. Consecutive keys
+    // within the bound of the same page are inserted under one latch of the page, a key out of it
+    // or splitting it takes the path of `insert` from the root.
+    pub fn insert_sorted(&self, keys: &[EntryKey]) -> usize {
+        debug_assert!(keys.windows(2).all(|pair| pair[0] <= pair[1]));
+        let mut inserted = 0;
+        let mut page: Option<NodeWriteGuard<KS, PS>> = None;
+        for key in keys {
+            let fits = page.as_ref().map_or(false, |guard| {
+                guard.is_ext() && guard.len() < KS::slice_len() && key < guard.right_bound()
+            });
+            if fits {
+                let guard = page.as_mut().unwrap();
+                let page_ref = guard.node_ref().clone();
+                // Never splits, so there is no parent to update
+                let insertion =
+                    guard
+                        .extnode_mut(self)
+                        .insert(key, self, &page_ref, &NodeCellRef::default());
+                if insertion.is_some() {
+                    self.len.fetch_add(1, Relaxed);
+                    inserted += 1;
+                }
+                continue;
+            }
+            // Release the latch before the insert takes it again
+            drop(page.take());
+            if self.insert(key) {
+                inserted += 1;
+            }
+            page = Some(self.latch_page_for(key));
+        }
+        inserted
+    }
+
+    fn latch_page_for(&self, key: &EntryKey) -> NodeWriteGuard<KS, PS> {
+        let mut node_ref = self.get_root();
+        loop {
+            match mut_search::<KS, PS>(&node_ref, key) {
+                MutSearchResult::Internal(sub_node) => node_ref = sub_node,
+                MutSearchResult::External => return write_targeted(write_node(&node_ref), key),
+            }
+        }
+    }
+
+    // Remove all keys sharing the prefix of `prefix` from the tree, returns the number of keys
+    // removed. Like other deletions, keys are only marked in the deletion set until merged out.
+    pub fn remove_prefix(&self, prefix: &EntryKey) -> usize {
+        let start = EntryKey::from_slice(&prefix[..KEY_SIZE - ID_SIZE]);
+        let mut cursor = self.seek(&start, Ordering::Forward);
+        let mut removed = 0;
+        while let Some(key) = cursor.next() {
+            if !key.key_prefixed(prefix) {
+                break;
+            }
+            if !self.deletion.contains(&key) {
+                self.deletion.insert(&key);
+                removed += 1;
+            }
+        }
+        removed
+    }
+
     pub fn merge_with_keys_(&self, keys: Vec<EntryKey>) {
         let keys_len = keys.len();
         if keys.len() == 0 {
@@ -268,9 +445,45 @@ where
         self.len.load(Relaxed)
     }
 
-    fn new_page_id() -> Id {
-        // TODO: achieve locality
-        Id::rand()
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    // Items exceeded the capacity, the tree should be merged to its next level
+    pub fn is_full(&self) -> bool {
+        self.len() > self.capacity
+    }
+
+    // Pages already in the partition, like the ones of a tree recovered from its pages
+    pub fn with_page_ids(self, ids: &[Id]) -> Self {
+        if let Some(partition) = self.partition {
+            let mut page_ids = self.page_ids.lock();
+            for id in ids.iter().filter(|id| id.higher == partition) {
+                page_ids.insert(id.lower);
+            }
+        }
+        self
+    }
+
+    // The page of the id is deleted or took another id, the id can be allocated again
+    pub fn release_page_id(&self, id: &Id) {
+        if self.partition == Some(id.higher) {
+            self.page_ids.lock().remove(&id.lower);
+        }
+    }
+
+    fn new_page_id(&self) -> Id {
+        let partition = match self.partition {
+            Some(partition) => partition,
+            None => return Id::rand(),
+        };
+        let mut page_ids = self.page_ids.lock();
+        loop {
+            let id = Id::new(partition, (self.tag << 32) | (Id::rand().lower >> 32));
+            if page_ids.insert(id.lower) {
+                return id;
+            }
+        }
     }
 }
 
@@ -336,6 +549,14 @@ where
         self.len()
     }
 
+    fn ideal_capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn oversized(&self) -> bool {
+        self.is_full()
+    }
+
     fn merge_to<'a>(
         &'a self,
         level: usize,
@@ -1,4 +1,5 @@
 use super::*;
+use crate::ram::cell::OwnedCell;
 use futures::FutureExt;
 use std::any::TypeId;
 use std::ptr;
@@ -336,6 +337,7 @@ pub trait AnyNode: Any + Send + Sync + 'static {
         deletion: &DeletionSet,
         neb: &Arc<crate::client::AsyncClient>,
     ) -> BoxFuture<()>;
+    fn persist_cell(&self, node_ref: &NodeCellRef, deletion: &DeletionSet) -> Option<OwnedCell>;
     unsafe fn take_all_refs(&self) -> Vec<NodeCellRef>;
 }
 
@@ -622,19 +624,7 @@ where
         deletion: &DeletionSet,
         neb: &Arc<crate::client::AsyncClient>,
     ) -> BoxFuture<()> {
-        let guard = write_node::<KS, PS>(node_ref);
-        let guard_ref = &*guard;
-        let cell = match guard_ref {
-            &NodeData::External(ref node) => Some(node.to_cell(&*deletion)),
-            &NodeData::Empty(_) => None,
-            _ => {
-                error!(
-                    "Cannot persist internal or other type of nodes, type {}",
-                    guard_ref.type_name()
-                );
-                unreachable!();
-            }
-        };
+        let cell = self.persist_cell(node_ref, deletion);
         let neb = neb.clone();
         async move {
             if let Some(cell) = cell {
@@ -658,6 +648,22 @@ where
         .boxed()
     }
 
+    fn persist_cell(&self, node_ref: &NodeCellRef, deletion: &DeletionSet) -> Option<OwnedCell> {
+        let guard = write_node::<KS, PS>(node_ref);
+        let guard_ref = &*guard;
+        match guard_ref {
+            &NodeData::External(ref node) => Some(node.to_cell(&*deletion)),
+            &NodeData::Empty(_) => None,
+            _ => {
+                error!(
+                    "Cannot persist internal or other type of nodes, type {}",
+                    guard_ref.type_name()
+                );
+                unreachable!();
+            }
+        }
+    }
+
     unsafe fn take_all_refs(&self) -> Vec<NodeCellRef> {
         let node = self.data.get().as_mut().unwrap();
         let mut res = vec![];
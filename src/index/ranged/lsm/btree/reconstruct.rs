@@ -128,6 +128,7 @@ where
 {
     info!("Reconstructing level tree from head {:?}", head_id);
     let mut len = 0;
+    let mut page_ids = vec![];
     let root = {
         let mut constructor = TreeConstructor::<KS, PS>::new();
         let mut prev_ref = NodeCellRef::new_none::<KS, PS>();
@@ -135,6 +136,7 @@ where
         let mut at_end = false;
         while !at_end {
             let cell = neb.read_cell(id).await.unwrap().unwrap();
+            page_ids.push(id);
             let page = ExtNode::<KS, PS>::from_cell(&cell);
             let next_id = page.next_id;
             let prev_id = page.prev_id;
@@ -162,7 +164,7 @@ where
         constructor.root()
     };
     info!("Reconstruct tree {:?} completed", head_id);
-    let tree = BPlusTree::from_root(root, head_id, len, deletion);
+    let tree = BPlusTree::from_root(root, head_id, len, deletion).with_page_ids(&page_ids);
     debug!("Verifying reconstruction at {}", level);
     // debug_assert!(verification::tree_has_no_empty_node(&tree));
     debug_assert!(verification::is_tree_in_order(&tree, level));
@@ -203,6 +205,10 @@ mod test {
                 backup_storage: None,
                 wal_storage: None,
                 index_enabled: false,
+                cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+                ready_timeout: DEFAULT_READY_TIMEOUT,
+                admission: AdmissionConfig::default(),
+                cell_index_capacity: default_cell_index_capacity(),
                 services: vec![Service::Cell],
             },
             &server_addr,
@@ -284,4 +290,105 @@ mod test {
             t.join().unwrap();
         }
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn gc_orphan_pages() {
+        let _ = env_logger::try_init();
+        let server_group = "btree-gc-orphan-pages";
+        let server_addr = String::from("127.0.0.1:5601");
+        let server = NebServer::new_from_opts(
+            &ServerOptions {
+                chunk_count: 1,
+                memory_size: 16 * 1024 * 1024,
+                backup_storage: None,
+                wal_storage: None,
+                index_enabled: false,
+                cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+                ready_timeout: DEFAULT_READY_TIMEOUT,
+                admission: AdmissionConfig::default(),
+                cell_index_capacity: default_cell_index_capacity(),
+                services: vec![Service::Cell],
+            },
+            &server_addr,
+            &server_group,
+        )
+        .await;
+        let client = Arc::new(
+            client::AsyncClient::new(
+                &server.rpc,
+                &server.membership,
+                &vec![server_addr],
+                server_group,
+            )
+            .await
+            .unwrap(),
+        );
+        client
+            .new_schema_with_id(page_schema())
+            .await
+            .unwrap()
+            .unwrap();
+        let page_cell = |id: Id, prev: Id, next: Id, key_base: u64| {
+            let mut value = OwnedValue::Map(OwnedMap::new());
+            value[*PREV_PAGE_KEY_HASH] = OwnedValue::Id(prev);
+            value[*NEXT_PAGE_KEY_HASH] = OwnedValue::Id(next);
+            value[*KEYS_KEY_HASH] = (0..PAGE_SIZE as u64)
+                .map(|i| {
+                    let key = EntryKey::from_id(&Id::new(key_base, i));
+                    SmallBytes::from_vec(key.as_slice().to_vec())
+                })
+                .collect_vec()
+                .value();
+            OwnedCell::new_with_id(*PAGE_SCHEMA_ID, &id, value)
+        };
+        // Pages of the tree are in its partition, tagged in the higher bits of their lower ids
+        let partition = 7;
+        let tag = 0x1234u64 << 32;
+        let num_linked = 10;
+        let linked_ids = (1..=num_linked)
+            .map(|i| Id::new(partition, tag | i))
+            .collect_vec();
+        for (i, id) in linked_ids.iter().enumerate() {
+            let prev = if i > 0 { linked_ids[i - 1] } else { Id::unit_id() };
+            let next = linked_ids.get(i + 1).cloned().unwrap_or(Id::unit_id());
+            client
+                .write_cell(page_cell(*id, prev, next, i as u64 + 1))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        // Pages written by an interrupted split, never linked into the chain
+        let orphan_ids = (100..103)
+            .map(|i| Id::new(partition, tag | i))
+            .collect_vec();
+        for id in &orphan_ids {
+            client
+                .write_cell(page_cell(*id, linked_ids[0], linked_ids[1], 100))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        // Pages of other trees, in the same partition under another tag and in another partition
+        let foreign_ids = vec![
+            Id::new(partition, (0x4321u64 << 32) | 1),
+            Id::new(partition + 1, tag | 1),
+        ];
+        for id in &foreign_ids {
+            client
+                .write_cell(page_cell(*id, Id::unit_id(), Id::unit_id(), 200))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        let deletion = Arc::new(HashSet::with_capacity(8));
+        let tree = LevelBPlusTree::from_head_id(&linked_ids[0], &client, &deletion, 0).await;
+        assert_eq!(tree.gc_orphan_pages(&client).await.unwrap(), orphan_ids.len());
+        for id in &orphan_ids {
+            assert!(client.read_cell(*id).await.unwrap().is_err());
+        }
+        for id in linked_ids.iter().chain(foreign_ids.iter()) {
+            assert!(client.read_cell(*id).await.unwrap().is_ok());
+        }
+        assert_eq!(tree.gc_orphan_pages(&client).await.unwrap(), 0);
+    }
 }
@@ -5,6 +5,7 @@ use super::node::NodeReadHandler;
 use super::*;
 use std::fmt::Debug;
 use std::marker::PhantomData;
+use std::ops::Bound;
 
 pub fn search_node<KS, PS>(
     node_ref: &NodeCellRef,
@@ -27,6 +28,7 @@ where
                 page: None,
                 marker: PhantomData,
                 current: None,
+                stop: Bound::Unbounded,
             };
             if let Some(right_node) = node.key_at_right_node(key) {
                 trace!("Search found a node at the right side");
@@ -40,6 +42,15 @@ where
                 }
             };
             match node {
+                &NodeData::External(ref n) if n.len == 0 => {
+                    // All keys of the node have been removed but it is still linked,
+                    // continue from its siblings like an empty node
+                    trace!("search in external for {:?} hit a node without keys", key);
+                    let mut cursor = gen_empty_cursor();
+                    cursor.page = Some(node_ref.clone());
+                    cursor.next();
+                    Ok(cursor)
+                }
                 &NodeData::External(ref n) => {
                     trace!(
                         "search in external for {:?}, len {}, ordering {:?}, content: {:?}",
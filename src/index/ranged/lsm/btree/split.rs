@@ -94,6 +94,7 @@ fn retain_by_node<KS, PS>(
                 num_removed_keys += node.len();
                 *node = NodeData::Empty(box Default::default());
                 make_deleted::<KS, PS>(&node_id);
+                tree.release_page_id(&node_id);
             }
             tree.len.fetch_sub(num_removed_keys, Release);
             info!("LSM tree retention removed {} keys", num_removed_keys);
@@ -1,30 +1,89 @@
-use super::external;
-use crate::client;
+use super::external::{self, ChangingNode, NEXT_PAGE_KEY_HASH, PAGE_SCHEMA_ID};
+use crate::client::{self, batch::BatchResult};
+use crate::ram::cell::{CellHeader, OwnedCell, WriteError};
+use crate::utils::env_or;
+use bifrost::rpc::RPCError;
+use crossbeam::queue::SegQueue;
+use dovahkiin::types::custom_types::id::Id;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use itertools::Itertools;
+use linked_hash_map::LinkedHashMap;
+use std::collections::HashSet;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+const WRITE_BACK_POLL_INTERVAL_MS: u64 = 10;
 
 static mut WB_STARTED: bool = false;
 lazy_static! {
     pub static ref CHANGE_PROGRESS: AtomicUsize = AtomicUsize::new(0);
 }
 
+// Where the write back sends changed nodes, the neb client in production
+pub trait WriteBackTarget: Send + Sync + 'static {
+    fn upsert_cells(
+        &self,
+        cells: Vec<OwnedCell>,
+    ) -> BoxFuture<Result<BatchResult<CellHeader, WriteError>, RPCError>>;
+    fn remove_cells(
+        &self,
+        ids: Vec<Id>,
+    ) -> BoxFuture<Result<BatchResult<(), WriteError>, RPCError>>;
+}
+
+impl WriteBackTarget for client::AsyncClient {
+    fn upsert_cells(
+        &self,
+        cells: Vec<OwnedCell>,
+    ) -> BoxFuture<Result<BatchResult<CellHeader, WriteError>, RPCError>> {
+        self.upsert_all_cells(cells).boxed()
+    }
+    fn remove_cells(
+        &self,
+        ids: Vec<Id>,
+    ) -> BoxFuture<Result<BatchResult<(), WriteError>, RPCError>> {
+        self.remove_all_cells(ids).boxed()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WriteBackConfig {
+    // Flush once this many changes are accumulated
+    pub max_batch: usize,
+    // Flush whatever accumulated after this window, even if the batch is not full
+    pub window: Duration,
+}
+
+impl Default for WriteBackConfig {
+    fn default() -> Self {
+        let max_batch = env_or("NEB_LSM_WRITE_BACK_BATCH", 128usize).max(1);
+        let window_ms = env_or("NEB_LSM_WRITE_BACK_WINDOW_MS", 500);
+        Self {
+            max_batch,
+            window: Duration::from_millis(window_ms),
+        }
+    }
+}
+
 pub fn start_external_nodes_write_back(client: &Arc<client::AsyncClient>) {
-    let client = client.clone();
+    start_external_nodes_write_back_with(client.clone(), WriteBackConfig::default());
+}
+
+pub fn start_external_nodes_write_back_with<T: WriteBackTarget>(
+    target: Arc<T>,
+    config: WriteBackConfig,
+) {
+    debug!("Starting external nodes write back with {:?}", config);
     tokio::spawn(async move {
         loop {
-            while let Some((id, changing)) = external::CHANGED_NODES.pop() {
-                match changing {
-                    external::ChangingNode::Modified(modified) => {
-                        modified.node.persist(&modified.deletion, &client).await;
-                    }
-                    external::ChangingNode::Deleted(id) => {
-                        let _ = client.remove_cell(id).await.unwrap();
-                    }
+            match write_back_batch(&external::CHANGED_NODES, &*target, &config).await {
+                Some(progress) => CHANGE_PROGRESS.store(progress, Ordering::Release),
+                None => {
+                    tokio::time::sleep(Duration::from_millis(WRITE_BACK_POLL_INTERVAL_MS)).await
                 }
-                CHANGE_PROGRESS.store(id, Ordering::Release);
             }
-            tokio::time::sleep(Duration::from_millis(500)).await;
         }
     });
     unsafe {
@@ -32,6 +91,85 @@ pub fn start_external_nodes_write_back(client: &Arc<client::AsyncClient>) {
     }
 }
 
+// Accumulate changes from the queue until the batch is full or the window is over, then write
+// them back in at most one upsert and one remove batch. Returns the newest change written back.
+pub(crate) async fn write_back_batch<T: WriteBackTarget + ?Sized>(
+    queue: &SegQueue<(usize, ChangingNode)>,
+    target: &T,
+    config: &WriteBackConfig,
+) -> Option<usize> {
+    let window_start = Instant::now();
+    let mut changes = Vec::with_capacity(config.max_batch);
+    loop {
+        while changes.len() < config.max_batch {
+            match queue.pop() {
+                Some(change) => changes.push(change),
+                None => break,
+            }
+        }
+        if changes.len() >= config.max_batch || window_start.elapsed() >= config.window {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(WRITE_BACK_POLL_INTERVAL_MS).min(config.window))
+            .await;
+    }
+    let num_changes = changes.len();
+    if num_changes == 0 {
+        return None;
+    }
+    let mut progress = 0;
+    // Only the last change of a node in the batch matters
+    let mut latest = LinkedHashMap::new();
+    for (change_id, changing) in changes {
+        progress = progress.max(change_id);
+        match changing {
+            ChangingNode::Modified(modified) => {
+                if let Some(cell) = modified.node.persist_cell(&modified.deletion) {
+                    latest.insert(cell.id(), Some(cell));
+                }
+            }
+            ChangingNode::Deleted(id) => {
+                latest.insert(id, None);
+            }
+        }
+    }
+    let mut upserts = vec![];
+    let mut removals = vec![];
+    for (id, cell) in latest {
+        match cell {
+            Some(cell) => upserts.push(cell),
+            None => removals.push(id),
+        }
+    }
+    trace!(
+        "Writing back {} changes, {} upserts, {} removals",
+        num_changes,
+        upserts.len(),
+        removals.len()
+    );
+    if !upserts.is_empty() {
+        match target.upsert_cells(upserts).await {
+            Ok(res) => {
+                for (_, e) in res.failures() {
+                    warn!("Cell node update error, error: {:?}", e);
+                }
+            }
+            Err(e) => error!("Cell node batch update error, error: {:?}", e),
+        }
+    }
+    if !removals.is_empty() {
+        match target.remove_cells(removals).await {
+            Ok(res) => {
+                for (_, e) in res.failures() {
+                    warn!("Cell node removal error, error: {:?}", e);
+                }
+            }
+            Err(e) => error!("Cell node batch removal error, error: {:?}", e),
+        }
+    }
+    Some(progress)
+}
+
 pub async fn wait_until_updated() {
     unsafe {
         if !WB_STARTED {
@@ -57,3 +195,43 @@ pub async fn wait_until_updated() {
     }
     debug!("Write back updated, {} cells", ops);
 }
+
+// Remove page cells of a tree not linked from it, leaked by interrupted splits. `owned` tells
+// pages allocated by the tree, `linked` lists the pages linked in the tree at the time of the call.
+// Pages listed before a split linked them are found linked when checked again, and kept.
+pub async fn gc_orphan_pages<O, L>(
+    neb: &client::AsyncClient,
+    owned: O,
+    linked: L,
+) -> Result<usize, RPCError>
+where
+    O: Fn(&Id) -> bool,
+    L: Fn() -> HashSet<Id>,
+{
+    let candidates = {
+        let linked = linked();
+        neb.cell_ids_of_schema(*PAGE_SCHEMA_ID)
+            .await?
+            .into_iter()
+            .filter(|id| owned(id) && !linked.contains(id))
+            .collect_vec()
+    };
+    if candidates.is_empty() {
+        return Ok(0);
+    }
+    let linked = linked();
+    let orphans = candidates
+        .into_iter()
+        .filter(|id| !linked.contains(id))
+        .collect_vec();
+    if orphans.is_empty() {
+        return Ok(0);
+    }
+    debug!(
+        "Removing {} orphan pages, {} pages linked",
+        orphans.len(),
+        linked.len()
+    );
+    let removed = neb.remove_all_cells(orphans).await?;
+    Ok(removed.num_successes())
+}
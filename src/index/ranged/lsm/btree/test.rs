@@ -1,10 +1,16 @@
 use super::dump::dump_tree;
 use super::reconstruct::TreeConstructor;
 use super::*;
+use crate::client::batch::BatchResult;
+use crate::ram::cell::{CellHeader, OwnedCell, WriteError};
 use crate::ram::types::RandValue;
+use bifrost::rpc::RPCError;
 use byteorder::BigEndian;
 use byteorder::WriteBytesExt;
+use crossbeam::queue::SegQueue;
 use dovahkiin::types::custom_types::id::Id;
+use futures::future;
+use futures::FutureExt;
 use itertools::Itertools;
 use lightning::map::HashSet;
 use rand::distributions::Uniform;
@@ -17,6 +23,8 @@ use std::fs::File;
 use std::io::Cursor as StdCursor;
 use std::io::Write;
 use std::mem::size_of;
+use std::ops::Bound::{self, *};
+use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
@@ -387,6 +395,56 @@ async fn level_merge() {
     }
 }
 
+#[test]
+fn level_merge_from() {
+    let _ = env_logger::try_init();
+    let range = 1000;
+    let deletion = deletion_set();
+    let tree_1 = TinyLevelBPlusTree::new(&deletion);
+    let tree_2 = LevelBPlusTree::new(&deletion);
+    let key_of = |n: u64| EntryKey::from_id(&Id::new(1, n));
+    for i in 0..range {
+        tree_1.insert(&key_of(i * 2));
+        tree_2.insert(&key_of(i * 2 + 1));
+    }
+    let start = key_of(range);
+    let merged =
+        level::level_merge_from(999, &tree_1, &tree_2, &start, &mut StdHashSet::new(), true);
+    assert!(merged > 0);
+    assert_eq!(tree_1.len(), range as usize - merged);
+    assert!(verification::tree_has_no_empty_node(&tree_1));
+    assert!(verification::is_tree_in_order(&tree_1, 0));
+    assert!(verification::is_tree_in_order(&tree_2, 0));
+    let found = |tree: &dyn LevelTree, key: &EntryKey| {
+        tree.seek_for(key, Ordering::Forward).current() == Some(key)
+    };
+    // Keys in one band are moved, the left edge stays
+    let moved = (0..range)
+        .map(|i| i * 2)
+        .filter(|n| found(&tree_2, &key_of(*n)))
+        .collect_vec();
+    assert_eq!(moved.len(), merged);
+    assert!(moved.windows(2).all(|pair| pair[1] == pair[0] + 2));
+    assert!(moved[0] > 0);
+    for i in 0..range {
+        let n = i * 2;
+        assert_ne!(found(&tree_1, &key_of(n)), moved.contains(&n));
+    }
+    // Pages at both sides of the band are linked together
+    let mut cursor = tree_1.seek(&*MIN_ENTRY_KEY, Ordering::Forward);
+    let remaining = (0..range)
+        .map(|i| i * 2)
+        .filter(|n| !moved.contains(n))
+        .map(key_of)
+        .collect_vec();
+    assert_eq!(cursor.collect_n(range as usize), remaining);
+    let mut backward = tree_1.seek(&*MAX_ENTRY_KEY, Ordering::Backward);
+    assert_eq!(
+        backward.collect_n(range as usize),
+        remaining.iter().rev().cloned().collect_vec()
+    );
+}
+
 #[test]
 fn level_merge_insertion() {
     let _ = env_logger::try_init();
@@ -521,3 +579,594 @@ fn reconstruct() {
         assert_eq!(&key, cursor.current().unwrap());
     }
 }
+
+#[derive(Default)]
+struct CountingWriteBack {
+    upsert_calls: AtomicUsize,
+    upserted: AtomicUsize,
+    remove_calls: AtomicUsize,
+    removed: AtomicUsize,
+}
+
+impl storage::WriteBackTarget for CountingWriteBack {
+    fn upsert_cells(
+        &self,
+        cells: Vec<OwnedCell>,
+    ) -> BoxFuture<Result<BatchResult<CellHeader, WriteError>, RPCError>> {
+        self.upsert_calls.fetch_add(1, Relaxed);
+        self.upserted.fetch_add(cells.len(), Relaxed);
+        let res = cells.into_iter().map(|cell| Ok(cell.header)).collect_vec();
+        future::ready(Ok(BatchResult::from(res))).boxed()
+    }
+    fn remove_cells(
+        &self,
+        ids: Vec<Id>,
+    ) -> BoxFuture<Result<BatchResult<(), WriteError>, RPCError>> {
+        self.remove_calls.fetch_add(1, Relaxed);
+        self.removed.fetch_add(ids.len(), Relaxed);
+        let res = ids.into_iter().map(|_| Ok(())).collect_vec();
+        future::ready(Ok(BatchResult::from(res))).boxed()
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn batched_write_back() {
+    let _ = env_logger::try_init();
+    let tree = LevelBPlusTree::new(&deletion_set());
+    for i in 0..2000 {
+        tree.insert(&EntryKey::from_id(&Id::new(1, i)));
+    }
+    // Mark every external node dirty, twice, in a private queue
+    let queue = SegQueue::new();
+    let mut pages = vec![];
+    let mut page = tree.seek(&*MIN_ENTRY_KEY, Ordering::Forward).page.unwrap();
+    loop {
+        let node = read_unchecked::<KeySlice, PtrSlice>(&page);
+        if !node.is_ext() {
+            break;
+        }
+        pages.push(page.clone());
+        let next = node.right_ref().unwrap().clone();
+        if next.is_default() {
+            break;
+        }
+        page = next;
+    }
+    let num_pages = pages.len();
+    assert!(num_pages > 32);
+    let mut change_id = 0;
+    for _ in 0..2 {
+        for page in &pages {
+            queue.push((
+                change_id,
+                ChangingNode::Modified(NodeModified {
+                    node: page.clone(),
+                    deletion: tree.deletion.clone(),
+                }),
+            ));
+            change_id += 1;
+        }
+    }
+    for i in 0..10 {
+        queue.push((change_id, ChangingNode::Deleted(Id::new(2, i))));
+        change_id += 1;
+    }
+    let target = CountingWriteBack::default();
+    let config = storage::WriteBackConfig {
+        max_batch: 16,
+        window: Duration::from_millis(100),
+    };
+    let mut progress = 0;
+    while let Some(newest) = storage::write_back_batch(&queue, &target, &config).await {
+        progress = newest;
+    }
+    assert_eq!(progress, change_id - 1);
+    let num_changes = num_pages * 2 + 10;
+    let num_batches = (num_changes + config.max_batch - 1) / config.max_batch;
+    // Every batch issues at most one upsert call, carrying many nodes at once
+    let upsert_calls = target.upsert_calls.load(Relaxed);
+    assert!(upsert_calls <= num_batches);
+    assert!(upsert_calls < num_pages);
+    // Each page changed twice, repeated changes in a batch are written once
+    let upserted = target.upserted.load(Relaxed);
+    assert!(upserted >= num_pages);
+    assert!(upserted <= num_pages * 2);
+    // Removals at the tail of the queue can straddle two batches
+    assert!(target.remove_calls.load(Relaxed) <= 2);
+    assert_eq!(target.removed.load(Relaxed), 10);
+}
+
+#[test]
+fn filtered_cursor() {
+    let _ = env_logger::try_init();
+    let tree = LevelBPlusTree::new(&deletion_set());
+    let num = 1000;
+    for n in 0..num {
+        tree.insert(&EntryKey::from_id(&Id::new(1, n)));
+    }
+    let is_even = |key: &EntryKey| key.id().lower % 2 == 0;
+    let start = EntryKey::from_id(&Id::new(1, 1));
+    let mut cursor = FilteredCursor::new(tree.seek(&start, Ordering::Forward), is_even);
+    for n in (2..num).step_by(2) {
+        let expected = EntryKey::from_id(&Id::new(1, n));
+        assert_eq!(cursor.current(), Some(&expected));
+        assert_eq!(cursor.next(), Some(expected));
+    }
+    assert!(cursor.current().is_none());
+    assert!(cursor.next().is_none());
+
+    let mut backward = FilteredCursor::new(tree.seek(&*MAX_ENTRY_KEY, Ordering::Backward), is_even);
+    for n in (0..num).step_by(2).rev() {
+        assert_eq!(backward.next(), Some(EntryKey::from_id(&Id::new(1, n))));
+    }
+    assert!(backward.next().is_none());
+}
+
+#[test]
+fn collect_n() {
+    let _ = env_logger::try_init();
+    let tree = LevelBPlusTree::new(&deletion_set());
+    let num = 1000;
+    for n in 0..num {
+        tree.insert(&EntryKey::from_id(&Id::new(1, n)));
+    }
+    let mut cursor = tree.seek(&*MIN_ENTRY_KEY, Ordering::Forward);
+    let first = cursor.collect_n(10);
+    assert_eq!(
+        first,
+        (0..10)
+            .map(|n| EntryKey::from_id(&Id::new(1, n)))
+            .collect_vec()
+    );
+    // Continues from where it stopped
+    assert_eq!(cursor.current(), Some(&EntryKey::from_id(&Id::new(1, 10))));
+    // Short range
+    let start = EntryKey::from_id(&Id::new(1, num - 5));
+    let mut cursor = tree.seek(&start, Ordering::Forward);
+    assert_eq!(cursor.collect_n(10).len(), 5);
+    assert!(cursor.collect_n(10).is_empty());
+    let mut backward = tree.seek(&*MAX_ENTRY_KEY, Ordering::Backward);
+    assert_eq!(
+        backward.collect_n(3),
+        vec![
+            EntryKey::from_id(&Id::new(1, num - 1)),
+            EntryKey::from_id(&Id::new(1, num - 2)),
+            EntryKey::from_id(&Id::new(1, num - 3)),
+        ]
+    );
+}
+
+#[test]
+fn seek_skips_drained_leaf() {
+    let _ = env_logger::try_init();
+    let tree = LevelBPlusTree::new(&deletion_set());
+    let num = 1000;
+    for n in 0..num {
+        tree.insert(&EntryKey::from_id(&Id::new(1, n)));
+    }
+    let key = EntryKey::from_id(&Id::new(1, num / 2));
+    let leaf = tree.seek(&key, Ordering::Forward).page.unwrap();
+    let (first, last) = {
+        let node = read_unchecked::<KeySlice, PtrSlice>(&leaf);
+        (node.first_key().id().lower, node.last_key().id().lower)
+    };
+    assert!(first > 0 && last + 1 < num);
+    // Remove all keys from the leaf but keep it linked with its siblings
+    {
+        let mut node = write_node::<KeySlice, PtrSlice>(&leaf);
+        let extnode = node.extnode_mut_no_persist();
+        while extnode.len > 0 {
+            extnode.remove_at(0);
+        }
+    }
+    let cursor = tree.seek(&key, Ordering::Forward);
+    assert_eq!(cursor.current().unwrap().id(), Id::new(1, last + 1));
+    let cursor = tree.seek(&key, Ordering::Backward);
+    assert_eq!(cursor.current().unwrap().id(), Id::new(1, first - 1));
+    // Scanning over the drained leaf
+    let start = EntryKey::from_id(&Id::new(1, first - 1));
+    let mut cursor = tree.seek(&start, Ordering::Forward);
+    assert_eq!(cursor.next().unwrap().id(), Id::new(1, first - 1));
+    assert_eq!(cursor.current().unwrap().id(), Id::new(1, last + 1));
+    let start = EntryKey::from_id(&Id::new(1, last + 1));
+    let mut cursor = tree.seek(&start, Ordering::Backward);
+    assert_eq!(cursor.next().unwrap().id(), Id::new(1, last + 1));
+    assert_eq!(cursor.current().unwrap().id(), Id::new(1, first - 1));
+}
+
+#[test]
+fn remove_prefix() {
+    let _ = env_logger::try_init();
+    let deletion = deletion_set();
+    let tree = LevelBPlusTree::new(&deletion);
+    let num = 100;
+    let removing_feature = [1u8; FEATURE_SIZE];
+    let keeping_features = [[0u8; FEATURE_SIZE], [2u8; FEATURE_SIZE]];
+    for n in 0..num {
+        let id = Id::new(1, n);
+        tree.insert(&EntryKey::from_props(&id, &removing_feature, 1, 1));
+        for feature in &keeping_features {
+            tree.insert(&EntryKey::from_props(&id, feature, 1, 1));
+        }
+    }
+    // Any id under the feature works as the prefix
+    let prefix = EntryKey::from_props(&Id::new(5, 5), &removing_feature, 1, 1);
+    assert_eq!(tree.remove_prefix(&prefix), num as usize);
+    assert_eq!(tree.remove_prefix(&prefix), 0);
+    let live = |key: &EntryKey| !deletion.contains(key);
+    let mut cursor = FilteredCursor::new(tree.seek(&*MIN_ENTRY_KEY, Ordering::Forward), live);
+    let mut remaining = 0;
+    while let Some(key) = cursor.next() {
+        assert!(!key.key_prefixed(&prefix));
+        remaining += 1;
+    }
+    assert_eq!(remaining, num as usize * keeping_features.len());
+}
+
+#[test]
+fn stable_scan_with_insertions() {
+    let _ = env_logger::try_init();
+    let num = 20000;
+    let tree = Arc::new(LevelBPlusTree::new(&deletion_set()));
+    let key_of = |n: u64| EntryKey::from_id(&Id::new(1, n));
+    for n in (0..num).step_by(2) {
+        tree.insert(&key_of(n));
+    }
+    let tree_2 = tree.clone();
+    let inserted = Arc::new(AtomicBool::new(false));
+    let inserted_2 = inserted.clone();
+    let inserter = thread::spawn(move || {
+        let mut odds = (0..num).filter(|n| n % 2 == 1).collect_vec();
+        odds.shuffle(&mut thread_rng());
+        for n in odds {
+            assert!(tree_2.insert(&key_of(n)));
+        }
+        inserted_2.store(true, Relaxed);
+    });
+    let mut scans = 0;
+    loop {
+        let inserting = !inserted.load(Relaxed);
+        let mut cursor = tree.scan(&*MIN_ENTRY_KEY);
+        let mut last: Option<EntryKey> = None;
+        let mut next_even = 0;
+        while let Some(key) = cursor.next() {
+            if let Some(last) = &last {
+                assert!(last < &key, "scan is not monotonic {:?} >= {:?}", last, key);
+            }
+            let n = key.id().lower;
+            if n % 2 == 0 {
+                // Keys present before the scan are neither skipped nor duplicated
+                assert_eq!(n, next_even);
+                next_even += 2;
+            }
+            last = Some(key);
+        }
+        assert_eq!(next_even, num);
+        scans += 1;
+        if !inserting {
+            break;
+        }
+    }
+    inserter.join().unwrap();
+    debug!("Scanned {} times along with insertions", scans);
+    let mut cursor = tree.scan(&key_of(num / 2));
+    for n in num / 2..num {
+        assert_eq!(cursor.next(), Some(key_of(n)));
+    }
+    assert!(cursor.next().is_none());
+}
+
+#[test]
+fn searches_along_root_splits() {
+    let _ = env_logger::try_init();
+    let num = 50000;
+    // Tiny pages and ascending keys split the root over and over
+    let tree = Arc::new(TinyLevelBPlusTree::new(&deletion_set()));
+    let next = Arc::new(AtomicUsize::new(0));
+    let inserted = Arc::new((0..num).map(|_| AtomicBool::new(false)).collect_vec());
+    let key_of = |n: usize| EntryKey::from_id(&Id::new(1, n as u64));
+    let inserters = (0..4)
+        .map(|_| {
+            let tree = tree.clone();
+            let next = next.clone();
+            let inserted = inserted.clone();
+            thread::spawn(move || loop {
+                let n = next.fetch_add(1, Relaxed);
+                if n >= num {
+                    break;
+                }
+                assert!(tree.insert(&key_of(n)));
+                inserted[n].store(true, Release);
+            })
+        })
+        .collect_vec();
+    let searchers = (0..4)
+        .map(|_| {
+            let tree = tree.clone();
+            let next = next.clone();
+            let inserted = inserted.clone();
+            thread::spawn(move || {
+                let mut rng = thread_rng();
+                let mut found = 0;
+                while next.load(Relaxed) < num {
+                    let n = rng.gen_range(0..next.load(Relaxed).min(num).max(1));
+                    if inserted[n].load(Acquire) {
+                        let key = key_of(n);
+                        assert_eq!(tree.seek(&key, Ordering::Forward).current(), Some(&key));
+                        found += 1;
+                    }
+                }
+                found
+            })
+        })
+        .collect_vec();
+    for inserter in inserters {
+        inserter.join().unwrap();
+    }
+    let found: usize = searchers.into_iter().map(|s| s.join().unwrap()).sum();
+    debug!("Found {} keys along with root splits", found);
+    assert_eq!(tree.len(), num);
+    let mut cursor = tree.seek(&key_of(0), Ordering::Forward);
+    for n in 0..num {
+        assert_eq!(cursor.next(), Some(key_of(n)));
+    }
+    assert!(cursor.next().is_none());
+}
+
+#[test]
+fn keys_sharing_feature() {
+    let _ = env_logger::try_init();
+    let tree = LevelBPlusTree::new(&deletion_set());
+    let num = PAGE_SIZE as u64 + 10;
+    let feature = [1u8; FEATURE_SIZE];
+    let key_of = |n: u64| EntryKey::from_props(&Id::new(1, n), &feature, 1, 1);
+    let mut ids = (0..num).collect_vec();
+    ids.shuffle(&mut thread_rng());
+    for &n in &ids {
+        assert!(tree.insert(&key_of(n)));
+    }
+    // Ids are part of the keys, the full leaf is split between ids of the same feature
+    let first_leaf = tree.seek(&key_of(0), Ordering::Forward).page.unwrap();
+    let last_leaf = tree.seek(&key_of(num - 1), Ordering::Forward).page.unwrap();
+    assert!(!first_leaf.ptr_eq(&last_leaf));
+    for n in 0..num {
+        let cursor = tree.seek(&key_of(n), Ordering::Forward);
+        assert_eq!(cursor.current(), Some(&key_of(n)));
+    }
+    let mut cursor = tree.seek(&key_of(0), Ordering::Forward);
+    for n in 0..num {
+        let key = cursor.next().unwrap();
+        assert!(key.key_prefixed(&key_of(0)));
+        assert_eq!(key.id(), Id::new(1, n));
+    }
+    assert!(cursor.next().is_none());
+}
+
+#[test]
+fn seek_range() {
+    let _ = env_logger::try_init();
+    let tree = LevelBPlusTree::new(&deletion_set());
+    let num = 1000;
+    for n in 0..num {
+        tree.insert(&EntryKey::from_id(&Id::new(1, n * 2)));
+    }
+    let key_of = |n: u64| EntryKey::from_id(&Id::new(1, n));
+    let range = |low: Bound<&EntryKey>, high: Bound<&EntryKey>, ordering: Ordering| {
+        let mut cursor = tree.seek_range(low, high, ordering);
+        let mut nums = vec![];
+        while let Some(key) = cursor.next() {
+            nums.push(key.id().lower);
+        }
+        assert!(cursor.current().is_none());
+        assert!(cursor.next().is_none());
+        nums
+    };
+    let (low, high) = (key_of(100), key_of(500));
+    let evens = |nums: std::ops::RangeInclusive<u64>| nums.filter(|n| n % 2 == 0).collect_vec();
+    assert_eq!(
+        range(Included(&low), Included(&high), Ordering::Forward),
+        evens(100..=500)
+    );
+    assert_eq!(
+        range(Excluded(&low), Excluded(&high), Ordering::Forward),
+        evens(102..=498)
+    );
+    assert_eq!(
+        range(Included(&low), Included(&high), Ordering::Backward),
+        evens(100..=500).into_iter().rev().collect_vec()
+    );
+    assert_eq!(
+        range(Excluded(&low), Excluded(&high), Ordering::Backward),
+        evens(102..=498).into_iter().rev().collect_vec()
+    );
+    // Bounds between keys
+    let (low, high) = (key_of(101), key_of(499));
+    assert_eq!(
+        range(Excluded(&low), Excluded(&high), Ordering::Forward),
+        evens(102..=498)
+    );
+    assert_eq!(
+        range(Included(&low), Included(&high), Ordering::Backward),
+        evens(102..=498).into_iter().rev().collect_vec()
+    );
+    // Open ends
+    assert_eq!(
+        range(Unbounded, Excluded(&key_of(10)), Ordering::Forward),
+        evens(0..=8)
+    );
+    assert_eq!(
+        range(Included(&key_of(1990)), Unbounded, Ordering::Backward),
+        evens(1990..=1998).into_iter().rev().collect_vec()
+    );
+    // Empty ranges
+    let (low, high) = (key_of(100), key_of(102));
+    assert!(range(Excluded(&low), Excluded(&high), Ordering::Forward).is_empty());
+    assert!(range(Excluded(&low), Excluded(&high), Ordering::Backward).is_empty());
+    assert!(range(Included(&high), Included(&low), Ordering::Forward).is_empty());
+    assert_eq!(
+        range(Included(&low), Included(&low), Ordering::Backward),
+        vec![100]
+    );
+}
+
+#[test]
+fn insert_sorted() {
+    let _ = env_logger::try_init();
+    let tree = LevelBPlusTree::new(&deletion_set());
+    let num = 10000;
+    let key_of = |n: u64| EntryKey::from_id(&Id::new(1, n));
+    // Every third key is in the tree already
+    for n in (0..num).step_by(3) {
+        tree.insert(&key_of(n));
+    }
+    let existing = tree.len();
+    let keys = (0..num).map(key_of).collect_vec();
+    assert_eq!(tree.insert_sorted(&keys), num as usize - existing);
+    assert_eq!(tree.len(), num as usize);
+    assert_eq!(tree.insert_sorted(&keys[..100]), 0);
+    assert!(verification::is_tree_in_order(&tree, 0));
+    let mut cursor = tree.seek(&*MIN_ENTRY_KEY, Ordering::Forward);
+    assert_eq!(cursor.collect_n(num as usize + 1), keys);
+    // Runs out of the existing keys
+    let more = (num..num * 2).map(key_of).collect_vec();
+    assert_eq!(tree.insert_sorted(&more), num as usize);
+    assert_eq!(tree.len(), num as usize * 2);
+    for n in 0..num * 2 {
+        assert_eq!(
+            tree.seek(&key_of(n), Ordering::Forward).current(),
+            Some(&key_of(n))
+        );
+    }
+}
+
+#[test]
+fn peek_n() {
+    let _ = env_logger::try_init();
+    let tree = LevelBPlusTree::new(&deletion_set());
+    let num = 1000;
+    let key_of = |n: u64| EntryKey::from_id(&Id::new(1, n));
+    for n in 0..num {
+        tree.insert(&key_of(n));
+    }
+    let mut cursor = tree.seek(&key_of(10), Ordering::Forward);
+    // Across pages, and the cursor stays where it is
+    let peeked = cursor.peek_n(100);
+    assert_eq!(peeked, (10..110).map(key_of).collect_vec());
+    assert_eq!(cursor.current(), Some(&key_of(10)));
+    assert_eq!(cursor.collect_n(100), peeked);
+    assert_eq!(cursor.peek_n(1), vec![key_of(110)]);
+    let mut backward = tree.seek(&key_of(5), Ordering::Backward);
+    assert_eq!(backward.peek_n(10), (0..=5).rev().map(key_of).collect_vec());
+    assert_eq!(backward.collect_n(10).len(), 6);
+    assert!(backward.peek_n(10).is_empty());
+    // Bounded by the range of the cursor
+    let range = tree.seek_range(
+        Bound::Included(&key_of(500)),
+        Bound::Excluded(&key_of(510)),
+        Ordering::Forward,
+    );
+    assert_eq!(range.peek_n(100), (500..510).map(key_of).collect_vec());
+}
+
+#[test]
+fn skip() {
+    let _ = env_logger::try_init();
+    let tree = LevelBPlusTree::new(&deletion_set());
+    let num = 1000;
+    let key_of = |n: u64| EntryKey::from_id(&Id::new(1, n));
+    for n in 0..num {
+        tree.insert(&key_of(n));
+    }
+    // Within the page and across pages
+    let mut cursor = tree.seek(&*MIN_ENTRY_KEY, Ordering::Forward);
+    assert_eq!(cursor.skip(0), 0);
+    assert_eq!(cursor.current(), Some(&key_of(0)));
+    assert_eq!(cursor.skip(3), 3);
+    assert_eq!(cursor.current(), Some(&key_of(3)));
+    assert_eq!(cursor.skip(500), 500);
+    assert_eq!(cursor.next(), Some(key_of(503)));
+    // Runs out of keys
+    assert_eq!(cursor.skip(num as usize), num as usize - 504);
+    assert!(cursor.next().is_none());
+    let mut backward = tree.seek(&key_of(600), Ordering::Backward);
+    assert_eq!(backward.skip(100), 100);
+    assert_eq!(backward.current(), Some(&key_of(500)));
+    assert_eq!(backward.skip(num as usize), 501);
+    assert!(backward.next().is_none());
+    // Stops at the bound of the range
+    let mut range = tree.seek_range(
+        Bound::Included(&key_of(100)),
+        Bound::Excluded(&key_of(400)),
+        Ordering::Forward,
+    );
+    assert_eq!(range.skip(250), 250);
+    assert_eq!(range.current(), Some(&key_of(350)));
+    assert_eq!(range.skip(100), 50);
+    assert!(range.next().is_none());
+}
+
+#[test]
+fn capacity() {
+    let _ = env_logger::try_init();
+    let tree = LevelBPlusTree::new(&deletion_set());
+    assert_eq!(tree.capacity(), ideal_capacity_from_node_size(PAGE_SIZE));
+    let tree = LevelBPlusTree::new(&deletion_set()).with_capacity(100);
+    for n in 0..100 {
+        tree.insert(&EntryKey::from_id(&Id::new(1, n)));
+    }
+    assert!(!tree.is_full());
+    assert!(!tree.oversized());
+    tree.insert(&EntryKey::from_id(&Id::new(1, 100)));
+    assert!(tree.is_full());
+    assert!(tree.oversized());
+    // Keys existing already are not counted
+    let tree = LevelBPlusTree::new(&deletion_set()).with_capacity(1);
+    tree.insert(&EntryKey::from_id(&Id::new(1, 1)));
+    tree.insert(&EntryKey::from_id(&Id::new(1, 1)));
+    assert!(!tree.is_full());
+}
+
+#[test]
+fn pages_in_partition() {
+    let _ = env_logger::try_init();
+    let tree = LevelBPlusTree::new_in_partition(&deletion_set(), 42);
+    let num = 1000;
+    for n in 0..num {
+        tree.insert(&EntryKey::from_id(&Id::new(1, n)));
+    }
+    let page_ids = |tree: &LevelBPlusTree| {
+        let mut page = tree.seek(&*MIN_ENTRY_KEY, Ordering::Forward).page.unwrap();
+        let mut ids = StdHashSet::new();
+        loop {
+            let (id, next) = {
+                let node = read_unchecked::<KeySlice, PtrSlice>(&page);
+                if node.is_none() {
+                    break;
+                }
+                (node.extnode().id, node.right_ref().unwrap().clone())
+            };
+            assert_eq!(id.higher, 42);
+            assert!(ids.insert(id));
+            page = next;
+        }
+        ids
+    };
+    // Leaves along the chain are split off from the root page
+    let ids = page_ids(&tree);
+    assert!(ids.len() > 1);
+    assert_eq!(tree.head_id().higher, 42);
+    let tracked = |tree: &LevelBPlusTree| {
+        let lowers = tree.page_ids.lock().iter().cloned().collect_vec();
+        lowers
+            .into_iter()
+            .map(|lower| Id::new(42, lower))
+            .collect::<StdHashSet<_>>()
+    };
+    assert_eq!(tracked(&tree), ids);
+    // Ids of pages merged away are released
+    let target = LevelBPlusTree::new(&deletion_set());
+    assert!(tree.merge_to(0, &target, &mut StdHashSet::new(), true) > 0);
+    let ids = page_ids(&tree);
+    assert!(ids.contains(&tree.head_id()));
+    assert_eq!(tracked(&tree), ids);
+}
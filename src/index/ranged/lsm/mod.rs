@@ -1,3 +1,4 @@
+pub mod scheduler;
 pub mod service;
 #[macro_use]
 pub mod btree;
@@ -0,0 +1,161 @@
+// Merges of oversized levels of LSM trees on a bounded number of workers. Levels closer to the
+// memory tree are merged first, so keys keep flowing out of the memory tree under bursts.
+
+use super::btree::storage;
+use super::tree::{LSMTree, MergeSource};
+use crate::ram::types::Id;
+use crate::utils::env_or;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+#[derive(Debug, Clone)]
+pub struct MergeSchedulerConfig {
+    // Number of merges running at the same time
+    pub workers: usize,
+    // Merges waiting for workers, further ones are dropped until checked again
+    pub queue_depth: usize,
+}
+
+impl Default for MergeSchedulerConfig {
+    fn default() -> Self {
+        let workers = env_or("NEB_LSM_MERGE_WORKERS", num_cpus::get().min(4)).max(1);
+        let queue_depth = env_or("NEB_LSM_MERGE_QUEUE_DEPTH", 1024);
+        Self {
+            workers,
+            queue_depth,
+        }
+    }
+}
+
+struct MergeQueue {
+    // Ordered by the source then by the time of scheduling
+    jobs: BTreeMap<(MergeSource, u64), (Id, Arc<LSMTree>)>,
+    queued: HashSet<(Id, MergeSource)>,
+    seq: u64,
+}
+
+pub struct MergeScheduler {
+    config: MergeSchedulerConfig,
+    queue: Mutex<MergeQueue>,
+    notify: Notify,
+    running: AtomicUsize,
+    completed: AtomicUsize,
+}
+
+impl MergeScheduler {
+    pub fn start(config: MergeSchedulerConfig) -> Arc<Self> {
+        debug!("Starting LSM merge scheduler with {:?}", config);
+        let scheduler = Arc::new(Self {
+            queue: Mutex::new(MergeQueue {
+                jobs: BTreeMap::new(),
+                queued: HashSet::new(),
+                seq: 0,
+            }),
+            notify: Notify::new(),
+            running: AtomicUsize::new(0),
+            completed: AtomicUsize::new(0),
+            config,
+        });
+        for _ in 0..scheduler.config.workers {
+            let scheduler = scheduler.clone();
+            tokio::spawn(async move {
+                loop {
+                    match scheduler.next_job() {
+                        Some((id, tree, source)) => scheduler.run(id, tree, source).await,
+                        None => scheduler.notify.notified().await,
+                    }
+                }
+            });
+        }
+        scheduler
+    }
+
+    // Queue a merge of the source, returns false if it is already queued or the queue is full
+    pub fn schedule(&self, id: Id, tree: &Arc<LSMTree>, source: MergeSource) -> bool {
+        {
+            let mut queue = self.queue.lock();
+            if queue.queued.len() >= self.config.queue_depth || !queue.queued.insert((id, source)) {
+                return false;
+            }
+            queue.seq += 1;
+            let seq = queue.seq;
+            queue.jobs.insert((source, seq), (id, tree.clone()));
+        }
+        trace!("Scheduled merge {:?} for tree {:?}", source, id);
+        self.notify.notify_one();
+        true
+    }
+
+    // Queue merges of all oversized levels of the tree, returns the number of merges queued
+    pub fn schedule_oversized(&self, id: Id, tree: &Arc<LSMTree>) -> usize {
+        tree.sources_to_merge()
+            .into_iter()
+            .filter(|source| self.schedule(id, tree, *source))
+            .count()
+    }
+
+    pub fn workers(&self) -> usize {
+        self.config.workers
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.config.queue_depth
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queue.lock().jobs.len()
+    }
+
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::Acquire)
+    }
+
+    // Number of merges took place since the scheduler started
+    pub fn completed(&self) -> usize {
+        self.completed.load(Ordering::Acquire)
+    }
+
+    pub fn is_idle(&self) -> bool {
+        let queue = self.queue.lock();
+        queue.jobs.is_empty() && self.running() == 0
+    }
+
+    fn next_job(&self) -> Option<(Id, Arc<LSMTree>, MergeSource)> {
+        let mut queue = self.queue.lock();
+        let key = *queue.jobs.keys().next()?;
+        let (id, tree) = queue.jobs.remove(&key).unwrap();
+        let source = key.0;
+        // Can be queued again from here, the merge checks the level before it starts
+        queue.queued.remove(&(id, source));
+        self.running.fetch_add(1, Ordering::AcqRel);
+        Some((id, tree, source))
+    }
+
+    async fn run(&self, id: Id, tree: Arc<LSMTree>, source: MergeSource) {
+        // Merges hold the thread for long, keep them off the threads of the runtime
+        let merging = tree.clone();
+        let merge = tokio::task::spawn_blocking(move || {
+            let mut deleted = HashSet::new();
+            let merged = merging.merge_source(source, &mut deleted);
+            (merged, deleted)
+        });
+        let (merged, deleted) = match merge.await {
+            Ok(res) => res,
+            Err(e) => {
+                error!("Merge {:?} of tree {:?} failed, {:?}", source, id, e);
+                (false, HashSet::new())
+            }
+        };
+        if merged {
+            storage::wait_until_updated().await;
+            tree.remove_deleted(deleted);
+            self.completed.fetch_add(1, Ordering::AcqRel);
+            // The level merged into may be oversized now
+            self.schedule_oversized(id, &tree);
+        }
+        self.running.fetch_sub(1, Ordering::AcqRel);
+    }
+}
@@ -2,8 +2,10 @@ use super::super::sm::client::SMClient;
 use super::super::trees::*;
 pub use super::btree::level::{LEVEL_1 as MIGRATE_SIZE, LEVEL_M as BLOCK_SIZE};
 use super::btree::storage;
+use super::scheduler::{MergeScheduler, MergeSchedulerConfig};
 use super::tree::*;
 use crate::client::AsyncClient;
+use crate::ram::cell::ReadError;
 use crate::ram::types::Id;
 use crate::ram::types::RandValue;
 use bifrost::conshash::ConsistentHashing;
@@ -32,6 +34,8 @@ pub enum OpResult<T> {
     OutOfBound,
     EpochMissMatch(u64, u64),
     Migrating,
+    // Too many keys are waiting for merges, retry later
+    Busy,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -42,7 +46,7 @@ pub struct ServBlock {
 
 pub struct DistLSMTree {
     id: Id,
-    tree: LSMTree,
+    tree: Arc<LSMTree>,
     prop: RwLock<DistProp>,
 }
 
@@ -79,14 +83,16 @@ service! {
     rpc load_tree(id: Id, boundary: Boundary, epoch: u64);
     rpc insert(id: Id, entry: EntryKey, epoch: u64) -> OpResult<bool>;
     rpc delete(id: Id, entry: EntryKey, epoch: u64) -> OpResult<bool>;
-    rpc seek(id: Id, entry: EntryKey, ordering: Ordering, buffer_size: u16, epoch: u64)
-        -> OpResult<ServBlock>;
+    rpc seek(id: Id, entry: EntryKey, ordering: Ordering, buffer_size: u16, epoch: u64,
+             lower: Option<EntryKey>, upper: Option<EntryKey>) -> OpResult<ServBlock>;
     rpc stat(id: Id) -> OpResult<LSMTreeStat>;
+    rpc verify(id: Id, lower: EntryKey, upper: EntryKey) -> OpResult<u64>;
 }
 
 pub struct LSMTreeService {
     client: Arc<AsyncClient>,
     trees: Arc<HashMap<Id, Arc<DistLSMTree>>>,
+    merges: Arc<MergeScheduler>,
 }
 
 impl Service for LSMTreeService {
@@ -126,13 +132,21 @@ impl Service for LSMTreeService {
     }
 
     fn insert(&self, id: Id, entry: EntryKey, epoch: u64) -> BoxFuture<OpResult<bool>> {
-        self.apply_in_ranged_tree(id, entry, epoch, |entry, tree| {
-            if tree.insert(&entry) {
-                OpResult::Successful(true)
-            } else {
-                OpResult::Successful(false)
+        let res = self.apply_in_ranged_tree(id, entry, epoch, |entry, tree| {
+            match tree.try_insert(&entry) {
+                Some(inserted) => OpResult::Successful(inserted),
+                None => OpResult::Busy,
             }
-        })
+        });
+        if let Some(dist_tree) = self.trees.get(&id) {
+            if dist_tree.tree.backlogged() {
+                self.merges.schedule_oversized(id, &dist_tree.tree);
+            } else if dist_tree.tree.mem_tree_oversized() {
+                self.merges
+                    .schedule(id, &dist_tree.tree, MergeSource::Memory);
+            }
+        }
+        res
     }
 
     fn delete(&self, id: Id, entry: EntryKey, epoch: u64) -> BoxFuture<OpResult<bool>> {
@@ -152,45 +166,18 @@ impl Service for LSMTreeService {
         ordering: Ordering,
         buffer_size: u16,
         epoch: u64,
+        lower: Option<EntryKey>,
+        upper: Option<EntryKey>,
     ) -> BoxFuture<OpResult<ServBlock>> {
         self.apply_in_ranged_tree(id, entry, epoch, |entry, tree| {
-            let buffer_size = buffer_size as usize;
-            let mut tree_cursor = tree.seek(&entry, ordering);
-            let mut buffer = Vec::with_capacity(buffer_size);
-            let mut num_collected = 0;
-            while num_collected < buffer_size {
-                if let Some(key) = tree_cursor.next() {
-                    let key_id = key.id();
-                    if let Some(last_key) = buffer.last() {
-                        if last_key == &key_id {
-                            continue;
-                        }
-                    }
-                    match ordering {
-                        Ordering::Forward => {
-                            if &key < entry {
-                                continue;
-                            }
-                        }
-                        Ordering::Backward => {
-                            if &key > entry {
-                                continue;
-                            }
-                        }
-                    }
-                    buffer.push(key_id);
-                    num_collected += 1;
-                } else {
-                    break;
-                }
-            }
-            let mut next = tree_cursor.current.as_ref().map(|(_, k)| k.clone());
-            // Skip next duplicates
-            while next.is_some() && next.as_ref().map(|k| k.id()).as_ref() == buffer.last() {
-                next = tree_cursor.next();
-            }
-            let lsm_cursor = ServBlock { buffer, next };
-            OpResult::Successful(lsm_cursor)
+            OpResult::Successful(seek_block(
+                tree,
+                entry,
+                ordering,
+                buffer_size as usize,
+                lower.as_ref(),
+                upper.as_ref(),
+            ))
         })
     }
 
@@ -203,12 +190,13 @@ impl Service for LSMTreeService {
                     .tree
                     .disk_trees
                     .iter()
-                    .map(|t| BTreeStat {
+                    .enumerate()
+                    .map(|(level, t)| BTreeStat {
                         size: t.size(),
                         count: t.count(),
                         head: t.head_id(),
-                        ideal_cap: t.ideal_capacity(),
-                        oversized: t.oversized(),
+                        ideal_cap: tree.tree.level_capacity(level),
+                        oversized: tree.tree.level_oversized(level),
                     })
                     .collect(),
             })
@@ -217,6 +205,118 @@ impl Service for LSMTreeService {
         })
         .boxed()
     }
+
+    // Delete keys from `lower` to `upper` of the tree referring to cells that do not exist.
+    // Cells are read block by block, so keys inserted during the verification may be missed.
+    fn verify(&self, id: Id, lower: EntryKey, upper: EntryKey) -> BoxFuture<OpResult<u64>> {
+        async move {
+            let dist_tree = match self.trees.get(&id) {
+                Some(tree) => tree,
+                None => return OpResult::NotFound,
+            };
+            if dist_tree.prop.read().migration.is_some() {
+                return OpResult::Migrating;
+            }
+            let mut repaired = 0;
+            let mut last: Option<EntryKey> = None;
+            loop {
+                let keys = {
+                    let start = last.as_ref().unwrap_or(&lower);
+                    let mut cursor = dist_tree.tree.seek(start, Ordering::Forward);
+                    let mut keys = Vec::with_capacity(BLOCK_SIZE);
+                    while keys.len() < BLOCK_SIZE {
+                        match cursor.next() {
+                            Some(key) if key > upper => break,
+                            Some(key) if &key < start || Some(&key) == last.as_ref() => continue,
+                            Some(key) => keys.push(key),
+                            None => break,
+                        }
+                    }
+                    keys
+                };
+                if keys.is_empty() {
+                    break;
+                }
+                for key in &keys {
+                    match self.client.read_cell(key.id()).await {
+                        Ok(Err(ReadError::CellDoesNotExisted)) => {
+                            if dist_tree.tree.delete(key) {
+                                repaired += 1;
+                            }
+                        }
+                        Ok(_) => {}
+                        Err(e) => warn!("Cannot verify index key {:?}, {:?}", key, e),
+                    }
+                }
+                last = keys.pop();
+            }
+            debug!("Verified tree {:?}, removed {} dangling keys", id, repaired);
+            OpResult::Successful(repaired)
+        }
+        .boxed()
+    }
+}
+
+// Collect a block of ids from `entry` in the ordering. Keys out of the inclusive bounds are
+// skipped, and the block has no next key once the bound in the ordering is crossed.
+pub(crate) fn seek_block(
+    tree: &LSMTree,
+    entry: &EntryKey,
+    ordering: Ordering,
+    buffer_size: usize,
+    lower: Option<&EntryKey>,
+    upper: Option<&EntryKey>,
+) -> ServBlock {
+    let below_lower = |key: &EntryKey| lower.map(|l| key < l).unwrap_or(false);
+    let above_upper = |key: &EntryKey| upper.map(|u| key > u).unwrap_or(false);
+    let crossed = |key: &EntryKey| match ordering {
+        Ordering::Forward => above_upper(key),
+        Ordering::Backward => below_lower(key),
+    };
+    let mut tree_cursor = tree.seek(entry, ordering);
+    let mut buffer = Vec::with_capacity(buffer_size);
+    let mut num_collected = 0;
+    while num_collected < buffer_size {
+        if let Some(key) = tree_cursor.next() {
+            if crossed(&key) {
+                return ServBlock { buffer, next: None };
+            }
+            let key_id = key.id();
+            if let Some(last_key) = buffer.last() {
+                if last_key == &key_id {
+                    continue;
+                }
+            }
+            match ordering {
+                Ordering::Forward => {
+                    if &key < entry {
+                        continue;
+                    }
+                }
+                Ordering::Backward => {
+                    if &key > entry {
+                        continue;
+                    }
+                }
+            }
+            if below_lower(&key) || above_upper(&key) {
+                continue;
+            }
+            buffer.push(key_id);
+            num_collected += 1;
+        } else {
+            break;
+        }
+    }
+    let mut next = tree_cursor.current.as_ref().map(|(_, k)| k.clone());
+    // Skip next duplicates
+    while next.is_some() && next.as_ref().map(|k| k.id()).as_ref() == buffer.last() {
+        next = tree_cursor.next();
+    }
+    if next.as_ref().map(|k| crossed(k)).unwrap_or(false) {
+        next = None;
+    }
+    ServBlock { buffer, next }
 }
 
 impl LSMTreeService {
@@ -224,10 +324,12 @@ impl LSMTreeService {
         info!("Initializing LSM tree service");
         let trees_map = Arc::new(HashMap::with_capacity(32));
         super::btree::storage::start_external_nodes_write_back(client);
-        Self::start_tree_balancer(&trees_map, client, sm_client);
+        let merges = MergeScheduler::start(MergeSchedulerConfig::default());
+        Self::start_tree_balancer(&trees_map, client, sm_client, &merges);
         Self {
             client: client.clone(),
             trees: trees_map,
+            merges,
         }
     }
 
@@ -235,19 +337,23 @@ impl LSMTreeService {
         trees_map: &Arc<HashMap<Id, Arc<DistLSMTree>>>,
         client: &Arc<AsyncClient>,
         sm_client: &Arc<SMClient>,
+        merges: &Arc<MergeScheduler>,
     ) {
         debug!("Starting range indexer tree balancer");
         let trees_map = trees_map.clone();
         let client = client.clone();
         let sm_client = sm_client.clone();
+        let merges = merges.clone();
         tokio::spawn(async move {
             loop {
-                let mut fast_mode = false;
                 for (_, dist_tree) in trees_map.entries() {
                     let tree = &dist_tree.tree;
-                    fast_mode = tree.merge_levels().await | fast_mode;
+                    // Merges missed by the queue are picked up here
+                    merges.schedule_oversized(dist_tree.id, tree);
                     if tree.oversized() {
                         info!("LSM Tree oversized {:?}, start migration", dist_tree.id);
+                        // Keys must stay in their levels while they are moved to the new tree
+                        tree.pause_merges().await;
                         // Tree oversized, need to migrate
                         let pivot_key = tree.pivot_key().unwrap();
                         let migration_target_id = Id::rand();
@@ -301,16 +407,15 @@ impl LSMTreeService {
                         debug!("Unmark migration {:?}", dist_tree.id);
                         tree.mark_migration(&dist_tree.id, None, &client).await;
                         tree.retain(&pivot_key);
+                        tree.resume_merges();
                         debug!(
                             "LSM tree migration from {:?} to {:?} succeed",
                             dist_tree.id, migration_target_id
                         );
                     }
                 }
-                if !fast_mode {
-                    // Sleep for a while to check for trees to be merge in levels
-                    tokio::time::sleep(Duration::from_millis(500)).await;
-                }
+                // Sleep for a while to check for trees to be migrated
+                tokio::time::sleep(Duration::from_millis(500)).await;
             }
         });
     }
@@ -364,7 +469,11 @@ impl DistLSMTree {
             migration,
             epoch,
         });
-        Self { id, tree, prop }
+        Self {
+            id,
+            tree: Arc::new(tree),
+            prop,
+        }
     }
 }
 
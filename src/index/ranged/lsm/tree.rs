@@ -3,23 +3,30 @@
 use super::btree::level::*;
 use super::btree::*;
 use crate::ram::schema::{Field, Schema};
+use crate::index::{ID_SIZE, KEY_SIZE};
 use crate::ram::types::*;
+use crate::utils::env_or;
+use crate::utils::hyperloglog::HyperLogLog;
 use crate::{client::AsyncClient, ram::cell::OwnedCell};
 use crossbeam_epoch::*;
+use itertools::Itertools;
 use lightning::map::HashSet as LFHashSet;
+use parking_lot::Mutex;
 use std::collections::HashSet as StdHashSet;
-use std::mem;
-use std::sync::atomic::Ordering::{Acquire, Release};
+use std::sync::atomic::Ordering::{Acquire, Release, SeqCst};
+use std::sync::atomic::{AtomicBool, AtomicUsize};
 use std::sync::Arc;
+use std::time::Duration;
 
 pub const LSM_TREE_SCHEMA_NAME: &'static str = "NEB_LSM_TREE";
 pub const LSM_TREE_LEVELS_NAME: &'static str = "levels";
 pub const LSM_TREE_MIGRATION_NAME: &'static str = "migration";
 pub const LAST_LEVEL_MULT_FACTOR: usize = 2;
 pub const INITIAL_TREE_EPOCH: u64 = 0;
+const MERGE_PAUSE_POLL_INTERVAL_MS: u64 = 10;
 
-type LevelTrees = [Box<dyn LevelTree>; NUM_LEVELS];
-type LevelCusors = [Box<dyn Cursor>; NUM_LEVELS + 2]; // 2 for mem and trans mem
+type LevelTrees = Vec<Box<dyn LevelTree>>;
+type LevelCusors = Vec<Box<dyn Cursor>>; // disk levels + 2 for mem and trans mem
 pub type DeletionSet = LFHashSet<EntryKey>;
 
 lazy_static! {
@@ -29,33 +36,115 @@ lazy_static! {
     pub static ref LSM_TREE_SCHEMA: Schema = lsm_treee_schema();
 }
 
+// Sizing of the disk levels, the number of levels is the number of capacities
+#[derive(Debug, Clone)]
+pub struct LSMTreeConfig {
+    // Number of keys a level can hold before merging to the next level
+    pub level_capacities: Vec<usize>,
+    // Keys in the memory tree and level 0 beyond which inserts are turned away until merges
+    // catch up, so writers are slowed down instead of growing level 0 without bound
+    pub max_unmerged_keys: usize,
+}
+
+impl LSMTreeConfig {
+    // Level 0 sized by its pages, each following level is `LEVEL_M * LEVEL_M` times larger
+    pub fn with_levels(num_levels: usize) -> Self {
+        assert!(num_levels >= 2, "LSM tree needs at least 2 disk levels");
+        let mut level_capacities = vec![ideal_capacity_from_node_size(LEVEL_0)];
+        for i in 1..num_levels {
+            // Deep levels are never filled, saturate instead of overflowing
+            level_capacities.push(level_capacities[i - 1].saturating_mul(LEVEL_M * LEVEL_M));
+        }
+        let max_unmerged_keys =
+            (ideal_capacity_from_node_size(LEVEL_M) + level_capacities[0]) * LEVEL_M;
+        Self {
+            level_capacities,
+            max_unmerged_keys,
+        }
+    }
+
+    pub fn num_levels(&self) -> usize {
+        self.level_capacities.len()
+    }
+}
+
+impl Default for LSMTreeConfig {
+    fn default() -> Self {
+        let mut num_levels = env_or("NEB_LSM_LEVELS", NUM_LEVELS);
+        if num_levels < 2 {
+            warn!(
+                "LSM tree needs at least 2 disk levels, got {}, using {}",
+                num_levels, NUM_LEVELS
+            );
+            num_levels = NUM_LEVELS;
+        }
+        let mut config = Self::with_levels(num_levels);
+        config.max_unmerged_keys = env_or("NEB_LSM_MAX_UNMERGED_KEYS", config.max_unmerged_keys);
+        config
+    }
+}
+
+// Tree whose keys are merged into the next level, ordered from the memory tree down
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MergeSource {
+    Memory,
+    Disk(usize),
+}
+
 pub struct LSMTree {
     pub mem_tree: Atomic<Box<dyn LevelTree>>,
     pub trans_mem_tree: Atomic<Box<dyn LevelTree>>,
     pub disk_trees: LevelTrees,
     pub deletion: Arc<DeletionSet>,
+    pub config: LSMTreeConfig,
+    // One for the memory tree followed by one for each disk level, a merge holds the locks of
+    // both of its source and target
+    merge_locks: Vec<Mutex<()>>,
+    merges_paused: AtomicBool,
+    active_merges: AtomicUsize,
 }
 
 impl LSMTree {
     pub async fn create(neb_client: &Arc<AsyncClient>, id: &Id) -> Self {
+        Self::create_with_config(neb_client, id, LSMTreeConfig::default()).await
+    }
+
+    pub async fn create_with_config(
+        neb_client: &Arc<AsyncClient>,
+        id: &Id,
+        config: LSMTreeConfig,
+    ) -> Self {
         let deletion_ref = Arc::new(LFHashSet::with_capacity(16));
         let tree_m = LevelMTree::new(&deletion_ref);
-        let tree_0 = Level0Tree::new(&deletion_ref);
-        let tree_1 = Level1Tree::new(&deletion_ref);
-        tree_0.persist_root(neb_client).await;
-        tree_1.persist_root(neb_client).await;
-        let level_ids = vec![tree_0.head_id(), tree_1.head_id()];
+        let mut disk_trees = LevelTrees::with_capacity(config.num_levels());
+        for level in 0..config.num_levels() {
+            disk_trees.push(create_disk_tree(level, id, neb_client, &deletion_ref).await);
+        }
+        let level_ids = disk_trees.iter().map(|tree| tree.head_id()).collect_vec();
         let lsm_tree_cell = lsm_tree_cell(&level_ids, id, None);
         neb_client.write_cell(lsm_tree_cell).await.unwrap().unwrap();
         Self {
             mem_tree: Atomic::new(box tree_m),
             trans_mem_tree: Atomic::null(),
-            disk_trees: [box tree_0, box tree_1],
+            merge_locks: merge_locks(disk_trees.len()),
+            merges_paused: AtomicBool::new(false),
+            active_merges: AtomicUsize::new(0),
+            disk_trees,
             deletion: deletion_ref,
+            config,
         }
     }
 
     pub async fn recover(neb_client: &Arc<AsyncClient>, lsm_tree_id: &Id) -> Self {
+        Self::recover_with_config(neb_client, lsm_tree_id, None).await
+    }
+
+    // Number of levels follows the record of the tree, the config is for sizing
+    pub async fn recover_with_config(
+        neb_client: &Arc<AsyncClient>,
+        lsm_tree_id: &Id,
+        config: Option<LSMTreeConfig>,
+    ) -> Self {
         info!("Recovering LSM tree {:?}", lsm_tree_id);
         let deletion_ref = Arc::new(LFHashSet::with_capacity(16));
         let cell = neb_client.read_cell(*lsm_tree_id).await.unwrap().unwrap();
@@ -64,18 +153,34 @@ impl LSMTree {
             .unwrap()
             .id()
             .unwrap();
-        let tree_0_id = &trees[0usize];
-        let tree_1_id = &trees[1usize];
         info!("Record shows trees {:?}", trees);
-        debug!("Recovering level 0 tree {:?}", tree_0_id);
-        let tree_0 = Level0Tree::from_head_id(tree_0_id, neb_client, &deletion_ref, 0).await;
-        debug!("Recovering level 1 tree {:?}", tree_1_id);
-        let tree_1 = Level1Tree::from_head_id(tree_1_id, neb_client, &deletion_ref, 1).await;
+        let config = match config {
+            Some(config) if config.num_levels() == trees.len() => config,
+            Some(config) => {
+                warn!(
+                    "LSM tree {:?} have {} levels, config expects {}",
+                    lsm_tree_id,
+                    trees.len(),
+                    config.num_levels()
+                );
+                LSMTreeConfig::with_levels(trees.len())
+            }
+            None => LSMTreeConfig::with_levels(trees.len()),
+        };
+        let mut disk_trees = LevelTrees::with_capacity(trees.len());
+        for (level, tree_id) in trees.iter().enumerate() {
+            debug!("Recovering level {} tree {:?}", level, tree_id);
+            disk_trees.push(recover_disk_tree(level, tree_id, neb_client, &deletion_ref).await);
+        }
         Self {
             mem_tree: Atomic::new(box LevelMTree::new(&deletion_ref)),
             trans_mem_tree: Atomic::null(),
-            disk_trees: [box tree_0, box tree_1],
+            merge_locks: merge_locks(disk_trees.len()),
+            merges_paused: AtomicBool::new(false),
+            active_merges: AtomicUsize::new(0),
+            disk_trees,
             deletion: deletion_ref,
+            config,
         }
     }
 
@@ -83,7 +188,28 @@ impl LSMTree {
         let guard = crossbeam_epoch::pin();
         let mem_tree_ptr = self.mem_tree.load(Acquire, &guard);
         let mem_tree = unsafe { mem_tree_ptr.as_ref().unwrap() };
-        mem_tree.insert_into(entry)
+        let inserted = mem_tree.insert_into(entry);
+        // Deleted keys are visible again once inserted, even when they are still in the levels
+        let undeleted = self.deletion.contains(entry);
+        if undeleted {
+            self.deletion.remove(entry);
+        }
+        inserted || undeleted
+    }
+
+    // Insert unless too many keys are waiting for merges, None for the writer to retry later
+    pub fn try_insert(&self, entry: &EntryKey) -> Option<bool> {
+        if self.backlogged() {
+            return None;
+        }
+        Some(self.insert(entry))
+    }
+
+    pub fn backlogged(&self) -> bool {
+        let guard = crossbeam_epoch::pin();
+        let mem_tree_ptr = self.mem_tree.load(Acquire, &guard);
+        let mem_tree = unsafe { mem_tree_ptr.as_ref().unwrap() };
+        mem_tree.count() + self.disk_trees[0].count() > self.config.max_unmerged_keys
     }
 
     pub fn delete(&self, entry: &EntryKey) -> bool {
@@ -100,52 +226,78 @@ impl LSMTree {
         LSMTreeCursor::new(entry, self, ordering)
     }
 
+    // Keys from `high` down to `low` inclusively. Bounds cover every id of their features, so the
+    // id suffix of `high` is raised to the max id and the one of `low` is lowered to the min id.
+    pub fn seek_range_backward(&self, high: &EntryKey, low: &EntryKey) -> LSMTreeRangeCursor {
+        let mut high = high.clone();
+        let mut low = low.clone();
+        high.set_id(&Id::new(u64::MAX, u64::MAX));
+        low.set_id(&Id::new(0, 0));
+        LSMTreeRangeCursor {
+            cursor: self.seek(&high, Ordering::Backward),
+            low,
+        }
+    }
+
     pub async fn merge_levels(&self) -> bool {
         let mut merged = false;
         let mut deleted = StdHashSet::new();
-        {
-            let guard = crossbeam_epoch::pin();
-            let mem_tree_ptr = self.mem_tree.load(Acquire, &guard);
-            let mem_tree = unsafe { mem_tree_ptr.as_ref().unwrap() };
-            if mem_tree.oversized() {
-                info!("Memory tree oversized, moving everything to disk tree");
-                // Memory tree oversized
-                // Will construct a new tree and swap the old one to trans_mem_tree for query and move all
-                // keys in old tree to disk tree level 0
-                let new_mem_tree: Box<dyn LevelTree> = box LevelMTree::new(&self.deletion);
-                let new_mem_tree_ptr = Owned::new(new_mem_tree).into_shared(&guard);
-                self.trans_mem_tree.store(mem_tree_ptr, Release);
-                self.mem_tree.store(new_mem_tree_ptr, Release);
-                info!("Starting moving memory tree...");
-                mem_tree.merge_all_to(0, &*self.disk_trees[0], &mut deleted, false);
-                info!("Memory tree merge completed, reset trans tree and destory old tree");
-                self.trans_mem_tree.store(Shared::null(), Release);
-                unsafe {
-                    guard.defer_destroy(mem_tree_ptr);
-                }
-                merged = true;
-                info!("Memory tree merging completed");
-            } else {
-                trace!("Memory tree not oversized");
-            }
-        }
+        merged |= self.merge_source(MergeSource::Memory, &mut deleted);
         storage::wait_until_updated().await;
         for i in 0..self.disk_trees.len() - 1 {
-            let level = i + 1;
-            if self.disk_trees[i].oversized() {
-                info!(
-                    "Level {}, {:?} tree oversized, merging",
-                    level,
-                    self.disk_trees[i].head_id()
-                );
-                self.disk_trees[i].merge_to(level, &*self.disk_trees[i + 1], &mut deleted, true);
+            if self.merge_source(MergeSource::Disk(i), &mut deleted) {
                 storage::wait_until_updated().await;
-                info!("Level {} merge completed", level);
                 merged = true;
-            } else {
-                trace!("Level {} tree not oversized", level);
             }
         }
+        self.remove_deleted(deleted);
+        merged
+    }
+
+    // Trees over their capacities, to be merged into their next levels
+    pub fn sources_to_merge(&self) -> Vec<MergeSource> {
+        let mut sources = vec![];
+        if self.mem_tree_oversized() {
+            sources.push(MergeSource::Memory);
+        }
+        for i in 0..self.disk_trees.len() - 1 {
+            if self.level_oversized(i) {
+                sources.push(MergeSource::Disk(i));
+            }
+        }
+        sources
+    }
+
+    pub fn mem_tree_oversized(&self) -> bool {
+        let guard = crossbeam_epoch::pin();
+        let mem_tree_ptr = self.mem_tree.load(Acquire, &guard);
+        unsafe { mem_tree_ptr.as_ref().unwrap() }.oversized()
+    }
+
+    // Merge the tree into its next level if it is still oversized, returns whether it merged.
+    // Merges of different sources can run concurrently, unless they share a level.
+    pub fn merge_source(&self, source: MergeSource, deleted: &mut StdHashSet<EntryKey>) -> bool {
+        self.active_merges.fetch_add(1, SeqCst);
+        let merged = if self.merges_paused.load(SeqCst) {
+            false
+        } else {
+            let lock_pos = match source {
+                MergeSource::Memory => 0,
+                MergeSource::Disk(i) => i + 1,
+            };
+            let _source_guard = self.merge_locks[lock_pos].lock();
+            let _target_guard = self.merge_locks[lock_pos + 1].lock();
+            match source {
+                MergeSource::Memory => self.merge_mem_tree(deleted),
+                MergeSource::Disk(i) => self.merge_disk_level(i, deleted),
+            }
+        };
+        self.active_merges.fetch_sub(1, SeqCst);
+        merged
+    }
+
+    // Merged keys in the deletion set are gone from the levels
+    pub fn remove_deleted(&self, deleted: StdHashSet<EntryKey>) {
         if !deleted.is_empty() {
             debug!(
                 "LSM merges collected {} deleted keys, remove them from deletion set",
@@ -155,11 +307,66 @@ impl LSMTree {
                 self.deletion.remove(&dk);
             }
         }
-        merged
+    }
+
+    // Stop merging and wait for running merges to finish, like before moving keys to another tree
+    pub async fn pause_merges(&self) {
+        self.merges_paused.store(true, SeqCst);
+        while self.active_merges.load(SeqCst) > 0 {
+            tokio::time::sleep(Duration::from_millis(MERGE_PAUSE_POLL_INTERVAL_MS)).await;
+        }
+    }
+
+    pub fn resume_merges(&self) {
+        self.merges_paused.store(false, SeqCst);
+    }
+
+    fn merge_mem_tree(&self, deleted: &mut StdHashSet<EntryKey>) -> bool {
+        let guard = crossbeam_epoch::pin();
+        let mem_tree_ptr = self.mem_tree.load(Acquire, &guard);
+        let mem_tree = unsafe { mem_tree_ptr.as_ref().unwrap() };
+        if !mem_tree.oversized() {
+            trace!("Memory tree not oversized");
+            return false;
+        }
+        info!("Memory tree oversized, moving everything to disk tree");
+        // Memory tree oversized
+        // Will construct a new tree and swap the old one to trans_mem_tree for query and move all
+        // keys in old tree to disk tree level 0
+        let new_mem_tree: Box<dyn LevelTree> = box LevelMTree::new(&self.deletion);
+        let new_mem_tree_ptr = Owned::new(new_mem_tree).into_shared(&guard);
+        self.trans_mem_tree.store(mem_tree_ptr, Release);
+        self.mem_tree.store(new_mem_tree_ptr, Release);
+        info!("Starting moving memory tree...");
+        mem_tree.merge_all_to(0, &*self.disk_trees[0], deleted, false);
+        info!("Memory tree merge completed, reset trans tree and destory old tree");
+        self.trans_mem_tree.store(Shared::null(), Release);
+        unsafe {
+            guard.defer_destroy(mem_tree_ptr);
+        }
+        info!("Memory tree merging completed");
+        true
+    }
+
+    fn merge_disk_level(&self, i: usize, deleted: &mut StdHashSet<EntryKey>) -> bool {
+        let level = i + 1;
+        if !self.level_oversized(i) {
+            trace!("Level {} tree not oversized", level);
+            return false;
+        }
+        info!(
+            "Level {}, {:?} tree oversized, merging",
+            level,
+            self.disk_trees[i].head_id()
+        );
+        self.disk_trees[i].merge_to(level, &*self.disk_trees[i + 1], deleted, true);
+        info!("Level {} merge completed", level);
+        true
     }
 
     pub fn retain(&self, pivot: &EntryKey) {
         info!("Retaining tree keys to {:?} for LSM tree split", pivot);
+        let _merge_guards = self.merge_locks.iter().map(|l| l.lock()).collect_vec();
         let guard = crossbeam_epoch::pin();
         let mem_tree_ptr = self.mem_tree.load(Acquire, &guard);
         let mem_tree = unsafe { mem_tree_ptr.as_ref().unwrap() };
@@ -270,12 +477,46 @@ impl LSMTree {
         client.update_cell(lsm_tree_cell).await.unwrap().unwrap();
     }
 
+    // Smallest and largest live keys across the memory and disk trees
+    pub fn key_bounds(&self) -> Option<(EntryKey, EntryKey)> {
+        // Cursors skip keys in the deletion set on `next`
+        let min = self.seek(&*MIN_ENTRY_KEY, Ordering::Forward).next()?;
+        let max = self.seek(&*MAX_ENTRY_KEY, Ordering::Backward).next()?;
+        Some((min, max))
+    }
+
+    // Estimated number of distinct features in keys starting with `field_prefix`, like the
+    // schema and field ids of an index, without keeping the scanned keys
+    pub fn approx_distinct(&self, field_prefix: &[u8]) -> u64 {
+        let feature_end = KEY_SIZE - ID_SIZE;
+        let feature_start = field_prefix.len().min(feature_end);
+        let mut hll = HyperLogLog::default();
+        let mut cursor = self.seek(&EntryKey::from_slice(field_prefix), Ordering::Forward);
+        while let Some(key) = cursor.next() {
+            let key_slice = key.as_slice();
+            if !key_slice.starts_with(field_prefix) {
+                break;
+            }
+            // Same keys from different levels are counted once
+            hll.insert(&key_slice[feature_start..feature_end]);
+        }
+        hll.estimate()
+    }
+
     pub fn merge_keys(&self, keys: Vec<EntryKey>) {
         self.last_level_tree().merge_with_keys(keys);
     }
 
     pub fn ideal_capacity(&self) -> usize {
-        self.last_level_tree().ideal_capacity() * LAST_LEVEL_MULT_FACTOR
+        self.level_capacity(self.disk_trees.len() - 1) * LAST_LEVEL_MULT_FACTOR
+    }
+
+    pub fn level_capacity(&self, level: usize) -> usize {
+        self.config.level_capacities[level]
+    }
+
+    pub fn level_oversized(&self, level: usize) -> bool {
+        self.disk_trees[level].count() > self.level_capacity(level)
     }
 
     pub fn count(&self) -> usize {
@@ -288,6 +529,8 @@ impl LSMTree {
     }
 }
 
+// Merges the level cursors. `current` is the leading live key among the levels, and `next`
+// returns it then moves every level holding it past it, so the two always agree.
 pub struct LSMTreeCursor {
     pub current: Option<(usize, EntryKey)>,
     cursors: LevelCusors,
@@ -302,66 +545,103 @@ impl LSMTreeCursor {
         let mem_tree_ptr = lsm_tree.mem_tree.load(Acquire, &guard);
         let trans_mem_tree_ptr = lsm_tree.trans_mem_tree.load(Acquire, &guard);
         let mem_tree = unsafe { mem_tree_ptr.as_ref().unwrap() };
-        let mut cursors = [
-            mem_tree.seek_for(key, ordering),
-            disk_trees[0].seek_for(key, ordering),
-            disk_trees[1].seek_for(key, ordering),
-            box DummyCursor, // for trans mem
-        ];
+        let mut cursors = LevelCusors::with_capacity(disk_trees.len() + 2);
+        // Levels from the most recent one, the memory tree being merged comes before disk trees
+        cursors.push(mem_tree.seek_for(key, ordering));
         if !trans_mem_tree_ptr.is_null() && trans_mem_tree_ptr != mem_tree_ptr {
             let trans_mem_tree = unsafe { trans_mem_tree_ptr.as_ref().unwrap() };
-            *cursors.last_mut().unwrap() = trans_mem_tree.seek_for(key, ordering);
+            cursors.push(trans_mem_tree.seek_for(key, ordering));
+        } else {
+            cursors.push(box DummyCursor);
+        }
+        for tree in disk_trees {
+            cursors.push(tree.seek_for(key, ordering));
         }
-        let current = Self::leading_tree_key(&cursors, ordering);
         let deletion = lsm_tree.deletion.clone();
-        Self {
+        let mut cursor = Self {
             cursors,
-            current,
+            current: None,
             ordering,
             deletion,
-        }
+        };
+        cursor.settle();
+        cursor
     }
 
+    // Identical keys in multiple levels lead from the most recent level
     fn leading_tree_key(cursors: &LevelCusors, ordering: Ordering) -> Option<(usize, EntryKey)> {
         match ordering {
             Ordering::Forward => cursors
                 .iter()
                 .enumerate()
                 .filter_map(|(i, c)| c.current().map(|c| (i, c)))
-                .min_by(|(_, x), (_, y)| x.cmp(y))
+                .min_by(|(i, x), (j, y)| x.cmp(y).then(i.cmp(j)))
                 .map(|(i, k)| (i, k.clone())),
             Ordering::Backward => cursors
                 .iter()
                 .enumerate()
                 .filter_map(|(i, c)| c.current().map(|c| (i, c)))
-                .max_by(|(_, x), (_, y)| x.cmp(y))
+                .max_by(|(i, x), (j, y)| x.cmp(y).then(j.cmp(i)))
                 .map(|(i, k)| (i, k.clone())),
         }
     }
+
+    // The same key can be in multiple levels before they are merged
+    fn advance_past(&mut self, key: &EntryKey) {
+        for cursor in self.cursors.iter_mut() {
+            if cursor.current() == Some(key) {
+                cursor.next();
+            }
+        }
+    }
+
+    // Compute the leading key, skipping keys in deletion set
+    fn settle(&mut self) {
+        loop {
+            self.current = Self::leading_tree_key(&self.cursors, self.ordering);
+            match &self.current {
+                Some((_, key)) if self.deletion.contains(key) => {
+                    let key = key.clone();
+                    self.advance_past(&key);
+                }
+                _ => return,
+            }
+        }
+    }
 }
 
 impl Cursor for LSMTreeCursor {
     fn current(&self) -> Option<&EntryKey> {
         self.current.as_ref().map(|(_, k)| k)
     }
+    // Keys deleted after the cursor settled on them are skipped as well. `current` can still show
+    // such a key until the cursor moves.
     fn next(&mut self) -> Option<EntryKey> {
-        loop {
-            if let Some((tree_id, _)) = self.current {
-                self.cursors[tree_id].next();
-                let mut other_entry = Self::leading_tree_key(&self.cursors, self.ordering);
-                mem::swap(&mut other_entry, &mut self.current);
-                let res = other_entry.map(|(_, key)| key);
-                if let Some(k) = &res {
-                    if self.deletion.contains(k) {
-                        // Skip keys in deletion set
-                        continue;
-                    }
-                }
-                return res;
-            } else {
-                return None;
+        if let Some((_, key)) = &self.current {
+            if self.deletion.contains(key) {
+                self.settle();
             }
         }
+        let (_, key) = self.current.take()?;
+        self.advance_past(&key);
+        self.settle();
+        Some(key)
+    }
+}
+
+// Backward cursor that ends once keys are below the lower bound
+pub struct LSMTreeRangeCursor {
+    cursor: LSMTreeCursor,
+    low: EntryKey,
+}
+
+impl Cursor for LSMTreeRangeCursor {
+    fn current(&self) -> Option<&EntryKey> {
+        self.cursor.current().filter(|key| *key >= &self.low)
+    }
+    fn next(&mut self) -> Option<EntryKey> {
+        self.current()?;
+        self.cursor.next()
     }
 }
 
@@ -401,11 +681,49 @@ fn lsm_tree_cell(level_ids: &Vec<Id>, id: &Id, migration: Option<Id>) -> OwnedCe
     OwnedCell::new_with_id(*LSM_TREE_SCHEMA_ID, id, OwnedValue::Map(cell_map))
 }
 
+// Level 0 have smaller pages, deeper levels share the largest page size.
+// Pages of all levels are placed along with the cell of the LSM tree.
+async fn create_disk_tree(
+    level: usize,
+    lsm_tree_id: &Id,
+    neb_client: &Arc<AsyncClient>,
+    deletion: &Arc<DeletionSet>,
+) -> Box<dyn LevelTree> {
+    match level {
+        0 => {
+            let tree = Level0Tree::new_in_partition(deletion, lsm_tree_id.higher);
+            tree.persist_root(neb_client).await;
+            box tree
+        }
+        _ => {
+            let tree = Level1Tree::new_in_partition(deletion, lsm_tree_id.higher);
+            tree.persist_root(neb_client).await;
+            box tree
+        }
+    }
+}
+
+async fn recover_disk_tree(
+    level: usize,
+    head_id: &Id,
+    neb_client: &Arc<AsyncClient>,
+    deletion: &Arc<DeletionSet>,
+) -> Box<dyn LevelTree> {
+    match level {
+        0 => box Level0Tree::from_head_id(head_id, neb_client, deletion, level).await,
+        _ => box Level1Tree::from_head_id(head_id, neb_client, deletion, level).await,
+    }
+}
+
 impl_btree_level!(LEVEL_M);
 type LevelMTreeKeySlice = [EntryKey; LEVEL_M];
 type LevelMTreePtrSlice = [NodeCellRef; LEVEL_M + 1];
 type LevelMTree = BPlusTree<LevelMTreeKeySlice, LevelMTreePtrSlice>;
 
+fn merge_locks(num_levels: usize) -> Vec<Mutex<()>> {
+    (0..=num_levels).map(|_| Mutex::new(())).collect()
+}
+
 impl_btree_level!(LEVEL_0);
 type Level0TreeKeySlice = [EntryKey; LEVEL_0];
 type Level0TreePtrSlice = [NodeCellRef; LEVEL_0 + 1];
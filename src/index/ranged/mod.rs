@@ -6,14 +6,17 @@ mod trees;
 #[cfg(test)]
 mod tests {
     use super::lsm::btree::storage;
+    use super::lsm::btree::Cursor;
     use super::lsm::btree::Ordering;
     use super::*;
     use crate::client::*;
     use crate::index::ranged::lsm::btree;
     use crate::index::EntryKey;
+    use crate::ram::cell::OwnedCell;
     use crate::ram::schema::*;
-    use crate::ram::types::Id;
+    use crate::ram::types::{Id, OwnedValue};
     use crate::server::*;
+    use bifrost_hasher::hash_str;
     use dovahkiin::types::Type;
     use futures::stream::FuturesUnordered;
     use itertools::Itertools;
@@ -35,6 +38,10 @@ mod tests {
                 backup_storage: None,
                 wal_storage: None,
                 index_enabled: false, // We don't use the high level index builder here
+                cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+                ready_timeout: DEFAULT_READY_TIMEOUT,
+                admission: AdmissionConfig::default(),
+                cell_index_capacity: default_cell_index_capacity(),
                 services: vec![Service::Cell, Service::RangedIndexer],
             },
             &server_addr,
@@ -165,6 +172,1482 @@ mod tests {
         );
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn configured_levels() {
+        let _ = env_logger::try_init();
+        let server_group = "lsm_levels_test";
+        let server_addr = String::from("127.0.0.1:5712");
+        let server = NebServer::new_from_opts(
+            &ServerOptions {
+                chunk_count: 1,
+                memory_size: 512 * 1024 * 1024,
+                backup_storage: None,
+                wal_storage: None,
+                index_enabled: false,
+                cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+                ready_timeout: DEFAULT_READY_TIMEOUT,
+                admission: AdmissionConfig::default(),
+                cell_index_capacity: default_cell_index_capacity(),
+                services: vec![Service::Cell],
+            },
+            &server_addr,
+            server_group,
+        )
+        .await;
+        let client = Arc::new(
+            AsyncClient::new(
+                &server.rpc,
+                &server.membership,
+                &vec![server_addr],
+                server_group,
+            )
+            .await
+            .unwrap(),
+        );
+        client
+            .new_schema_with_id(lsm::tree::LSM_TREE_SCHEMA.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        client
+            .new_schema_with_id(btree::page_schema())
+            .await
+            .unwrap()
+            .unwrap();
+        // Small capacities so a few thousand keys go through all 5 levels
+        let config = lsm::tree::LSMTreeConfig {
+            level_capacities: vec![256, 1024, 2048, 4096, 1 << 20],
+            max_unmerged_keys: 1 << 20,
+        };
+        let tree =
+            lsm::tree::LSMTree::create_with_config(&client, &Id::new(1, 1), config.clone()).await;
+        assert_eq!(tree.disk_trees.len(), 5);
+        let num_keys = 8192;
+        let mut reached = vec![false; config.num_levels()];
+        for i in 0..num_keys {
+            assert!(tree.insert(&EntryKey::from_id(&Id::new(1, i))));
+            if i % 64 == 0 {
+                while tree.merge_levels().await {
+                    for (level, t) in tree.disk_trees.iter().enumerate() {
+                        reached[level] |= t.count() > 0;
+                    }
+                }
+            }
+        }
+        assert_eq!(reached, vec![true; config.num_levels()]);
+        for level in 0..config.num_levels() - 1 {
+            assert!(!tree.level_oversized(level), "level {} oversized", level);
+        }
+        let mut cursor = tree.seek(&EntryKey::from_id(&Id::new(1, 0)), Ordering::Forward);
+        for i in 0..num_keys {
+            assert_eq!(cursor.next(), Some(EntryKey::from_id(&Id::new(1, i))));
+        }
+        assert_eq!(cursor.next(), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn merge_scheduler() {
+        let _ = env_logger::try_init();
+        let server_group = "lsm_merge_scheduler_test";
+        let server_addr = String::from("127.0.0.1:5717");
+        let server = NebServer::new_from_opts(
+            &ServerOptions {
+                chunk_count: 1,
+                memory_size: 512 * 1024 * 1024,
+                backup_storage: None,
+                wal_storage: None,
+                index_enabled: false,
+                cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+                ready_timeout: DEFAULT_READY_TIMEOUT,
+                admission: AdmissionConfig::default(),
+                cell_index_capacity: default_cell_index_capacity(),
+                services: vec![Service::Cell],
+            },
+            &server_addr,
+            server_group,
+        )
+        .await;
+        let client = Arc::new(
+            AsyncClient::new(
+                &server.rpc,
+                &server.membership,
+                &vec![server_addr],
+                server_group,
+            )
+            .await
+            .unwrap(),
+        );
+        client
+            .new_schema_with_id(lsm::tree::LSM_TREE_SCHEMA.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        client
+            .new_schema_with_id(btree::page_schema())
+            .await
+            .unwrap()
+            .unwrap();
+        let config = lsm::tree::LSMTreeConfig {
+            level_capacities: vec![256, 1024, 2048, 4096, 1 << 20],
+            max_unmerged_keys: 1 << 20,
+        };
+        let tree_id = Id::new(1, 1);
+        let tree = Arc::new(
+            lsm::tree::LSMTree::create_with_config(&client, &tree_id, config.clone()).await,
+        );
+        let scheduler_config = lsm::scheduler::MergeSchedulerConfig {
+            workers: 4,
+            queue_depth: 64,
+        };
+        let scheduler = lsm::scheduler::MergeScheduler::start(scheduler_config);
+        let num_keys = 8192;
+        // Burst of inserts while merges are running in the background
+        for i in 0..num_keys {
+            assert!(tree.insert(&EntryKey::from_id(&Id::new(1, i))));
+            if i % 64 == 0 {
+                scheduler.schedule_oversized(tree_id, &tree);
+            }
+        }
+        while !scheduler.is_idle() || scheduler.schedule_oversized(tree_id, &tree) > 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(scheduler.completed() > 1);
+        assert!(tree.sources_to_merge().is_empty());
+        let mut cursor = tree.seek(&EntryKey::from_id(&Id::new(1, 0)), Ordering::Forward);
+        for i in 0..num_keys {
+            assert_eq!(cursor.next(), Some(EntryKey::from_id(&Id::new(1, i))));
+        }
+        assert_eq!(cursor.next(), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn key_bounds() {
+        let _ = env_logger::try_init();
+        let server_group = "lsm_bounds_test";
+        let server_addr = String::from("127.0.0.1:5713");
+        let server = NebServer::new_from_opts(
+            &ServerOptions {
+                chunk_count: 1,
+                memory_size: 512 * 1024 * 1024,
+                backup_storage: None,
+                wal_storage: None,
+                index_enabled: false,
+                cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+                ready_timeout: DEFAULT_READY_TIMEOUT,
+                admission: AdmissionConfig::default(),
+                cell_index_capacity: default_cell_index_capacity(),
+                services: vec![Service::Cell],
+            },
+            &server_addr,
+            server_group,
+        )
+        .await;
+        let client = Arc::new(
+            AsyncClient::new(
+                &server.rpc,
+                &server.membership,
+                &vec![server_addr],
+                server_group,
+            )
+            .await
+            .unwrap(),
+        );
+        client
+            .new_schema_with_id(lsm::tree::LSM_TREE_SCHEMA.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        client
+            .new_schema_with_id(btree::page_schema())
+            .await
+            .unwrap()
+            .unwrap();
+        let config = lsm::tree::LSMTreeConfig {
+            level_capacities: vec![256, 1024, 1 << 20],
+            max_unmerged_keys: 1 << 20,
+        };
+        let tree = lsm::tree::LSMTree::create_with_config(&client, &Id::new(1, 1), config).await;
+        assert_eq!(tree.key_bounds(), None);
+        let mut rng = rand::thread_rng();
+        let mut nums = (0..4096u64).map(|n| n * 3 + 7).collect_vec();
+        nums.as_mut_slice().shuffle(&mut rng);
+        for (i, num) in nums.iter().enumerate() {
+            tree.insert(&EntryKey::from_id(&Id::new(1, *num)));
+            if i % 64 == 0 {
+                while tree.merge_levels().await {}
+            }
+        }
+        // Keys are spread over the memory tree and multiple disk levels
+        assert!(tree.disk_trees.iter().filter(|t| t.count() > 0).count() > 1);
+        let mut reference = nums.clone();
+        reference.sort();
+        let key_of = |num: u64| EntryKey::from_id(&Id::new(1, num));
+        assert_eq!(
+            tree.key_bounds(),
+            Some((key_of(reference[0]), key_of(*reference.last().unwrap())))
+        );
+        // Deleted keys are not bounds
+        for num in reference.iter().take(3).chain(reference.iter().rev().take(3)) {
+            assert!(tree.delete(&key_of(*num)));
+        }
+        assert_eq!(
+            tree.key_bounds(),
+            Some((
+                key_of(reference[3]),
+                key_of(reference[reference.len() - 4])
+            ))
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cursor_across_levels() {
+        let _ = env_logger::try_init();
+        let server_group = "lsm_cursor_levels_test";
+        let server_addr = String::from("127.0.0.1:5721");
+        let server = NebServer::new_from_opts(
+            &ServerOptions {
+                chunk_count: 1,
+                memory_size: 512 * 1024 * 1024,
+                backup_storage: None,
+                wal_storage: None,
+                index_enabled: false,
+                cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+                ready_timeout: DEFAULT_READY_TIMEOUT,
+                admission: AdmissionConfig::default(),
+                cell_index_capacity: default_cell_index_capacity(),
+                services: vec![Service::Cell],
+            },
+            &server_addr,
+            server_group,
+        )
+        .await;
+        let client = Arc::new(
+            AsyncClient::new(
+                &server.rpc,
+                &server.membership,
+                &vec![server_addr],
+                server_group,
+            )
+            .await
+            .unwrap(),
+        );
+        client
+            .new_schema_with_id(lsm::tree::LSM_TREE_SCHEMA.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        client
+            .new_schema_with_id(btree::page_schema())
+            .await
+            .unwrap()
+            .unwrap();
+        let config = lsm::tree::LSMTreeConfig {
+            level_capacities: vec![256, 1024, 1 << 20],
+            max_unmerged_keys: 1 << 20,
+        };
+        let tree = lsm::tree::LSMTree::create_with_config(&client, &Id::new(1, 1), config).await;
+        let key_of = |num: u64| EntryKey::from_id(&Id::new(1, num));
+        let mut rng = rand::thread_rng();
+        let mut nums = (0..4096u64).collect_vec();
+        nums.as_mut_slice().shuffle(&mut rng);
+        for (i, num) in nums.iter().enumerate() {
+            tree.insert(&key_of(*num));
+            if i % 64 == 0 {
+                while tree.merge_levels().await {}
+            }
+        }
+        assert!(tree.disk_trees.iter().filter(|t| t.count() > 0).count() > 1);
+        // Keys already on disk are inserted to the memory tree again, and some are deleted
+        for num in (0..4096).step_by(7) {
+            tree.insert(&key_of(num));
+        }
+        for num in (0..4096).step_by(5) {
+            assert!(tree.delete(&key_of(num)));
+        }
+        let expected = (0..4096).filter(|n| n % 5 != 0).map(key_of).collect_vec();
+        for ordering in vec![Ordering::Forward, Ordering::Backward] {
+            let (start, expected) = match ordering {
+                Ordering::Forward => (key_of(0), expected.clone()),
+                Ordering::Backward => (key_of(4095), expected.iter().rev().cloned().collect_vec()),
+            };
+            let mut cursor = tree.seek(&start, ordering);
+            for (i, key) in expected.iter().enumerate() {
+                // Current is stable across calls and always the key next returns
+                if i % 2 == 0 {
+                    assert_eq!(cursor.current(), Some(key));
+                    assert_eq!(cursor.current(), Some(key));
+                }
+                assert_eq!(cursor.next().as_ref(), Some(key));
+            }
+            assert_eq!(cursor.current(), None);
+            assert_eq!(cursor.next(), None);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn duplicate_across_levels() {
+        let _ = env_logger::try_init();
+        let server_group = "lsm_duplicate_levels_test";
+        let server_addr = String::from("127.0.0.1:5726");
+        let server = NebServer::new_from_opts(
+            &ServerOptions {
+                chunk_count: 1,
+                memory_size: 512 * 1024 * 1024,
+                backup_storage: None,
+                wal_storage: None,
+                index_enabled: false,
+                cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+                ready_timeout: DEFAULT_READY_TIMEOUT,
+                admission: AdmissionConfig::default(),
+                cell_index_capacity: default_cell_index_capacity(),
+                services: vec![Service::Cell],
+            },
+            &server_addr,
+            server_group,
+        )
+        .await;
+        let client = Arc::new(
+            AsyncClient::new(
+                &server.rpc,
+                &server.membership,
+                &vec![server_addr],
+                server_group,
+            )
+            .await
+            .unwrap(),
+        );
+        client
+            .new_schema_with_id(lsm::tree::LSM_TREE_SCHEMA.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        client
+            .new_schema_with_id(btree::page_schema())
+            .await
+            .unwrap()
+            .unwrap();
+        let config = lsm::tree::LSMTreeConfig {
+            level_capacities: vec![256, 1024, 1 << 20],
+            max_unmerged_keys: 1 << 20,
+        };
+        let tree = lsm::tree::LSMTree::create_with_config(&client, &Id::new(1, 1), config).await;
+        let key_of = |num: u64| EntryKey::from_id(&Id::new(1, num));
+        for num in 0..1024 {
+            tree.insert(&key_of(num));
+            if num % 64 == 0 {
+                while tree.merge_levels().await {}
+            }
+        }
+        let on_disk = (0..1024)
+            .find(|num| {
+                tree.disk_trees.iter().any(|t| {
+                    t.seek_for(&key_of(*num), Ordering::Forward).current() == Some(&key_of(*num))
+                })
+            })
+            .unwrap();
+        // Merged into a disk level but not yet removed from the memory tree
+        assert!(tree.insert(&key_of(on_disk)));
+        for ordering in vec![Ordering::Forward, Ordering::Backward] {
+            let (start, expected) = match ordering {
+                Ordering::Forward => (key_of(0), (0..1024).map(key_of).collect_vec()),
+                Ordering::Backward => (key_of(1023), (0..1024).rev().map(key_of).collect_vec()),
+            };
+            let mut cursor = tree.seek(&start, ordering);
+            let mut keys = vec![];
+            while let Some(key) = cursor.current().cloned() {
+                if key == key_of(on_disk) {
+                    // Led by the memory tree
+                    assert_eq!(cursor.current.as_ref().map(|(level, _)| *level), Some(0));
+                }
+                assert_eq!(cursor.next(), Some(key.clone()));
+                keys.push(key);
+            }
+            assert_eq!(keys, expected);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn delete_while_scanning() {
+        let _ = env_logger::try_init();
+        let server_group = "lsm_delete_scanning_test";
+        let server_addr = String::from("127.0.0.1:5727");
+        let server = NebServer::new_from_opts(
+            &ServerOptions {
+                chunk_count: 1,
+                memory_size: 512 * 1024 * 1024,
+                backup_storage: None,
+                wal_storage: None,
+                index_enabled: false,
+                cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+                ready_timeout: DEFAULT_READY_TIMEOUT,
+                admission: AdmissionConfig::default(),
+                cell_index_capacity: default_cell_index_capacity(),
+                services: vec![Service::Cell],
+            },
+            &server_addr,
+            server_group,
+        )
+        .await;
+        let client = Arc::new(
+            AsyncClient::new(
+                &server.rpc,
+                &server.membership,
+                &vec![server_addr],
+                server_group,
+            )
+            .await
+            .unwrap(),
+        );
+        client
+            .new_schema_with_id(lsm::tree::LSM_TREE_SCHEMA.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        client
+            .new_schema_with_id(btree::page_schema())
+            .await
+            .unwrap()
+            .unwrap();
+        let config = lsm::tree::LSMTreeConfig {
+            level_capacities: vec![256, 1024, 1 << 20],
+            max_unmerged_keys: 1 << 20,
+        };
+        let tree = lsm::tree::LSMTree::create_with_config(&client, &Id::new(1, 1), config).await;
+        let key_of = |num: u64| EntryKey::from_id(&Id::new(1, num));
+        for num in 0..1024 {
+            tree.insert(&key_of(num));
+            if num % 64 == 0 {
+                while tree.merge_levels().await {}
+            }
+        }
+        // Deleted after the cursor is created and before any merge, including its current key
+        let mut cursor = tree.seek(&key_of(0), Ordering::Forward);
+        assert_eq!(cursor.current(), Some(&key_of(0)));
+        for num in (0..1024).step_by(3) {
+            assert!(tree.delete(&key_of(num)));
+        }
+        let mut keys = vec![];
+        while let Some(key) = cursor.next() {
+            keys.push(key);
+        }
+        let expected = (0..1024).filter(|n| n % 3 != 0).map(key_of).collect_vec();
+        assert_eq!(keys, expected);
+        let mut backward = tree.seek(&key_of(1023), Ordering::Backward);
+        let mut keys = vec![];
+        while let Some(key) = backward.next() {
+            keys.push(key);
+        }
+        assert_eq!(keys, expected.iter().rev().cloned().collect_vec());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reinsert_deleted() {
+        let _ = env_logger::try_init();
+        let server_group = "lsm_reinsert_deleted_test";
+        let server_addr = String::from("127.0.0.1:5722");
+        let server = NebServer::new_from_opts(
+            &ServerOptions {
+                chunk_count: 1,
+                memory_size: 512 * 1024 * 1024,
+                backup_storage: None,
+                wal_storage: None,
+                index_enabled: false,
+                cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+                ready_timeout: DEFAULT_READY_TIMEOUT,
+                admission: AdmissionConfig::default(),
+                cell_index_capacity: default_cell_index_capacity(),
+                services: vec![Service::Cell],
+            },
+            &server_addr,
+            server_group,
+        )
+        .await;
+        let client = Arc::new(
+            AsyncClient::new(
+                &server.rpc,
+                &server.membership,
+                &vec![server_addr],
+                server_group,
+            )
+            .await
+            .unwrap(),
+        );
+        client
+            .new_schema_with_id(lsm::tree::LSM_TREE_SCHEMA.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        client
+            .new_schema_with_id(btree::page_schema())
+            .await
+            .unwrap()
+            .unwrap();
+        let config = lsm::tree::LSMTreeConfig {
+            level_capacities: vec![256, 1024, 1 << 20],
+            max_unmerged_keys: 1 << 20,
+        };
+        let tree = lsm::tree::LSMTree::create_with_config(&client, &Id::new(1, 1), config).await;
+        let key_of = |num: u64| EntryKey::from_id(&Id::new(1, num));
+        let found =
+            |num: u64| tree.seek(&key_of(num), Ordering::Forward).current() == Some(&key_of(num));
+        for num in 0..1024 {
+            tree.insert(&key_of(num));
+            if num % 64 == 0 {
+                while tree.merge_levels().await {}
+            }
+        }
+        // One key on disk and another one in the memory tree
+        let on_disk = (0..1024)
+            .find(|num| {
+                tree.disk_trees.iter().any(|t| {
+                    t.seek_for(&key_of(*num), Ordering::Forward).current() == Some(&key_of(*num))
+                })
+            })
+            .unwrap();
+        tree.insert(&key_of(2048));
+        for num in &[on_disk, 2048] {
+            assert!(tree.delete(&key_of(*num)));
+            assert!(!found(*num));
+            assert!(!tree.delete(&key_of(*num)));
+        }
+        // Visible again without any merge
+        for num in &[on_disk, 2048] {
+            assert!(tree.insert(&key_of(*num)));
+            assert!(found(*num));
+        }
+        let mut cursor = tree.seek(&key_of(0), Ordering::Forward);
+        let mut keys = vec![];
+        while let Some(key) = cursor.next() {
+            keys.push(key);
+        }
+        assert_eq!(keys, (0..1024).chain(vec![2048]).map(key_of).collect_vec());
+        // And stays after merges
+        while tree.merge_levels().await {}
+        assert!(found(on_disk));
+        assert!(found(2048));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn insert_backpressure() {
+        let _ = env_logger::try_init();
+        let server_group = "lsm_backpressure_test";
+        let server_addr = String::from("127.0.0.1:5723");
+        let server = NebServer::new_from_opts(
+            &ServerOptions {
+                chunk_count: 1,
+                memory_size: 512 * 1024 * 1024,
+                backup_storage: None,
+                wal_storage: None,
+                index_enabled: false,
+                cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+                ready_timeout: DEFAULT_READY_TIMEOUT,
+                admission: AdmissionConfig::default(),
+                cell_index_capacity: default_cell_index_capacity(),
+                services: vec![Service::Cell],
+            },
+            &server_addr,
+            server_group,
+        )
+        .await;
+        let client = Arc::new(
+            AsyncClient::new(
+                &server.rpc,
+                &server.membership,
+                &vec![server_addr],
+                server_group,
+            )
+            .await
+            .unwrap(),
+        );
+        client
+            .new_schema_with_id(lsm::tree::LSM_TREE_SCHEMA.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        client
+            .new_schema_with_id(btree::page_schema())
+            .await
+            .unwrap()
+            .unwrap();
+        let max_unmerged_keys = 1024;
+        let config = lsm::tree::LSMTreeConfig {
+            level_capacities: vec![256, 1024, 1 << 20],
+            max_unmerged_keys,
+        };
+        let tree = lsm::tree::LSMTree::create_with_config(&client, &Id::new(1, 1), config).await;
+        let key_of = |num: u64| EntryKey::from_id(&Id::new(1, num));
+        // Keys pile up in the memory tree with merges stalled, until inserts are turned away
+        tree.pause_merges().await;
+        let mut num = 0;
+        while let Some(inserted) = tree.try_insert(&key_of(num)) {
+            assert!(inserted);
+            num += 1;
+            assert!(num <= max_unmerged_keys as u64 + 1);
+        }
+        assert_eq!(num, max_unmerged_keys as u64 + 1);
+        assert!(tree.backlogged());
+        assert!(!tree.merge_levels().await);
+        assert_eq!(tree.try_insert(&key_of(num)), None);
+        // Inserts are taken again once merges drain the memory tree and level 0
+        tree.resume_merges();
+        while tree.backlogged() {
+            assert!(tree.merge_levels().await);
+        }
+        assert_eq!(tree.try_insert(&key_of(num)), Some(true));
+        let mut cursor = tree.seek(&key_of(0), Ordering::Forward);
+        for n in 0..=num {
+            assert_eq!(cursor.next(), Some(key_of(n)));
+        }
+        assert_eq!(cursor.next(), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn empty_seek() {
+        let _ = env_logger::try_init();
+        let server_group = "ranged_empty_seek_test";
+        let server_addr = String::from("127.0.0.1:5714");
+        let server = NebServer::new_from_opts(
+            &ServerOptions {
+                chunk_count: 1,
+                memory_size: 512 * 1024 * 1024,
+                backup_storage: None,
+                wal_storage: None,
+                index_enabled: false,
+                cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+                ready_timeout: DEFAULT_READY_TIMEOUT,
+                admission: AdmissionConfig::default(),
+                cell_index_capacity: default_cell_index_capacity(),
+                services: vec![Service::Cell, Service::RangedIndexer],
+            },
+            &server_addr,
+            server_group,
+        )
+        .await;
+        let client = Arc::new(
+            AsyncClient::new(
+                &server.rpc,
+                &server.membership,
+                &vec![server_addr],
+                server_group,
+            )
+            .await
+            .unwrap(),
+        );
+        client.new_schema_with_id(schema()).await.unwrap().unwrap();
+        let index_client = Arc::new(client::RangedQueryClient::new(
+            &server.consh,
+            &server.raft_client,
+        ));
+        let seek = |num: u64, ordering: Ordering| {
+            let index_client = index_client.clone();
+            async move {
+                let key = EntryKey::from_id(&Id::new(1, num));
+                tokio::time::timeout(
+                    Duration::from_secs(5),
+                    client::RangedQueryClient::seek(&index_client, &key, ordering, 8),
+                )
+                .await
+                .expect("Empty seek should not be retried")
+                .unwrap()
+            }
+        };
+        // Nothing in the index
+        assert!(seek(10, Ordering::Forward).await.is_none());
+        assert!(seek(10, Ordering::Backward).await.is_none());
+        for num in 20..30 {
+            let key = EntryKey::from_id(&Id::new(1, num));
+            assert!(index_client.insert(&key).await.unwrap());
+        }
+        // Beyond the keys in either ordering
+        assert!(seek(30, Ordering::Forward).await.is_none());
+        assert!(seek(19, Ordering::Backward).await.is_none());
+        let cursor = seek(25, Ordering::Forward).await.unwrap();
+        assert_eq!(cursor.current(), Some(&Id::new(1, 25)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn approx_distinct() {
+        let _ = env_logger::try_init();
+        let server_group = "lsm_distinct_test";
+        let server_addr = String::from("127.0.0.1:5715");
+        let server = NebServer::new_from_opts(
+            &ServerOptions {
+                chunk_count: 1,
+                memory_size: 512 * 1024 * 1024,
+                backup_storage: None,
+                wal_storage: None,
+                index_enabled: false,
+                cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+                ready_timeout: DEFAULT_READY_TIMEOUT,
+                admission: AdmissionConfig::default(),
+                cell_index_capacity: default_cell_index_capacity(),
+                services: vec![Service::Cell],
+            },
+            &server_addr,
+            server_group,
+        )
+        .await;
+        let client = Arc::new(
+            AsyncClient::new(
+                &server.rpc,
+                &server.membership,
+                &vec![server_addr],
+                server_group,
+            )
+            .await
+            .unwrap(),
+        );
+        client
+            .new_schema_with_id(lsm::tree::LSM_TREE_SCHEMA.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        client
+            .new_schema_with_id(btree::page_schema())
+            .await
+            .unwrap()
+            .unwrap();
+        let config = lsm::tree::LSMTreeConfig {
+            level_capacities: vec![256, 1024, 1 << 20],
+            max_unmerged_keys: 1 << 20,
+        };
+        let tree = lsm::tree::LSMTree::create_with_config(&client, &Id::new(1, 1), config).await;
+        let prefix_of = |field: u64| {
+            let mut prefix = vec![];
+            prefix.extend_from_slice(&11u32.to_be_bytes());
+            prefix.extend_from_slice(&(field as u32).to_be_bytes());
+            prefix
+        };
+        assert_eq!(tree.approx_distinct(&prefix_of(1)), 0);
+        // Every feature of the large field appears in two cells, the small field has 10 features
+        let large_distinct = 3000u64;
+        let mut keys = (0..large_distinct * 2)
+            .map(|n| {
+                let feature = (n % large_distinct).to_be_bytes();
+                EntryKey::from_props(&Id::new(1, n + 1), &feature, 1, 11)
+            })
+            .chain((0..1000u64).map(|n| {
+                let feature = (n % 10).to_be_bytes();
+                EntryKey::from_props(&Id::new(2, n + 1), &feature, 2, 11)
+            }))
+            .collect_vec();
+        let mut rng = rand::thread_rng();
+        keys.as_mut_slice().shuffle(&mut rng);
+        for (i, key) in keys.iter().enumerate() {
+            tree.insert(key);
+            if i % 64 == 0 {
+                while tree.merge_levels().await {}
+            }
+        }
+        let estimate = tree.approx_distinct(&prefix_of(1));
+        let error = (estimate as f64 - large_distinct as f64).abs() / large_distinct as f64;
+        // Well within 3 standard errors of the default precision
+        assert!(error < 0.03, "Estimated {}, expected {}", estimate, large_distinct);
+        let estimate = tree.approx_distinct(&prefix_of(2));
+        assert!(estimate >= 9 && estimate <= 11, "Estimated {}", estimate);
+        assert_eq!(tree.approx_distinct(&prefix_of(3)), 0);
+    }
+
+    #[test]
+    fn owners_for_range() {
+        use super::sm::{trees_in_range, TreePlacement};
+        use super::trees::{max_entry_key, min_entry_key};
+        use std::collections::BTreeMap;
+        let key_of = |num: u64| EntryKey::from_id(&Id::new(1, num));
+        let pivot = key_of(100);
+        let mut trees = BTreeMap::new();
+        trees.insert(min_entry_key(), TreePlacement::new(Id::new(2, 1)));
+        trees.insert(pivot.clone(), TreePlacement::new(Id::new(2, 2)));
+        let owners = |lower: u64, upper: u64| {
+            trees_in_range(&trees, &key_of(lower), &key_of(upper))
+                .into_iter()
+                .map(|tree| (tree.placement.id, tree.lower, tree.upper))
+                .collect_vec()
+        };
+        // Crossing the split point
+        assert_eq!(
+            owners(50, 150),
+            vec![
+                (Id::new(2, 1), min_entry_key(), pivot.clone()),
+                (Id::new(2, 2), pivot.clone(), max_entry_key())
+            ]
+        );
+        // On either side of it
+        assert_eq!(
+            owners(10, 99),
+            vec![(Id::new(2, 1), min_entry_key(), pivot.clone())]
+        );
+        assert_eq!(
+            owners(100, 200),
+            vec![(Id::new(2, 2), pivot.clone(), max_entry_key())]
+        );
+        assert_eq!(owners(50, 100).len(), 2);
+        assert!(owners(150, 50).is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn bounded_seek() {
+        let _ = env_logger::try_init();
+        let server_group = "lsm_bounded_seek_test";
+        let server_addr = String::from("127.0.0.1:5716");
+        let server = NebServer::new_from_opts(
+            &ServerOptions {
+                chunk_count: 1,
+                memory_size: 512 * 1024 * 1024,
+                backup_storage: None,
+                wal_storage: None,
+                index_enabled: false,
+                cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+                ready_timeout: DEFAULT_READY_TIMEOUT,
+                admission: AdmissionConfig::default(),
+                cell_index_capacity: default_cell_index_capacity(),
+                services: vec![Service::Cell],
+            },
+            &server_addr,
+            server_group,
+        )
+        .await;
+        let client = Arc::new(
+            AsyncClient::new(
+                &server.rpc,
+                &server.membership,
+                &vec![server_addr],
+                server_group,
+            )
+            .await
+            .unwrap(),
+        );
+        client
+            .new_schema_with_id(lsm::tree::LSM_TREE_SCHEMA.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        client
+            .new_schema_with_id(btree::page_schema())
+            .await
+            .unwrap()
+            .unwrap();
+        let tree = lsm::tree::LSMTree::create(&client, &Id::new(1, 1)).await;
+        let key_of = |num: u64| EntryKey::from_id(&Id::new(1, num));
+        let ids_of = |nums: std::ops::RangeInclusive<u64>| nums.map(|n| Id::new(1, n)).collect_vec();
+        for num in 0..100 {
+            tree.insert(&key_of(num));
+        }
+        let seek = |entry: u64, ordering: Ordering, lower: u64, upper: u64| {
+            lsm::service::seek_block(
+                &tree,
+                &key_of(entry),
+                ordering,
+                16,
+                Some(&key_of(lower)),
+                Some(&key_of(upper)),
+            )
+        };
+        // Block stops at the upper bound, there is nothing next
+        let block = seek(20, Ordering::Forward, 20, 30);
+        assert_eq!(block.buffer, ids_of(20..=30));
+        assert!(block.next.is_none());
+        // Full block within the bound still has the next key
+        let block = seek(20, Ordering::Forward, 20, 60);
+        assert_eq!(block.buffer, ids_of(20..=35));
+        assert_eq!(block.next, Some(key_of(36)));
+        let block = seek(36, Ordering::Forward, 20, 40);
+        assert_eq!(block.buffer, ids_of(36..=40));
+        assert!(block.next.is_none());
+        // Bound exactly at the end of a full block
+        let block = seek(20, Ordering::Forward, 20, 35);
+        assert_eq!(block.buffer, ids_of(20..=35));
+        assert!(block.next.is_none());
+        // Backward stops at the lower bound
+        let block = seek(50, Ordering::Backward, 45, 60);
+        assert_eq!(block.buffer, ids_of(45..=50).into_iter().rev().collect_vec());
+        assert!(block.next.is_none());
+        // Unbounded
+        let block = lsm::service::seek_block(&tree, &key_of(90), Ordering::Forward, 16, None, None);
+        assert_eq!(block.buffer, ids_of(90..=99));
+        assert!(block.next.is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn range_backward() {
+        let _ = env_logger::try_init();
+        let server_group = "lsm_range_backward_test";
+        let server_addr = String::from("127.0.0.1:5724");
+        let server = NebServer::new_from_opts(
+            &ServerOptions {
+                chunk_count: 1,
+                memory_size: 512 * 1024 * 1024,
+                backup_storage: None,
+                wal_storage: None,
+                index_enabled: false,
+                cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+                ready_timeout: DEFAULT_READY_TIMEOUT,
+                admission: AdmissionConfig::default(),
+                cell_index_capacity: default_cell_index_capacity(),
+                services: vec![Service::Cell],
+            },
+            &server_addr,
+            server_group,
+        )
+        .await;
+        let client = Arc::new(
+            AsyncClient::new(
+                &server.rpc,
+                &server.membership,
+                &vec![server_addr],
+                server_group,
+            )
+            .await
+            .unwrap(),
+        );
+        client
+            .new_schema_with_id(lsm::tree::LSM_TREE_SCHEMA.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        client
+            .new_schema_with_id(btree::page_schema())
+            .await
+            .unwrap()
+            .unwrap();
+        let tree = lsm::tree::LSMTree::create(&client, &Id::new(1, 1)).await;
+        let key_of = |num: u64, id: &Id| EntryKey::from_props(id, &num.to_be_bytes(), 1, 1);
+        for num in 0..1000 {
+            tree.insert(&key_of(num, &Id::new(1, num + 1)));
+        }
+        // Ids of the bounds are irrelevant
+        let mut cursor =
+            tree.seek_range_backward(&key_of(500, &Id::unit_id()), &key_of(400, &Id::unit_id()));
+        let mut nums = vec![];
+        while let Some(key) = cursor.next() {
+            nums.push(key.id().lower - 1);
+        }
+        assert_eq!(nums.len(), 101);
+        assert_eq!(nums, (400..=500).rev().collect_vec());
+        assert!(cursor.current().is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn create_index() {
+        let _ = env_logger::try_init();
+        let server_group = "ranged_create_index_test";
+        let server_addr = String::from("127.0.0.1:5718");
+        let server = NebServer::new_from_opts(
+            &ServerOptions {
+                chunk_count: 1,
+                memory_size: 512 * 1024 * 1024,
+                backup_storage: None,
+                wal_storage: None,
+                index_enabled: true,
+                cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+                ready_timeout: DEFAULT_READY_TIMEOUT,
+                admission: AdmissionConfig::default(),
+                cell_index_capacity: default_cell_index_capacity(),
+                services: vec![Service::Cell, Service::RangedIndexer],
+            },
+            &server_addr,
+            server_group,
+        )
+        .await;
+        let meta_servers = vec![server_addr];
+        let new_client =
+            || AsyncClient::new(&server.rpc, &server.membership, &meta_servers, server_group);
+        let client = new_client().await.unwrap();
+        let other_client = new_client().await.unwrap();
+        let schema = Schema::new_with_id(
+            12,
+            "scores",
+            None,
+            Field::new(
+                "*",
+                Type::Map,
+                false,
+                false,
+                Some(vec![Field::new(
+                    "score",
+                    Type::U64,
+                    false,
+                    false,
+                    None,
+                    vec![],
+                )]),
+                vec![],
+            ),
+            false,
+            false,
+        );
+        let field_id = hash_str("score");
+        client
+            .new_schema_with_id(schema.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        async fn write(client: &AsyncClient, schema_id: u32, num: u64, score: u64) {
+            let cell = OwnedCell::new_with_id(
+                schema_id,
+                &Id::new(1, num),
+                data_map_value! { score: score },
+            );
+            client.write_cell(cell).await.unwrap().unwrap();
+        }
+        // Existing cells, scores in the reverse order of ids
+        for num in 0..10 {
+            write(&client, schema.id, num, (10 - num) * 10).await;
+        }
+        assert_eq!(
+            client
+                .create_index(schema.id, field_id, IndexType::Ranged)
+                .await
+                .unwrap(),
+            10
+        );
+        assert!(matches!(
+            client
+                .create_index(schema.id, hash_str("missing"), IndexType::Ranged)
+                .await,
+            Err(CreateIndexError::SchemaError(
+                SchemaError::FieldDoesNotExisted(_)
+            ))
+        ));
+        // The index is in the schema seen by other clients and servers
+        let indexed = other_client
+            .get_all_schema()
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|s| s.id == schema.id)
+            .unwrap();
+        assert_eq!(indexed.index_fields[&field_id], vec![IndexType::Ranged]);
+        assert_eq!(
+            server.meta.schemas.get(&schema.id).unwrap().index_fields[&field_id],
+            vec![IndexType::Ranged]
+        );
+        // New cells are indexed on write
+        write(&other_client, schema.id, 10, 5).await;
+        let index_client = Arc::new(client::RangedQueryClient::new(
+            &other_client.conshash,
+            &other_client.raft_client,
+        ));
+        let key = EntryKey::from_props(
+            &Id::new(0, 0),
+            &OwnedValue::U64(0).feature(),
+            field_id,
+            schema.id,
+        );
+        let mut cursor = client::RangedQueryClient::seek(&index_client, &key, Ordering::Forward, 4)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut ids = vec![];
+        for _ in 0..11 {
+            ids.push(cursor.next().await.unwrap().unwrap().lower);
+        }
+        assert_eq!(ids, vec![10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn ranged_desc_query() {
+        let _ = env_logger::try_init();
+        let server_group = "ranged_desc_query_test";
+        let server_addr = String::from("127.0.0.1:5728");
+        let server = NebServer::new_from_opts(
+            &ServerOptions {
+                chunk_count: 1,
+                memory_size: 512 * 1024 * 1024,
+                backup_storage: None,
+                wal_storage: None,
+                index_enabled: true,
+                cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+                ready_timeout: DEFAULT_READY_TIMEOUT,
+                admission: AdmissionConfig::default(),
+                cell_index_capacity: default_cell_index_capacity(),
+                services: vec![Service::Cell, Service::RangedIndexer],
+            },
+            &server_addr,
+            server_group,
+        )
+        .await;
+        let client = AsyncClient::new(
+            &server.rpc,
+            &server.membership,
+            &vec![server_addr],
+            server_group,
+        )
+        .await
+        .unwrap();
+        // Indexed both ways
+        let schema = Schema::new_with_id(
+            13,
+            "desc_scores",
+            None,
+            Field::new(
+                "*",
+                Type::Map,
+                false,
+                false,
+                Some(vec![Field::new(
+                    "score",
+                    Type::U64,
+                    false,
+                    false,
+                    None,
+                    vec![IndexType::Ranged, IndexType::RangedDesc],
+                )]),
+                vec![],
+            ),
+            false,
+            false,
+        );
+        let field_id = hash_str("score");
+        client
+            .new_schema_with_id(schema.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        let scores = vec![5u64, 300, 1, 70000, 42];
+        for (num, score) in scores.iter().enumerate() {
+            let cell = OwnedCell::new_with_id(
+                schema.id,
+                &Id::new(1, num as u64),
+                data_map_value! { score: *score },
+            );
+            client.write_cell(cell).await.unwrap().unwrap();
+        }
+        let index_client = Arc::new(client::RangedQueryClient::new(
+            &client.conshash,
+            &client.raft_client,
+        ));
+        // Cells are numbered by the positions of their scores
+        let nums_by_scores = |ordered: Vec<u64>| {
+            ordered
+                .into_iter()
+                .map(|score| scores.iter().position(|s| *s == score).unwrap() as u64)
+                .collect_vec()
+        };
+        let key = EntryKey::from_props(
+            &Id::new(0, 0),
+            &OwnedValue::U64(0).feature(),
+            field_id,
+            schema.id,
+        );
+        let mut cursor = client::RangedQueryClient::seek(&index_client, &key, Ordering::Forward, 2)
+            .await
+            .unwrap()
+            .unwrap();
+        let ascending = cursor.collect_n(scores.len()).await.unwrap();
+        assert_eq!(
+            ascending.iter().map(|id| id.lower).collect_vec(),
+            nums_by_scores(vec![1, 5, 42, 300, 70000])
+        );
+        let from = OwnedValue::U64(u64::MAX).feature();
+        let mut cursor =
+            client::RangedQueryClient::seek_desc(&index_client, schema.id, field_id, &from, 2)
+                .await
+                .unwrap()
+                .unwrap();
+        let descending = cursor.collect_n(scores.len()).await.unwrap();
+        assert_eq!(
+            descending.iter().map(|id| id.lower).collect_vec(),
+            nums_by_scores(vec![70000, 300, 42, 5, 1])
+        );
+        // From a score in the middle
+        let from = OwnedValue::U64(300).feature();
+        let mut cursor =
+            client::RangedQueryClient::seek_desc(&index_client, schema.id, field_id, &from, 2)
+                .await
+                .unwrap()
+                .unwrap();
+        let descending = cursor.collect_n(3).await.unwrap();
+        assert_eq!(
+            descending.iter().map(|id| id.lower).collect_vec(),
+            nums_by_scores(vec![300, 42, 5])
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn composite_prefix_query() {
+        let _ = env_logger::try_init();
+        let server_group = "composite_prefix_query_test";
+        let server_addr = String::from("127.0.0.1:5729");
+        let server = NebServer::new_from_opts(
+            &ServerOptions {
+                chunk_count: 1,
+                memory_size: 512 * 1024 * 1024,
+                backup_storage: None,
+                wal_storage: None,
+                index_enabled: true,
+                cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+                ready_timeout: DEFAULT_READY_TIMEOUT,
+                admission: AdmissionConfig::default(),
+                cell_index_capacity: default_cell_index_capacity(),
+                services: vec![Service::Cell, Service::RangedIndexer],
+            },
+            &server_addr,
+            server_group,
+        )
+        .await;
+        let client = AsyncClient::new(
+            &server.rpc,
+            &server.membership,
+            &vec![server_addr],
+            server_group,
+        )
+        .await
+        .unwrap();
+        let schema = Schema::new_with_id(
+            14,
+            "composite_places",
+            None,
+            Field::new(
+                "*",
+                Type::Map,
+                false,
+                false,
+                Some(vec![
+                    Field::new("country", Type::String, false, false, None, vec![]),
+                    Field::new("city", Type::String, false, false, None, vec![]),
+                ]),
+                vec![],
+            ),
+            false,
+            false,
+        )
+        .with_composite_index(&["country", "city"])
+        .unwrap();
+        client
+            .new_schema_with_id(schema.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        let places = vec![
+            ("us", "nyc"),
+            ("cn", "bj"),
+            ("us", "la"),
+            ("cn", "sh"),
+            ("uk", "ldn"),
+        ];
+        for (num, (country, city)) in places.iter().enumerate() {
+            let cell = OwnedCell::new_with_id(
+                schema.id,
+                &Id::new(1, num as u64),
+                data_map_value! { country: *country, city: *city },
+            );
+            client.write_cell(cell).await.unwrap().unwrap();
+        }
+        let index_client = Arc::new(client::RangedQueryClient::new(
+            &client.conshash,
+            &client.raft_client,
+        ));
+        let field_ids = &schema.composite_indices[0];
+        let feature_of = |s: &str| OwnedValue::String(s.to_string()).feature();
+        // Cells of the country, ordered by their cities
+        let mut cursor = client::RangedQueryClient::seek_composite(
+            &index_client,
+            schema.id,
+            field_ids,
+            &[feature_of("us")],
+            1,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        let matched = cursor.collect_n(places.len()).await.unwrap();
+        assert_eq!(matched.iter().map(|id| id.lower).collect_vec(), vec![2, 0]);
+        // Prefix of all the fields
+        let mut cursor = client::RangedQueryClient::seek_composite(
+            &index_client,
+            schema.id,
+            field_ids,
+            &[feature_of("cn"), feature_of("sh")],
+            1,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        let matched = cursor.collect_n(places.len()).await.unwrap();
+        assert_eq!(matched.iter().map(|id| id.lower).collect_vec(), vec![3]);
+        // Nothing with the prefix
+        assert!(client::RangedQueryClient::seek_composite(
+            &index_client,
+            schema.id,
+            field_ids,
+            &[feature_of("fr")],
+            1,
+        )
+        .await
+        .unwrap()
+        .is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn collect_n() {
+        let _ = env_logger::try_init();
+        let server_group = "ranged_collect_n_test";
+        let server_addr = String::from("127.0.0.1:5719");
+        let server = NebServer::new_from_opts(
+            &ServerOptions {
+                chunk_count: 1,
+                memory_size: 512 * 1024 * 1024,
+                backup_storage: None,
+                wal_storage: None,
+                index_enabled: false,
+                cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+                ready_timeout: DEFAULT_READY_TIMEOUT,
+                admission: AdmissionConfig::default(),
+                cell_index_capacity: default_cell_index_capacity(),
+                services: vec![Service::Cell, Service::RangedIndexer],
+            },
+            &server_addr,
+            server_group,
+        )
+        .await;
+        let client = Arc::new(
+            AsyncClient::new(
+                &server.rpc,
+                &server.membership,
+                &vec![server_addr],
+                server_group,
+            )
+            .await
+            .unwrap(),
+        );
+        client.new_schema_with_id(schema()).await.unwrap().unwrap();
+        let index_client = Arc::new(client::RangedQueryClient::new(
+            &server.consh,
+            &server.raft_client,
+        ));
+        let num = 1000;
+        for n in 0..num {
+            let key = EntryKey::from_id(&Id::new(1, n));
+            assert!(index_client.insert(&key).await.unwrap());
+        }
+        let seek = |n: u64| {
+            let index_client = index_client.clone();
+            async move {
+                let key = EntryKey::from_id(&Id::new(1, n));
+                // Blocks smaller than the results to fetch more than one
+                client::RangedQueryClient::seek(&index_client, &key, Ordering::Forward, 4)
+                    .await
+                    .unwrap()
+                    .unwrap()
+            }
+        };
+        let mut cursor = seek(0).await;
+        let first = cursor.collect_n(10).await.unwrap();
+        assert_eq!(first, (0..10).map(|n| Id::new(1, n)).collect_vec());
+        assert_eq!(cursor.next().await.unwrap(), Some(Id::new(1, 10)));
+        // Fewer at the end of the keys
+        let mut cursor = seek(num - 5).await;
+        let last = cursor.collect_n(10).await.unwrap();
+        assert_eq!(last, (num - 5..num).map(|n| Id::new(1, n)).collect_vec());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn verify_index() {
+        let _ = env_logger::try_init();
+        let server_group = "ranged_verify_index_test";
+        let server_addr = String::from("127.0.0.1:5720");
+        // No indexer, cells are removed without updating their index entries
+        let server = NebServer::new_from_opts(
+            &ServerOptions {
+                chunk_count: 1,
+                memory_size: 512 * 1024 * 1024,
+                backup_storage: None,
+                wal_storage: None,
+                index_enabled: false,
+                cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+                ready_timeout: DEFAULT_READY_TIMEOUT,
+                admission: AdmissionConfig::default(),
+                cell_index_capacity: default_cell_index_capacity(),
+                services: vec![Service::Cell, Service::RangedIndexer],
+            },
+            &server_addr,
+            server_group,
+        )
+        .await;
+        let client = AsyncClient::new(
+            &server.rpc,
+            &server.membership,
+            &vec![server_addr],
+            server_group,
+        )
+        .await
+        .unwrap();
+        let schema = Schema::new_with_id(
+            13,
+            "verified",
+            None,
+            Field::new(
+                "*",
+                Type::Map,
+                false,
+                false,
+                Some(vec![Field::new(
+                    "score",
+                    Type::U64,
+                    false,
+                    false,
+                    None,
+                    vec![IndexType::Ranged],
+                )]),
+                vec![],
+            ),
+            false,
+            false,
+        );
+        let field_id = hash_str("score");
+        client
+            .new_schema_with_id(schema.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        let index_client = Arc::new(client::RangedQueryClient::new(
+            &server.consh,
+            &server.raft_client,
+        ));
+        let key_of = |num: u64| {
+            EntryKey::from_props(
+                &Id::new(1, num),
+                &OwnedValue::U64(num).feature(),
+                field_id,
+                schema.id,
+            )
+        };
+        for num in 0..10 {
+            let cell =
+                OwnedCell::new_with_id(schema.id, &Id::new(1, num), data_map_value! { score: num });
+            client.write_cell(cell).await.unwrap().unwrap();
+            assert!(index_client.insert(&key_of(num)).await.unwrap());
+        }
+        client.remove_cell(Id::new(1, 3)).await.unwrap().unwrap();
+        client.remove_cell(Id::new(1, 7)).await.unwrap().unwrap();
+        assert_eq!(
+            index_client
+                .verify_index(schema.id, field_id)
+                .await
+                .unwrap(),
+            2
+        );
+        let mut cursor =
+            client::RangedQueryClient::seek(&index_client, &key_of(0), Ordering::Forward, 4)
+                .await
+                .unwrap()
+                .unwrap();
+        let ids = cursor.collect_n(10).await.unwrap();
+        assert_eq!(
+            ids.iter().map(|id| id.lower).collect_vec(),
+            vec![0, 1, 2, 4, 5, 6, 8, 9]
+        );
+        // Nothing left to repair
+        assert_eq!(
+            index_client
+                .verify_index(schema.id, field_id)
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
     fn schema() -> Schema {
         Schema::new_with_id(
             11,
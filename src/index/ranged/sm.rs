@@ -38,6 +38,7 @@ pub struct MasterTreeSM {
 raft_state_machine! {
     def qry locate_key(entry: EntryKey) -> (EntryKey, TreePlacement, EntryKey);
     def qry next_tree(tree_lower: EntryKey, ordering: Ordering) -> Option<TreeInfo>;
+    def qry trees_in_range(lower: EntryKey, upper: EntryKey) -> Vec<TreeInfo>;
     def cmd split(src_tree: Id, new_tree: Id, pivot: EntryKey);
     // No subscription for clients
 }
@@ -97,6 +98,10 @@ impl StateMachineCmds for MasterTreeSM {
         .boxed()
     }
 
+    fn trees_in_range(&self, lower: EntryKey, upper: EntryKey) -> BoxFuture<Vec<TreeInfo>> {
+        future::ready(trees_in_range(&self.tree, &lower, &upper)).boxed()
+    }
+
     fn split(&mut self, src_tree: Id, new_tree: Id, pivot: EntryKey) -> BoxFuture<()> {
         // Call this after the tree have been split and persisted
         async move {
@@ -180,6 +185,38 @@ impl MasterTreeSM {
     }
 }
 
+// Trees owning any key from `lower` to `upper` inclusively, in key order
+pub(crate) fn trees_in_range(
+    trees: &BTreeMap<EntryKey, TreePlacement>,
+    lower: &EntryKey,
+    upper: &EntryKey,
+) -> Vec<TreeInfo> {
+    if lower > upper {
+        return vec![];
+    }
+    let start = match trees.range(..=lower).last() {
+        Some((tree_lower, _)) => tree_lower,
+        None => return vec![],
+    };
+    let mut res = vec![];
+    let mut iter = trees.range(start..).peekable();
+    while let Some((tree_lower, placement)) = iter.next() {
+        if tree_lower > upper {
+            break;
+        }
+        let tree_upper = iter
+            .peek()
+            .map(|(k, _)| *k)
+            .unwrap_or_else(|| &*MAX_ENTRY_KEY);
+        res.push(TreeInfo {
+            lower: tree_lower.clone(),
+            upper: tree_upper.clone(),
+            placement: placement.clone(),
+        });
+    }
+    res
+}
+
 impl TreePlacement {
     pub fn new(id: Id) -> Self {
         TreePlacement {
@@ -65,6 +65,79 @@ pub trait Slice<T: Default>: Send + Sync {
 pub trait Cursor: Send {
     fn next(&mut self) -> Option<EntryKey>;
     fn current(&self) -> Option<&EntryKey>;
+
+    // Take up to `n` entries from the cursor, fewer if it is exhausted before
+    fn collect_n(&mut self, n: usize) -> Vec<EntryKey> {
+        let mut res = Vec::with_capacity(n);
+        while res.len() < n {
+            match self.next() {
+                Some(key) => res.push(key),
+                None => break,
+            }
+        }
+        res
+    }
+
+    // Advance past up to `n` entries, returns the number of entries actually passed
+    fn skip(&mut self, n: usize) -> usize {
+        let mut skipped = 0;
+        while skipped < n && self.next().is_some() {
+            skipped += 1;
+        }
+        skipped
+    }
+}
+
+// Cursor adapter only yielding the entries accepted by the predicate
+pub struct FilteredCursor<C, F>
+where
+    C: Cursor,
+    F: Fn(&EntryKey) -> bool + Send,
+{
+    inner: C,
+    predicate: F,
+}
+
+impl<C, F> FilteredCursor<C, F>
+where
+    C: Cursor,
+    F: Fn(&EntryKey) -> bool + Send,
+{
+    pub fn new(inner: C, predicate: F) -> Self {
+        let mut cursor = Self { inner, predicate };
+        cursor.skip_rejected();
+        cursor
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+
+    // Move the inner cursor to the next entry accepted by the predicate
+    fn skip_rejected(&mut self) {
+        while let Some(key) = self.inner.current() {
+            if (self.predicate)(key) {
+                break;
+            }
+            self.inner.next();
+        }
+    }
+}
+
+impl<C, F> Cursor for FilteredCursor<C, F>
+where
+    C: Cursor,
+    F: Fn(&EntryKey) -> bool + Send,
+{
+    fn next(&mut self) -> Option<EntryKey> {
+        let res = self.inner.next();
+        self.skip_rejected();
+        res
+    }
+
+    fn current(&self) -> Option<&EntryKey> {
+        self.inner.current()
+    }
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
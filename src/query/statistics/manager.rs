@@ -0,0 +1,211 @@
+// Keeps statistics of the chunks fresh for the planner. Statistics are recomputed in the
+// background periodically, or on demand for a schema, and readers take the latest snapshot.
+
+use super::{ChunkStatistics, SchemaStatistics};
+use crate::ram::chunk::Chunks;
+use crate::utils::env_or;
+use lightning::map::{Map, ObjectMap};
+use parking_lot::Mutex;
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+#[derive(Debug, Clone)]
+pub struct StatisticsConfig {
+    pub refresh_interval: Duration,
+}
+
+impl Default for StatisticsConfig {
+    fn default() -> Self {
+        let refresh_interval = env_or("NEB_STATISTICS_REFRESH_INTERVAL_MS", 60000);
+        Self {
+            refresh_interval: Duration::from_millis(refresh_interval),
+        }
+    }
+}
+
+pub struct StatisticsManager {
+    chunks: Arc<Chunks>,
+    // Latest statistics by chunk id
+    statistics: Vec<ChunkStatistics>,
+    // Refreshes are not overlapped, so older statistics never replace newer ones
+    refresh_lock: Mutex<()>,
+}
+
+impl StatisticsManager {
+    // Refreshes in the background until the manager is dropped
+    pub fn start(chunks: &Arc<Chunks>, config: StatisticsConfig) -> Arc<Self> {
+        let manager = Arc::new(Self {
+            chunks: chunks.clone(),
+            statistics: chunks
+                .list
+                .iter()
+                .map(|_| ChunkStatistics {
+                    schemas: ObjectMap::with_capacity(16),
+                })
+                .collect(),
+            refresh_lock: Mutex::new(()),
+        });
+        let weak_manager = Arc::downgrade(&manager);
+        thread::Builder::new()
+            .name("Statistics refresher".into())
+            .spawn(move || loop {
+                thread::sleep(config.refresh_interval);
+                match weak_manager.upgrade() {
+                    Some(manager) => manager.refresh(),
+                    None => break,
+                }
+            })
+            .unwrap();
+        manager
+    }
+
+    // Recompute statistics of all schemas
+    pub fn refresh(&self) {
+        let _guard = self.refresh_lock.lock();
+        self.chunks
+            .list
+            .par_iter()
+            .zip(self.statistics.par_iter())
+            .for_each(|(chunk, current)| {
+                let fresh = ChunkStatistics::from_chunk(chunk);
+                let fresh_ids = fresh
+                    .schemas
+                    .entries()
+                    .into_iter()
+                    .map(|(schema_id, stat)| {
+                        current.schemas.insert(&schema_id, stat);
+                        schema_id
+                    })
+                    .collect::<HashSet<_>>();
+                // Schemas without cells anymore
+                for (schema_id, _) in current.schemas.entries() {
+                    if !fresh_ids.contains(&schema_id) {
+                        current.schemas.remove(&schema_id);
+                    }
+                }
+            });
+    }
+
+    // Recompute statistics of the schema right away, without waiting for the next refresh
+    pub fn refresh_now(&self, schema_id: u32) {
+        let _guard = self.refresh_lock.lock();
+        let key = schema_id as usize;
+        self.chunks
+            .list
+            .par_iter()
+            .zip(self.statistics.par_iter())
+            .for_each(|(chunk, current)| {
+                match ChunkStatistics::from_chunk_schema(chunk, schema_id)
+                    .schemas
+                    .get(&key)
+                {
+                    Some(stat) => {
+                        current.schemas.insert(&key, stat);
+                    }
+                    None => {
+                        current.schemas.remove(&key);
+                    }
+                }
+            });
+    }
+
+    // Latest statistics of the schema in each chunk that has its cells
+    pub fn schema_statistics(&self, schema_id: u32) -> Vec<Arc<SchemaStatistics>> {
+        self.statistics
+            .iter()
+            .filter_map(|chunk_stat| chunk_stat.schemas.get(&(schema_id as usize)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ram::cell::OwnedCell;
+    use crate::ram::schema::{Field, IndexType, Schema};
+    use crate::ram::types::{Id, Type};
+
+    fn write_cells(chunks: &Chunks, schema_id: u32, range: std::ops::Range<u64>) {
+        for n in range {
+            let mut cell = OwnedCell::new_with_id(
+                schema_id,
+                &Id::new(n % 2, n + 1),
+                data_map_value! { score: n },
+            );
+            chunks.write_cell(&mut cell).unwrap();
+        }
+    }
+
+    #[test]
+    fn refresh_statistics() {
+        let _ = env_logger::try_init();
+        let chunks = Chunks::new_dummy(2, 8 * 1024 * 1024);
+        let schema = Schema::new_with_id(
+            1,
+            "statistics",
+            None,
+            Field::new(
+                "*",
+                Type::Map,
+                false,
+                false,
+                Some(vec![Field::new(
+                    "score",
+                    Type::U64,
+                    false,
+                    false,
+                    None,
+                    vec![IndexType::Ranged],
+                )]),
+                vec![],
+            ),
+            false,
+            false,
+        );
+        chunks.list[0].meta.schemas.new_schema(schema.clone());
+        let count = |manager: &StatisticsManager| {
+            manager
+                .schema_statistics(schema.id)
+                .iter()
+                .map(|stat| stat.count)
+                .sum::<usize>()
+        };
+        // Nothing is computed until the first refresh
+        let manager = StatisticsManager::start(
+            &chunks,
+            StatisticsConfig {
+                refresh_interval: Duration::from_secs(3600),
+            },
+        );
+        write_cells(&chunks, schema.id, 0..100);
+        assert!(manager.schema_statistics(schema.id).is_empty());
+        manager.refresh_now(schema.id);
+        let stats = manager.schema_statistics(schema.id);
+        assert_eq!(stats.len(), 2);
+        assert!(stats.iter().all(|stat| stat.timestamp > 0));
+        assert_eq!(count(&manager), 100);
+        write_cells(&chunks, schema.id, 100..150);
+        assert_eq!(count(&manager), 100);
+        manager.refresh_now(schema.id);
+        assert_eq!(count(&manager), 150);
+        // Background refreshes pick up new cells as well
+        let manager = StatisticsManager::start(
+            &chunks,
+            StatisticsConfig {
+                refresh_interval: Duration::from_millis(10),
+            },
+        );
+        write_cells(&chunks, schema.id, 150..200);
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while count(&manager) != 200 {
+            assert!(
+                std::time::Instant::now() < deadline,
+                "Statistics are not refreshed in time"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
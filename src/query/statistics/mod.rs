@@ -6,7 +6,7 @@ use std::{
     sync::Arc,
 };
 
-use dovahkiin::types::SharedValue;
+use dovahkiin::types::{OwnedValue, SharedValue};
 
 use crate::ram::{
     cell::{header_from_chunk_raw, select_from_chunk_raw},
@@ -15,10 +15,14 @@ use crate::ram::{
 };
 
 mod histogram;
+pub mod manager;
 pub mod sm;
 
 pub struct SchemaStatistics {
     pub histogram: HashMap<u64, [HistogramKey; HISTOGRAM_TARGET_BUCKETS + 1]>,
+    // Values explicitly set to null for each field, they are not in the histogram.
+    // NA values are unknown so they are not counted anywhere.
+    pub null_counts: HashMap<u64, usize>,
     pub count: usize,
     pub segs: usize,
     pub bytes: usize,
@@ -29,6 +33,59 @@ pub struct ChunkStatistics {
     pub schemas: ObjectMap<Arc<SchemaStatistics>>,
 }
 
+impl SchemaStatistics {
+    // Fraction of the values of the field in [low, high], interpolated linearly in the buckets
+    // of the equi-depth histogram. Fields without histogram are assumed to be not selective.
+    pub fn estimate_selectivity(&self, field_id: u64, low: &OwnedValue, high: &OwnedValue) -> f64 {
+        let histogram = match self.histogram.get(&field_id) {
+            Some(histogram) => histogram,
+            None => return 1.0,
+        };
+        let below_high = histogram_fraction(histogram, high.feature(), true);
+        let below_low = histogram_fraction(histogram, low.feature(), false);
+        (below_high - below_low).max(0.0).min(1.0)
+    }
+}
+
+// Fraction of values less than the key, or not greater than the key when inclusive
+fn histogram_fraction(
+    histogram: &[HistogramKey; HISTOGRAM_TARGET_BUCKETS + 1],
+    key: HistogramKey,
+    inclusive: bool,
+) -> f64 {
+    let buckets = HISTOGRAM_TARGET_BUCKETS as f64;
+    if key < histogram[0] {
+        return 0.0;
+    }
+    if key > histogram[HISTOGRAM_TARGET_BUCKETS] {
+        return 1.0;
+    }
+    match histogram.binary_search(&key) {
+        // Boundaries can repeat for frequent values, take all or none of their buckets
+        Ok(pos) if inclusive => {
+            let last = (pos..=HISTOGRAM_TARGET_BUCKETS)
+                .take_while(|i| histogram[*i] == key)
+                .last()
+                .unwrap();
+            last as f64 / buckets
+        }
+        Ok(pos) => {
+            let first = (0..=pos)
+                .rev()
+                .take_while(|i| histogram[*i] == key)
+                .last()
+                .unwrap();
+            first as f64 / buckets
+        }
+        Err(pos) => {
+            let lower = u64::from_be_bytes(histogram[pos - 1]) as f64;
+            let upper = u64::from_be_bytes(histogram[pos]) as f64;
+            let in_bucket = (u64::from_be_bytes(key) as f64 - lower) / (upper - lower);
+            (pos - 1) as f64 / buckets + in_bucket / buckets
+        }
+    }
+}
+
 const HISTOGRAM_PARTITATION_SIZE: usize = 1024;
 const HISTOGRAM_PARTITATION_BUCKETS: usize = 128;
 const HISTOGRAM_TARGET_BUCKETS: usize = 100;
@@ -37,6 +94,15 @@ type HistogramKey = [u8; 8];
 
 impl ChunkStatistics {
     pub fn from_chunk(chunk: &Chunk) -> Self {
+        Self::build(chunk, None)
+    }
+
+    // Statistics of cells of the schema only, other cells are skipped
+    pub fn from_chunk_schema(chunk: &Chunk, schema_id: u32) -> Self {
+        Self::build(chunk, Some(schema_id))
+    }
+
+    fn build(chunk: &Chunk, only_schema: Option<u32>) -> Self {
         let histogram_partitations = chunk
             .cell_index
             .entries()
@@ -45,13 +111,13 @@ impl ChunkStatistics {
             .collect_vec();
         let partitations: Vec<_> = histogram_partitations
             .into_par_iter()
-            .map(|partitation| build_partitation_statistics(partitation, chunk))
+            .map(|partitation| build_partitation_statistics(partitation, chunk, only_schema))
             .collect();
         let schema_ids: Vec<_> = partitations
             .iter()
-            .map(|(sizes, _, _, _)| sizes.keys())
+            .map(|(sizes, _, _, _, _)| sizes.keys())
             .flatten()
-            .dedup()
+            .unique()
             .collect();
         let total_size = schema_ids
             .iter()
@@ -60,7 +126,7 @@ impl ChunkStatistics {
                     *sid,
                     partitations
                         .iter()
-                        .map(|(sizes, _, _, _)| sizes.get(sid).unwrap_or(&0))
+                        .map(|(sizes, _, _, _, _)| sizes.get(sid).unwrap_or(&0))
                         .sum::<usize>(),
                 )
             })
@@ -72,7 +138,7 @@ impl ChunkStatistics {
                     *sid,
                     partitations
                         .iter()
-                        .map(|(_, _, counts, _)| counts.get(sid).unwrap_or(&0))
+                        .map(|(_, _, counts, _, _)| counts.get(sid).unwrap_or(&0))
                         .sum::<usize>(),
                 )
             })
@@ -84,11 +150,22 @@ impl ChunkStatistics {
                     *sid,
                     partitations
                         .iter()
-                        .map(|(_, segs, _, _)| segs.get(sid).map(|set| set.len()).unwrap_or(0))
+                        .map(|(_, segs, _, _, _)| segs.get(sid).map(|set| set.len()).unwrap_or(0))
                         .sum::<usize>(),
                 )
             })
             .collect::<HashMap<_, _>>();
+        let mut total_null_counts = HashMap::<u32, HashMap<u64, usize>>::new();
+        for (_, _, _, _, nulls) in &partitations {
+            for (sid, field_nulls) in nulls {
+                let schema_nulls = total_null_counts
+                    .entry(*sid)
+                    .or_insert_with(|| HashMap::new());
+                for (field_id, num) in field_nulls {
+                    *schema_nulls.entry(*field_id).or_insert(0) += num;
+                }
+            }
+        }
         let empty_histo = Default::default();
         let mut schema_histograms = schema_ids
             .iter()
@@ -96,22 +173,23 @@ impl ChunkStatistics {
                 (*sid, {
                     let parted_histos = partitations
                         .iter()
-                        .map(|(_, _, _, histo)| histo.get(sid).unwrap_or(&empty_histo))
+                        .map(|(_, _, _, histo, _)| histo.get(sid).unwrap_or(&empty_histo))
                         .collect_vec();
                     let field_ids = parted_histos
                         .iter()
                         .map(|histo_map| histo_map.keys())
                         .flatten()
-                        .dedup()
+                        .unique()
                         .collect::<Vec<_>>();
                     field_ids
                         .par_iter()
-                        .map(|field_id| {
+                        .filter_map(|field_id| {
                             let schema_field_histograms = parted_histos
                                 .iter()
-                                .map(|histo_map| &histo_map[field_id])
+                                .filter_map(|histo_map| histo_map.get(field_id))
                                 .collect_vec();
-                            (**field_id, build_histogram(schema_field_histograms))
+                            let histogram = build_histogram(schema_field_histograms)?;
+                            Some((**field_id, histogram))
                         })
                         .collect::<HashMap<u64, _>>()
                 })
@@ -123,6 +201,7 @@ impl ChunkStatistics {
         for schema_id in schema_ids {
             let statistics = SchemaStatistics {
                 histogram: schema_histograms.remove(&schema_id).unwrap(),
+                null_counts: total_null_counts.remove(schema_id).unwrap_or_default(),
                 count: *total_counts.get(&schema_id).unwrap(),
                 segs: *total_segs.get(&schema_id).unwrap(),
                 bytes: *total_size.get(&schema_id).unwrap(),
@@ -134,22 +213,36 @@ impl ChunkStatistics {
             schemas: schema_statistics,
         }
     }
+
+    pub fn schema_stats(&self, schema_id: u32) -> Option<Arc<SchemaStatistics>> {
+        self.schemas.get(&(schema_id as usize))
+    }
+
+    // Number of cells of the schema when the statistics were taken, zero for schemas without cells
+    pub fn estimated_count(&self, schema_id: u32) -> usize {
+        self.schema_stats(schema_id)
+            .map(|stat| stat.count)
+            .unwrap_or(0)
+    }
 }
 
 fn build_partitation_statistics(
     partitation: Vec<(usize, usize)>,
     chunk: &Chunk,
+    only_schema: Option<u32>,
 ) -> (
     HashMap<u32, usize>,
     HashMap<u32, HashSet<usize>>,
     HashMap<u32, usize>,
     HashMap<u32, HashMap<u64, (Vec<HistogramKey>, usize, usize)>>,
+    HashMap<u32, HashMap<u64, usize>>,
 ) {
     // Build exact histogram for each of the partitation and then approximate overall histogram
     let mut sizes = HashMap::new();
     let mut segs = HashMap::new();
     let mut counts = HashMap::new();
     let mut exact_accumlators = HashMap::new();
+    let mut null_counts = HashMap::new();
     let partitation_size = partitation.len();
     for (hash, _) in partitation {
         let loc = if let Ok(ptr) = chunk.location_for_read(hash as u64) {
@@ -163,9 +256,14 @@ fn build_partitation_statistics(
                 let cell_size = entry_header.content_length as usize;
                 let cell_seg = chunk.allocator.id_by_addr(*loc);
                 let schema_id = header.schema;
+                if only_schema.map(|id| id != schema_id).unwrap_or(false) {
+                    continue;
+                }
                 if let Some(schema) = chunk.meta.schemas.get(&schema_id) {
                     let fields = schema.index_fields.keys().cloned().collect_vec();
-                    if let Ok(partial_cell) = select_from_chunk_raw(*loc, chunk, fields.as_slice())
+                    // Keeps the schema the values are read by, not the one fields are listed from
+                    if let Ok((partial_cell, _schema)) =
+                        select_from_chunk_raw(*loc, chunk, fields.as_slice())
                     {
                         let field_array = if fields.len() == 1 {
                             vec![partial_cell]
@@ -179,10 +277,18 @@ fn build_partitation_statistics(
                             continue;
                         };
                         for (i, val) in field_array.into_iter().enumerate() {
-                            if val == SharedValue::Null || val == SharedValue::NA {
+                            let field_id = fields[i];
+                            if val == SharedValue::Null {
+                                *null_counts
+                                    .entry(schema_id)
+                                    .or_insert_with(|| HashMap::new())
+                                    .entry(field_id)
+                                    .or_insert(0) += 1;
+                                continue;
+                            }
+                            if val == SharedValue::NA {
                                 continue;
                             }
-                            let field_id = fields[i];
                             exact_accumlators
                                 .entry(schema_id)
                                 .or_insert_with(|| HashMap::new())
@@ -219,7 +325,7 @@ fn build_partitation_statistics(
             (schema_id, compiled_histograms)
         })
         .collect::<HashMap<_, _>>();
-    (sizes, segs, counts, histograms)
+    (sizes, segs, counts, histograms, null_counts)
 }
 
 fn build_partitation_histogram(mut items: Vec<HistogramKey>) -> (Vec<HistogramKey>, usize) {
@@ -238,17 +344,17 @@ fn build_partitation_histogram(mut items: Vec<HistogramKey>) -> (Vec<HistogramKe
     (histogram, depth)
 }
 
+// None when there is no key in any partitation, for fields without any value
 fn build_histogram(
     partitations: Vec<&(Vec<HistogramKey>, usize, usize)>,
-) -> [HistogramKey; HISTOGRAM_TARGET_BUCKETS + 1] {
+) -> Option<[HistogramKey; HISTOGRAM_TARGET_BUCKETS + 1]> {
     // Build the approximated histogram from partitation histograms
     // https://arxiv.org/abs/1606.05633
+    if partitations.iter().all(|(histo, _, _)| histo.is_empty()) {
+        return None;
+    }
     let mut part_idxs = vec![0; partitations.len()];
-    let part_histos = partitations
-        .iter()
-        .map(|(histo, _, _)| histo)
-        .filter(|histo| !histo.is_empty())
-        .collect_vec();
+    let part_histos = partitations.iter().map(|(histo, _, _)| histo).collect_vec();
     let num_total = partitations.iter().map(|(_, num, _)| num).sum::<usize>();
     let part_depths = partitations
         .iter()
@@ -259,7 +365,7 @@ fn build_histogram(
     // Perform a merge sort for sorted pre-histogram
     let mut filled = target_width;
     let mut last_key = Default::default();
-    'HISTO_CONST: for i in 0..HISTOGRAM_PARTITATION_BUCKETS {
+    'HISTO_CONST: for i in 0..HISTOGRAM_TARGET_BUCKETS {
         loop {
             let (key, ended) = if let Some((part_idx, histo)) = part_histos
                 .iter()
@@ -281,15 +387,23 @@ fn build_histogram(
             };
             last_key = key;
             let idx = last_key.1;
+            // Boundaries are the first keys at multiples of the target width, carry the rest over
             if filled >= target_width || ended {
                 target_histogram[i] = last_key.0;
-                filled = 0;
+                filled = filled.saturating_sub(target_width) + part_depths[idx];
                 continue 'HISTO_CONST;
             }
             filled += part_depths[idx];
         }
     }
-    target_histogram
+    // The last boundary is the largest key
+    target_histogram[HISTOGRAM_TARGET_BUCKETS] = part_histos
+        .iter()
+        .filter_map(|histo| histo.last())
+        .max()
+        .cloned()
+        .unwrap_or(last_key.0);
+    Some(target_histogram)
 }
 
 #[cfg(test)]
@@ -333,4 +447,137 @@ mod tests {
             (expect, 3)
         );
     }
+
+    #[test]
+    fn empty_histogram() {
+        assert!(build_histogram(vec![]).is_none());
+        let empty = (vec![], 0, 1);
+        assert!(build_histogram(vec![&empty, &empty]).is_none());
+        let (histo, depth) = build_partitation_histogram(vec![OwnedValue::U64(1).feature()]);
+        let single = (histo, 1, depth);
+        let histogram = build_histogram(vec![&empty, &single]).unwrap();
+        assert_eq!(histogram[HISTOGRAM_TARGET_BUCKETS], OwnedValue::U64(1).feature());
+    }
+
+    #[test]
+    fn estimated_count() {
+        use crate::ram::cell::OwnedCell;
+        use crate::ram::chunk::Chunks;
+        use crate::ram::schema::{Field, IndexType, Schema};
+        use crate::ram::types::{Id, Type};
+
+        let chunks = Chunks::new_dummy(1, 8 * 1024 * 1024);
+        let chunk = &chunks.list[0];
+        let fields = Field::new(
+            "*",
+            Type::Map,
+            false,
+            false,
+            Some(vec![Field::new(
+                "score",
+                Type::U64,
+                false,
+                false,
+                None,
+                vec![IndexType::Ranged],
+            )]),
+            vec![],
+        );
+        for schema_id in 1..=2 {
+            let schema =
+                Schema::new_with_id(schema_id, "scores", None, fields.clone(), false, false);
+            chunk.meta.schemas.new_schema(schema);
+        }
+        // Cells of both schemas are spread over the partitions
+        for n in 0..4000u64 {
+            let schema_id = if n % 4 == 0 { 2 } else { 1 };
+            let mut cell =
+                OwnedCell::new_with_id(schema_id, &Id::new(1, n + 1), data_map_value! { score: n });
+            chunks.write_cell(&mut cell).unwrap();
+        }
+        let statistics = ChunkStatistics::from_chunk(chunk);
+        assert_eq!(statistics.estimated_count(1), 3000);
+        assert_eq!(statistics.estimated_count(2), 1000);
+        assert_eq!(statistics.schema_stats(2).unwrap().count, 1000);
+        assert_eq!(statistics.estimated_count(3), 0);
+        assert!(statistics.schema_stats(3).is_none());
+    }
+
+    #[test]
+    fn histogram_selectivity() {
+        let mut histogram = [[0u8; 8]; HISTOGRAM_TARGET_BUCKETS + 1];
+        for (i, key) in histogram.iter_mut().enumerate() {
+            *key = OwnedValue::U64(100 + i as u64 * 10).feature();
+        }
+        let statistics = SchemaStatistics {
+            histogram: vec![(1, histogram)].into_iter().collect(),
+            null_counts: HashMap::new(),
+            count: 1000,
+            segs: 1,
+            bytes: 0,
+            timestamp: 0,
+        };
+        let selectivity = |low: u64, high: u64| {
+            statistics.estimate_selectivity(1, &OwnedValue::U64(low), &OwnedValue::U64(high))
+        };
+        let approx = |x: f64, y: f64| (x - y).abs() < 1e-9;
+        assert_eq!(selectivity(100, 1100), 1.0);
+        assert_eq!(selectivity(0, 2000), 1.0);
+        assert!(approx(selectivity(350, 600), 0.25));
+        assert!(approx(selectivity(355, 600), 0.245));
+        assert!(approx(selectivity(355, 605), 0.25));
+        assert!(approx(selectivity(0, 150), 0.05));
+        assert!(approx(selectivity(1050, 2000), 0.05));
+        // Out of the boundaries or reversed
+        assert_eq!(selectivity(0, 50), 0.0);
+        assert_eq!(selectivity(1200, 2000), 0.0);
+        assert_eq!(selectivity(600, 350), 0.0);
+        // Unknown field
+        assert_eq!(
+            statistics.estimate_selectivity(2, &OwnedValue::U64(0), &OwnedValue::U64(1)),
+            1.0
+        );
+    }
+
+    #[test]
+    fn chunk_histogram_selectivity() {
+        use crate::ram::cell::OwnedCell;
+        use crate::ram::chunk::Chunks;
+        use crate::ram::schema::{Field, IndexType, Schema};
+        use crate::ram::types::{Id, Type};
+
+        let chunks = Chunks::new_dummy(1, 8 * 1024 * 1024);
+        let chunk = &chunks.list[0];
+        let fields = Field::new(
+            "*",
+            Type::Map,
+            false,
+            false,
+            Some(vec![Field::new(
+                "score",
+                Type::U64,
+                false,
+                false,
+                None,
+                vec![IndexType::Ranged],
+            )]),
+            vec![],
+        );
+        let schema = Schema::new_with_id(1, "scores", None, fields, false, false);
+        let field_id = *schema.index_fields.keys().next().unwrap();
+        chunk.meta.schemas.new_schema(schema);
+        for n in 0..5000u64 {
+            let mut cell =
+                OwnedCell::new_with_id(1, &Id::new(1, n + 1), data_map_value! { score: n });
+            chunks.write_cell(&mut cell).unwrap();
+        }
+        let statistics = ChunkStatistics::from_chunk(chunk).schema_stats(1).unwrap();
+        let selectivity = |low: u64, high: u64| {
+            statistics.estimate_selectivity(field_id, &OwnedValue::U64(low), &OwnedValue::U64(high))
+        };
+        assert_eq!(selectivity(0, 4999), 1.0);
+        assert!((selectivity(1000, 2999) - 0.4).abs() < 0.05);
+        assert!((selectivity(4000, 10000) - 0.2).abs() < 0.05);
+        assert_eq!(selectivity(5000, 10000), 0.0);
+    }
 }
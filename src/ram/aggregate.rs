@@ -0,0 +1,179 @@
+// Grouped aggregation over cells of a schema. Every chunk builds partial aggregates of its own
+// cells in parallel, partials are merged on the server and again on the client across servers.
+use super::chunk::Chunks;
+use super::types::OwnedValue;
+use crate::utils::env_or;
+use rayon::prelude::*;
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aggregation {
+    // Number of cells in the group, the aggregated field is ignored
+    Count,
+    Sum,
+    Min,
+    Max,
+    Mean,
+}
+
+// Partial aggregate of a group. Numbers of all types are aggregated as `f64`, values that are not
+// numbers are skipped.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct AggResult {
+    pub agg: Aggregation,
+    pub count: u64,
+    acc: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GroupedAggResult {
+    pub groups: HashMap<OwnedValue, AggResult>,
+    // Cells of groups beyond the bound on the number of groups are aggregated together here.
+    // Partials are bounded on their own, so a group can be partly in both places.
+    pub overflow: Option<AggResult>,
+}
+
+impl AggResult {
+    pub fn new(agg: Aggregation) -> Self {
+        let acc = match agg {
+            Aggregation::Min => f64::INFINITY,
+            Aggregation::Max => f64::NEG_INFINITY,
+            _ => 0.0,
+        };
+        Self { agg, count: 0, acc }
+    }
+
+    pub fn add(&mut self, value: &OwnedValue) {
+        if self.agg == Aggregation::Count {
+            self.count += 1;
+            return;
+        }
+        let num = match as_f64(value) {
+            Some(num) => num,
+            None => return,
+        };
+        self.count += 1;
+        match self.agg {
+            Aggregation::Sum | Aggregation::Mean => self.acc += num,
+            Aggregation::Min => self.acc = self.acc.min(num),
+            Aggregation::Max => self.acc = self.acc.max(num),
+            Aggregation::Count => unreachable!(),
+        }
+    }
+
+    pub fn merge(&mut self, other: &AggResult) {
+        debug_assert_eq!(self.agg, other.agg);
+        self.count += other.count;
+        match self.agg {
+            Aggregation::Sum | Aggregation::Mean => self.acc += other.acc,
+            Aggregation::Min => self.acc = self.acc.min(other.acc),
+            Aggregation::Max => self.acc = self.acc.max(other.acc),
+            Aggregation::Count => {}
+        }
+    }
+
+    // None when no value was aggregated, except for counts
+    pub fn value(&self) -> Option<f64> {
+        match self.agg {
+            Aggregation::Count => Some(self.count as f64),
+            _ if self.count == 0 => None,
+            Aggregation::Mean => Some(self.acc / self.count as f64),
+            _ => Some(self.acc),
+        }
+    }
+}
+
+impl GroupedAggResult {
+    pub fn add(&mut self, group: OwnedValue, value: &OwnedValue, agg: Aggregation, bound: usize) {
+        if let Some(res) = self.groups.get_mut(&group) {
+            res.add(value);
+        } else if self.groups.len() < bound {
+            let mut res = AggResult::new(agg);
+            res.add(value);
+            self.groups.insert(group, res);
+        } else {
+            self.overflow
+                .get_or_insert_with(|| AggResult::new(agg))
+                .add(value);
+        }
+    }
+
+    pub fn merge(&mut self, other: GroupedAggResult, bound: usize) {
+        for (group, partial) in other.groups {
+            if let Some(res) = self.groups.get_mut(&group) {
+                res.merge(&partial);
+            } else if self.groups.len() < bound {
+                self.groups.insert(group, partial);
+            } else {
+                self.merge_overflow(&partial);
+            }
+        }
+        if let Some(partial) = other.overflow {
+            self.merge_overflow(&partial);
+        }
+    }
+
+    fn merge_overflow(&mut self, partial: &AggResult) {
+        match &mut self.overflow {
+            Some(res) => res.merge(partial),
+            None => self.overflow = Some(*partial),
+        }
+    }
+}
+
+// Bound on the number of groups by default, for group keys of high cardinality
+pub fn default_max_groups() -> u32 {
+    env_or("NEB_AGGREGATE_MAX_GROUPS", 65536)
+}
+
+// Aggregate top level fields of cells of the schema, grouped by the value of another field
+pub fn aggregate_grouped(
+    chunks: &Chunks,
+    schema_id: u32,
+    group_by: u64,
+    agg_field: u64,
+    agg: Aggregation,
+    max_groups: usize,
+) -> GroupedAggResult {
+    chunks
+        .list
+        .par_iter()
+        .map(|chunk| {
+            let partial = RefCell::new(GroupedAggResult::default());
+            chunk.scan_schema(schema_id, &|cell| {
+                let cell = cell.to_owned();
+                let map = match cell.data.Map() {
+                    Some(map) => map,
+                    None => return,
+                };
+                let group = map.get_by_key_id(group_by).clone();
+                let value = map.get_by_key_id(agg_field);
+                partial.borrow_mut().add(group, value, agg, max_groups);
+            });
+            partial.into_inner()
+        })
+        .reduce(GroupedAggResult::default, |mut a, b| {
+            a.merge(b, max_groups);
+            a
+        })
+}
+
+fn as_f64(value: &OwnedValue) -> Option<f64> {
+    match value {
+        OwnedValue::I8(n) => Some(*n as f64),
+        OwnedValue::I16(n) => Some(*n as f64),
+        OwnedValue::I32(n) => Some(*n as f64),
+        OwnedValue::I64(n) => Some(*n as f64),
+        OwnedValue::U8(n) => Some(*n as f64),
+        OwnedValue::U16(n) => Some(*n as f64),
+        OwnedValue::U32(n) => Some(*n as f64),
+        OwnedValue::U64(n) => Some(*n as f64),
+        OwnedValue::F32(_) => value.f32().map(|n| {
+            let n: f32 = (*n).into();
+            n as f64
+        }),
+        OwnedValue::F64(_) => value.f64().map(|n| (*n).into()),
+        _ => None,
+    }
+}
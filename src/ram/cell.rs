@@ -4,7 +4,10 @@ use crate::ram::entry::*;
 use crate::ram::io::{reader, writer};
 use crate::ram::mem_cursor::*;
 use crate::ram::schema::{Field, Schema};
-use crate::ram::types::{Id, OwnedValue, RandValue, SharedValue, Value};
+use crate::ram::types::{
+    self, bool_io, u32_io, Id, OwnedValue, RandValue, SharedValue, Type, Value,
+};
+use crate::utils::env_or;
 use byteorder::{ReadBytesExt, WriteBytesExt};
 use lightning::map::WordMutexGuard;
 use serde::Serialize;
@@ -36,7 +39,26 @@ pub enum WriteError {
     UserCanceledUpdate,
     DeletionPredictionFailed,
     NetworkingError,
-    DataMismatchSchema(Field, OwnedValue),
+    // Value is not of the type of the field, carrying the value
+    TypeMismatch {
+        field: Field,
+        expected: Type,
+        got: OwnedValue,
+    },
+    UnexpectedNull(Field),
+    // Array field with a value not an array, or the other way around
+    ExpectedArray(Field),
+    ExpectedScalar(Field),
+    // String is not one of the values of the enum field
+    UnknownEnumValue(Field, String),
+    // Compare and swap failed, carrying the current version of the cell
+    CellVersionMismatch(u64),
+    // Chunk is close to full and cleaning cannot keep up, retry later
+    Overloaded,
+    // Key field of the schema is absent from the cell data
+    KeyFieldMissing,
+    // Cell id is not derived from its key field, carrying the expected id
+    CellIdMismatchKey(Id),
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
@@ -46,6 +68,11 @@ pub enum ReadError {
     NetworkingError,
     CellTypeIsNotMapForSelect,
     CellIdIsUnitId,
+    FieldIsNotPrimArray(u64),
+    // Written with an interned dynamic field name missing from the dictionary of the chunk
+    UnknownDynamicFieldName(u32),
+    // Cells cannot be read in frames of no size
+    InvalidFrameSize,
 }
 
 impl CellHeader {
@@ -75,6 +102,71 @@ impl CellHeader {
 pub const CELL_HEADER_SIZE: usize = std::mem::size_of::<CellHeader>();
 pub const CELL_HEADER_SIZE_U32: u32 = CELL_HEADER_SIZE as u32;
 
+// Layout of the cell header in the entry. Legacy cells are plain cell entries with the header
+// only, later layouts use the versioned cell entry type and lead the header with the layout byte,
+// so cells in different layouts can live in the same chunk. Cells are migrated to the layout of
+// the chunk when they are written again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderLayout {
+    V1 = 1,
+    V2 = 2,
+}
+
+impl HeaderLayout {
+    // Layout with the largest header, sizes bounded by it hold for cells of every layout
+    pub const LARGEST: HeaderLayout = HeaderLayout::V2;
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(HeaderLayout::V1),
+            2 => Some(HeaderLayout::V2),
+            _ => None,
+        }
+    }
+
+    pub fn entry_type(&self) -> EntryType {
+        match self {
+            HeaderLayout::V1 => EntryType::CELL,
+            _ => EntryType::VERSIONED_CELL,
+        }
+    }
+
+    // Offset of the header fields from the entry content
+    pub fn fields_offset(&self) -> usize {
+        match self {
+            HeaderLayout::V1 => 0,
+            HeaderLayout::V2 => 1,
+        }
+    }
+
+    // Offset of the cell data from the entry content
+    pub fn header_size(&self) -> usize {
+        self.fields_offset() + CELL_HEADER_SIZE
+    }
+
+    pub fn of_entry(addr: usize, entry_header: &EntryHeader) -> Self {
+        match entry_header.entry_type {
+            EntryType::CELL => HeaderLayout::V1,
+            EntryType::VERSIONED_CELL => {
+                let byte = unsafe { *(addr as *const u8) };
+                Self::from_byte(byte)
+                    .unwrap_or_else(|| panic!("Unknown cell header layout {} at {}", byte, addr))
+            }
+            t => panic!("Entry at {} is not a cell, type {:?}", addr, t),
+        }
+    }
+}
+
+impl Default for HeaderLayout {
+    fn default() -> Self {
+        let layout = env_or("NEB_CELL_HEADER_LAYOUT", 1);
+        Self::from_byte(layout).unwrap_or_else(|| {
+            warn!("Unknown cell header layout {}, using layout 1", layout);
+            HeaderLayout::V1
+        })
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct OwnedCell {
     pub header: CellHeader,
@@ -117,11 +209,33 @@ impl OwnedCell {
         Some(Self::new_with_id(schema_id, &id, value))
     }
 
+    // Cells of schemas with key fields must have the id derived from the key, as `new` does, so
+    // writes by natural key always land on the same cell
+    pub fn check_key(&self, schema: &Schema) -> Result<(), WriteError> {
+        if let Some(ref keys) = schema.key_field {
+            let value = match self.data {
+                OwnedValue::Map(ref data) => data.get_in_by_ids(keys.iter()),
+                _ => &OwnedValue::Null,
+            };
+            if let &OwnedValue::Null = value {
+                return Err(WriteError::KeyFieldMissing);
+            }
+            let expected = Self::encode_cell_key(schema.id, value);
+            if self.id() != expected {
+                return Err(WriteError::CellIdMismatchKey(expected));
+            }
+        }
+        Ok(())
+    }
+
+    // Stamps are the written time of the TTL fields of the schema, in their order
     pub fn write_to_chunk_with_schema(
         &mut self,
         chunk: &Chunk,
         schema: &Schema,
+        field_stamps: &[u32],
     ) -> Result<usize, WriteError> {
+        self.check_key(schema)?;
         let mut tail_offset: usize = schema.static_bound;
         let mut instructions = Vec::<writer::Instruction>::new();
         writer::plan_write_field(
@@ -131,15 +245,28 @@ impl OwnedCell {
             &mut instructions,
             false,
         )?;
+        writer::plan_write_appended_fields(
+            &mut tail_offset,
+            &schema.fields,
+            &self.data,
+            &mut instructions,
+        )?;
         if schema.is_dynamic {
             writer::plan_write_dynamic_fields(
                 &mut tail_offset,
                 &schema.fields,
                 &self.data,
                 &mut instructions,
+                chunk.dynamic_names_to_intern(),
             )?;
         }
-        let entry_body_size = tail_offset + CELL_HEADER_SIZE;
+        let layout = chunk.header_layout;
+        let header_size = layout.header_size();
+        debug_assert_eq!(
+            field_stamps.len() * FIELD_STAMP_SIZE,
+            field_stamps_size(schema)
+        );
+        let entry_body_size = tail_offset + header_size + field_stamps.len() * FIELD_STAMP_SIZE;
         let len_bytes = Entry::count_len_bytes(entry_body_size as u32);
         let total_size = Entry::size(len_bytes, entry_body_size as u32);
         if total_size > MAX_CELL_SIZE {
@@ -147,6 +274,7 @@ impl OwnedCell {
         }
         let addr_opt = chunk.try_acquire(total_size);
         self.header.version += 1;
+        self.header.timestamp = clock::now();
         match addr_opt {
             None => {
                 error!(
@@ -159,20 +287,30 @@ impl OwnedCell {
                 let addr = pending_entry.addr;
                 Entry::encode_to(
                     addr,
-                    EntryType::CELL,
+                    layout.entry_type(),
                     entry_body_size as u32,
                     len_bytes,
                     |content_addr| {
+                        if layout != HeaderLayout::V1 {
+                            unsafe {
+                                *(content_addr as *mut u8) = layout as u8;
+                            }
+                        }
                         // write cell header
                         let header = &self.header;
-                        let mut cursor = addr_to_header_cursor(content_addr);
+                        let mut cursor =
+                            addr_to_header_cursor(content_addr + layout.fields_offset());
                         cursor.write_u64::<Endian>(header.version).unwrap();
                         cursor.write_u32::<Endian>(header.timestamp).unwrap();
                         cursor.write_u32::<Endian>(header.schema).unwrap();
                         cursor.write_u64::<Endian>(header.partition).unwrap();
                         cursor.write_u64::<Endian>(header.hash).unwrap();
                         release_cursor(cursor);
-                        writer::execute_plan(content_addr + CELL_HEADER_SIZE, &instructions);
+                        writer::execute_plan(content_addr + header_size, &instructions);
+                        let stamps_addr = content_addr + header_size + tail_offset;
+                        for (i, stamp) in field_stamps.iter().enumerate() {
+                            u32_io::write(stamp, stamps_addr + i * FIELD_STAMP_SIZE);
+                        }
                     },
                 );
                 return Ok(addr);
@@ -185,6 +323,14 @@ impl OwnedCell {
     pub fn set_id(&mut self, id: &Id) {
         self.header.set_id(id)
     }
+    // Bumped on every write, use it as the expected version for compare and swap
+    pub fn version(&self) -> u64 {
+        self.header.version
+    }
+    // Seconds since the epoch of the last write, kept when the cleaner moves the cell
+    pub fn timestamp(&self) -> u32 {
+        self.header.timestamp
+    }
 }
 
 impl Index<u64> for OwnedCell {
@@ -218,7 +364,10 @@ impl IndexMut<u64> for OwnedCell {
 #[derive(Debug)]
 pub struct SharedCellData {
     pub header: CellHeader,
+    // Names of enum values in the data are borrowed from the schema of the cell, convert values
+    // with `owned` to keep them beyond the cell
     pub data: SharedValue,
+    schema: Option<SchemaRef>,
 }
 
 impl SharedCellData {
@@ -227,7 +376,15 @@ impl SharedCellData {
         let (header, data_ptr, _) = header_from_chunk_raw(ptr)?;
         let schema_id = &header.schema;
         if let Some(schema) = chunk.meta.schemas.get(schema_id) {
-            let cell = Self::from_data(header, reader::read_by_schema(data_ptr, &*schema));
+            let data_len = data_len_from_chunk_raw(ptr, data_ptr, &*schema);
+            let mut data =
+                reader::read_by_schema(data_ptr, data_len, &*schema, &chunk.dynamic_names)?;
+            expire_fields(ptr, &*schema, &mut data);
+            let cell = Self {
+                header,
+                data,
+                schema: Some(schema.clone()),
+            };
             Ok((cell, schema))
         } else {
             error!("Schema {} does not existed to read", schema_id);
@@ -235,7 +392,11 @@ impl SharedCellData {
         }
     }
     pub fn from_data(header: CellHeader, data: SharedValue) -> Self {
-        Self { header, data }
+        Self {
+            header,
+            data,
+            schema: None,
+        }
     }
     pub fn id(&self) -> Id {
         self.header.id()
@@ -302,7 +463,7 @@ impl<'a> SharedCell<'a> {
         ptr: WordMutexGuard<'a>,
         chunk: &'a Chunk,
         fields: &[u64],
-    ) -> Result<SharedData<'a, SharedValue>, ReadError> {
+    ) -> Result<SharedData<'a, (SharedValue, SchemaRef)>, ReadError> {
         select_from_chunk_raw(*ptr, chunk, fields).map(|val| SharedData {
             guard: ptr,
             inner: val,
@@ -353,7 +514,8 @@ impl Cell for SharedCellData {
 }
 
 pub fn cell_header_from_entry_content_addr(addr: usize, entry_header: &EntryHeader) -> CellHeader {
-    let mut cursor = addr_to_header_cursor(addr);
+    let layout = HeaderLayout::of_entry(addr, entry_header);
+    let mut cursor = addr_to_header_cursor(addr + layout.fields_offset());
     let header = CellHeader {
         version: cursor.read_u64::<Endian>().unwrap(),
         timestamp: cursor.read_u32::<Endian>().unwrap(),
@@ -370,24 +532,229 @@ pub fn header_from_chunk_raw(ptr: usize) -> Result<(CellHeader, usize, EntryHead
         return Err(ReadError::CellIdIsUnitId);
     }
     let (_, header) = Entry::decode_from(ptr, |addr, entry_header| {
-        assert_eq!(entry_header.entry_type, EntryType::CELL);
+        assert!(entry_header.entry_type.is_cell());
+        let layout = HeaderLayout::of_entry(addr, &entry_header);
         let header = cell_header_from_entry_content_addr(addr, &entry_header);
-        (header, addr + CELL_HEADER_SIZE, entry_header)
+        (header, addr + layout.header_size(), entry_header)
     });
     Ok(header)
 }
 
+fn entry_end_from_chunk_raw(ptr: usize) -> usize {
+    let (_, entry_end) = Entry::decode_from(ptr, |addr, entry_header| {
+        addr + entry_header.content_length as usize
+    });
+    entry_end
+}
+
+// Size of the cell data from the data address, cells end right after their last field, followed by
+// the stamps of their TTL fields
+fn data_len_from_chunk_raw(ptr: usize, data_ptr: usize, schema: &Schema) -> usize {
+    entry_end_from_chunk_raw(ptr) - field_stamps_size(schema) - data_ptr
+}
+
+// Fields with TTL are stamped with their own written time at the end of the cell entry. Fields
+// cannot be added with a TTL, so all cells of a schema have the same number of stamps.
+const FIELD_STAMP_SIZE: usize = 4;
+
+fn field_stamps_size(schema: &Schema) -> usize {
+    schema.ttl_fields().len() * FIELD_STAMP_SIZE
+}
+
+// Written time of the TTL fields of the cell, in the order of `Schema::ttl_fields`
+pub fn field_stamps_from_chunk_raw(ptr: usize, schema: &Schema) -> Vec<u32> {
+    let count = schema.ttl_fields().len();
+    let stamps_addr = entry_end_from_chunk_raw(ptr) - count * FIELD_STAMP_SIZE;
+    (0..count)
+        .map(|i| *u32_io::read(stamps_addr + i * FIELD_STAMP_SIZE))
+        .collect()
+}
+
+// Stored values of the TTL fields of the cell with their stamps, expired ones included
+pub fn ttl_fields_from_chunk_raw(
+    ptr: usize,
+    chunk: &Chunk,
+    schema: &Schema,
+) -> Result<Vec<(OwnedValue, u32)>, ReadError> {
+    let (header, data_ptr, _) = header_from_chunk_raw(ptr)?;
+    if header.schema != schema.id {
+        return Ok(vec![]);
+    }
+    let field_ids = schema
+        .ttl_fields()
+        .into_iter()
+        .map(|f| f.name_id)
+        .collect::<Vec<_>>();
+    let data_len = data_len_from_chunk_raw(ptr, data_ptr, schema);
+    let values = match reader::read_by_schema_selected(
+        data_ptr,
+        data_len,
+        schema,
+        &field_ids,
+        &chunk.dynamic_names,
+    )? {
+        SharedValue::Array(values) if field_ids.len() > 1 => values,
+        value => vec![value],
+    };
+    Ok(values
+        .into_iter()
+        .map(|v| v.owned())
+        .zip(field_stamps_from_chunk_raw(ptr, schema))
+        .collect())
+}
+
+// Values selected with the schema they are read by, which names of enum values are borrowed from
 pub fn select_from_chunk_raw(
     ptr: usize,
     chunk: &Chunk,
     fields: &[u64],
-) -> Result<SharedValue, ReadError> {
+) -> Result<(SharedValue, SchemaRef), ReadError> {
     let (header, data_ptr, _) = header_from_chunk_raw(ptr)?;
     let schema_id = &header.schema;
     if let Some(schema) = chunk.meta.schemas.get(schema_id) {
-        Ok(reader::read_by_schema_selected(data_ptr, &*schema, fields))
+        let data_len = data_len_from_chunk_raw(ptr, data_ptr, &*schema);
+        let mut data = reader::read_by_schema_selected(
+            data_ptr,
+            data_len,
+            &*schema,
+            fields,
+            &chunk.dynamic_names,
+        )?;
+        if fields.is_empty() {
+            expire_fields(ptr, &*schema, &mut data);
+        } else {
+            let expired = expired_field_ids(&*schema, &field_stamps_from_chunk_raw(ptr, &*schema));
+            if fields.len() == 1 {
+                if expired.contains(&fields[0]) {
+                    data = SharedValue::Null;
+                }
+            } else if let SharedValue::Array(ref mut values) = data {
+                for (field, value) in fields.iter().zip(values.iter_mut()) {
+                    if expired.contains(field) {
+                        *value = SharedValue::Null;
+                    }
+                }
+            }
+        }
+        Ok((data, schema))
     } else {
         error!("Schema {} does not existed to read", schema_id);
         return Err(ReadError::SchemaDoesNotExisted(*schema_id));
     }
 }
+
+// Whether the whole cell outlived the TTL of its schema
+pub fn cell_expired(header: &CellHeader, schema: &Schema) -> bool {
+    schema
+        .ttl_secs
+        .map(|ttl| clock::now().saturating_sub(header.timestamp) >= ttl)
+        .unwrap_or(false)
+}
+
+// Fields outlived their TTL since their stamps
+pub fn expired_field_ids(schema: &Schema, field_stamps: &[u32]) -> Vec<u64> {
+    let now = clock::now();
+    schema
+        .ttl_fields()
+        .into_iter()
+        .zip(field_stamps)
+        .filter(|(f, stamp)| {
+            f.ttl_secs
+                .map(|ttl| now.saturating_sub(**stamp) >= ttl)
+                .unwrap_or(false)
+        })
+        .map(|(f, _)| f.name_id)
+        .collect()
+}
+
+// Expired fields are kept in the storage until they are rewritten, but read as null
+fn expire_fields(ptr: usize, schema: &Schema, data: &mut SharedValue) {
+    let expired = expired_field_ids(schema, &field_stamps_from_chunk_raw(ptr, schema));
+    if expired.is_empty() {
+        return;
+    }
+    if let SharedValue::Map(ref mut map) = data {
+        for field_id in expired {
+            map.insert_key_id(field_id, SharedValue::Null);
+        }
+    }
+}
+
+// Decodes members of a top level primitive array field one at a time from the chunk, instead of
+// materializing the whole array. The cell lock is held until the iterator is dropped.
+pub struct ArrayFieldIter<'a> {
+    _guard: WordMutexGuard<'a>,
+    elem_type: Type,
+    ptr: usize,
+    remaining: usize,
+}
+
+impl<'a> ArrayFieldIter<'a> {
+    pub fn from_chunk_raw(
+        guard: WordMutexGuard<'a>,
+        chunk: &Chunk,
+        field_id: u64,
+    ) -> Result<Self, ReadError> {
+        let (header, data_ptr, _) = header_from_chunk_raw(*guard)?;
+        let schema = match chunk.meta.schemas.get(&header.schema) {
+            Some(schema) => schema,
+            None => {
+                error!("Schema {} does not existed to read", header.schema);
+                return Err(ReadError::SchemaDoesNotExisted(header.schema));
+            }
+        };
+        let field = match (schema.field_index.get(&field_id), &schema.fields.sub_fields) {
+            (Some(path), Some(fields)) if path.len() == 1 => &fields[path[0]],
+            _ => return Err(ReadError::FieldIsNotPrimArray(field_id)),
+        };
+        // Added fields have no pointers in the static part, enum values need the names to decode
+        if !field.is_array
+            || field.sub_fields.is_some()
+            || field.appended
+            || field.enum_values.is_some()
+        {
+            return Err(ReadError::FieldIsNotPrimArray(field_id));
+        }
+        let expired = expired_field_ids(&*schema, &field_stamps_from_chunk_raw(*guard, &*schema))
+            .contains(&field_id);
+        let mut iter = Self {
+            _guard: guard,
+            elem_type: field.data_type,
+            ptr: 0,
+            remaining: 0,
+        };
+        if expired {
+            return Ok(iter);
+        }
+        // Same layout as the reader, array fields point to their data in the tail
+        let mut ptr = data_ptr + *u32_io::read(data_ptr + field.offset.unwrap()) as usize;
+        if field.nullable {
+            let is_null = *bool_io::read(ptr);
+            ptr += 1;
+            if is_null {
+                return Ok(iter);
+            }
+        }
+        iter.remaining = *u32_io::read(ptr) as usize;
+        iter.ptr = ptr + u32_io::type_size();
+        Ok(iter)
+    }
+}
+
+impl<'a> Iterator for ArrayFieldIter<'a> {
+    type Item = OwnedValue;
+
+    fn next(&mut self) -> Option<OwnedValue> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let value = types::get_shared_val(self.elem_type, self.ptr).owned();
+        self.ptr += types::get_size(self.elem_type, self.ptr);
+        self.remaining -= 1;
+        Some(value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
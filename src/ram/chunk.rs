@@ -1,23 +1,45 @@
+use crate::ram::clock;
 use crate::ram::entry::{Entry, EntryContent, EntryType};
 use crate::ram::schema::{LocalSchemasCache, SchemaRef};
-use crate::ram::segs::{Segment, SegmentAllocator, SEGMENT_SIZE, SEGMENT_SIZE_U32};
+use crate::ram::segs::{
+    self, LibcAllocator, RegionAllocator, ScanError, Segment, SegmentAllocator, SEGMENT_SIZE,
+    SEGMENT_SIZE_U32,
+};
 use crate::ram::tombstone::{Tombstone, TOMBSTONE_ENTRY_SIZE, TOMBSTONE_SIZE};
 use crate::ram::types::{Id, SharedValue};
 use crate::server::ServerMeta;
 use crate::{index::builder::IndexBuilder, ram::cell::*};
 use crate::{
     index::builder::{probe_cell_indices, IndexRes},
-    ram::cleaner::Cleaner,
+    ram::cleaner::{
+        self,
+        compact::CompactCleaner,
+        policy::{CleaningPolicy, DefaultPolicy},
+        Cleaner,
+    },
 };
 
+use super::io::names::{self, NameDict};
 use super::{io::reader, schema::Schema};
-use crate::utils::upper_power_of_2;
+use crate::utils::{env_or, upper_power_of_2};
 use bifrost::utils::time::get_time;
+use crossbeam::queue::SegQueue;
 use lightning::linked_map::{LinkedObjectMap, NodeRef as MapNodeRef};
 use lightning::map::*;
-use parking_lot::Mutex;
-use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use parking_lot::{Mutex, RwLock};
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// Extension of backups consumed by restores
+const RESTORING_BACKUP_EXT: &str = "restoring";
 
 pub type CellReadGuard<'a> = lightning::map::WordMutexGuard<'a>;
 pub type CellWriteGuard<'a> = lightning::map::WordMutexGuard<'a>;
@@ -25,6 +47,8 @@ pub type CellWriteGuard<'a> = lightning::map::WordMutexGuard<'a>;
 pub struct Chunk {
     pub id: usize,
     pub cell_index: WordMap,
+    // Slots the cell index was created with
+    pub cell_index_capacity: usize,
     pub segs: LinkedObjectMap<Segment>,
     pub head_seg_id: AtomicU64,
     pub meta: Arc<ServerMeta>,
@@ -36,8 +60,159 @@ pub struct Chunk {
     pub allocator: SegmentAllocator,
     pub alloc_lock: Mutex<()>,
     pub index_builder: Option<Arc<IndexBuilder>>,
+    pub content_index: Mutex<ContentIndex>,
+    admission: RwLock<AdmissionConfig>,
+    // Space of the segments other than the head, for admission near the space limit
+    sealed_space: RwLock<SealedSpace>,
+    pub header_layout: HeaderLayout,
+    pub compact_workers: usize,
+    // Bits of the living rate threshold for partial cleaning, set by the cleaner of the chunk
+    cleaner_living_rate_threshold: AtomicU32,
+    cleaning_policy: RwLock<Arc<dyn CleaningPolicy>>,
+    pub dynamic_names: NameDict,
+    // Write names of dynamic fields as ids of `dynamic_names`, cells written either way are readable
+    pub intern_dynamic_names: bool,
+    pub archive: ArchiveConfig,
+    // Ids of segments replaced as the head, waiting to be archived
+    pub sealed_segs: SegQueue<u64>,
+    restoring: Mutex<RestoringBackups>,
+}
+
+// Backups consumed by a restore, kept aside until the segments the cells were restored to are
+// archived. They are read again by restores until then.
+#[derive(Default)]
+struct RestoringBackups {
+    segs: HashSet<u64>,
+    files: Vec<PathBuf>,
+}
+
+// Initial capacity of the cell index of each chunk. A larger index spreads concurrent writers
+// over more slots and defers resizing the index under load.
+pub fn default_cell_index_capacity() -> usize {
+    num_cpus::get() * 1024
+}
+
+// Verify the cell index against the segments of every chunk when the server starts
+pub fn self_check_on_startup() -> bool {
+    env_or("NEB_STARTUP_SELF_CHECK", false)
+}
+
+// Load cells from the segment backups of every chunk when the server starts
+pub fn restore_on_startup() -> bool {
+    env_or("NEB_STARTUP_RESTORE_BACKUP", false)
+}
+
+// New cells are rejected as overloaded once the chunk is filled over `space_rate`, and either
+// most of the space is living or too many segments are waiting for the cleaner
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdmissionConfig {
+    pub space_rate: f32,
+    pub living_rate: f32,
+    pub max_cleaner_backlog: usize,
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        Self {
+            space_rate: 0.9,
+            living_rate: 0.8,
+            max_cleaner_backlog: 16,
+        }
+    }
+}
+
+// Summed up when the head segment is replaced and after cleaning. Cells removed in between are
+// still counted as living until then.
+#[derive(Debug, Clone, Copy, Default)]
+struct SealedSpace {
+    used: usize,
+    living: usize,
+    // Segments living below the admission living rate
+    backlog: usize,
+}
+
+// With a backup storage, segments are archived in the background once they are sealed, when
+// they are replaced as the head for being full, instead of waiting for the cleaner
+#[derive(Debug, Clone)]
+pub struct ArchiveConfig {
+    pub auto_archive: bool,
+    // Sleep of the archiver when no segment is sealed
+    pub poll_interval: Duration,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        let auto_archive = env_or("NEB_AUTO_ARCHIVE_SEGMENTS", true);
+        let poll_interval_ms = env_or("NEB_ARCHIVE_POLL_INTERVAL_MS", 100);
+        Self {
+            auto_archive,
+            poll_interval: Duration::from_millis(poll_interval_ms),
+        }
+    }
+}
+
+// Content hash of cells written in dedup mode to their cell hashes, and the reverse for removal.
+// Cells may be updated afterwards, so hits are verified against the stored content.
+#[derive(Default)]
+pub struct ContentIndex {
+    by_content: HashMap<Id, u64>,
+    by_cell: HashMap<u64, Id>,
+}
+
+impl ContentIndex {
+    fn insert(&mut self, content: Id, hash: u64) {
+        self.by_content.insert(content, hash);
+        self.by_cell.insert(hash, content);
+    }
+    fn remove_cell(&mut self, hash: u64) {
+        if let Some(content) = self.by_cell.remove(&hash) {
+            self.by_content.remove(&content);
+        }
+    }
+}
+
+// Discrepancies between the cell index of a chunk and the cells in its segments
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConsistencyReport {
+    pub chunk_id: usize,
+    // Indexed hashes with addresses that are not cell entries in the segments
+    pub dangling: Vec<(u64, usize)>,
+    // Indexed hashes with cells of other hashes at their addresses, and the hashes found
+    pub mismatched: Vec<(u64, u64)>,
+    // Hashes of cells in the segments, neither indexed nor removed by tombstones
+    pub unindexed: Vec<u64>,
+    // Segments with scans halted by corrupt entries
+    pub corrupted: Vec<(u64, ScanError)>,
+}
+
+impl ConsistencyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.dangling.is_empty()
+            && self.mismatched.is_empty()
+            && self.unindexed.is_empty()
+            && self.corrupted.is_empty()
+    }
 }
 
+// Space usage of a segment, for monitoring fragmentation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SegmentStat {
+    pub chunk_id: usize,
+    pub id: u64,
+    // Bytes appended to the segment
+    pub used: u32,
+    // Bytes of dead cells and tombstones, reclaimable by the cleaner
+    pub dead: u32,
+    pub living_rate: f32,
+}
+
+pub fn content_hash(cell: &OwnedCell) -> Id {
+    Id::from_obj(&(cell.header.schema, &cell.data))
+}
+
+// Stripes of the content hashes to the chunks holding the cells written in dedup mode
+const CONTENT_STRIPES: usize = 64;
+
 impl Chunk {
     fn new(
         id: usize,
@@ -46,8 +221,10 @@ impl Chunk {
         index_builder: Option<Arc<IndexBuilder>>,
         backup_storage: Option<String>,
         wal_storage: Option<String>,
+        cell_index_capacity: usize,
+        region: &dyn RegionAllocator,
     ) -> Chunk {
-        let allocator = SegmentAllocator::new(size);
+        let allocator = SegmentAllocator::new(id, size, region);
         let bootstrap_segment = allocator
             .alloc_seg(&backup_storage, &wal_storage)
             .expect(&format!("No space left for first segment in chunk {}", id));
@@ -61,11 +238,17 @@ impl Chunk {
         };
         debug!("Creating chunk {}, num segments {}", id, num_segs);
         let segs = LinkedObjectMap::with_capacity(upper_power_of_2(num_segs));
-        let index = WordMap::with_capacity(64);
+        let cell_index_capacity = upper_power_of_2(cell_index_capacity.max(1));
+        let index = WordMap::with_capacity(cell_index_capacity);
+        // The dictionary is only kept in memory, cells restored from backups or the log would
+        // refer to names it no longer has
+        let intern_dynamic_names =
+            names::intern_by_default() && backup_storage.is_none() && wal_storage.is_none();
         let chunk = Chunk {
             id,
             segs,
             cell_index: index,
+            cell_index_capacity,
             meta,
             backup_storage,
             wal_storage,
@@ -76,15 +259,57 @@ impl Chunk {
             head_seg_id: AtomicU64::new(bootstrap_segment.id),
             gc_lock: Mutex::new(()),
             alloc_lock: Mutex::new(()), // TODO: optimize this
+            content_index: Mutex::new(ContentIndex::default()),
+            admission: RwLock::new(AdmissionConfig::default()),
+            sealed_space: RwLock::new(SealedSpace::default()),
+            header_layout: HeaderLayout::default(),
+            compact_workers: cleaner::default_compact_workers(),
+            cleaner_living_rate_threshold: AtomicU32::new(
+                cleaner::DEFAULT_LIVING_RATE_THRESHOLD.to_bits(),
+            ),
+            cleaning_policy: RwLock::new(Arc::new(DefaultPolicy)),
+            dynamic_names: NameDict::default(),
+            intern_dynamic_names,
+            archive: ArchiveConfig::default(),
+            sealed_segs: SegQueue::new(),
+            restoring: Mutex::new(RestoringBackups::default()),
         };
         chunk.put_segment(bootstrap_segment);
         return chunk;
     }
 
+    pub fn dynamic_names_to_intern(&self) -> Option<&NameDict> {
+        if self.intern_dynamic_names {
+            Some(&self.dynamic_names)
+        } else {
+            None
+        }
+    }
+
+    pub fn cleaning_policy(&self) -> Arc<dyn CleaningPolicy> {
+        self.cleaning_policy.read().clone()
+    }
+
+    pub fn cleaner_living_rate_threshold(&self) -> f32 {
+        f32::from_bits(self.cleaner_living_rate_threshold.load(Ordering::Relaxed))
+    }
+
+    pub fn set_cleaner_living_rate_threshold(&self, threshold: f32) {
+        self.cleaner_living_rate_threshold
+            .store(threshold.to_bits(), Ordering::Relaxed);
+    }
+
+    // Takes effect from the next round of cleaning
+    pub fn set_cleaning_policy(&self, policy: Arc<dyn CleaningPolicy>) {
+        *self.cleaning_policy.write() = policy;
+    }
+
     fn get_head_seg_id(&self) -> u64 {
         self.head_seg_id.load(Ordering::Acquire)
     }
 
+    // Entries are always appended to the head segment until it is full, so temporally
+    // adjacent writes stay spatially adjacent. A new head is only allocated on overflow.
     pub fn try_acquire(&self, size: u32) -> Option<PendingEntry> {
         let mut tried_gc = false;
         loop {
@@ -136,6 +361,10 @@ impl Chunk {
                         let new_seg_id = new_seg.id;
                         self.put_segment(new_seg);
                         self.head_seg_id.store(new_seg_id, Ordering::Release);
+                        self.refresh_sealed_space();
+                        if self.auto_archive() {
+                            self.sealed_segs.push(head_seg_id as u64);
+                        }
                     }
                     // whether the segment acquisition success or not,
                     // try to get the new segment and try again
@@ -191,11 +420,19 @@ impl Chunk {
             .map_err(|(e, _)| e)
     }
 
-    fn read_selected(&self, hash: u64, fields: &[u64]) -> Result<SharedValue, ReadError> {
+    fn read_selected(
+        &self,
+        hash: u64,
+        fields: &[u64],
+    ) -> Result<(SharedValue, SchemaRef), ReadError> {
         let loc = self.location_for_read(hash)?;
         select_from_chunk_raw(*loc, self, fields)
     }
 
+    fn array_field_iter(&self, hash: u64, field_id: u64) -> Result<ArrayFieldIter, ReadError> {
+        ArrayFieldIter::from_chunk_raw(self.location_for_read(hash)?, self, field_id)
+    }
+
     fn read_partial_raw(&self, hash: u64, offset: usize, len: usize) -> Result<Vec<u8>, ReadError> {
         let loc = self.location_for_read(hash)?;
         let head_ptr = *loc + offset;
@@ -206,18 +443,60 @@ impl Chunk {
         Ok(data.to_vec())
     }
 
+    // The whole cell entry as stored in the segment, copied without decoding
+    fn read_cell_raw(&self, hash: u64) -> Result<Vec<u8>, ReadError> {
+        let loc = self.location_for_read(hash)?;
+        let entry_pos = *loc;
+        let (entry_header, content_pos) = Entry::decode_from(entry_pos, |pos, _| pos);
+        let len = content_pos - entry_pos + entry_header.content_length as usize;
+        Ok(unsafe { std::slice::from_raw_parts(entry_pos as *const u8, len) }.to_vec())
+    }
+
     pub fn write_cell_to_chunk(
         &self,
         cell: &mut OwnedCell,
     ) -> Result<(usize, SchemaRef), WriteError> {
         let schema_id = cell.header.schema;
         if let Some(schema) = self.meta.schemas.get(&schema_id) {
-            Ok((cell.write_to_chunk_with_schema(self, &*schema)?, schema))
+            let field_stamps = self.field_stamps_for(cell, &*schema);
+            Ok((
+                cell.write_to_chunk_with_schema(self, &*schema, &field_stamps)?,
+                schema,
+            ))
         } else {
             Err(WriteError::SchemaDoesNotExisted(schema_id))
         }
     }
 
+    // Written time of the TTL fields of the cell. Values unchanged from the stored cell keep their
+    // stamps while they live, so rewriting other fields does not extend their lives. Expired ones
+    // written again are stamped anew to be read back.
+    fn field_stamps_for(&self, cell: &OwnedCell, schema: &Schema) -> Vec<u32> {
+        let ttl_fields = schema.ttl_fields();
+        if ttl_fields.is_empty() {
+            return vec![];
+        }
+        let stored = self
+            .location_for_read(cell.header.hash)
+            .and_then(|loc| ttl_fields_from_chunk_raw(*loc, self, schema))
+            .unwrap_or_default();
+        let stored_stamps = stored.iter().map(|(_, stamp)| *stamp).collect::<Vec<_>>();
+        let expired = expired_field_ids(schema, &stored_stamps);
+        let now = clock::now();
+        ttl_fields
+            .iter()
+            .enumerate()
+            .map(|(i, field)| match stored.get(i) {
+                Some((value, stamp))
+                    if *value == cell[field.name_id] && !expired.contains(&field.name_id) =>
+                {
+                    *stamp
+                }
+                _ => now,
+            })
+            .collect()
+    }
+
     fn ensure_indices(&self, new_cell: &OwnedCell, old_cell: Option<&SharedCell>, schema: &Schema) {
         if let Some(index_builder) = &self.index_builder {
             let old_indices = old_cell.map(|cell| probe_cell_indices(cell, &*schema));
@@ -242,8 +521,62 @@ impl Chunk {
         }
     }
 
+    pub fn set_admission(&self, config: AdmissionConfig) {
+        *self.admission.write() = config;
+        self.refresh_sealed_space();
+    }
+
+    // Sum up the space of the segments other than the head, for the cleaner changed them or the
+    // head was sealed
+    pub fn refresh_sealed_space(&self) {
+        let living_rate = self.admission.read().living_rate;
+        let head_id = self.get_head_seg_id();
+        let mut sealed = SealedSpace::default();
+        for seg in self.segments().iter().filter(|seg| seg.id != head_id) {
+            sealed.used += seg.used_spaces() as usize;
+            sealed.living += seg.living_space() as usize;
+            if seg.living_rate() < living_rate {
+                sealed.backlog += 1;
+            }
+        }
+        *self.sealed_space.write() = sealed;
+    }
+
+    // Soft limit for new cells ahead of running out of space
+    fn admit_write(&self) -> Result<(), WriteError> {
+        let config = self.admission.read().clone();
+        let space_limit = (self.capacity as f64 * config.space_rate as f64) as usize;
+        // Allocated segments are a cheap upper bound of the used space
+        if self.total_space.load(Ordering::Relaxed) + SEGMENT_SIZE < space_limit {
+            return Ok(());
+        }
+        // Only the head is changed by writes, the rest are kept summed up
+        let sealed = *self.sealed_space.read();
+        let (mut used, mut living, mut backlog) = (sealed.used, sealed.living, sealed.backlog);
+        if let Some(head) = self.segs.get(&(self.get_head_seg_id() as usize)) {
+            used += head.used_spaces() as usize;
+            living += head.living_space() as usize;
+            if head.living_rate() < config.living_rate {
+                backlog += 1;
+            }
+        }
+        if used < space_limit {
+            return Ok(());
+        }
+        let living_rate = living as f32 / used as f32;
+        if living_rate >= config.living_rate || backlog >= config.max_cleaner_backlog {
+            debug!(
+                "Chunk {} overloaded, used {}, living rate {}, cleaner backlog {}",
+                self.id, used, living_rate, backlog
+            );
+            return Err(WriteError::Overloaded);
+        }
+        Ok(())
+    }
+
     fn write_cell(&self, cell: &mut OwnedCell) -> Result<CellHeader, WriteError> {
         debug!("Writing cell {:?} to chunk {}", cell.id(), self.id);
+        self.admit_write()?;
         let (cell_loc, schema) = self.write_cell_to_chunk(cell)?;
         match self.cell_index.try_insert_locked(cell.header.hash as usize) {
             Some(mut guard) => {
@@ -255,6 +588,59 @@ impl Chunk {
         Ok(cell.header)
     }
 
+    // Header of the cell with the same content written in dedup mode, if it still exists
+    fn find_by_content(&self, content: &Id, cell: &OwnedCell) -> Option<CellHeader> {
+        let mut content_index = self.content_index.lock();
+        let hash = *content_index.by_content.get(content)?;
+        match self.read_cell(hash) {
+            Ok(existing)
+                if existing.header.schema == cell.header.schema
+                    && existing.to_owned().data == cell.data =>
+            {
+                Some(existing.header)
+            }
+            _ => {
+                // Removed or updated afterwards
+                content_index.remove_cell(hash);
+                None
+            }
+        }
+    }
+
+    fn write_cell_dedup(
+        &self,
+        cell: &mut OwnedCell,
+        content: Id,
+    ) -> Result<CellHeader, WriteError> {
+        let header = self.write_cell(cell)?;
+        self.content_index.lock().insert(content, header.hash);
+        Ok(header)
+    }
+
+    // Whether restored cells were written in dedup mode is not kept, cells of schemas with
+    // indices are indexed by their contents for dedup writes to resolve to them. Cells of other
+    // schemas are not read and hashed.
+    fn index_restored_contents(&self, hashes: &[u64]) {
+        let mut content_index = self.content_index.lock();
+        for hash in hashes {
+            let indexed = self
+                .head_cell(*hash)
+                .ok()
+                .and_then(|header| self.meta.schemas.get(&header.schema))
+                .map_or(false, |schema| !schema.index_fields.is_empty());
+            if !indexed {
+                continue;
+            }
+            if let Ok(cell) = self.read_cell(*hash) {
+                content_index.insert(content_hash(&cell.to_owned()), *hash);
+            }
+        }
+    }
+
+    fn indexed_contents(&self) -> Vec<Id> {
+        self.content_index.lock().by_content.keys().cloned().collect()
+    }
+
     fn old_index_res<'a>(
         &'a self,
         cell_loc: &WordMutexGuard<'a>,
@@ -378,6 +764,7 @@ impl Chunk {
             let cell_location = *guard;
             self.put_tombstone_by_cell_loc(cell_location)?;
             guard.remove();
+            self.content_index.lock().remove_cell(hash);
             Ok(())
         } else {
             Err(WriteError::CellDoesNotExisted)
@@ -400,6 +787,7 @@ impl Chunk {
                         } else {
                             self.remove_indices(&cell, &schema);
                             cell.into_guard().remove();
+                            self.content_index.lock().remove_cell(hash);
                             Ok(())
                         }
                     } else {
@@ -521,6 +909,23 @@ impl Chunk {
         self.segs.all_values()
     }
 
+    // Space usage of all segments by segment id
+    pub fn fragmentation_report(&self) -> Vec<SegmentStat> {
+        let mut stats = self
+            .segments()
+            .iter()
+            .map(|seg| SegmentStat {
+                chunk_id: self.id,
+                id: seg.id,
+                used: seg.used_spaces(),
+                dead: seg.total_dead_space(),
+                living_rate: seg.living_rate(),
+            })
+            .collect::<Vec<_>>();
+        stats.sort_by_key(|stat| stat.id);
+        stats
+    }
+
     // Scan for dead tombstone. This will scan the whole segment, decoding all entry header
     // and looking for those with entry type tombstone.
     // It is resource intensive so there will be some rules to skip the scan.
@@ -577,7 +982,8 @@ impl Chunk {
         }
     }
 
-    pub fn segs_for_compact_cleaner(&self) -> Vec<MapNodeRef<Segment>> {
+    // Segments with living rate below the threshold, the ones worth compacting first
+    pub fn segs_for_compact_cleaner(&self, living_rate_threshold: f32) -> Vec<MapNodeRef<Segment>> {
         let utilization_selection = self
             .segments()
             .into_iter()
@@ -585,7 +991,7 @@ impl Chunk {
                 let rate = seg.living_rate();
                 (seg, rate)
             })
-            .filter(|(_, utilization)| *utilization < 90f32);
+            .filter(|(_, utilization)| *utilization < living_rate_threshold);
         let head_seg_id = self.get_head_seg_id();
         let mut list: Vec<_> = utilization_selection
             .filter(|(seg, _)| seg.id != head_seg_id && seg.no_references())
@@ -619,16 +1025,71 @@ impl Chunk {
             if seg_id as u64 == head_id {
                 continue;
             } // never archive head segments
-            let seg_key = seg_id as usize;
-            if let Some(segment) = self.segs.get(&seg_key) {
-                if !segment
-                    .archived
-                    .compare_and_swap(false, true, Ordering::Relaxed)
-                {
-                    if let Err(e) = segment.archive() {
-                        error!("cannot archive segment {}, reason:{:?}", self.id, e)
+            self.archive_segment(seg_id as u64);
+        }
+    }
+
+    fn auto_archive(&self) -> bool {
+        self.archive.auto_archive && self.backup_storage.is_some()
+    }
+
+    // Archive segments sealed since the last call, returns the number of segments archived
+    pub fn archive_sealed_segments(&self) -> usize {
+        if self.sealed_segs.is_empty() {
+            return 0;
+        }
+        // The cleaner must not move entries of the segment while it is written to the backup
+        let _gc_guard = self.gc_lock.lock();
+        let mut archived = 0;
+        while let Some(seg_id) = self.sealed_segs.pop() {
+            if self.archive_segment(seg_id) {
+                archived += 1;
+            }
+        }
+        archived
+    }
+
+    fn archive_segment(&self, seg_id: u64) -> bool {
+        if let Some(segment) = self.segs.get(&(seg_id as usize)) {
+            if !segment
+                .archived
+                .compare_and_swap(false, true, Ordering::Relaxed)
+            {
+                return match segment.archive() {
+                    Ok(archived) => {
+                        if archived {
+                            self.release_restored_backups(seg_id);
+                        }
+                        archived
                     }
-                }
+                    Err(e) => {
+                        error!(
+                            "cannot archive segment {} of chunk {}, reason:{:?}",
+                            seg_id, self.id, e
+                        );
+                        false
+                    }
+                };
+            }
+        }
+        false
+    }
+
+    fn release_restored_backups(&self, archived_seg_id: u64) {
+        let mut restoring = self.restoring.lock();
+        if restoring.segs.remove(&archived_seg_id) {
+            Self::remove_restored_backups(&mut restoring);
+        }
+    }
+
+    // Remove the backups consumed by restores once no restored cell is left out of the archives
+    fn remove_restored_backups(restoring: &mut RestoringBackups) {
+        if !restoring.segs.is_empty() {
+            return;
+        }
+        for path in restoring.files.drain(..) {
+            if let Err(e) = fs::remove_file(&path) {
+                warn!("Cannot remove restored backup {:?}, {:?}", path, e);
             }
         }
     }
@@ -644,7 +1105,7 @@ impl Chunk {
                 trace!("Iterating live entries on chunk {} segment {}. Got {:?} at {} size {}",
                        chunk_id, seg.id, entry_header.entry_type, entry_meta.entry_pos, entry_size);
                 match entry_header.entry_type {
-                    EntryType::CELL => {
+                    EntryType::CELL | EntryType::VERSIONED_CELL => {
                         trace!("Entry at {} is a cell", entry_meta.entry_pos);
                         let cell_header =
                             cell_header_from_entry_content_addr(
@@ -692,10 +1153,349 @@ impl Chunk {
             })
     }
 
+    // Load cells from the segment backups in the backup storage, like after a restart. The last
+    // written entry of every cell is copied to the chunk unless a tombstone removed it, cells
+    // already in the chunk are kept. Consumed backups are renamed for segments to archive again
+    // under the same ids, and removed after the segments holding the restored cells are archived.
+    // Returns the number of cells restored.
+    pub fn restore_from_backup(&self) -> io::Result<usize> {
+        let backup_dir = match &self.backup_storage {
+            Some(dir) if Path::new(dir).exists() => dir,
+            _ => return Ok(0),
+        };
+        let mut backups = vec![];
+        for dir_entry in fs::read_dir(backup_dir)? {
+            let path = dir_entry?.path();
+            let ext = path.extension().and_then(|ext| ext.to_str());
+            if ext == Some("backup") || ext == Some(RESTORING_BACKUP_EXT) {
+                // Renamed backups start with their segment ids
+                let seg_id = path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str()?.split('.').next()?.parse().ok());
+                if let Some(seg_id) = seg_id {
+                    backups.push((path, seg_id));
+                }
+            }
+        }
+        // Restored entries archived again are equal to the ones in the renamed backups, the
+        // archived ones come later to win the ties, for tombstones refer to their segments
+        backups.sort_by_key(|(path, _)| {
+            path.extension().map_or(true, |ext| ext != RESTORING_BACKUP_EXT)
+        });
+        // cell hash -> the last written entry of the cell, its segment, and the backup and offset
+        // it was found at. Backups are streamed, only the chosen entries are read again to copy.
+        let mut latest: HashMap<u64, (CellHeader, u64, usize, usize, usize)> = HashMap::new();
+        // Removed cells by their hashes, segments and versions -> where the removal was seen. In
+        // the segment of the cell, it only removes the entries appended before the tombstone.
+        let mut removed: HashMap<(u64, u64, u64), usize> = HashMap::new();
+        for (backup, (path, seg_id)) in backups.iter().enumerate() {
+            let error = segs::scan_entries(fs::File::open(path)?, |offset, entry_meta| {
+                match entry_meta.entry_header.entry_type {
+                    EntryType::CELL | EntryType::VERSIONED_CELL => {
+                        let header = cell_header_from_entry_content_addr(
+                            entry_meta.body_pos,
+                            &entry_meta.entry_header,
+                        );
+                        // Cells can be written again after removal, versions start over then.
+                        // Entries are appended in order, the later one wins in a segment.
+                        let order = (header.timestamp, header.version);
+                        let newer = latest
+                            .get(&header.hash)
+                            .map_or(true, |(last, ..)| order >= (last.timestamp, last.version));
+                        if newer {
+                            let found = (header, *seg_id, backup, offset, entry_meta.entry_size);
+                            latest.insert(header.hash, found);
+                        }
+                    }
+                    EntryType::TOMBSTONE => {
+                        let tombstone =
+                            Tombstone::read_from_entry_content_addr(entry_meta.body_pos);
+                        let seen_at = if tombstone.segment_id == *seg_id {
+                            offset
+                        } else {
+                            usize::MAX
+                        };
+                        let key = (tombstone.hash, tombstone.segment_id, tombstone.version);
+                        let removed_at = removed.entry(key).or_insert(0);
+                        *removed_at = (*removed_at).max(seen_at);
+                    }
+                    _ => {}
+                }
+            })?;
+            if let Some(e) = error {
+                warn!(
+                    "Backup {:?} is corrupted, entries after it are lost, {:?}",
+                    path, e
+                );
+            }
+        }
+        // Tombstones point to the exact entry they removed
+        let mut chosen = latest
+            .into_iter()
+            .filter(|(hash, (header, seg_id, _, offset, _))| {
+                let removed_at = removed.get(&(*hash, *seg_id, header.version));
+                removed_at.map_or(true, |pos| *pos <= *offset)
+                    && self.cell_index.get_from_mutex(&(*hash as usize)).is_none()
+            })
+            .map(|(hash, (_, _, backup, offset, size))| (backup, offset, size, hash))
+            .collect::<Vec<_>>();
+        // Read the chosen entries file by file, forward in each
+        chosen.sort_unstable();
+        let mut restored = vec![];
+        let mut restored_segs = HashSet::new();
+        let mut file: Option<(usize, fs::File)> = None;
+        let mut entry = vec![];
+        for (backup, offset, size, hash) in chosen {
+            if file.as_ref().map_or(true, |(opened, _)| *opened != backup) {
+                file = Some((backup, fs::File::open(&backups[backup].0)?));
+            }
+            // Read before acquiring, segments never hold entries partially read
+            let (_, backup_file) = file.as_mut().unwrap();
+            entry.resize(size, 0);
+            backup_file.seek(SeekFrom::Start(offset as u64))?;
+            backup_file.read_exact(&mut entry)?;
+            let pending_entry = match self.try_acquire(size as u32) {
+                Some(pending_entry) => pending_entry,
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "No space left to restore cells",
+                    ))
+                }
+            };
+            unsafe {
+                ptr::copy_nonoverlapping(entry.as_ptr(), pending_entry.addr as *mut u8, size);
+            }
+            match self.cell_index.try_insert_locked(hash as usize) {
+                Some(mut guard) => {
+                    *guard = pending_entry.addr;
+                    restored_segs.insert(pending_entry.seg.id);
+                    restored.push(hash);
+                }
+                // Written in the meantime
+                None => self.mark_dead_entry_with_seg(pending_entry.addr, &pending_entry.seg),
+            }
+        }
+        let mut restoring = self.restoring.lock();
+        for (path, seg_id) in &backups {
+            if path.extension().map_or(false, |ext| ext == RESTORING_BACKUP_EXT) {
+                continue;
+            }
+            let renamed = (0..)
+                .map(|n| path.with_extension(format!("{}.{}", n, RESTORING_BACKUP_EXT)))
+                .find(|renamed| !renamed.exists())
+                .unwrap();
+            debug!("Backup of segment {} consumed as {:?}", seg_id, renamed);
+            fs::rename(path, &renamed)?;
+            restoring.files.push(renamed);
+        }
+        for (path, _) in backups {
+            let renamed = path.extension().map_or(false, |ext| ext == RESTORING_BACKUP_EXT);
+            if renamed && !restoring.files.contains(&path) {
+                restoring.files.push(path);
+            }
+        }
+        restoring.segs.extend(restored_segs);
+        let pending_segs = restoring.segs.iter().cloned().collect::<Vec<_>>();
+        Self::remove_restored_backups(&mut restoring);
+        drop(restoring);
+        // Segments other than the head hold their final content, the head is archived on sealing
+        let _gc_guard = self.gc_lock.lock();
+        let head_id = self.get_head_seg_id();
+        for seg_id in pending_segs {
+            if seg_id != head_id {
+                self.archive_segment(seg_id);
+            }
+        }
+        self.index_restored_contents(&restored);
+        debug!("Restored {} cells to chunk {}", restored.len(), self.id);
+        Ok(restored.len())
+    }
+
+    // Cross validate the cell index with the segments. Cells are not locked, so the result is
+    // only reliable when there are no writers, like right after recovery.
+    pub fn self_check(&self) -> ConsistencyReport {
+        let _gc_guard = self.gc_lock.lock();
+        let mut report = ConsistencyReport {
+            chunk_id: self.id,
+            ..Default::default()
+        };
+        let mut cells = HashMap::new();
+        let mut removed = HashSet::new();
+        for seg in self.segments() {
+            let mut entries = seg.entry_iter();
+            for entry_meta in entries.by_ref() {
+                let entry_header = entry_meta.entry_header;
+                if entry_header.entry_type.is_cell() {
+                    let header =
+                        cell_header_from_entry_content_addr(entry_meta.body_pos, &entry_header);
+                    cells.insert(entry_meta.entry_pos, header.hash);
+                } else if entry_header.entry_type == EntryType::TOMBSTONE {
+                    let tombstone = Tombstone::read_from_entry_content_addr(entry_meta.body_pos);
+                    removed.insert(tombstone.hash);
+                }
+            }
+            if let Some(e) = entries.error() {
+                report.corrupted.push((seg.id, e));
+            }
+        }
+        let indexed = self
+            .cell_index
+            .entries()
+            .into_iter()
+            .map(|(hash, addr)| (hash as u64, addr))
+            .collect::<HashMap<_, _>>();
+        for (&hash, &addr) in &indexed {
+            match cells.get(&addr) {
+                Some(&found) if found == hash => {}
+                Some(&found) => report.mismatched.push((hash, found)),
+                None => report.dangling.push((hash, addr)),
+            }
+        }
+        let mut unindexed = cells
+            .values()
+            .filter(|hash| !indexed.contains_key(hash) && !removed.contains(hash))
+            .cloned()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+        unindexed.sort();
+        report.unindexed = unindexed;
+        report.dangling.sort();
+        report.mismatched.sort();
+        report
+    }
+
     pub fn cell_count(&self) -> usize {
         self.cell_index.len()
     }
 
+    pub fn cell_ids(&self) -> Vec<Id> {
+        self.cell_index
+            .entries()
+            .into_iter()
+            .filter_map(|(hash, _)| {
+                let loc = self.location_for_read(hash as u64).ok()?;
+                let (header, _, _) = header_from_chunk_raw(*loc).ok()?;
+                Some(header.id())
+            })
+            .collect()
+    }
+
+    pub fn cell_ids_of_schema(&self, schema_id: u32) -> Vec<Id> {
+        self.cell_index
+            .entries()
+            .into_iter()
+            .filter_map(|(hash, _)| {
+                let loc = self.location_for_read(hash as u64).ok()?;
+                let (header, _, _) = header_from_chunk_raw(*loc).ok()?;
+                if header.schema == schema_id {
+                    Some(header.id())
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    // Ids of the cells with ids in [start, end), compared by partition then hash
+    pub fn cell_ids_in_range(&self, start: &Id, end: &Id) -> Vec<Id> {
+        let range = (start.higher, start.lower)..(end.higher, end.lower);
+        self.cell_index
+            .entries()
+            .into_iter()
+            .filter_map(|(hash, _)| {
+                let loc = self.location_for_read(hash as u64).ok()?;
+                let (header, _, _) = header_from_chunk_raw(*loc).ok()?;
+                let id = header.id();
+                if range.contains(&(id.higher, id.lower)) {
+                    Some(id)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    // Apply `f` to every cell of the schema in this chunk, one cell locked at a time
+    pub fn scan_schema<F>(&self, schema_id: u32, f: &F)
+    where
+        F: Fn(SharedCell),
+    {
+        for (hash, _) in self.cell_index.entries() {
+            let loc = match self.location_for_read(hash as u64) {
+                Ok(loc) => loc,
+                Err(_) => continue, // removed during the scan
+            };
+            match header_from_chunk_raw(*loc) {
+                Ok((header, _, _)) if header.schema == schema_id => {}
+                _ => continue,
+            }
+            match SharedCell::from_chunk_raw(loc, self) {
+                Ok((cell, _)) => f(cell),
+                Err((e, _)) => warn!("Cannot read cell {} on scan, {:?}", hash, e),
+            }
+        }
+    }
+
+    // Remove cells outlived the TTL of their schemas, as if they were removed by users so their
+    // indices are removed as well. Returns the number of cells removed.
+    pub fn expire_cells(&self) -> usize {
+        let mut ttl_schemas = HashMap::new();
+        let mut expired = 0;
+        for (hash, _) in self.cell_index.entries() {
+            let header = match self.head_cell(hash as u64) {
+                Ok(header) => header,
+                Err(_) => continue,
+            };
+            let schema = ttl_schemas
+                .entry(header.schema)
+                .or_insert_with(|| {
+                    self.meta
+                        .schemas
+                        .get(&header.schema)
+                        .filter(|schema| schema.ttl_secs.is_some())
+                })
+                .clone();
+            let schema = match schema {
+                Some(schema) => schema,
+                None => continue,
+            };
+            // Check again under the lock, the cell may have been updated since
+            if cell_expired(&header, &schema)
+                && self
+                    .remove_cell_by(hash as u64, |cell| cell_expired(&cell.header, &schema))
+                    .is_ok()
+            {
+                expired += 1;
+            }
+        }
+        expired
+    }
+
+    // Return the chunk to a pristine state and keep its segments for new cells, to reuse the memory
+    // in tests. The cleaner and segment allocation are locked out, but there must be no readers or
+    // writers. Indices of the cells are left as is.
+    pub fn clear(&self) {
+        let _gc_guard = self.gc_lock.lock();
+        let _alloc_guard = self.alloc_lock.lock();
+        for (hash, _) in self.cell_index.entries() {
+            if let Some(guard) = self.cell_index.lock(hash) {
+                guard.remove();
+            }
+        }
+        for seg in self.segments() {
+            seg.append_header.store(seg.addr, Ordering::Release);
+            seg.dead_space.store(0, Ordering::Relaxed);
+            seg.tombstones.store(0, Ordering::Relaxed);
+            seg.dead_tombstones.store(0, Ordering::Relaxed);
+            seg.last_tombstones_scanned.store(0, Ordering::Relaxed);
+            seg.archived.store(false, Ordering::Relaxed);
+        }
+        *self.content_index.lock() = ContentIndex::default();
+        *self.sealed_space.write() = SealedSpace::default();
+    }
+
     pub fn seg_count(&self) -> usize {
         self.segs.len()
     }
@@ -721,6 +1521,9 @@ impl Drop for PendingEntry {
 
 pub struct Chunks {
     pub list: Vec<Chunk>,
+    // Content hash -> id of the chunk with the cell written in dedup mode, the stripe of a content
+    // is locked across looking it up and writing it
+    content_chunks: Vec<Mutex<HashMap<Id, usize>>>,
 }
 
 impl Chunks {
@@ -731,6 +1534,29 @@ impl Chunks {
         index_builder: Option<Arc<IndexBuilder>>,
         backup_storage: Option<String>,
         wal_storage: Option<String>,
+        cell_index_capacity: usize,
+    ) -> Arc<Chunks> {
+        Self::new_with_allocator(
+            count,
+            size,
+            meta,
+            index_builder,
+            backup_storage,
+            wal_storage,
+            cell_index_capacity,
+            &LibcAllocator,
+        )
+    }
+    // Chunks with memory regions from the allocator, like files mapped by `MmapAllocator`
+    pub fn new_with_allocator(
+        count: usize,
+        size: usize,
+        meta: Arc<ServerMeta>,
+        index_builder: Option<Arc<IndexBuilder>>,
+        backup_storage: Option<String>,
+        wal_storage: Option<String>,
+        cell_index_capacity: usize,
+        region: &dyn RegionAllocator,
     ) -> Arc<Chunks> {
         let chunk_size = size / count;
         let mut chunks = Vec::new();
@@ -750,9 +1576,41 @@ impl Chunks {
                 index_builder.clone(),
                 backup_storage,
                 wal_storage,
+                cell_index_capacity,
+                region,
             ));
         }
-        Arc::new(Chunks { list: chunks })
+        let chunks = Arc::new(Chunks {
+            list: chunks,
+            content_chunks: (0..CONTENT_STRIPES).map(|_| Mutex::new(HashMap::new())).collect(),
+        });
+        if chunks.list.iter().any(|chunk| chunk.auto_archive()) {
+            Self::start_archiver(&chunks);
+        }
+        chunks
+    }
+    // Stops after the chunks are dropped
+    fn start_archiver(chunks: &Arc<Chunks>) {
+        let chunks = Arc::downgrade(chunks);
+        thread::Builder::new()
+            .name("Segment archiver".into())
+            .spawn(move || loop {
+                let (archived, poll_interval) = match chunks.upgrade() {
+                    Some(chunks) => (
+                        chunks
+                            .list
+                            .iter()
+                            .map(|chunk| chunk.archive_sealed_segments())
+                            .sum::<usize>(),
+                        chunks.list[0].archive.poll_interval,
+                    ),
+                    None => break,
+                };
+                if archived == 0 {
+                    thread::sleep(poll_interval);
+                }
+            })
+            .unwrap();
     }
     pub fn new_dummy(count: usize, size: usize) -> Arc<Chunks> {
         Chunks::new(
@@ -764,11 +1622,18 @@ impl Chunks {
             None,
             None,
             None,
+            default_cell_index_capacity(),
         )
     }
+    // Index in `list` of the chunk holding the cell of the id
+    pub fn chunk_id_for(&self, id: &Id) -> usize {
+        self.chunk_id_by_partition(id.higher)
+    }
+    fn chunk_id_by_partition(&self, partition: u64) -> usize {
+        partition as usize % self.list.len()
+    }
     fn locate_chunk_by_partition(&self, partition: u64) -> &Chunk {
-        let chunk_id = partition as usize % self.list.len();
-        return &self.list[chunk_id];
+        return &self.list[self.chunk_id_by_partition(partition)];
     }
     fn locate_chunk_by_key(&self, key: &Id) -> (&Chunk, u64) {
         return (self.locate_chunk_by_partition(key.higher), key.lower);
@@ -781,10 +1646,14 @@ impl Chunks {
         &self,
         key: &Id,
         fields: &[u64],
-    ) -> Result<SharedValue, ReadError> {
+    ) -> Result<(SharedValue, SchemaRef), ReadError> {
         let (chunk, hash) = self.locate_chunk_by_key(key);
         return chunk.read_selected(hash, fields);
     }
+    pub fn array_field_iter(&self, key: &Id, field_id: u64) -> Result<ArrayFieldIter, ReadError> {
+        let (chunk, hash) = self.locate_chunk_by_key(key);
+        return chunk.array_field_iter(hash, field_id);
+    }
     pub fn read_partial_raw(
         &self,
         key: &Id,
@@ -794,6 +1663,57 @@ impl Chunks {
         let (chunk, hash) = self.locate_chunk_by_key(key);
         return chunk.read_partial_raw(hash, offset, len);
     }
+    pub fn read_cell_raw(&self, key: &Id) -> Result<Vec<u8>, ReadError> {
+        let (chunk, hash) = self.locate_chunk_by_key(key);
+        return chunk.read_cell_raw(hash);
+    }
+    pub fn cell_ids(&self) -> Vec<Id> {
+        self.list.iter().flat_map(|chunk| chunk.cell_ids()).collect()
+    }
+    pub fn cell_ids_of_schema(&self, schema_id: u32) -> Vec<Id> {
+        self.list
+            .iter()
+            .flat_map(|chunk| chunk.cell_ids_of_schema(schema_id))
+            .collect()
+    }
+    pub fn cell_ids_in_range(&self, start: &Id, end: &Id) -> Vec<Id> {
+        self.list
+            .iter()
+            .flat_map(|chunk| chunk.cell_ids_in_range(start, end))
+            .collect()
+    }
+    // Scan cells of the schema with one worker per chunk
+    pub fn parallel_scan<F>(&self, schema_id: u32, f: F)
+    where
+        F: Fn(SharedCell) + Sync,
+    {
+        self.list
+            .par_iter()
+            .for_each(|chunk| chunk.scan_schema(schema_id, &f));
+    }
+    // Map cells of the schema in parallel and combine the results, None if there are no cells
+    pub fn map_reduce<T, M, R>(&self, schema_id: u32, map: M, reduce: R) -> Option<T>
+    where
+        T: Send,
+        M: Fn(SharedCell) -> T + Sync,
+        R: Fn(T, T) -> T + Sync,
+    {
+        self.list
+            .par_iter()
+            .filter_map(|chunk| {
+                let acc = std::cell::RefCell::new(None);
+                chunk.scan_schema(schema_id, &|cell| {
+                    let mapped = map(cell);
+                    let mut acc = acc.borrow_mut();
+                    *acc = Some(match acc.take() {
+                        Some(prev) => reduce(prev, mapped),
+                        None => mapped,
+                    });
+                });
+                acc.into_inner()
+            })
+            .reduce_with(|a, b| reduce(a, b))
+    }
     pub fn head_cell(&self, key: &Id) -> Result<CellHeader, ReadError> {
         let (chunk, hash) = self.locate_chunk_by_key(key);
         return chunk.head_cell(hash);
@@ -806,6 +1726,31 @@ impl Chunks {
         let chunk = self.locate_chunk_by_partition(cell.header.partition);
         return chunk.write_cell(cell);
     }
+    // Write the cell unless a cell with identical content was written in dedup mode under the same
+    // schema, in which case the header of the existing cell is returned
+    pub fn write_cell_dedup(&self, cell: &mut OwnedCell) -> Result<CellHeader, WriteError> {
+        let content = content_hash(cell);
+        let mut content_chunks = self.content_stripe(&content).lock();
+        if let Some(chunk_id) = content_chunks.get(&content).cloned() {
+            match self.list[chunk_id].find_by_content(&content, cell) {
+                Some(header) => {
+                    debug!("Cell {:?} deduplicated to {:?}", cell.id(), header.id());
+                    return Ok(header);
+                }
+                // Removed or updated afterwards
+                None => {
+                    content_chunks.remove(&content);
+                }
+            }
+        }
+        let chunk = self.locate_chunk_by_partition(cell.header.partition);
+        let header = chunk.write_cell_dedup(cell, content)?;
+        content_chunks.insert(content, chunk.id);
+        Ok(header)
+    }
+    fn content_stripe(&self, content: &Id) -> &Mutex<HashMap<Id, usize>> {
+        &self.content_chunks[(content.higher ^ content.lower) as usize % CONTENT_STRIPES]
+    }
     pub fn update_cell(&self, cell: &mut OwnedCell) -> Result<CellHeader, WriteError> {
         let chunk = self.locate_chunk_by_partition(cell.header.partition);
         return chunk.update_cell(cell);
@@ -821,6 +1766,57 @@ impl Chunks {
         let chunk = self.locate_chunk_by_partition(cell.header.partition);
         return chunk.upsert_cell(cell);
     }
+    // Update the cell only if its current version is the expected one
+    pub fn cas_cell(
+        &self,
+        cell: &mut OwnedCell,
+        expected_version: u64,
+    ) -> Result<CellHeader, WriteError> {
+        let current_version = std::cell::Cell::new(expected_version);
+        let res = self.update_cell_by(&cell.id(), |current| {
+            let version = current.header.version;
+            if version == expected_version {
+                Some(OwnedCell {
+                    header: current.header,
+                    data: cell.data.clone(),
+                })
+            } else {
+                current_version.set(version);
+                None
+            }
+        });
+        match res {
+            Ok(new_cell) => {
+                cell.header = new_cell.header;
+                Ok(new_cell.header)
+            }
+            Err(WriteError::UserCanceledUpdate) => {
+                Err(WriteError::CellVersionMismatch(current_version.get()))
+            }
+            Err(e) => Err(e),
+        }
+    }
+    // Remove the cell only if it was not changed since the expected version
+    pub fn remove_cell_if_version(
+        &self,
+        key: &Id,
+        expected_version: u64,
+    ) -> Result<(), WriteError> {
+        let current_version = std::cell::Cell::new(None);
+        let res = self.remove_cell_by(key, |cell| {
+            let version = cell.header.version;
+            if version != expected_version {
+                current_version.set(Some(version));
+            }
+            version == expected_version
+        });
+        match (res, current_version.get()) {
+            (Err(WriteError::CellDoesNotExisted), Some(version)) => {
+                Err(WriteError::CellVersionMismatch(version))
+            }
+            (res, _) => res,
+        }
+    }
     pub fn remove_cell(&self, key: &Id) -> Result<(), WriteError> {
         let (chunk, hash) = self.locate_chunk_by_key(key);
         return chunk.remove_cell(hash);
@@ -840,4 +1836,73 @@ impl Chunks {
     pub fn count(&self) -> usize {
         self.list.iter().map(|c| c.count()).sum()
     }
+
+    pub fn self_check(&self) -> Vec<ConsistencyReport> {
+        self.list.par_iter().map(|chunk| chunk.self_check()).collect()
+    }
+
+    pub fn fragmentation_report(&self) -> Vec<SegmentStat> {
+        self.list
+            .iter()
+            .flat_map(|chunk| chunk.fragmentation_report())
+            .collect()
+    }
+
+    // Compact the `n` segments with the lowest living rates across all chunks right away, instead
+    // of waiting for the cleaner. Returns the number of bytes reclaimed.
+    pub fn compact_worst(&self, n: usize) -> usize {
+        // Cleaners of all chunks are locked out until done
+        let _gc_guards = self
+            .list
+            .iter()
+            .map(|chunk| chunk.gc_lock.lock())
+            .collect::<Vec<_>>();
+        let mut candidates = self
+            .list
+            .iter()
+            .flat_map(|chunk| {
+                chunk
+                    .cleaning_policy()
+                    .select_segments(chunk, cleaner::FULL_PASS_LIVING_RATE_THRESHOLD)
+                    .into_iter()
+                    .map(move |seg| (chunk, seg.living_rate(), seg))
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by(|(_, rate1, _), (_, rate2, _)| rate1.partial_cmp(rate2).unwrap());
+        let mut cleaned = 0;
+        let mut touched_chunks = HashSet::new();
+        for (chunk, _, seg) in candidates.into_iter().take(n) {
+            let seg_cleaned = CompactCleaner::clean_segment(chunk, &seg);
+            chunk.total_space.fetch_sub(seg_cleaned, Ordering::Relaxed);
+            touched_chunks.insert(chunk.id);
+            cleaned += seg_cleaned;
+        }
+        // Admission works on the sealed space summed up before the compaction
+        for chunk_id in touched_chunks {
+            self.list[chunk_id].refresh_sealed_space();
+        }
+        cleaned
+    }
+
+    // Cells restored from backups of all chunks. Chunks are restored one at a time to keep only
+    // one backup open for reading.
+    pub fn restore_from_backup(&self) -> io::Result<usize> {
+        let restored = self
+            .list
+            .iter()
+            .map(|chunk| chunk.restore_from_backup())
+            .sum::<io::Result<usize>>()?;
+        for chunk in &self.list {
+            for content in chunk.indexed_contents() {
+                self.content_stripe(&content).lock().insert(content, chunk.id);
+            }
+        }
+        Ok(restored)
+    }
+
+    pub fn set_cleaning_policy(&self, policy: Arc<dyn CleaningPolicy>) {
+        for chunk in &self.list {
+            chunk.set_cleaning_policy(policy.clone());
+        }
+    }
 }
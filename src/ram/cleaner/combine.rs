@@ -51,6 +51,16 @@ impl CombinedCleaner {
             );
             return 0;
         }
+        if let Some((seg_id, e)) = segments
+            .iter()
+            .find_map(|seg| seg.scan_error().map(|e| (seg.id, e)))
+        {
+            error!(
+                "Skip combining segments of chunk {} for segment {} is corrupted, {:?}",
+                chunk.id, seg_id, e
+            );
+            return 0;
+        }
         debug!("Combining segments");
 
         let space_to_collect = segments
@@ -21,6 +21,15 @@ impl CompactCleaner {
             );
             return 0;
         }
+        // Cells after a corrupt entry cannot be located by the scan, yet they are still indexed.
+        // Relocating or removing the segment would leave them pointing to freed memory.
+        if let Some(e) = seg.scan_error() {
+            error!(
+                "Skip cleaning chunk {} segment {} for it is corrupted, {:?}",
+                chunk.id, seg.id, e
+            );
+            return 0;
+        }
 
         // Previous implementation is inplace compaction. Segments are mutable and subject to changes.
         // Log-structured cleaner suggests new segment allocation and copy living entries from the
@@ -74,7 +83,7 @@ impl CompactCleaner {
                 let entry_pos = entry.meta.entry_pos;
                 if cursor != entry_pos {
                     // Need to move
-                    let cell_migration = if entry.meta.entry_header.entry_type == EntryType::CELL {
+                    let cell_migration = if entry.meta.entry_header.entry_type.is_cell() {
                         // Is cell
                         let header = entry.content.as_cell_header();
                         trace!(
@@ -132,6 +141,12 @@ impl CompactCleaner {
             });
         seg.append_header.store(cursor, Ordering::Release);
         seg.shrink(cursor - seg_addr);
+        if seg.archived.load(Ordering::Acquire) {
+            // Entries moved, the backup no longer matches the segment
+            if let Err(e) = seg.rearchive() {
+                error!("cannot rearchive segment {} after compact, {:?}", seg.id, e);
+            }
+        }
         let space_cleaned = seg.used_spaces() as usize - live_size;
         debug!(
             "Clean finished for segment {} from chunk {}, cleaned {}",
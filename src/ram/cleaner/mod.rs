@@ -1,54 +1,102 @@
 use crate::ram::chunk::{Chunk, Chunks};
+use crate::utils::env_or;
 use rayon::prelude::*;
 use std::env;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
+use std::thread::JoinHandle;
 use std::time::Duration;
 
 pub mod combine;
 pub mod compact;
+pub mod policy;
 #[cfg(test)]
 mod tests;
 
+// Segments with living rate at or above this threshold are not worth compacting
+pub const DEFAULT_LIVING_RATE_THRESHOLD: f32 = 0.8;
+// Living rate threshold for full pass, any segment with fragments will be compacted
+pub const FULL_PASS_LIVING_RATE_THRESHOLD: f32 = 1.0;
+
+// Number of segments of a chunk compacted at the same time
+pub fn default_compact_workers() -> usize {
+    env_or("NEB_CLEANER_COMPACT_WORKERS", num_cpus::get()).max(1)
+}
+
 #[allow(dead_code)]
 pub struct Cleaner {
     chunks: Arc<Chunks>,
     stopped: Arc<AtomicBool>,
+    // Joined on drop, for the thread not to outlive the cleaner
+    handle: Option<JoinHandle<()>>,
 }
 
 // The two-level cleaner
 impl Cleaner {
-    pub fn new_and_start(chunks: Arc<Chunks>) -> Cleaner {
-        debug!("Starting cleaner for {} chunks", chunks.list.len());
+    pub fn new_and_start(chunks: Arc<Chunks>, living_rate_threshold: f32) -> Cleaner {
+        debug!(
+            "Starting cleaner for {} chunks, living rate threshold {}",
+            chunks.list.len(),
+            living_rate_threshold
+        );
+        // Also taken by cleaning from the chunks themselves
+        for chunk in &chunks.list {
+            chunk.set_cleaner_living_rate_threshold(living_rate_threshold);
+        }
         let stop_tag = Arc::new(AtomicBool::new(false));
-        let cleaner = Cleaner {
-            chunks: chunks.clone(),
-            stopped: stop_tag.clone(),
-        };
         let stop_tag_ref_clone = stop_tag.clone();
         let checks_ref_clone = chunks.clone();
         let sleep_interval_ms = env::var("NEB_CLEANER_SLEEP_INTERVAL_MS")
             .unwrap_or("100".to_string())
             .parse::<u64>()
             .unwrap();
+        // Trickle fragments below the threshold will be reclaimed by a full pass every few rounds
+        let full_pass_rounds = env_or("NEB_CLEANER_FULL_PASS_ROUNDS", 100u64).max(1);
         // Put follwing procedures in separate threads for real-time scheduling
-        thread::Builder::new()
+        let handle = thread::Builder::new()
             .name("Cleaner main".into())
             .spawn(move || {
-                while !stop_tag_ref_clone.load(Ordering::Relaxed) {
+                let mut round: u64 = 0;
+                while !stop_tag_ref_clone.load(Ordering::Acquire) {
+                    round += 1;
+                    let threshold = if round % full_pass_rounds == 0 {
+                        debug!("Cleaner full pass at round {}", round);
+                        FULL_PASS_LIVING_RATE_THRESHOLD
+                    } else {
+                        living_rate_threshold
+                    };
                     checks_ref_clone.list.par_iter().for_each(|chunk| {
-                        Self::clean(chunk, false);
+                        Self::clean_with_threshold(chunk, false, threshold);
                     });
                     thread::sleep(Duration::from_millis(sleep_interval_ms));
                 }
                 warn!("Cleaner main thread stopped");
             })
             .unwrap();
-        return cleaner;
+        return Cleaner {
+            chunks,
+            stopped: stop_tag,
+            handle: Some(handle),
+        };
+    }
+    // Stop cleaning after the current round, the thread is joined when the cleaner is dropped
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Release);
     }
     pub fn clean(chunk: &Chunk, full: bool) {
-        debug!("Ready for clean {}, full {}", chunk.id, full);
+        let threshold = if full {
+            FULL_PASS_LIVING_RATE_THRESHOLD
+        } else {
+            chunk.cleaner_living_rate_threshold()
+        };
+        Self::clean_with_threshold(chunk, full, threshold)
+    }
+    pub fn clean_with_threshold(chunk: &Chunk, full: bool, living_rate_threshold: f32) {
+        debug!(
+            "Ready for clean {}, full {}, threshold {}",
+            chunk.id, full, living_rate_threshold
+        );
         let guard = if full {
             Some(chunk.gc_lock.lock())
         } else {
@@ -65,55 +113,102 @@ impl Cleaner {
         );
         let segments_compact_per_turn = if full { num_segs } else { num_segs / 5 + 1 };
         let segments_combine_per_turn = if full { num_segs } else { num_segs / 5 + 2 };
+        // Expiring needs a scan on all cells, only worth it on full passes. Tombstones of expired
+        // cells are put before compaction, so their space can be reclaimed in the same pass.
+        if living_rate_threshold >= FULL_PASS_LIVING_RATE_THRESHOLD {
+            let expired = chunk.expire_cells();
+            if expired > 0 {
+                debug!("Expired {} cells in chunk {}", expired, chunk.id);
+            }
+        }
         // have to put it right here for cleaners will clear the tombstone death counter
         chunk.scan_tombstone_survival();
         let mut cleaned_space: usize = 0;
-        {
-            debug!("Starting compact {}", chunk.id);
-            let segments_for_compact = chunk.segs_for_compact_cleaner();
-            debug!(
-                "Selected {} segments for compaction",
-                segments_for_compact.len()
-            );
-            if !segments_for_compact.is_empty() {
-                trace!(
-                    "Chunk {} have {} segments to compact, overflow {}",
-                    chunk.id,
-                    segments_for_compact.len(),
-                    segments_compact_per_turn
-                );
-                cleaned_space += segments_for_compact
-                    .into_par_iter()
-                    .take(segments_compact_per_turn) // limit max segment to clean per turn
-                    .map(|segment| compact::CompactCleaner::clean_segment(chunk, &segment))
-                    .sum::<usize>();
-            }
-        }
-
-        {
-            debug!("Starting combine {}", chunk.id);
-            let segments_candidates_for_combine: Vec<_> = chunk.segs_for_combine_cleaner();
-            let num_segments_candidates_for_combine = segments_candidates_for_combine.len();
-            let segments_for_combine: Vec<_> = segments_candidates_for_combine
-                .into_iter()
-                .take(segments_combine_per_turn)
-                .collect();
-            if !segments_for_combine.is_empty() {
-                debug!(
-                    "Have {} segments to combine, candidates {}",
-                    segments_for_combine.len(),
-                    num_segments_candidates_for_combine
-                );
-                cleaned_space +=
-                    combine::CombinedCleaner::combine_segments(chunk, &segments_for_combine);
-            }
-        }
-
+        cleaned_space += Self::compact(chunk, living_rate_threshold, segments_compact_per_turn);
+        cleaned_space += Self::combine(chunk, segments_combine_per_turn);
         chunk
             .total_space
             .fetch_sub(cleaned_space, Ordering::Relaxed);
+        chunk.refresh_sealed_space();
         debug!("Archiving segments");
         chunk.check_and_archive_segments();
         debug!("Chunk Cleaned {}", chunk.id);
     }
+
+    fn compact(chunk: &Chunk, living_rate_threshold: f32, max_segments: usize) -> usize {
+        debug!("Starting compact {}", chunk.id);
+        // Policies may select any segment, the head and the referenced ones are still in use
+        let head_seg_id = chunk.head_seg_id.load(Ordering::Acquire);
+        let segments_for_compact: Vec<_> = chunk
+            .cleaning_policy()
+            .select_segments(chunk, living_rate_threshold)
+            .into_iter()
+            .filter(|seg| seg.id != head_seg_id && seg.no_references())
+            .collect();
+        debug!(
+            "Selected {} segments for compaction",
+            segments_for_compact.len()
+        );
+        if segments_for_compact.is_empty() {
+            return 0;
+        }
+        trace!(
+            "Chunk {} have {} segments to compact, overflow {}",
+            chunk.id,
+            segments_for_compact.len(),
+            max_segments
+        );
+        // Each worker compacts its share of segments one by one. Compaction locks only one cell
+        // at a time in its own segment, so workers never lock cells and segments in conflicting
+        // orders.
+        let workers = chunk.compact_workers.max(1);
+        let mut worker_segments: Vec<Vec<_>> = (0..workers).map(|_| vec![]).collect();
+        segments_for_compact
+            .into_iter()
+            .take(max_segments) // limit max segment to clean per turn
+            .enumerate()
+            .for_each(|(i, segment)| worker_segments[i % workers].push(segment));
+        worker_segments
+            .into_par_iter()
+            .map(|segments| {
+                segments
+                    .iter()
+                    .map(|segment| compact::CompactCleaner::clean_segment(chunk, segment))
+                    .sum::<usize>()
+            })
+            .sum::<usize>()
+    }
+
+    fn combine(chunk: &Chunk, max_segments: usize) -> usize {
+        debug!("Starting combine {}", chunk.id);
+        let policy = chunk.cleaning_policy();
+        let segments_candidates_for_combine: Vec<_> = chunk
+            .segs_for_combine_cleaner()
+            .into_iter()
+            .filter(|seg| policy.should_clean(seg))
+            .collect();
+        let num_segments_candidates_for_combine = segments_candidates_for_combine.len();
+        let segments_for_combine: Vec<_> = segments_candidates_for_combine
+            .into_iter()
+            .take(max_segments)
+            .collect();
+        if segments_for_combine.is_empty() {
+            return 0;
+        }
+        debug!(
+            "Have {} segments to combine, candidates {}",
+            segments_for_combine.len(),
+            num_segments_candidates_for_combine
+        );
+        combine::CombinedCleaner::combine_segments(chunk, &segments_for_combine)
+    }
+}
+
+impl Drop for Cleaner {
+    fn drop(&mut self) {
+        self.stop();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
 }
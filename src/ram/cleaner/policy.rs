@@ -0,0 +1,32 @@
+use crate::ram::chunk::Chunk;
+use crate::ram::segs::Segment;
+use lightning::linked_map::NodeRef as MapNodeRef;
+
+// Decides which segments of a chunk the cleaner works on. The head segment and segments still
+// referenced are never cleaned regardless of the policy.
+pub trait CleaningPolicy: Send + Sync {
+    // Whether the segment can be compacted or combined at all
+    fn should_clean(&self, segment: &Segment) -> bool;
+
+    // Segments to compact, the ones worth it the most first
+    fn select_segments(
+        &self,
+        chunk: &Chunk,
+        living_rate_threshold: f32,
+    ) -> Vec<MapNodeRef<Segment>> {
+        chunk
+            .segs_for_compact_cleaner(living_rate_threshold)
+            .into_iter()
+            .filter(|seg| self.should_clean(seg))
+            .collect()
+    }
+}
+
+// Cleans any segment below the living rate threshold
+pub struct DefaultPolicy;
+
+impl CleaningPolicy for DefaultPolicy {
+    fn should_clean(&self, _segment: &Segment) -> bool {
+        true
+    }
+}
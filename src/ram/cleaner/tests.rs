@@ -1,16 +1,20 @@
 use super::*;
 use crate::ram::cell::*;
-use crate::ram::chunk::Chunks;
+use crate::ram::chunk::{default_cell_index_capacity, AdmissionConfig, Chunks};
 use crate::ram::entry::{EntryContent, EntryType};
 use crate::ram::schema::Field;
 use crate::ram::schema::*;
+use crate::ram::segs::Segment;
 use crate::ram::types::*;
 use crate::server::ServerMeta;
 use env_logger;
+use lightning::linked_map::NodeRef as MapNodeRef;
 use lightning::map::Map;
 use std;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::thread;
 
 pub const DATA_SIZE: usize = 1000 * 1024; // nearly 1MB
 const MAX_SEGMENT_SIZE: usize = 8 * 1024 * 1024;
@@ -50,6 +54,7 @@ pub fn full_clean_cycle() {
         None,
         None,
         None,
+        default_cell_index_capacity(),
     );
     let chunk = &chunks.list[0];
 
@@ -211,3 +216,499 @@ pub fn full_clean_cycle() {
         assert_eq!(cell.to_owned().data, default_cell(&id).data);
     });
 }
+
+#[test]
+pub fn compact_living_rate_threshold() {
+    let _ = env_logger::try_init();
+    let schema = Schema::new(
+        "cleaner_threshold_test",
+        None,
+        default_fields(),
+        false,
+        false,
+    );
+    let schemas = LocalSchemasCache::new_local("");
+    schemas.new_schema(schema);
+    let chunks = Chunks::new(
+        1,                    // single chunk
+        MAX_SEGMENT_SIZE * 3, // chunk three segments
+        Arc::new(ServerMeta { schemas }),
+        None,
+        None,
+        None,
+        default_cell_index_capacity(),
+    );
+    let chunk = &chunks.list[0];
+
+    // fill up two segments and put one more cell to move the head to the third segment
+    for i in 0..17 {
+        let mut cell = default_cell(&Id::new(0, i));
+        chunks.write_cell(&mut cell).unwrap();
+    }
+    assert_eq!(chunk.seg_count(), 3);
+
+    // segment 0 is mostly alive, segment 1 is mostly dead
+    chunks.remove_cell(&Id::new(0, 0)).unwrap();
+    for i in 8..14 {
+        chunks.remove_cell(&Id::new(0, i)).unwrap();
+    }
+    let seg0_used = chunk.segs.get(&0).unwrap().used_spaces();
+    let seg1_used = chunk.segs.get(&1).unwrap().used_spaces();
+    assert!(chunk.segs.get(&0).unwrap().living_rate() >= DEFAULT_LIVING_RATE_THRESHOLD);
+    assert!(chunk.segs.get(&1).unwrap().living_rate() < DEFAULT_LIVING_RATE_THRESHOLD);
+
+    let candidates: Vec<_> = chunk
+        .segs_for_compact_cleaner(DEFAULT_LIVING_RATE_THRESHOLD)
+        .iter()
+        .map(|seg| seg.id)
+        .collect();
+    assert_eq!(candidates, vec![1]);
+
+    // only the mostly dead segment should be compacted
+    assert!(Cleaner::compact(chunk, DEFAULT_LIVING_RATE_THRESHOLD, 3) > 0);
+    assert_eq!(chunk.segs.get(&0).unwrap().used_spaces(), seg0_used);
+    assert!(chunk.segs.get(&1).unwrap().used_spaces() < seg1_used);
+
+    // full pass should reclaim the trickle fragments from the mostly alive segment
+    assert!(Cleaner::compact(chunk, FULL_PASS_LIVING_RATE_THRESHOLD, 3) > 0);
+    assert!(chunk.segs.get(&0).unwrap().used_spaces() < seg0_used);
+
+    (1..8).chain(14..17).for_each(|id| {
+        let id = Id::new(0, id);
+        let cell = chunks.read_cell(&id).unwrap();
+        assert_eq!(cell.to_owned().data, default_cell(&id).data);
+    });
+}
+
+#[test]
+pub fn partial_clean_configured_threshold() {
+    let _ = env_logger::try_init();
+    let schema = Schema::new(
+        "cleaner_configured_threshold_test",
+        None,
+        default_fields(),
+        false,
+        false,
+    );
+    let schemas = LocalSchemasCache::new_local("");
+    schemas.new_schema(schema);
+    let chunks = Chunks::new(
+        1,
+        MAX_SEGMENT_SIZE * 3,
+        Arc::new(ServerMeta { schemas }),
+        None,
+        None,
+        None,
+        default_cell_index_capacity(),
+    );
+    let chunk = &chunks.list[0];
+    for i in 0..17 {
+        let mut cell = default_cell(&Id::new(0, i));
+        chunks.write_cell(&mut cell).unwrap();
+    }
+    // Segment 0 is mostly alive, and the only one to clean
+    chunks.remove_cell(&Id::new(0, 0)).unwrap();
+    chunks.set_cleaning_policy(Arc::new(SegmentsPolicy(vec![0])));
+    let seg0_used = chunk.segs.get(&0).unwrap().used_spaces();
+    assert!(chunk.segs.get(&0).unwrap().living_rate() >= DEFAULT_LIVING_RATE_THRESHOLD);
+    Cleaner::clean(chunk, false);
+    assert_eq!(chunk.segs.get(&0).unwrap().used_spaces(), seg0_used);
+    // Partial cleaning takes the threshold of the cleaner, which may have cleaned it already
+    drop(Cleaner::new_and_start(chunks.clone(), 0.95));
+    assert_eq!(chunk.cleaner_living_rate_threshold(), 0.95);
+    Cleaner::clean(chunk, false);
+    assert!(chunk.segs.get(&0).unwrap().used_spaces() < seg0_used);
+}
+
+#[test]
+pub fn concurrent_compaction() {
+    let _ = env_logger::try_init();
+    let schema = Schema::new(
+        "cleaner_concurrent_test",
+        None,
+        default_fields(),
+        false,
+        false,
+    );
+    let schemas = LocalSchemasCache::new_local("");
+    schemas.new_schema(schema);
+    let mut chunks = Chunks::new(
+        1,
+        MAX_SEGMENT_SIZE * 16,
+        Arc::new(ServerMeta { schemas }),
+        None,
+        None,
+        None,
+        default_cell_index_capacity(),
+    );
+    Arc::get_mut(&mut chunks).unwrap().list[0].compact_workers = 4;
+    let small_cell = |id: &Id, round: u8| OwnedCell {
+        header: CellHeader::new(0, id),
+        data: data_map_value!(
+            id: id.lower as i32,
+            data: std::iter::repeat(round).take(4096).collect::<Vec<_>>()
+        ),
+    };
+    let stopped = Arc::new(AtomicBool::new(false));
+    let cleaner = {
+        let chunks = chunks.clone();
+        let stopped = stopped.clone();
+        thread::spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                Cleaner::clean(&chunks.list[0], true);
+            }
+        })
+    };
+    let writers = (0..4u64)
+        .map(|t| {
+            let chunks = chunks.clone();
+            thread::spawn(move || {
+                // Round of the cell if it should be alive
+                let mut expected = HashMap::new();
+                for i in 0..2000u64 {
+                    let id = Id::new(0, t * 10000 + i % 500);
+                    let round = (i / 500) as u8;
+                    let mut cell = small_cell(&id, round);
+                    if i % 7 == 0 {
+                        if expected.remove(&id).is_some() {
+                            chunks.remove_cell(&id).unwrap();
+                        }
+                    } else {
+                        chunks.upsert_cell(&mut cell).unwrap();
+                        expected.insert(id, round);
+                    }
+                }
+                expected
+            })
+        })
+        .collect::<Vec<_>>();
+    let expected = writers
+        .into_iter()
+        .flat_map(|w| w.join().unwrap())
+        .collect::<HashMap<_, _>>();
+    stopped.store(true, Ordering::Relaxed);
+    cleaner.join().unwrap();
+    Cleaner::clean(&chunks.list[0], true);
+    assert_eq!(chunks.count(), expected.len());
+    for (id, round) in &expected {
+        let cell = chunks.read_cell(id).unwrap();
+        assert_eq!(cell.to_owned().data, small_cell(id, *round).data);
+    }
+}
+
+struct SegmentsPolicy(Vec<u64>);
+
+impl policy::CleaningPolicy for SegmentsPolicy {
+    fn should_clean(&self, segment: &Segment) -> bool {
+        self.0.contains(&segment.id)
+    }
+}
+
+#[test]
+pub fn custom_cleaning_policy() {
+    let _ = env_logger::try_init();
+    let schema = Schema::new("cleaner_policy_test", None, default_fields(), false, false);
+    let schemas = LocalSchemasCache::new_local("");
+    schemas.new_schema(schema);
+    let chunks = Chunks::new(
+        1,
+        MAX_SEGMENT_SIZE * 3,
+        Arc::new(ServerMeta { schemas }),
+        None,
+        None,
+        None,
+        default_cell_index_capacity(),
+    );
+    let chunk = &chunks.list[0];
+    for i in 0..17 {
+        let mut cell = default_cell(&Id::new(0, i));
+        chunks.write_cell(&mut cell).unwrap();
+    }
+    assert_eq!(chunk.seg_count(), 3);
+    // Both segments are worth cleaning by default
+    for i in (0..16).step_by(2) {
+        chunks.remove_cell(&Id::new(0, i)).unwrap();
+    }
+    let mut candidates: Vec<_> = chunk
+        .segs_for_compact_cleaner(DEFAULT_LIVING_RATE_THRESHOLD)
+        .iter()
+        .map(|seg| seg.id)
+        .collect();
+    candidates.sort();
+    assert_eq!(candidates, vec![0, 1]);
+    let seg0_used = chunk.segs.get(&0).unwrap().used_spaces();
+    let seg1_used = chunk.segs.get(&1).unwrap().used_spaces();
+    let seg1_entries = chunk.segs.get(&1).unwrap().entry_iter().count();
+    chunks.set_cleaning_policy(Arc::new(SegmentsPolicy(vec![0])));
+    Cleaner::clean(chunk, true);
+    assert!(chunk.segs.get(&0).unwrap().used_spaces() < seg0_used);
+    let seg1 = chunk.segs.get(&1).unwrap();
+    assert_eq!(seg1.used_spaces(), seg1_used);
+    assert_eq!(seg1.entry_iter().count(), seg1_entries);
+    // Back to the default policy, the other segment is cleaned as well
+    chunks.set_cleaning_policy(Arc::new(policy::DefaultPolicy));
+    Cleaner::clean(chunk, true);
+    assert!(chunk
+        .segments()
+        .iter()
+        .all(|seg| seg.id != 1 || seg.used_spaces() < seg1_used));
+    (0..17).filter(|i| i % 2 == 1 || *i == 16).for_each(|id| {
+        let id = Id::new(0, id);
+        let cell = chunks.read_cell(&id).unwrap();
+        assert_eq!(cell.to_owned().data, default_cell(&id).data);
+    });
+}
+
+#[test]
+pub fn schema_ttl() {
+    let _ = env_logger::try_init();
+    let schemas = LocalSchemasCache::new_local("");
+    schemas.new_schema(Schema::new(
+        "cleaner_lasting",
+        None,
+        default_fields(),
+        false,
+        false,
+    ));
+    schemas.new_schema(
+        Schema::new_with_id(1, "cleaner_ttl", None, default_fields(), false, false).with_ttl(1),
+    );
+    let chunks = Chunks::new(
+        1,
+        MAX_SEGMENT_SIZE * 3,
+        Arc::new(ServerMeta { schemas }),
+        None,
+        None,
+        None,
+        default_cell_index_capacity(),
+    );
+    let chunk = &chunks.list[0];
+    for i in 1..9 {
+        let id = Id::new(0, i);
+        let mut cell = default_cell(&id);
+        cell.header = CellHeader::new(i as u32 % 2, &id);
+        chunks.write_cell(&mut cell).unwrap();
+    }
+    // Not expired yet
+    Cleaner::clean(chunk, true);
+    assert_eq!(chunk.cell_count(), 8);
+
+    thread::sleep(std::time::Duration::from_secs(3));
+    // Only full passes expire cells
+    Cleaner::clean(chunk, false);
+    assert_eq!(chunk.cell_count(), 8);
+    Cleaner::clean(chunk, true);
+    assert_eq!(chunk.cell_count(), 4);
+    for i in 1..9 {
+        let id = Id::new(0, i);
+        match chunks.read_cell(&id) {
+            Ok(cell) => {
+                assert_eq!(i % 2, 0);
+                assert_eq!(cell.to_owned().data, default_cell(&id).data);
+            }
+            Err(e) => {
+                assert_eq!(i % 2, 1);
+                assert!(matches!(e, ReadError::CellDoesNotExisted));
+            }
+        }
+    }
+}
+
+#[test]
+pub fn stop_cleaner() {
+    let _ = env_logger::try_init();
+    let schema = Schema::new("cleaner_stop_test", None, default_fields(), false, false);
+    let schemas = LocalSchemasCache::new_local("");
+    schemas.new_schema(schema);
+    let chunks = Chunks::new(
+        1,
+        MAX_SEGMENT_SIZE * 3,
+        Arc::new(ServerMeta { schemas }),
+        None,
+        None,
+        None,
+        default_cell_index_capacity(),
+    );
+    let chunk = &chunks.list[0];
+    for i in 0..17 {
+        let mut cell = default_cell(&Id::new(0, i));
+        chunks.write_cell(&mut cell).unwrap();
+    }
+    let cleaner = Cleaner::new_and_start(chunks.clone(), DEFAULT_LIVING_RATE_THRESHOLD);
+    cleaner.stop();
+    // Joins the cleaner thread
+    drop(cleaner);
+
+    // Fragments left after stopping are not cleaned anymore
+    for i in 8..14 {
+        chunks.remove_cell(&Id::new(0, i)).unwrap();
+    }
+    let seg1_used = chunk.segs.get(&1).unwrap().used_spaces();
+    let total_space = chunk.total_space.load(Ordering::Relaxed);
+    thread::sleep(std::time::Duration::from_millis(500));
+    assert_eq!(chunk.segs.get(&1).unwrap().used_spaces(), seg1_used);
+    assert_eq!(chunk.total_space.load(Ordering::Relaxed), total_space);
+    assert_eq!(
+        chunk
+            .segs_for_compact_cleaner(DEFAULT_LIVING_RATE_THRESHOLD)
+            .len(),
+        1
+    );
+}
+
+#[test]
+pub fn compact_worst_segments() {
+    let _ = env_logger::try_init();
+    let schema = Schema::new("cleaner_worst_test", None, default_fields(), false, false);
+    let schemas = LocalSchemasCache::new_local("");
+    schemas.new_schema(schema);
+    let chunks = Chunks::new(
+        1,
+        MAX_SEGMENT_SIZE * 3,
+        Arc::new(ServerMeta { schemas }),
+        None,
+        None,
+        None,
+        default_cell_index_capacity(),
+    );
+    let chunk = &chunks.list[0];
+    for i in 0..17 {
+        let mut cell = default_cell(&Id::new(0, i));
+        chunks.write_cell(&mut cell).unwrap();
+    }
+    // segment 0 is mostly alive, segment 1 is mostly dead
+    chunks.remove_cell(&Id::new(0, 0)).unwrap();
+    for i in 8..14 {
+        chunks.remove_cell(&Id::new(0, i)).unwrap();
+    }
+    let seg0_used = chunk.segs.get(&0).unwrap().used_spaces();
+    let seg1_used = chunk.segs.get(&1).unwrap().used_spaces();
+    // Overloaded until the space of about 3 dead cells is reclaimed
+    let head_seg_id = chunk.head_seg_id.load(Ordering::Relaxed) as usize;
+    let head_used = chunk.segs.get(&head_seg_id).unwrap().used_spaces();
+    let space_limit = (seg0_used + seg1_used - seg1_used * 3 / 8 + head_used) as f32;
+    chunk.set_admission(AdmissionConfig {
+        space_rate: space_limit / chunk.capacity as f32,
+        living_rate: 0.0,
+        max_cleaner_backlog: 0,
+    });
+    let mut new_cell = default_cell(&Id::new(0, 17));
+    assert_eq!(
+        chunks.write_cell(&mut new_cell.clone()).unwrap_err(),
+        WriteError::Overloaded
+    );
+
+    // only the worst segment is compacted
+    assert!(chunks.compact_worst(1) > 0);
+    assert_eq!(chunk.segs.get(&0).unwrap().used_spaces(), seg0_used);
+    assert!(chunk.segs.get(&1).unwrap().used_spaces() < seg1_used);
+    // The compacted segment is counted by the admission right away
+    chunks.write_cell(&mut new_cell).unwrap();
+    chunks.remove_cell(&Id::new(0, 17)).unwrap();
+
+    assert!(chunks.compact_worst(5) > 0);
+    assert!(chunk.segs.get(&0).unwrap().used_spaces() < seg0_used);
+    assert_eq!(chunks.compact_worst(5), 0);
+
+    (1..8).chain(14..17).for_each(|id| {
+        let id = Id::new(0, id);
+        let cell = chunks.read_cell(&id).unwrap();
+        assert_eq!(cell.to_owned().data, default_cell(&id).data);
+    });
+}
+
+#[test]
+pub fn skip_corrupt_segments() {
+    let _ = env_logger::try_init();
+    let schema = Schema::new("cleaner_corrupt_test", None, default_fields(), false, false);
+    let schemas = LocalSchemasCache::new_local("");
+    schemas.new_schema(schema);
+    let chunks = Chunks::new(
+        1,
+        MAX_SEGMENT_SIZE * 3,
+        Arc::new(ServerMeta { schemas }),
+        None,
+        None,
+        None,
+        default_cell_index_capacity(),
+    );
+    let chunk = &chunks.list[0];
+    for i in 0..16 {
+        let mut cell = default_cell(&Id::new(0, i));
+        chunks.write_cell(&mut cell).unwrap();
+    }
+    for i in 0..4 {
+        chunks.remove_cell(&Id::new(0, i * 2)).unwrap();
+    }
+    // Unknown entry type in the middle of the first segment
+    let corrupt_addr = chunks.address_of(&Id::new(0, 5));
+    unsafe { *(corrupt_addr as *mut u8) = 0b1000_0001 };
+    let seg0 = chunk.segs.get(&0).unwrap();
+    assert!(seg0.scan_error().is_some());
+    let seg0_used = seg0.used_spaces();
+    assert_eq!(compact::CompactCleaner::clean_segment(chunk, &seg0), 0);
+    assert_eq!(seg0.used_spaces(), seg0_used);
+    assert_eq!(
+        combine::CombinedCleaner::combine_segments(chunk, &chunk.segments()),
+        0
+    );
+    assert_eq!(chunk.seg_count(), 2);
+    // Cells behind the corrupt entry are still in place
+    (7..16).for_each(|id| {
+        let id = Id::new(0, id);
+        let cell = chunks.read_cell(&id).unwrap();
+        assert_eq!(cell.to_owned().data, default_cell(&id).data);
+    });
+}
+
+// Selects every segment for compaction, including the ones in use
+struct AllSegmentsPolicy;
+
+impl policy::CleaningPolicy for AllSegmentsPolicy {
+    fn should_clean(&self, _segment: &Segment) -> bool {
+        true
+    }
+    fn select_segments(
+        &self,
+        chunk: &Chunk,
+        _living_rate_threshold: f32,
+    ) -> Vec<MapNodeRef<Segment>> {
+        chunk.segments()
+    }
+}
+
+#[test]
+pub fn policy_skips_head_segment() {
+    let _ = env_logger::try_init();
+    let schema = Schema::new("cleaner_head_test", None, default_fields(), false, false);
+    let schemas = LocalSchemasCache::new_local("");
+    schemas.new_schema(schema);
+    let chunks = Chunks::new(
+        1,
+        MAX_SEGMENT_SIZE * 3,
+        Arc::new(ServerMeta { schemas }),
+        None,
+        None,
+        None,
+        default_cell_index_capacity(),
+    );
+    let chunk = &chunks.list[0];
+    for i in 0..17 {
+        let mut cell = default_cell(&Id::new(0, i));
+        chunks.write_cell(&mut cell).unwrap();
+    }
+    // Dead entries in the head segment
+    chunks.remove_cell(&Id::new(0, 16)).unwrap();
+    let head_seg_id = chunk.head_seg_id.load(Ordering::Acquire);
+    assert_eq!(head_seg_id, 2);
+    let head_used = chunk.segs.get(&2).unwrap().used_spaces();
+    chunks.set_cleaning_policy(Arc::new(AllSegmentsPolicy));
+    Cleaner::clean(chunk, true);
+    assert_eq!(chunk.head_seg_id.load(Ordering::Acquire), head_seg_id);
+    assert_eq!(chunk.segs.get(&2).unwrap().used_spaces(), head_used);
+    (0..16).for_each(|id| {
+        let id = Id::new(0, id);
+        let cell = chunks.read_cell(&id).unwrap();
+        assert_eq!(cell.to_owned().data, default_cell(&id).data);
+    });
+}
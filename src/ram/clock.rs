@@ -4,13 +4,14 @@ use std::thread::{sleep, spawn};
 use std::time::Duration;
 use std::time::SystemTime;
 
-// A not so accurate but fast wall clock for cell timestamp
+// A not so accurate but fast wall clock for cell timestamp, in seconds.
+// It never goes backwards when the system clock is adjusted, so later writes never get earlier timestamps.
 lazy_static! {
     static ref WALL_CLOCK: Arc<AtomicU32> = {
         let atomic = Arc::new(AtomicU32::new(actual_now()));
         let atomic_clone = atomic.clone();
-        spawn(move || {
-            atomic_clone.store(actual_now(), Ordering::Relaxed);
+        spawn(move || loop {
+            atomic_clone.fetch_max(actual_now(), Ordering::Relaxed);
             sleep(Duration::from_secs(1));
         });
         return atomic;
@@ -24,6 +25,20 @@ fn actual_now() -> u32 {
         .as_secs() as u32
 }
 
+#[cfg(test)]
+thread_local! {
+    // Seconds the clock runs ahead on the thread, for tests to pass time without waiting
+    static SKEW: std::cell::Cell<u32> = std::cell::Cell::new(0);
+}
+
 pub fn now() -> u32 {
-    WALL_CLOCK.load(Ordering::Relaxed)
+    let now = WALL_CLOCK.load(Ordering::Relaxed);
+    #[cfg(test)]
+    let now = now + SKEW.with(|skew| skew.get());
+    now
+}
+
+#[cfg(test)]
+pub fn skew(secs: u32) {
+    SKEW.with(|skew| skew.set(skew.get() + secs));
 }
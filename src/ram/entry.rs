@@ -8,6 +8,14 @@ bitflags! {
         const UNDECIDED =   0b0000_0000;
         const CELL =        0b0001_0000;
         const TOMBSTONE =   0b0010_0000;
+        // Cell with its header layout byte ahead of the header
+        const VERSIONED_CELL = 0b0100_0000;
+    }
+}
+
+impl EntryType {
+    pub fn is_cell(&self) -> bool {
+        *self == EntryType::CELL || *self == EntryType::VERSIONED_CELL
     }
 }
 
@@ -0,0 +1,51 @@
+use crate::utils::env_or;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+
+// Names of dynamic fields interned in a chunk. Cells written with interning on refer to the
+// names by their ids in the dictionary instead of carrying them. Ids are never reused, the
+// dictionary only grows for the lifetime of the chunk.
+#[derive(Default)]
+pub struct NameDict {
+    inner: RwLock<NameDictInner>,
+}
+
+#[derive(Default)]
+struct NameDictInner {
+    names: Vec<String>,
+    ids: HashMap<String, u32>,
+}
+
+impl NameDict {
+    pub fn intern(&self, name: &str) -> u32 {
+        if let Some(id) = self.inner.read().ids.get(name) {
+            return *id;
+        }
+        let mut inner = self.inner.write();
+        if let Some(id) = inner.ids.get(name) {
+            return *id;
+        }
+        let id = inner.names.len() as u32;
+        inner.names.push(name.to_string());
+        inner.ids.insert(name.to_string(), id);
+        id
+    }
+
+    pub fn name(&self, id: u32) -> Option<String> {
+        self.inner.read().names.get(id as usize).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.read().names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Whether new chunks write dynamic field names to their dictionaries. Dictionaries are not
+// persisted, chunks with backup or log storage never intern.
+pub fn intern_by_default() -> bool {
+    env_or("NEB_INTERN_DYNAMIC_NAMES", false)
+}
@@ -1,13 +1,31 @@
-use itertools::Itertools;
-
+use crate::ram::cell::ReadError;
 use crate::ram::schema::{Field, Schema};
 use crate::ram::types;
 use crate::ram::types::{bool_io, u32_io, SharedMap, SharedValue, Type};
 
-use super::writer::{ARRAY_TYPE_MASK, NULL_PLACEHOLDER};
+use super::names::NameDict;
+use super::writer::{ARRAY_TYPE_MASK, INTERNED_MAP_PLACEHOLDER, NA_PLACEHOLDER, NULL_PLACEHOLDER};
 use dovahkiin::types::key_hash;
 use std::collections::HashMap;
 
+// Shared values borrow the chunk, values of enum fields borrow their names from the schema
+// instead. Schemas are never changed in place, replacing one drops the old names, so values read
+// are always returned along with the schema they are read by.
+fn shared_enum_value(value: &str) -> &'static str {
+    unsafe { &*(value as *const str) }
+}
+
+fn read_enum(base_ptr: usize, values: &[String], field_offset: &mut usize) -> SharedValue {
+    let field_ptr = base_ptr + *field_offset;
+    *field_offset += types::u16_io::type_size();
+    let ordinal = *types::get_shared_val(Type::U16, field_ptr).u16().unwrap();
+    match values.get(ordinal as usize) {
+        Some(value) => SharedValue::String(shared_enum_value(value)),
+        // Written with values the schema does not declare anymore
+        None => SharedValue::NA,
+    }
+}
+
 fn read_field(
     base_ptr: usize,
     field: &Field,
@@ -21,6 +39,9 @@ fn read_field(
     } else if !field.is_var() {
         // Is not inside var and field not var, read from the offset in field
         &mut rec_field_offset
+    } else if field.sub_fields.is_some() && !field.is_array {
+        // Maps have no pointers, sub fields go on with the tail offset like on write
+        tail_offset
     } else {
         // Is not inside var and field is var, read the pointer and direct to it
         *tail_offset = *u32_io::read(base_ptr + field.offset.unwrap()) as usize;
@@ -40,7 +61,7 @@ fn read_field(
         let mut sub_field = field.clone();
         sub_field.is_array = false;
         *field_offset += u32_io::type_size();
-        if field.sub_fields.is_none() {
+        if field.sub_fields.is_none() && field.enum_values.is_none() {
             // maybe primitive array
             let mut ptr = base_ptr + *field_offset;
             let val = types::get_shared_prim_array_val(field.data_type, len as usize, &mut ptr);
@@ -56,7 +77,21 @@ fn read_field(
         } else {
             let mut vals = Vec::<SharedValue>::new();
             for _ in 0..len {
-                let nxt_val = read_field(base_ptr, &sub_field, true, field_offset);
+                let nxt_val = match field.enum_values {
+                    // Names are borrowed from the field of the schema, not the cloned one
+                    Some(ref values) => {
+                        let is_null = field.nullable && *bool_io::read(base_ptr + *field_offset);
+                        if field.nullable {
+                            *field_offset += 1;
+                        }
+                        if is_null {
+                            SharedValue::Null
+                        } else {
+                            read_enum(base_ptr, values, field_offset)
+                        }
+                    }
+                    None => read_field(base_ptr, &sub_field, true, field_offset),
+                };
                 vals.push(nxt_val);
             }
             SharedValue::Array(vals)
@@ -64,11 +99,16 @@ fn read_field(
     } else if let Some(ref subs) = field.sub_fields {
         trace!("Field {} is map", field.name);
         let mut map = SharedMap::new();
-        for sub in subs {
-            map.insert_key_id(sub.name_id, read_field(base_ptr, &sub, is_var, field_offset));
+        let subs = subs.iter().filter(|sub| !sub.appended);
+        for sub in subs.clone() {
+            let value = read_field(base_ptr, &sub, is_var, field_offset);
+            map.insert_key_id(sub.name_id, value);
         }
-        map.fields = subs.iter().map(|sub| &sub.name).cloned().collect();
+        map.fields = subs.map(|sub| &sub.name).cloned().collect();
         SharedValue::Map(map)
+    } else if let Some(ref values) = field.enum_values {
+        trace!("Field {} is enum", field.name);
+        read_enum(base_ptr, values, field_offset)
     } else {
         let field_ptr = base_ptr + *field_offset;
         *field_offset += types::get_size(field.data_type, field_ptr);
@@ -78,8 +118,13 @@ fn read_field(
     }
 }
 
-pub fn read_attach_dynamic_part(mut tail_ptr: usize, dest: &mut SharedValue) {
-    let src = read_dynamic_value(&mut tail_ptr);
+// Names of dynamic fields written with interning are resolved from the dictionary
+pub fn read_attach_dynamic_part(
+    mut tail_ptr: usize,
+    dest: &mut SharedValue,
+    dict: &NameDict,
+) -> Result<(), ReadError> {
+    let src = read_dynamic_value(&mut tail_ptr, dict)?;
     if let &mut SharedValue::Map(ref mut map_dest) = dest {
         if let SharedValue::Map(mut map_src) = src {
             map_dest.fields.append(&mut map_src.fields);
@@ -88,11 +133,12 @@ pub fn read_attach_dynamic_part(mut tail_ptr: usize, dest: &mut SharedValue) {
             }
         }
     }
+    Ok(())
 }
 
 const MAP_TYPE_ID: u8 = Type::Map.id();
 
-fn read_dynamic_value(ptr: &mut usize) -> SharedValue {
+fn read_dynamic_value(ptr: &mut usize, dict: &NameDict) -> Result<SharedValue, ReadError> {
     let type_id = types::get_shared_val(Type::U8, *ptr).u8().unwrap();
     let is_array = type_id & ARRAY_TYPE_MASK == ARRAY_TYPE_MASK;
     *ptr += types::u8_io::type_size();
@@ -106,14 +152,16 @@ fn read_dynamic_value(ptr: &mut usize) -> SharedValue {
                 types::get_shared_prim_array_val(Type::from_id(base_type), *len as usize, ptr)
             {
                 // ptr have been moved by `get_shared_prim_array_val`
-                return SharedValue::PrimArray(prim_arr);
+                return Ok(SharedValue::PrimArray(prim_arr));
             } else {
                 panic!("Cannot read prim array for dynamic field");
             }
         } else {
-            let array = (0..*len).map(|_| read_dynamic_value(ptr)).collect();
+            let array = (0..*len)
+                .map(|_| read_dynamic_value(ptr, dict))
+                .collect::<Result<_, _>>()?;
             // ptr have been moved by recursion
-            return SharedValue::Array(array);
+            return Ok(SharedValue::Array(array));
         }
     } else if *type_id == MAP_TYPE_ID {
         // Map
@@ -123,69 +171,130 @@ fn read_dynamic_value(ptr: &mut usize) -> SharedValue {
             .map(|_| {
                 let name = types::get_shared_val(Type::String, *ptr).string().unwrap();
                 *ptr += types::string_io::size_at(*ptr);
-                let value = read_dynamic_value(ptr);
-                (name, value)
+                let value = read_dynamic_value(ptr, dict)?;
+                Ok((name.to_owned(), value))
             })
-            .collect_vec();
-        let mut fields = Vec::with_capacity(field_value_pair.len());
-        let mut map = HashMap::with_capacity(field_value_pair.len());
-        for (name, value) in field_value_pair {
-            let id = key_hash(&name);
-            fields.push(name.to_owned());
-            map.insert(id, value);
-        }
-        return SharedValue::Map(SharedMap { fields, map });
+            .collect::<Result<Vec<_>, ReadError>>()?;
+        return Ok(dynamic_map(field_value_pair));
+    } else if *type_id == INTERNED_MAP_PLACEHOLDER {
+        let len = types::get_shared_val(Type::U32, *ptr).u32().unwrap();
+        *ptr += types::u32_io::type_size();
+        let field_value_pair = (0..*len)
+            .map(|_| {
+                let name_id = *types::get_shared_val(Type::U32, *ptr).u32().unwrap();
+                *ptr += types::u32_io::type_size();
+                // The dictionary is not carried along with the cell, like in a copy of its entry
+                let name = dict
+                    .name(name_id)
+                    .ok_or(ReadError::UnknownDynamicFieldName(name_id))?;
+                let value = read_dynamic_value(ptr, dict)?;
+                Ok((name, value))
+            })
+            .collect::<Result<Vec<_>, ReadError>>()?;
+        return Ok(dynamic_map(field_value_pair));
     } else if *type_id == NULL_PLACEHOLDER {
-        return SharedValue::Null;
+        return Ok(SharedValue::Null);
+    } else if *type_id == NA_PLACEHOLDER {
+        return Ok(SharedValue::NA);
     } else {
         let ty = Type::from_id(*type_id);
         let value = types::get_shared_val(ty, *ptr);
         *ptr += types::get_size(ty, *ptr);
-        return value;
+        return Ok(value);
     }
 }
 
-pub fn read_by_schema(ptr: usize, schema: &Schema) -> SharedValue {
+fn dynamic_map(field_value_pair: Vec<(String, SharedValue)>) -> SharedValue {
+    let mut fields = Vec::with_capacity(field_value_pair.len());
+    let mut map = HashMap::with_capacity(field_value_pair.len());
+    for (name, value) in field_value_pair {
+        map.insert(key_hash(&name), value);
+        fields.push(name);
+    }
+    SharedValue::Map(SharedMap { fields, map })
+}
+
+// Fields added to the schema are after all the other fields. Cells written before end ahead of
+// them, with the tail offset at the end of the data.
+fn read_appended_fields(
+    base_ptr: usize,
+    data_len: usize,
+    field: &Field,
+    tail_offset: &mut usize,
+    dest: &mut SharedValue,
+) {
+    if let (SharedValue::Map(map), Some(subs)) = (dest, &field.sub_fields) {
+        for sub in subs.iter().filter(|sub| sub.appended) {
+            let value = if *tail_offset < data_len {
+                read_field(base_ptr, sub, true, tail_offset)
+            } else {
+                SharedValue::Null
+            };
+            map.insert_key_id(sub.name_id, value);
+            map.fields.push(sub.name.clone());
+        }
+    }
+}
+
+pub fn read_by_schema(
+    ptr: usize,
+    data_len: usize,
+    schema: &Schema,
+    dict: &NameDict,
+) -> Result<SharedValue, ReadError> {
     let mut tail_offset = schema.static_bound;
     let mut schema_value = read_field(ptr, &schema.fields, false, &mut tail_offset);
+    read_appended_fields(
+        ptr,
+        data_len,
+        &schema.fields,
+        &mut tail_offset,
+        &mut schema_value,
+    );
     if schema.is_dynamic {
-        read_attach_dynamic_part(ptr + tail_offset, &mut schema_value)
+        read_attach_dynamic_part(ptr + tail_offset, &mut schema_value, dict)?;
     }
-    schema_value
+    Ok(schema_value)
 }
 
-pub fn read_by_schema_selected(ptr: usize, schema: &Schema, fields: &[u64]) -> SharedValue {
+pub fn read_by_schema_selected(
+    ptr: usize,
+    data_len: usize,
+    schema: &Schema,
+    fields: &[u64],
+    dict: &NameDict,
+) -> Result<SharedValue, ReadError> {
     let mut tail_offset = schema.static_bound;
     if fields.is_empty() {
-        return read_by_schema(ptr, schema);
+        return read_by_schema(ptr, data_len, schema, dict);
     }
-    if let Some(schema_fields) = &schema.fields.sub_fields {
+    if schema.fields.sub_fields.is_some() {
         let mut res = vec![];
-        'SEARCH:
-        for field in fields {
-            if let Some(index_path) = schema.field_index.get(field) {
-                if !index_path.is_empty() { 
-                    if let Some(mut field) = schema_fields.get(index_path[0]) {
-                        for i in index_path.iter().skip(1) {
-                            if let Some(Some(sub_field)) = field.sub_fields.as_ref().map(|sub| sub.get(*i)) {
-                                field = sub_field;
-                            } else {
-                                break;
-                            }
-                        }
-                        let field_data = read_field(ptr, field, false, &mut tail_offset);
-                        if fields.len() == 1 {
-                            return field_data;
-                        } else {
-                            res.push(field_data);
-                            continue 'SEARCH;
-                        }
+        for field_id in fields {
+            let field = match schema.field_by_id(*field_id) {
+                Some(field) => field,
+                None => {
+                    res.push(SharedValue::Null);
+                    continue;
+                }
+            };
+            let field_data = if field.appended {
+                // Located after all the other fields
+                match read_by_schema(ptr, data_len, schema, dict)? {
+                    SharedValue::Map(mut map) => {
+                        map.map.remove(&field.name_id).unwrap_or(SharedValue::Null)
                     }
+                    _ => SharedValue::Null,
                 }
+            } else {
+                read_field(ptr, field, false, &mut tail_offset)
+            };
+            if fields.len() == 1 {
+                return Ok(field_data);
             }
-            res.push(SharedValue::Null);
+            res.push(field_data);
         }
-        return SharedValue::Array(res)
+        return Ok(SharedValue::Array(res))
     }
-    SharedValue::Null
+    Ok(SharedValue::Null)
 }
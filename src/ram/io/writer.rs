@@ -1,3 +1,4 @@
+use super::names::NameDict;
 use crate::ram::cell::*;
 use crate::ram::schema::Field;
 use crate::ram::types;
@@ -42,7 +43,7 @@ pub fn plan_write_field<'a>(
     let offset = if let Some(ref subs) = field.sub_fields {
         if let OwnedValue::Array(_) = value {
             if !field.is_array {
-                return Err(WriteError::DataMismatchSchema(field.clone(), value.clone()));
+                return Err(WriteError::ExpectedScalar(field.clone()));
             }
             if !is_var {
                 trace!(
@@ -68,13 +69,19 @@ pub fn plan_write_field<'a>(
                 field.name,
                 tail_offset
             );
-            for sub in subs {
-                let val = map.get_by_key_id(sub.name_id);
+            for sub in subs.iter().filter(|sub| !sub.appended) {
+                let mut val = map.get_by_key_id(sub.name_id);
+                if let &OwnedValue::Null = val {
+                    // Renamed fields can also be written with their current names
+                    val = map.get_by_key_id(key_hash(&sub.name));
+                }
                 plan_write_field(tail_offset, &sub, val, &mut ins, is_var)?;
             }
             return Ok(());
+        } else if field.is_array {
+            return Err(WriteError::ExpectedArray(field.clone()));
         } else {
-            return Err(WriteError::DataMismatchSchema(field.clone(), value.clone()));
+            return Err(type_mismatch(field, Type::Map, value));
         }
     } else if is_field_var {
         // Write position tag for variable sized field
@@ -138,7 +145,7 @@ pub fn plan_write_field<'a>(
             for val in array {
                 plan_write_field(offset, &sub_field, val, &mut ins, true)?;
             }
-        } else if let OwnedValue::PrimArray(ref array) = value {
+        } else if let (OwnedValue::PrimArray(ref array), None) = (value, &field.enum_values) {
             let len = array.len();
             let size = array.size();
             // for prim array, just clone it and push into the instruction list with length
@@ -160,8 +167,11 @@ pub fn plan_write_field<'a>(
                 offset: *offset,
             });
             *offset += size;
+        } else if let OwnedValue::PrimArray(_) = value {
+            // Enum arrays are written from arrays of strings
+            return Err(type_mismatch(field, Type::String, value));
         } else {
-            return Err(WriteError::DataMismatchSchema(field.clone(), value.clone()));
+            return Err(WriteError::ExpectedArray(field.clone()));
         }
     } else {
         let is_null = match value {
@@ -169,9 +179,24 @@ pub fn plan_write_field<'a>(
             _ => false,
         };
         if !field.nullable && is_null {
-            return Err(WriteError::DataMismatchSchema(field.clone(), value.clone()));
+            return Err(WriteError::UnexpectedNull(field.clone()));
         }
-        if !is_null {
+        if !is_null && field.enum_values.is_some() {
+            let ordinal = match value {
+                OwnedValue::String(value) => field
+                    .enum_ordinal(value)
+                    .ok_or_else(|| WriteError::UnknownEnumValue(field.clone(), value.clone()))?,
+                _ => return Err(type_mismatch(field, Type::String, value)),
+            };
+            trace!("Pushing enum ordinal inst with {} at {}", ordinal, *offset);
+            ins.push(Instruction {
+                data_type: Type::U16,
+                val: InstData::Val(OwnedValue::U16(ordinal)),
+                offset: *offset,
+            });
+            *offset += types::u16_io::type_size();
+        } else if !is_null {
+            check_scalar(field, value)?;
             let size = types::get_vsize(field.data_type, &value);
             ins.push(Instruction {
                 data_type: field.data_type,
@@ -192,14 +217,75 @@ pub fn plan_write_field<'a>(
     return Ok(());
 }
 
+fn type_mismatch(field: &Field, expected: Type, value: &OwnedValue) -> WriteError {
+    WriteError::TypeMismatch {
+        field: field.clone(),
+        expected,
+        got: value.clone(),
+    }
+}
+
+fn check_scalar(field: &Field, value: &OwnedValue) -> Result<(), WriteError> {
+    let value_type = match value {
+        OwnedValue::Array(_) | OwnedValue::PrimArray(_) => {
+            return Err(WriteError::ExpectedScalar(field.clone()))
+        }
+        OwnedValue::Map(_) => Type::Map,
+        OwnedValue::Bool(_) => Type::Bool,
+        OwnedValue::Char(_) => Type::Char,
+        OwnedValue::I8(_) => Type::I8,
+        OwnedValue::I16(_) => Type::I16,
+        OwnedValue::I32(_) => Type::I32,
+        OwnedValue::I64(_) => Type::I64,
+        OwnedValue::U8(_) => Type::U8,
+        OwnedValue::U16(_) => Type::U16,
+        OwnedValue::U32(_) => Type::U32,
+        OwnedValue::U64(_) => Type::U64,
+        OwnedValue::F32(_) => Type::F32,
+        OwnedValue::F64(_) => Type::F64,
+        OwnedValue::String(_) => Type::String,
+        OwnedValue::Id(_) => Type::Id,
+        OwnedValue::Bytes(_) => Type::Bytes,
+        OwnedValue::SmallBytes(_) => Type::SmallBytes,
+        // Other types are written as they are
+        _ => return Ok(()),
+    };
+    if value_type == field.data_type {
+        Ok(())
+    } else {
+        Err(type_mismatch(field, field.data_type, value))
+    }
+}
+
+// Fields added to the schema go after all the other fields, for cells written before to end ahead
+// of them
+pub fn plan_write_appended_fields<'a>(
+    tail_offset: &mut usize,
+    field: &Field,
+    value: &'a OwnedValue,
+    ins: &mut Vec<Instruction<'a>>,
+) -> Result<(), WriteError> {
+    if let (OwnedValue::Map(map), Some(subs)) = (value, &field.sub_fields) {
+        for sub in subs.iter().filter(|sub| sub.appended) {
+            plan_write_field(tail_offset, sub, map.get_by_key_id(sub.name_id), ins, true)?;
+        }
+    }
+    Ok(())
+}
+
+// Names of dynamic fields are written to the dictionary and referred by ids, if there is one
 pub fn plan_write_dynamic_fields<'a>(
     offset: &mut usize,
     field: &Field,
     value: &'a OwnedValue,
     ins: &mut Vec<Instruction<'a>>,
+    dict: Option<&NameDict>,
 ) -> Result<(), WriteError> {
     if let (OwnedValue::Map(data_all), &Some(ref fields)) = (value, &field.sub_fields) {
-        let schema_keys: HashSet<u64> = fields.iter().map(|f| f.name_id).collect();
+        let schema_keys: HashSet<u64> = fields
+            .iter()
+            .flat_map(|f| vec![f.name_id, key_hash(&f.name)])
+            .collect();
         let dynamic_map: HashMap<_, _> = data_all
             .map
             .iter()
@@ -215,23 +301,30 @@ pub fn plan_write_dynamic_fields<'a>(
             })
             .collect();
         if !dynamic_map.is_empty() {}
-        plan_write_dynamic_map(offset, &dynamic_names, &dynamic_map, ins)?;
+        plan_write_dynamic_map(offset, &dynamic_names, &dynamic_map, ins, dict)?;
     }
     return Ok(());
 }
 
 pub const ARRAY_TYPE_MASK: u8 = !(!0 << 1 >> 1); // 1000000...
-pub const NULL_PLACEHOLDER: u8 = ARRAY_TYPE_MASK >> 1; // 1000000...
+pub const NULL_PLACEHOLDER: u8 = ARRAY_TYPE_MASK >> 1; // 0100000...
+pub const NA_PLACEHOLDER: u8 = NULL_PLACEHOLDER | 1; // 0100001...
+pub const INTERNED_MAP_PLACEHOLDER: u8 = NULL_PLACEHOLDER | 2; // 0100010...
 
 pub fn plan_write_dynamic_map<'a>(
     offset: &mut usize,
     names: &Vec<&String>,
     map: &HashMap<&u64, &'a OwnedValue>,
     ins: &mut Vec<Instruction<'a>>,
+    dict: Option<&NameDict>,
 ) -> Result<(), WriteError> {
+    let type_code = match dict {
+        Some(_) => INTERNED_MAP_PLACEHOLDER,
+        None => Type::Map.id(),
+    };
     ins.push(Instruction {
         data_type: types::TYPE_CODE_TYPE,
-        val: InstData::Val(OwnedValue::U8(Type::Map.id())),
+        val: InstData::Val(OwnedValue::U8(type_code)),
         offset: *offset,
     });
     *offset += types::u8_io::type_size();
@@ -244,15 +337,27 @@ pub fn plan_write_dynamic_map<'a>(
     *offset += types::u32_io::type_size();
     for name in names {
         let id = key_hash(name);
-        let name_value = OwnedValue::String((*name).to_owned());
-        let name_size = types::get_vsize(name_value.base_type(), &name_value);
-        ins.push(Instruction {
-            data_type: name_value.base_type(),
-            val: InstData::Val(name_value),
-            offset: *offset,
-        });
-        *offset += name_size;
-        plan_write_dynamic_value(offset, map.get(&id).unwrap(), ins)?;
+        match dict {
+            Some(dict) => {
+                ins.push(Instruction {
+                    data_type: Type::U32,
+                    val: InstData::Val(OwnedValue::U32(dict.intern(name))),
+                    offset: *offset,
+                });
+                *offset += types::u32_io::type_size();
+            }
+            None => {
+                let name_value = OwnedValue::String((*name).to_owned());
+                let name_size = types::get_vsize(name_value.base_type(), &name_value);
+                ins.push(Instruction {
+                    data_type: name_value.base_type(),
+                    val: InstData::Val(name_value),
+                    offset: *offset,
+                });
+                *offset += name_size;
+            }
+        }
+        plan_write_dynamic_value(offset, map.get(&id).unwrap(), ins, dict)?;
     }
     Ok(())
 }
@@ -261,6 +366,7 @@ pub fn plan_write_dynamic_value<'a>(
     offset: &mut usize,
     value: &'a OwnedValue,
     ins: &mut Vec<Instruction<'a>>,
+    dict: Option<&NameDict>,
 ) -> Result<(), WriteError> {
     let base_type = value.base_type();
     match &value {
@@ -281,7 +387,7 @@ pub fn plan_write_dynamic_value<'a>(
             });
             *offset += types::u32_io::type_size();
             for val in array {
-                plan_write_dynamic_value(offset, val, ins)?;
+                plan_write_dynamic_value(offset, val, ins, dict)?;
             }
         }
         &OwnedValue::PrimArray(array) => {
@@ -312,9 +418,11 @@ pub fn plan_write_dynamic_value<'a>(
             &map.fields.iter().collect(),
             &map.map.iter().collect(),
             ins,
+            dict,
         )?,
-        &OwnedValue::Null | OwnedValue::NA => {
-            // Write a placeholder because mapping required
+        // Null is a value explicitly set to nothing, NA is absent or unknown. Write distinct
+        // placeholders to keep them apart on read, for mapping required
+        &OwnedValue::Null => {
             ins.push(Instruction {
                 data_type: types::TYPE_CODE_TYPE,
                 val: InstData::Val(OwnedValue::U8(NULL_PLACEHOLDER)),
@@ -322,6 +430,14 @@ pub fn plan_write_dynamic_value<'a>(
             });
             *offset += types::u8_io::type_size();
         }
+        &OwnedValue::NA => {
+            ins.push(Instruction {
+                data_type: types::TYPE_CODE_TYPE,
+                val: InstData::Val(OwnedValue::U8(NA_PLACEHOLDER)),
+                offset: *offset,
+            });
+            *offset += types::u8_io::type_size();
+        }
         _ => {
             // Primitives
             let ty = value.base_type();
@@ -1,6 +1,7 @@
 #[macro_use]
 pub mod mem_cursor;
 
+pub mod aggregate;
 pub mod cell;
 pub mod chunk;
 pub mod cleaner;
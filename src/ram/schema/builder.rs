@@ -0,0 +1,115 @@
+// Fluent construction of the field tree of schemas, with data types named by the methods instead
+// of passed by hand. Offsets are assigned when the schema is created from the fields.
+use super::{Field, IndexType, Schema};
+use dovahkiin::types::Type;
+
+#[derive(Debug, Clone, Default)]
+pub struct SchemaBuilder {
+    fields: Vec<Field>,
+}
+
+macro_rules! typed_fields {
+    ($($method:ident => $t:ident),*) => {
+        $(
+            pub fn $method(self, name: &str, nullable: bool) -> Self {
+                self.field(name, Type::$t, nullable)
+            }
+        )*
+    };
+}
+
+impl SchemaBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    typed_fields!(
+        i8_field => I8,
+        i16_field => I16,
+        i32_field => I32,
+        i64_field => I64,
+        u8_field => U8,
+        u16_field => U16,
+        u32_field => U32,
+        u64_field => U64,
+        f32_field => F32,
+        f64_field => F64,
+        bool_field => Bool,
+        char_field => Char,
+        string_field => String,
+        bytes_field => Bytes,
+        small_bytes_field => SmallBytes,
+        id_field => Id
+    );
+
+    pub fn field(mut self, name: &str, data_type: Type, nullable: bool) -> Self {
+        self.fields
+            .push(Field::new(name, data_type, nullable, false, None, vec![]));
+        self
+    }
+
+    pub fn array_of(mut self, name: &str, data_type: Type, nullable: bool) -> Self {
+        self.fields
+            .push(Field::new(name, data_type, nullable, true, None, vec![]));
+        self
+    }
+
+    // Map field with the fields added by `build_sub`
+    pub fn nested<F>(mut self, name: &str, nullable: bool, build_sub: F) -> Self
+    where
+        F: FnOnce(SchemaBuilder) -> SchemaBuilder,
+    {
+        let subs = build_sub(SchemaBuilder::new()).fields;
+        self.fields.push(Field::new(
+            name,
+            Type::Map,
+            nullable,
+            false,
+            Some(subs),
+            vec![],
+        ));
+        self
+    }
+
+    // Array of maps with the fields added by `build_sub`
+    pub fn nested_array<F>(mut self, name: &str, nullable: bool, build_sub: F) -> Self
+    where
+        F: FnOnce(SchemaBuilder) -> SchemaBuilder,
+    {
+        let subs = build_sub(SchemaBuilder::new()).fields;
+        self.fields.push(Field::new(
+            name,
+            Type::Map,
+            nullable,
+            true,
+            Some(subs),
+            vec![],
+        ));
+        self
+    }
+
+    // Index the field added last
+    pub fn indexed(mut self, index: IndexType) -> Self {
+        self.fields
+            .last_mut()
+            .expect("No field to index")
+            .indices
+            .push(index);
+        self
+    }
+
+    // Root field of the schema, a map of all fields added
+    pub fn fields(self) -> Field {
+        Field::new("*", Type::Map, false, false, Some(self.fields), vec![])
+    }
+
+    pub fn build(
+        self,
+        name: &str,
+        key_field: Option<Vec<String>>,
+        is_dynamic: bool,
+        is_scannable: bool,
+    ) -> Schema {
+        Schema::new(name, key_field, self.fields(), is_dynamic, is_scannable)
+    }
+}
@@ -0,0 +1,148 @@
+// Schemas are kept by the schema state machine and sent over RPC in bincode, which carries
+// neither field names nor defaults, so adding fields to schemas breaks decoding the ones encoded
+// before. Schemas are encoded after a marker and the version of their layout. Schemas encoded
+// before versioning start with their ids instead, and are decoded by the original layout.
+use super::{Field, IndexType, Schema};
+use dovahkiin::types::Type;
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeTuple, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+
+// Never taken by schema ids, rejected by `Schema::check`
+pub const SCHEMA_ENCODING_MARKER: u32 = u32::MAX;
+// Bump on changing the fields of `Schema` or `Field`, and keep decoding the former versions
+const SCHEMA_ENCODING_VERSION: u8 = 1;
+// Elements of the longest layout, the marker and the version included
+const MAX_SCHEMA_ELEMENTS: usize = 16;
+
+// Fields as encoded before versioning
+#[derive(Deserialize)]
+struct FieldV0 {
+    data_type: Type,
+    nullable: bool,
+    is_array: bool,
+    sub_fields: Option<Vec<FieldV0>>,
+    name: String,
+    name_id: u64,
+    indices: Vec<IndexType>,
+    offset: Option<usize>,
+}
+
+impl From<FieldV0> for Field {
+    fn from(field: FieldV0) -> Self {
+        Field {
+            data_type: field.data_type,
+            nullable: field.nullable,
+            is_array: field.is_array,
+            sub_fields: field
+                .sub_fields
+                .map(|subs| subs.into_iter().map(Field::from).collect()),
+            name: field.name,
+            name_id: field.name_id,
+            indices: field.indices,
+            offset: field.offset,
+            derived_from: None,
+            ttl_secs: None,
+            enum_values: None,
+            appended: false,
+        }
+    }
+}
+
+impl Serialize for Schema {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(MAX_SCHEMA_ELEMENTS)?;
+        tuple.serialize_element(&SCHEMA_ENCODING_MARKER)?;
+        tuple.serialize_element(&SCHEMA_ENCODING_VERSION)?;
+        tuple.serialize_element(&self.id)?;
+        tuple.serialize_element(&self.name)?;
+        tuple.serialize_element(&self.key_field)?;
+        tuple.serialize_element(&self.str_key_field)?;
+        tuple.serialize_element(&self.field_index)?;
+        tuple.serialize_element(&self.id_index)?;
+        tuple.serialize_element(&self.index_fields)?;
+        tuple.serialize_element(&self.fields)?;
+        tuple.serialize_element(&self.static_bound)?;
+        tuple.serialize_element(&self.is_dynamic)?;
+        tuple.serialize_element(&self.is_scannable)?;
+        tuple.serialize_element(&self.aliases)?;
+        tuple.serialize_element(&self.ttl_secs)?;
+        tuple.serialize_element(&self.composite_indices)?;
+        tuple.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Schema {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_tuple(MAX_SCHEMA_ELEMENTS, SchemaVisitor)
+    }
+}
+
+struct SchemaVisitor;
+
+fn next<'de, A, T>(seq: &mut A, index: &mut usize) -> Result<T, A::Error>
+where
+    A: SeqAccess<'de>,
+    T: Deserialize<'de>,
+{
+    let element = seq
+        .next_element()?
+        .ok_or_else(|| de::Error::invalid_length(*index, &"an encoded schema"))?;
+    *index += 1;
+    Ok(element)
+}
+
+impl<'de> Visitor<'de> for SchemaVisitor {
+    type Value = Schema;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an encoded schema")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Schema, A::Error> {
+        let mut index = 0;
+        let first: u32 = next(&mut seq, &mut index)?;
+        if first != SCHEMA_ENCODING_MARKER {
+            return Ok(Schema {
+                id: first,
+                name: next(&mut seq, &mut index)?,
+                key_field: next(&mut seq, &mut index)?,
+                str_key_field: next(&mut seq, &mut index)?,
+                field_index: next(&mut seq, &mut index)?,
+                id_index: next(&mut seq, &mut index)?,
+                index_fields: next(&mut seq, &mut index)?,
+                fields: next::<_, FieldV0>(&mut seq, &mut index)?.into(),
+                static_bound: next(&mut seq, &mut index)?,
+                is_dynamic: next(&mut seq, &mut index)?,
+                is_scannable: next(&mut seq, &mut index)?,
+                aliases: HashMap::new(),
+                ttl_secs: None,
+                composite_indices: vec![],
+            });
+        }
+        let version: u8 = next(&mut seq, &mut index)?;
+        if version != SCHEMA_ENCODING_VERSION {
+            return Err(de::Error::custom(format!(
+                "unknown schema encoding version {}",
+                version
+            )));
+        }
+        Ok(Schema {
+            id: next(&mut seq, &mut index)?,
+            name: next(&mut seq, &mut index)?,
+            key_field: next(&mut seq, &mut index)?,
+            str_key_field: next(&mut seq, &mut index)?,
+            field_index: next(&mut seq, &mut index)?,
+            id_index: next(&mut seq, &mut index)?,
+            index_fields: next(&mut seq, &mut index)?,
+            fields: next(&mut seq, &mut index)?,
+            static_bound: next(&mut seq, &mut index)?,
+            is_dynamic: next(&mut seq, &mut index)?,
+            is_scannable: next(&mut seq, &mut index)?,
+            aliases: next(&mut seq, &mut index)?,
+            ttl_secs: next(&mut seq, &mut index)?,
+            composite_indices: next(&mut seq, &mut index)?,
+        })
+    }
+}
@@ -4,10 +4,12 @@ use bifrost_hasher::hash_str;
 
 use dovahkiin::types::Type;
 use lightning::map::{HashMap as LFHashMap, Map, ObjectMap};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::mem;
 use std::sync::atomic::AtomicU32;
 
+use super::cell::{HeaderLayout, MAX_CELL_SIZE};
+use super::entry::Entry;
 use super::types;
 use core::borrow::Borrow;
 use std::string::String;
@@ -17,9 +19,12 @@ use futures::prelude::*;
 use futures::FutureExt;
 use std::ops::Deref;
 
+pub mod builder;
+pub mod encoding;
 pub mod sm;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// Encoded with its layout version, see `encoding`
+#[derive(Debug, Clone)]
 pub struct Schema {
     pub id: u32,
     pub name: String,
@@ -32,6 +37,39 @@ pub struct Schema {
     pub static_bound: usize,
     pub is_dynamic: bool,
     pub is_scannable: bool,
+    // Hash of former and current names of renamed fields -> field id used in the cell data
+    pub aliases: HashMap<u64, u64>,
+    // Cells expire this long after their last write, removed by the cleaner
+    pub ttl_secs: Option<u32>,
+    // Ranged indices over multiple top level fields, by field ids in the declared order
+    pub composite_indices: Vec<Vec<u64>>,
+}
+
+// Cells larger than this are rejected on write anyway
+pub const DEFAULT_MAX_CELL_SIZE: usize = MAX_CELL_SIZE as usize;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum SchemaError {
+    CellTooLarge { estimated: usize, max: usize },
+    FieldDoesNotExisted(String),
+    FieldAlreadyExisted(String),
+    SchemaDoesNotExisted(u32),
+    SchemaNameDoesNotExisted(String),
+    // Field of the path has a shape cells cannot be encoded in
+    InvalidField { path: String, reason: String },
+    // Nothing is imported when any of the schemas conflicts
+    ImportConflicts(Vec<SchemaConflict>),
+    // Id marks versioned schema encodings
+    ReservedId(u32),
+}
+
+// Imported schema clashing with a registered one, or another one in the same import
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum SchemaConflict {
+    // Id is taken by the schema of the name
+    IdTaken { id: u32, name: String },
+    // Name is taken by the schema of the id
+    NameTaken { name: String, id: u32 },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
@@ -40,6 +78,9 @@ pub enum IndexType {
     Hashed,
     Vectorized,
     Statistics,
+    // Ranged with inverted features, forward scans yield values from the largest.
+    // Kept last for schemas encoded before it to decode with the same index types.
+    RangedDesc,
 }
 
 impl Schema {
@@ -79,8 +120,49 @@ impl Schema {
             field_index,
             id_index,
             index_fields,
+            aliases: HashMap::new(),
+            ttl_secs: None,
+            composite_indices: vec![],
         }
     }
+    pub fn with_ttl(mut self, ttl_secs: u32) -> Schema {
+        self.ttl_secs = Some(ttl_secs);
+        self
+    }
+    // Ranged index on top level fields together, ordered by the first field, then the next, and so
+    // on. Array fields cannot be in composite indices.
+    pub fn with_composite_index(mut self, fields: &[&str]) -> Result<Schema, SchemaError> {
+        let invalid = |path: &str, reason: &str| {
+            Err(SchemaError::InvalidField {
+                path: path.to_string(),
+                reason: reason.to_string(),
+            })
+        };
+        if fields.len() < 2 {
+            return invalid(
+                &fields.join(","),
+                "composite index needs more than one field",
+            );
+        }
+        let mut field_ids = Vec::with_capacity(fields.len());
+        for &name in fields {
+            let field = self
+                .fields
+                .sub_fields
+                .iter()
+                .flatten()
+                .find(|f| f.name == name);
+            match field {
+                Some(field) if field.is_array => {
+                    return invalid(name, "array fields cannot be in composite indices")
+                }
+                Some(_) => field_ids.push(hash_str(name)),
+                None => return Err(SchemaError::FieldDoesNotExisted(name.to_string())),
+            }
+        }
+        self.composite_indices.push(field_ids);
+        Ok(self)
+    }
     pub fn new_with_id(
         id: u32,
         name: &str,
@@ -93,6 +175,241 @@ impl Schema {
         schema.id = id;
         schema
     }
+    // Same as `new`, but rejects invalid fields and schemas whose smallest possible cell is over
+    // `max_cell_size`
+    pub fn new_checked(
+        name: &str,
+        key_field: Option<Vec<String>>,
+        fields: Field,
+        is_dynamic: bool,
+        is_scannable: bool,
+        max_cell_size: usize,
+    ) -> Result<Schema, SchemaError> {
+        fields.validate()?;
+        let schema = Schema::new(name, key_field, fields, is_dynamic, is_scannable);
+        schema.check_cell_size(max_cell_size)?;
+        Ok(schema)
+    }
+    // Lower bound of the cell size, the static part plus empty values for all variable fields, in
+    // an entry with the largest header. Replicas check schemas by it in the state machine, so it
+    // does not depend on the layout the local chunks write cells in.
+    pub fn min_cell_size(&self) -> usize {
+        self.min_cell_size_in(HeaderLayout::LARGEST)
+    }
+    pub fn min_cell_size_in(&self, layout: HeaderLayout) -> usize {
+        const LEN_SIZE: usize = mem::size_of::<u32>();
+        let var_size: usize = self
+            .leaf_fields()
+            .iter()
+            .filter(|f| f.is_array || !types::fixed_size(f.data_type))
+            .map(|f| if f.nullable { 1 } else { LEN_SIZE })
+            .sum();
+        // Same as the size checked on writes
+        let body_size = (layout.header_size() + self.static_bound + var_size) as u32;
+        Entry::size(Entry::count_len_bytes(body_size), body_size) as usize
+    }
+    // Checks for schemas registered to the cluster, against the hard limit of cell sizes
+    pub fn check(&self) -> Result<(), SchemaError> {
+        if self.id == encoding::SCHEMA_ENCODING_MARKER {
+            return Err(SchemaError::ReservedId(self.id));
+        }
+        self.fields.validate()?;
+        self.check_cell_size(DEFAULT_MAX_CELL_SIZE)
+    }
+    pub fn check_cell_size(&self, max_cell_size: usize) -> Result<(), SchemaError> {
+        let estimated = self.min_cell_size();
+        if estimated > max_cell_size {
+            error!(
+                "Schema {} cannot fit in cells, estimated size {}, max {}",
+                self.name, estimated, max_cell_size
+            );
+            return Err(SchemaError::CellTooLarge {
+                estimated,
+                max: max_cell_size,
+            });
+        }
+        Ok(())
+    }
+    pub fn derived_fields(&self) -> Vec<&Field> {
+        self.fields
+            .sub_fields
+            .iter()
+            .flatten()
+            .filter(|f| f.derived_from.is_some())
+            .collect()
+    }
+    pub fn ttl_fields(&self) -> Vec<&Field> {
+        self.fields
+            .sub_fields
+            .iter()
+            .flatten()
+            .filter(|f| f.ttl_secs.is_some())
+            .collect()
+    }
+    // Rename a top level field. The field keeps its id so existing cells are still readable, and
+    // both names resolve to it through `field_id`.
+    pub fn rename_field(&mut self, old_name: &str, new_name: &str) -> Result<(), SchemaError> {
+        let subs = self.fields.sub_fields.as_mut();
+        if subs
+            .as_ref()
+            .map(|subs| subs.iter().any(|f| f.name == new_name))
+            .unwrap_or(false)
+        {
+            return Err(SchemaError::FieldAlreadyExisted(new_name.to_string()));
+        }
+        let field = match subs.and_then(|subs| subs.iter_mut().find(|f| f.name == old_name)) {
+            Some(field) => field,
+            None => return Err(SchemaError::FieldDoesNotExisted(old_name.to_string())),
+        };
+        field.name = new_name.to_string();
+        let field_id = field.name_id;
+        let old_hash = hash_str(old_name);
+        let new_hash = hash_str(new_name);
+        self.aliases.insert(old_hash, field_id);
+        self.aliases.insert(new_hash, field_id);
+        // Field paths are looked up by name, make them available under both names
+        if let Some(path) = self.field_index.get(&old_hash).cloned() {
+            self.field_index.insert(new_hash, path);
+        }
+        if let Some(path) = self.id_index.get(&old_hash).cloned() {
+            self.id_index.insert(new_hash, path);
+        }
+        if let Some(keys) = self.str_key_field.as_mut() {
+            keys.iter_mut()
+                .filter(|k| k.as_str() == old_name)
+                .for_each(|k| *k = new_name.to_string());
+        }
+        Ok(())
+    }
+    // Id of the field in the cell data, resolving former names of renamed fields
+    pub fn field_id(&self, name: &str) -> u64 {
+        let hash = hash_str(name);
+        self.aliases.get(&hash).cloned().unwrap_or(hash)
+    }
+    // Index the field by its name path hash, as keys of `index_fields`. Returns false if the field
+    // already has the index. Cells written before need to be backfilled to show up in the index.
+    pub fn add_index(&mut self, field_id: u64, index: IndexType) -> Result<bool, SchemaError> {
+        let path = match self.field_index.get(&field_id) {
+            Some(path) if self.id_index.contains_key(&field_id) => path.clone(),
+            _ => return Err(SchemaError::FieldDoesNotExisted(field_id.to_string())),
+        };
+        let indices = self.index_fields.entry(field_id).or_insert_with(Vec::new);
+        if indices.contains(&index) {
+            return Ok(false);
+        }
+        indices.push(index);
+        // Keep the field tree in sync, for schemas rebuilt from it
+        let mut field = &mut self.fields;
+        for i in path {
+            field = &mut field.sub_fields.as_mut().unwrap()[i];
+        }
+        field.indices.push(index);
+        Ok(true)
+    }
+    // Append a nullable top level field without moving the existing ones. Cells written before
+    // end ahead of the field and read it as null.
+    pub fn add_field(&mut self, mut field: Field) -> Result<(), SchemaError> {
+        let invalid = |reason: &str| {
+            Err(SchemaError::InvalidField {
+                path: field.name.clone(),
+                reason: reason.to_string(),
+            })
+        };
+        // Dynamic fields are right after the variable sized part, where the field would be
+        if self.is_dynamic {
+            return invalid("fields cannot be added to dynamic schemas");
+        }
+        if !field.nullable {
+            return invalid("added fields must be nullable");
+        }
+        // Cells written before have no stamps for the field
+        if field.ttl_secs.is_some() {
+            return invalid("added fields cannot have TTL");
+        }
+        Field::validate_subs(std::slice::from_ref(&field), "")?;
+        if field.sub_fields.is_some() {
+            return invalid("added fields cannot be maps");
+        }
+        let subs = self.fields.sub_fields.get_or_insert_with(Vec::new);
+        if subs.iter().any(|f| f.name_id == field.name_id)
+            || self.aliases.contains_key(&field.name_id)
+        {
+            return Err(SchemaError::FieldAlreadyExisted(field.name));
+        }
+        // Only for the field paths, the static part and its bound stay the same
+        let mut offset = self.static_bound;
+        let name_path = field.name.clone();
+        let name_id = field.name_id;
+        field.assign_offsets(
+            &mut offset,
+            &mut self.field_index,
+            &mut self.id_index,
+            &mut self.index_fields,
+            name_path,
+            vec![subs.len()],
+            vec![name_id],
+        );
+        field.appended = true;
+        subs.push(field);
+        Ok(())
+    }
+    // Id of the nested field at the path of names from the top level, like ["sub", "sub1"]
+    pub fn field_id_for_path(&self, path: &[&str]) -> Option<u64> {
+        let field_id = self.field_id(&path.join("|"));
+        if self.field_index.contains_key(&field_id) {
+            Some(field_id)
+        } else {
+            None
+        }
+    }
+    // Names of the fields from the top level to the field, with current names of renamed fields
+    pub fn path_for_field_id(&self, field_id: u64) -> Option<Vec<String>> {
+        let mut field = &self.fields;
+        let mut path = vec![];
+        for i in self.field_index.get(&field_id)? {
+            field = field.sub_fields.as_ref()?.get(*i)?;
+            path.push(field.name.clone());
+        }
+        Some(path)
+    }
+    pub fn field_by_id(&self, field_id: u64) -> Option<&Field> {
+        let mut field = &self.fields;
+        for i in self.field_index.get(&field_id)? {
+            field = field.sub_fields.as_ref()?.get(*i)?;
+        }
+        Some(field)
+    }
+    // Leaf fields in layout order, with offsets and types. Taken from the field paths indexed by
+    // `assign_offsets`, which sort in the layout order. Arrays are leaves, their members have no
+    // paths for they have no static offsets.
+    pub fn leaf_fields(&self) -> Vec<LeafField> {
+        let mut paths = self
+            .field_index
+            .iter()
+            .map(|(id, path)| (path, *id))
+            .collect::<Vec<_>>();
+        // Renamed fields are indexed under both names
+        paths.sort();
+        paths.dedup_by(|(a, _), (b, _)| a == b);
+        paths
+            .into_iter()
+            .filter_map(|(_, id)| {
+                let field = self.field_by_id(id)?;
+                if field.sub_fields.is_some() && !field.is_array {
+                    return None;
+                }
+                let name_path = self.path_for_field_id(id)?.join("|");
+                Some(LeafField {
+                    name_id: hash_str(&name_path),
+                    offset: field.offset.unwrap_or(0),
+                    data_type: field.data_type,
+                    is_array: field.is_array,
+                    nullable: field.nullable,
+                    name_path,
+                })
+            })
+            .collect()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
@@ -105,6 +422,21 @@ pub struct Field {
     pub name_id: u64,
     pub indices: Vec<IndexType>,
     pub offset: Option<usize>,
+    pub derived_from: Option<DerivedFrom>,
+    // Seconds after the cell is written for the field to read as null. Only top level fields.
+    pub ttl_secs: Option<u32>,
+    // Values of an enum field, stored as u16 ordinals in this order and read back as strings
+    pub enum_values: Option<Vec<String>>,
+    // Top level field added to an existing schema, laid out after the variable sized part of cells
+    pub appended: bool,
+}
+
+// Materialized field, value is looked up from another cell by the id in `id_field` and
+// inlined from its `source_field` on write. Only top level fields are supported.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DerivedFrom {
+    pub id_field: String,
+    pub source_field: String,
 }
 
 impl Field {
@@ -125,7 +457,64 @@ impl Field {
             sub_fields,
             indices,
             offset: None,
+            derived_from: None,
+            ttl_secs: None,
+            enum_values: None,
+            appended: false,
+        }
+    }
+    pub fn derive_from(mut self, id_field: &str, source_field: &str) -> Field {
+        self.derived_from = Some(DerivedFrom {
+            id_field: id_field.to_string(),
+            source_field: source_field.to_string(),
+        });
+        self
+    }
+    pub fn with_ttl(mut self, ttl_secs: u32) -> Field {
+        self.ttl_secs = Some(ttl_secs);
+        self
+    }
+    pub fn with_enum_values(mut self, values: &[&str]) -> Field {
+        assert!(values.len() <= u16::MAX as usize + 1);
+        self.data_type = Type::U16;
+        self.enum_values = Some(values.iter().map(|v| v.to_string()).collect());
+        self
+    }
+    pub fn enum_ordinal(&self, value: &str) -> Option<u16> {
+        self.enum_values
+            .as_ref()?
+            .iter()
+            .position(|v| v == value)
+            .map(|ordinal| ordinal as u16)
+    }
+    // Check the shape of the field tree, the root may have no sub fields for dynamic schemas
+    pub fn validate(&self) -> Result<(), SchemaError> {
+        match &self.sub_fields {
+            Some(subs) => Self::validate_subs(subs, ""),
+            None => Ok(()),
+        }
+    }
+    fn validate_subs(subs: &[Field], name_path: &str) -> Result<(), SchemaError> {
+        let mut name_ids = HashSet::new();
+        for sub in subs {
+            let path = sub_name_path(name_path, &sub.name);
+            let invalid = |reason: &str| {
+                Err(SchemaError::InvalidField {
+                    path: path.clone(),
+                    reason: reason.to_string(),
+                })
+            };
+            if !name_ids.insert(sub.name_id) {
+                return invalid("duplicated name");
+            }
+            match (&sub.sub_fields, sub.data_type) {
+                (Some(subs), Type::Map) if !subs.is_empty() => Self::validate_subs(subs, &path)?,
+                (_, Type::Map) => return invalid("map without sub fields"),
+                (Some(_), _) => return invalid("sub fields on a field not a map"),
+                (None, _) => {}
+            }
         }
+        Ok(())
     }
     fn assign_offsets(
         &mut self,
@@ -148,17 +537,12 @@ impl Field {
             // u32 as indication of the offset to the actual data
             *offset += POINTER_SIZE;
         } else if let Some(ref mut subs) = self.sub_fields {
-            let format_name = if name_path.is_empty() {
-                name_path
-            } else {
-                format!("{}|", name_path)
-            };
             subs.iter_mut().enumerate().for_each(|(i, f)| {
                 let mut new_path = field_path.clone();
                 let mut new_id = id_path.clone();
                 new_path.push(i);
                 new_id.push(f.name_id);
-                let new_name_path = format!("{}{}", format_name, f.name);
+                let new_name_path = sub_name_path(&name_path, &f.name);
                 f.assign_offsets(
                     offset,
                     field_index,
@@ -199,6 +583,54 @@ impl Field {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeafField {
+    pub name_id: u64,
+    pub name_path: String,
+    pub offset: usize,
+    pub data_type: Type,
+    pub is_array: bool,
+    pub nullable: bool,
+}
+
+// Conditions for listing schemas, all present conditions have to match
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct SchemaFilter {
+    pub name_prefix: Option<String>,
+    pub is_dynamic: Option<bool>,
+    pub is_scannable: Option<bool>,
+    // Has a field of the type at any level, the root map excluded
+    pub field_type: Option<Type>,
+}
+
+impl SchemaFilter {
+    pub fn matches(&self, schema: &Schema) -> bool {
+        self.name_prefix
+            .as_ref()
+            .map_or(true, |prefix| schema.name.starts_with(prefix))
+            && self.is_dynamic.map_or(true, |d| schema.is_dynamic == d)
+            && self.is_scannable.map_or(true, |s| schema.is_scannable == s)
+            && self
+                .field_type
+                .map_or(true, |t| has_sub_field_type(&schema.fields, t))
+    }
+}
+
+fn has_sub_field_type(field: &Field, data_type: Type) -> bool {
+    field.sub_fields.as_ref().map_or(false, |subs| {
+        subs.iter()
+            .any(|sub| sub.data_type == data_type || has_sub_field_type(sub, data_type))
+    })
+}
+
+fn sub_name_path(name_path: &str, sub_name: &str) -> String {
+    if name_path.is_empty() {
+        sub_name.to_string()
+    } else {
+        format!("{}|{}", name_path, sub_name)
+    }
+}
+
 pub struct SchemasMap {
     schema_map: ObjectMap<SchemaRef>,
     name_map: LFHashMap<String, usize>,
@@ -261,6 +693,9 @@ impl LocalSchemasCache {
         let m = &self.map;
         m.name_to_id(name)
     }
+    pub fn get_all(&self) -> Vec<Schema> {
+        self.map.get_all()
+    }
 }
 
 impl SchemasMap {
@@ -313,6 +748,62 @@ impl SchemasMap {
             .map(|(_, s_ref)| (**s_ref).clone())
             .collect()
     }
+    fn find(&self, filter: &SchemaFilter) -> Vec<Schema> {
+        self.schema_map
+            .entries()
+            .iter()
+            .filter(|(_, s_ref)| filter.matches(s_ref))
+            .map(|(_, s_ref)| (**s_ref).clone())
+            .collect()
+    }
+    // Register all the schemas or none of them. Schemas registered with the same id and name are
+    // skipped, returns the newly registered ones. Schemas are checked like new ones.
+    fn import(&self, schemas: Vec<Schema>) -> Result<Vec<Schema>, SchemaError> {
+        for schema in &schemas {
+            schema.check()?;
+        }
+        let mut conflicts = vec![];
+        let mut new_ids = HashMap::new();
+        let mut new_names = HashMap::new();
+        let mut new_schemas = vec![];
+        for schema in schemas {
+            let name_of_id = self
+                .get(&schema.id)
+                .map(|s| s.name.clone())
+                .or_else(|| new_ids.get(&schema.id).cloned());
+            let id_of_name = self
+                .name_to_id(&schema.name)
+                .or_else(|| new_names.get(&schema.name).cloned());
+            match (name_of_id, id_of_name) {
+                (None, None) => {
+                    new_ids.insert(schema.id, schema.name.clone());
+                    new_names.insert(schema.name.clone(), schema.id);
+                    new_schemas.push(schema);
+                }
+                (name, id) => {
+                    if let Some(name) = name.filter(|name| name != &schema.name) {
+                        conflicts.push(SchemaConflict::IdTaken {
+                            id: schema.id,
+                            name,
+                        });
+                    }
+                    if let Some(id) = id.filter(|id| *id != schema.id) {
+                        conflicts.push(SchemaConflict::NameTaken {
+                            name: schema.name.clone(),
+                            id,
+                        });
+                    }
+                }
+            }
+        }
+        if !conflicts.is_empty() {
+            return Err(SchemaError::ImportConflicts(conflicts));
+        }
+        for schema in &new_schemas {
+            self.new_schema(schema.clone());
+        }
+        Ok(new_schemas)
+    }
     fn load_from_list(&mut self, data: Vec<Schema>) {
         for schema in data {
             let id = schema.id as usize;
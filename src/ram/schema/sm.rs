@@ -22,8 +22,12 @@ pub struct SchemasSM {
 raft_state_machine! {
     def qry get_all() -> Vec<Schema>;
     def qry get(id: u32) -> Option<Schema>;
-    def cmd new_schema(schema: Schema) -> Result<(), NotifyError>;
+    def qry find(filter: SchemaFilter) -> Vec<Schema>;
+    def cmd new_schema(schema: Schema) -> Result<(), SchemaError>;
     def cmd del_schema(name: String) -> Result<(), NotifyError>;
+    def cmd add_index(schema_id: u32, field_id: u64, index: IndexType) -> Result<Schema, SchemaError>;
+    def cmd add_field(name: String, field: Field) -> Result<Schema, SchemaError>;
+    def cmd import_schemas(schemas: Vec<Schema>) -> Result<Vec<u32>, SchemaError>;
     def cmd next_id() -> u32;
     def sub on_schema_added() -> Schema;
     def sub on_schema_deleted() -> String;
@@ -40,12 +44,22 @@ impl StateMachineCmds for SchemasSM {
         }))
         .boxed()
     }
-    fn new_schema(&mut self, schema: Schema) -> BoxFuture<Result<(), NotifyError>> {
-        self.map.new_schema(schema.clone());
+    fn find(&self, filter: SchemaFilter) -> BoxFuture<Vec<Schema>> {
+        future::ready(self.map.find(&filter)).boxed()
+    }
+    fn new_schema(&mut self, schema: Schema) -> BoxFuture<Result<(), SchemaError>> {
         async move {
-            self.callback
+            // Clients check as well, commands may come from others
+            schema.check()?;
+            self.map.new_schema(schema.clone());
+            let schema_id = schema.id;
+            if let Err(e) = self
+                .callback
                 .notify(commands::on_schema_added::new(), schema)
-                .await?;
+                .await
+            {
+                warn!("Cannot notify schema {} added, {:?}", schema_id, e);
+            }
             Ok(())
         }
         .boxed()
@@ -60,6 +74,70 @@ impl StateMachineCmds for SchemasSM {
         }
         .boxed()
     }
+    fn add_index(
+        &mut self,
+        schema_id: u32,
+        field_id: u64,
+        index: IndexType,
+    ) -> BoxFuture<Result<Schema, SchemaError>> {
+        async move {
+            let mut schema = match self.map.get(&schema_id) {
+                Some(schema) => (*schema).clone(),
+                None => return Err(SchemaError::SchemaDoesNotExisted(schema_id)),
+            };
+            if schema.add_index(field_id, index)? {
+                // Replaces the schema in the caches of servers and clients
+                self.map.new_schema(schema.clone());
+                if let Err(e) = self
+                    .callback
+                    .notify(commands::on_schema_added::new(), schema.clone())
+                    .await
+                {
+                    warn!("Cannot notify index added to schema {}, {:?}", schema_id, e);
+                }
+            }
+            Ok(schema)
+        }
+        .boxed()
+    }
+    fn add_field(&mut self, name: String, field: Field) -> BoxFuture<Result<Schema, SchemaError>> {
+        async move {
+            let mut schema = match self.map.get_by_name(&name) {
+                Some(schema) => (*schema).clone(),
+                None => return Err(SchemaError::SchemaNameDoesNotExisted(name)),
+            };
+            schema.add_field(field)?;
+            // Replaces the schema in the caches of servers and clients
+            self.map.new_schema(schema.clone());
+            if let Err(e) = self
+                .callback
+                .notify(commands::on_schema_added::new(), schema.clone())
+                .await
+            {
+                warn!("Cannot notify field added to schema {}, {:?}", name, e);
+            }
+            Ok(schema)
+        }
+        .boxed()
+    }
+    fn import_schemas(&mut self, schemas: Vec<Schema>) -> BoxFuture<Result<Vec<u32>, SchemaError>> {
+        async move {
+            let imported = self.map.import(schemas)?;
+            let ids = imported.iter().map(|schema| schema.id).collect();
+            for schema in imported {
+                let schema_id = schema.id;
+                if let Err(e) = self
+                    .callback
+                    .notify(commands::on_schema_added::new(), schema)
+                    .await
+                {
+                    warn!("Cannot notify imported schema {}, {:?}", schema_id, e);
+                }
+            }
+            Ok(ids)
+        }
+        .boxed()
+    }
     fn next_id(&mut self) -> BoxFuture<u32> {
         future::ready(self.map.next_id()).boxed()
     }
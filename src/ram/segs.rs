@@ -1,16 +1,18 @@
 use crate::ram::chunk::Chunk;
 use crate::ram::entry;
-use crate::ram::entry::EntryMeta;
+use crate::ram::entry::{EntryMeta, EntryType};
 use crate::ram::tombstone::TOMBSTONE_SIZE_U32;
 use libc::*;
 use lightning::list::WordList;
 use parking_lot;
-use std::fs::{copy, create_dir_all, remove_file, File};
+use std::fs::{copy, create_dir_all, remove_file, File, OpenOptions};
 use std::io;
 use std::io::prelude::*;
-use std::io::BufWriter;
+use std::io::{BufReader, BufWriter};
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::ptr;
+use std::slice;
 use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU32, AtomicUsize, Ordering, Ordering::*};
 
 pub const SEGMENT_SIZE_U32: u32 = 8 * 1024 * 1024;
@@ -81,9 +83,16 @@ impl Segment {
 
     pub fn try_acquire(&self, size: u32) -> Option<usize> {
         let size = size as usize;
+        if size > self.bound - self.addr {
+            return None;
+        }
         loop {
             let curr_last = self.append_header.load(Ordering::Acquire);
-            let exp_last = curr_last + size;
+            // Corrupted sizes must not wrap the header around and pass the bound check
+            let exp_last = match curr_last.checked_add(size) {
+                Some(exp_last) => exp_last,
+                None => return None,
+            };
             if exp_last > self.bound {
                 return None;
             } else {
@@ -116,11 +125,20 @@ impl Segment {
 
     pub fn entry_iter(&self) -> SegmentEntryIter {
         SegmentEntryIter {
+            base: self.addr,
             bound: self.append_header(),
             cursor: self.addr,
+            error: None,
         }
     }
 
+    // Error of a full scan over the entries, cleaners must not relocate entries of corrupt segments
+    pub fn scan_error(&self) -> Option<ScanError> {
+        let mut entries = self.entry_iter();
+        entries.by_ref().for_each(|_| {});
+        entries.error()
+    }
+
     pub fn dead_space(&self) -> u32 {
         self.dead_space.load(Ordering::Relaxed)
     }
@@ -178,6 +196,9 @@ impl Segment {
                 );
                 return Ok(false);
             }
+            if let Some(dir) = backup_file_path.parent() {
+                create_dir_all(dir)?;
+            }
             if let Some(ref wal_file) = self.wal_file_name {
                 // if there is a WAL file ready, copy this file to backup
                 if let Some(ref file_mutex) = self.wal_file {
@@ -186,27 +207,44 @@ impl Segment {
                     let _ = file_mutex.lock();
                     copy(wal_file, backup_file)?;
                     remove_file(wal_file)?;
+                    return Ok(true);
                 } else {
                     panic!()
                 }
             } else {
-                let backup_file = File::create(backup_file_path)?;
-                let seg_size = self.append_header.load(Ordering::Relaxed) - self.addr;
-                let mut buffer = BufWriter::with_capacity(seg_size, backup_file);
-                unsafe {
-                    for offset in 0..seg_size {
-                        let ptr = (self.addr + offset) as *const u8;
-                        let byte = *ptr;
-                        buffer.write(&[byte])?;
-                    }
-                }
-                buffer.flush()?;
+                self.dump(backup_file_path)?;
                 return Ok(true);
             }
         }
         return Ok(false);
     }
 
+    // Overwrite the backup with the current content of the segment, for the backup is stale after
+    // the cleaner moved entries of an archived segment
+    pub fn rearchive(&self) -> Result<bool, io::Error> {
+        if let &Some(ref backup_file) = &self.backup_file_name {
+            while !self.no_references() { /* wait until all references released */ }
+            let backup_file_path = Path::new(backup_file);
+            if backup_file_path.exists() {
+                remove_file(backup_file_path)?;
+            } else if let Some(dir) = backup_file_path.parent() {
+                create_dir_all(dir)?;
+            }
+            self.dump(backup_file_path)?;
+            return Ok(true);
+        }
+        return Ok(false);
+    }
+
+    fn dump(&self, path: &Path) -> Result<(), io::Error> {
+        let mut backup_file = File::create(path)?;
+        let seg_size = self.append_header.load(Ordering::Relaxed) - self.addr;
+        // Written in one go, the segment is already contiguous in memory
+        let data = unsafe { slice::from_raw_parts(self.addr as *const u8, seg_size) };
+        backup_file.write_all(data)?;
+        backup_file.flush()
+    }
+
     pub fn write_wal(&self, addr: usize, size: u32) -> io::Result<()> {
         if let Some(ref wal_file) = self.wal_file {
             let mut file = wal_file.lock();
@@ -252,9 +290,55 @@ impl Segment {
     }
 }
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ScanError {
+    // Entry at the offset from the segment address has a malformed header or runs past the bound
+    CorruptEntry { offset: usize },
+}
+
+// Iteration halts at the first corrupt entry, the entries after it cannot be located anymore
 pub struct SegmentEntryIter {
+    base: usize,
     bound: usize,
     cursor: usize,
+    error: Option<ScanError>,
+}
+
+impl SegmentEntryIter {
+    // Entries of segment content outside of segments, like a backup read back from the disk
+    pub fn over_buffer(buffer: &[u8]) -> SegmentEntryIter {
+        let base = buffer.as_ptr() as usize;
+        SegmentEntryIter {
+            base,
+            bound: base + buffer.len(),
+            cursor: base,
+            error: None,
+        }
+    }
+
+    pub fn error(&self) -> Option<ScanError> {
+        self.error
+    }
+
+    // Flag byte needs a known entry type, and its length bytes must fit in a u32 and the bound
+    fn header_valid(&self) -> bool {
+        let flag_byte = unsafe { *(self.cursor as *const u8) };
+        let len_bytes = (flag_byte & 0b0000_1111) as usize;
+        EntryType::from_bits(flag_byte & 0b1111_0000).is_some()
+            && len_bytes <= 4
+            && self.cursor + 1 + len_bytes <= self.bound
+    }
+
+    fn halt(&mut self) -> Option<EntryMeta> {
+        let offset = self.cursor - self.base;
+        error!(
+            "Corrupt entry at offset {} of segment at {}",
+            offset, self.base
+        );
+        self.error = Some(ScanError::CorruptEntry { offset });
+        self.cursor = self.bound;
+        None
+    }
 }
 
 impl Iterator for SegmentEntryIter {
@@ -265,6 +349,9 @@ impl Iterator for SegmentEntryIter {
         if cursor >= self.bound {
             return None;
         }
+        if !self.header_valid() {
+            return self.halt();
+        }
         let (_, entry_meta) = entry::Entry::decode_from(cursor, |body_pos, header| {
             let entry_header_size = body_pos - cursor;
             let entry_size = entry_header_size + header.content_length as usize;
@@ -277,37 +364,156 @@ impl Iterator for SegmentEntryIter {
                 entry_pos: cursor,
             };
         });
+        if entry_meta.entry_size == 0 || cursor + entry_meta.entry_size > self.bound {
+            return self.halt();
+        }
         self.cursor += entry_meta.entry_size;
         Some(entry_meta)
     }
 }
 
+// Streams the entries of segment content from the reader, like a backup on the disk, holding one
+// entry in memory at a time. `f` gets the offset of each entry in the content and its meta, which
+// locates the entry in a buffer reused for the next one. Scanning halts at the first corrupt
+// entry like `SegmentEntryIter` does, including the one cut short by the end of the content.
+pub fn scan_entries<R, F>(reader: R, mut f: F) -> io::Result<Option<ScanError>>
+where
+    R: Read,
+    F: FnMut(usize, &EntryMeta),
+{
+    let mut reader = BufReader::new(reader);
+    let mut buffer = Vec::new();
+    let mut offset = 0;
+    loop {
+        if reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        let corrupt = Some(ScanError::CorruptEntry { offset });
+        let mut flag_byte = [0u8; 1];
+        reader.read_exact(&mut flag_byte)?;
+        let len_bytes = (flag_byte[0] & 0b0000_1111) as usize;
+        if EntryType::from_bits(flag_byte[0] & 0b1111_0000).is_none() || len_bytes > 4 {
+            return Ok(corrupt);
+        }
+        let mut raw_len = [0u8; 4];
+        if !read_fully(&mut reader, &mut raw_len[..len_bytes])? {
+            return Ok(corrupt);
+        }
+        let header_size = 1 + len_bytes;
+        let entry_size = header_size + u32::from_le_bytes(raw_len) as usize;
+        // No entry outgrows its segment
+        if entry_size > SEGMENT_SIZE {
+            return Ok(corrupt);
+        }
+        buffer.clear();
+        buffer.push(flag_byte[0]);
+        buffer.extend_from_slice(&raw_len[..len_bytes]);
+        buffer.resize(entry_size, 0);
+        if !read_fully(&mut reader, &mut buffer[header_size..])? {
+            return Ok(corrupt);
+        }
+        let entry_meta = SegmentEntryIter::over_buffer(&buffer).next().unwrap();
+        f(offset, &entry_meta);
+        offset += entry_size;
+    }
+}
+
+// False when the reader ends before the buffer is filled
+fn read_fully<R: Read>(reader: &mut R, buffer: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buffer) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
 pub const PAGE_SHIFT: usize = 12; // 4K
 pub const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
 
-pub struct SegmentAllocator {
-    base: usize,
-    offset: AtomicUsize,
-    limit: usize,
-    gc_threshold: usize,
-    free: WordList,
+// Provides the memory region of a chunk, which segments are allocated from
+pub trait RegionAllocator: Send + Sync {
+    // Address of a readable and writable region of `size` bytes for the chunk
+    fn alloc_region(&self, chunk_id: usize, size: usize) -> usize;
 }
 
-impl SegmentAllocator {
-    pub fn new(chunk_size: usize) -> Self {
-        let overflow = SEGMENT_SIZE - PAGE_SIZE;
-        let aligned_size = chunk_size + overflow;
+// Anonymous private memory, lost when the process exits
+pub struct LibcAllocator;
+
+impl RegionAllocator for LibcAllocator {
+    fn alloc_region(&self, _chunk_id: usize, size: usize) -> usize {
         let ptr = unsafe {
             libc::mmap(
                 ptr::null_mut(),
-                aligned_size,
+                size,
                 PROT_READ | PROT_WRITE,
                 MAP_ANONYMOUS | MAP_PRIVATE,
                 -1,
                 0,
             )
         };
-        let addr = ptr as usize;
+        assert_ne!(ptr, MAP_FAILED, "Cannot map {} bytes of memory", size);
+        ptr as usize
+    }
+}
+
+// Shared mapping of a file for each chunk in the directory, the file keeps the chunk memory
+pub struct MmapAllocator {
+    pub dir: String,
+}
+
+impl MmapAllocator {
+    pub fn new(dir: &str) -> Self {
+        Self {
+            dir: dir.to_string(),
+        }
+    }
+
+    pub fn file_name(&self, chunk_id: usize) -> String {
+        format!("{}/chunk-mem-{}", self.dir, chunk_id)
+    }
+}
+
+impl RegionAllocator for MmapAllocator {
+    fn alloc_region(&self, chunk_id: usize, size: usize) -> usize {
+        create_dir_all(&self.dir).unwrap();
+        let file_name = self.file_name(chunk_id);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&file_name)
+            .unwrap(); // fast fail
+        file.set_len(size as u64).unwrap();
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                PROT_READ | PROT_WRITE,
+                MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        assert_ne!(ptr, MAP_FAILED, "Cannot map {} bytes of {}", size, file_name);
+        debug!("Mapped chunk {} memory to file {}", chunk_id, file_name);
+        // The mapping stays valid after the file is closed
+        ptr as usize
+    }
+}
+
+pub struct SegmentAllocator {
+    base: usize,
+    offset: AtomicUsize,
+    limit: usize,
+    gc_threshold: usize,
+    free: WordList,
+}
+
+impl SegmentAllocator {
+    pub fn new(chunk_id: usize, chunk_size: usize, region: &dyn RegionAllocator) -> Self {
+        let overflow = SEGMENT_SIZE - PAGE_SIZE;
+        let aligned_size = chunk_size + overflow;
+        let addr = region.alloc_region(chunk_id, aligned_size);
         let start = addr + overflow;
         let aligned_addr = start & SEGMENT_MASK;
         Self {
@@ -1,10 +1,13 @@
 use crate::ram::cell::*;
 use crate::ram::chunk::Chunks;
+use crate::ram::clock;
+use crate::ram::entry::EntryType;
 use crate::ram::schema::*;
 use crate::ram::types;
 use crate::ram::types::*;
 
 use super::*;
+use std::sync::Arc;
 
 pub const CHUNK_SIZE: usize = 8 * 1024 * 1024;
 
@@ -116,3 +119,522 @@ pub fn dynamic() {
         assert!(stored_cell.data["major"].string().is_none());
     }
 }
+
+#[test]
+pub fn field_ttl() {
+    let fields = Field::new(
+        "*",
+        Type::Map,
+        false,
+        false,
+        Some(vec![
+            Field::new("id", Type::I64, false, false, None, vec![]),
+            Field::new("name", Type::String, false, false, None, vec![]),
+            Field::new("score", Type::U64, false, false, None, vec![]).with_ttl(10),
+            Field::new("level", Type::U64, false, false, None, vec![]).with_ttl(10),
+        ]),
+        vec![],
+    );
+    let id = Id::new(1, 1);
+    let schema = Schema::new_with_id(1, "ttl", None, fields, false, false);
+    let chunks = Chunks::new_dummy(1, CHUNK_SIZE);
+    chunks.list[0].meta.schemas.new_schema(schema.clone());
+    let mut cell = OwnedCell {
+        header: CellHeader::new(schema.id, &id),
+        data: data_map_value! {
+            id: 100 as i64,
+            score: 70 as u64,
+            level: 1 as u64,
+            name: String::from("Jack")
+        },
+    };
+    chunks.write_cell(&mut cell).unwrap();
+    assert_eq!(
+        chunks.read_cell(&id).unwrap().data["score"].u64().unwrap(),
+        &70
+    );
+    // Rewriting the cell only renews the stamps of the changed fields
+    clock::skew(6);
+    cell.data["name"] = OwnedValue::String(String::from("John"));
+    cell.data["level"] = OwnedValue::U64(2);
+    chunks.update_cell(&mut cell).unwrap();
+    clock::skew(6);
+    {
+        let stored_cell = chunks.read_cell(&id).unwrap();
+        assert!(matches!(stored_cell.data["score"], SharedValue::Null));
+        assert_eq!(stored_cell.data["level"].u64().unwrap(), &2);
+        assert_eq!(stored_cell.data["id"].i64().unwrap(), &100);
+        assert_eq!(stored_cell.data["name"].string().unwrap(), "John");
+    }
+    let (selected, _) = chunks.read_selected(&id, &[key_hash("score")]).unwrap();
+    assert!(matches!(selected, SharedValue::Null));
+    let (selected, _) = chunks
+        .read_selected(&id, &[key_hash("name"), key_hash("score")])
+        .unwrap();
+    if let SharedValue::Array(values) = selected {
+        assert_eq!(values[0].string().unwrap(), "John");
+        assert!(matches!(values[1], SharedValue::Null));
+    } else {
+        panic!("selected fields should be an array");
+    }
+    clock::skew(6);
+    {
+        let stored_cell = chunks.read_cell(&id).unwrap();
+        assert!(matches!(stored_cell.data["level"], SharedValue::Null));
+    }
+    // Expired fields written again with the same values live again
+    chunks.update_cell(&mut cell).unwrap();
+    let stored_cell = chunks.read_cell(&id).unwrap();
+    assert_eq!(stored_cell.data["score"].u64().unwrap(), &70);
+    assert_eq!(stored_cell.data["level"].u64().unwrap(), &2);
+}
+
+#[test]
+pub fn array_field_iter() {
+    let fields = Field::new(
+        "*",
+        Type::Map,
+        false,
+        false,
+        Some(vec![
+            Field::new("id", Type::I64, false, false, None, vec![]),
+            Field::new("nums", Type::U64, false, true, None, vec![]),
+            Field::new("tags", Type::String, true, true, None, vec![]),
+        ]),
+        vec![],
+    );
+    let schema = Schema::new_with_id(1, "arrays", None, fields, false, false);
+    let chunks = Chunks::new_dummy(1, CHUNK_SIZE);
+    chunks.list[0].meta.schemas.new_schema(schema.clone());
+    let id = Id::new(1, 1);
+    let num = 100_000;
+    let mut cell = OwnedCell {
+        header: CellHeader::new(schema.id, &id),
+        data: data_map_value! {
+            id: 1 as i64,
+            nums: OwnedValue::PrimArray(OwnedPrimArray::U64((0..num).collect())),
+            tags: OwnedValue::Null
+        },
+    };
+    chunks.write_cell(&mut cell).unwrap();
+    let full = chunks.read_cell(&id).unwrap().to_owned();
+    let full_nums = match &full.data["nums"] {
+        OwnedValue::PrimArray(OwnedPrimArray::U64(nums)) => nums.clone(),
+        v => panic!("{:?}", v),
+    };
+    let iter = chunks.array_field_iter(&id, key_hash("nums")).unwrap();
+    assert_eq!(iter.size_hint(), (num as usize, Some(num as usize)));
+    let mut count = 0;
+    for (i, val) in iter.enumerate() {
+        assert_eq!(val.u64().unwrap(), &full_nums[i]);
+        count += 1;
+    }
+    assert_eq!(count, full_nums.len());
+    // Null arrays are empty
+    assert_eq!(
+        chunks
+            .array_field_iter(&id, key_hash("tags"))
+            .unwrap()
+            .count(),
+        0
+    );
+    assert!(matches!(
+        chunks.array_field_iter(&id, key_hash("id")),
+        Err(ReadError::FieldIsNotPrimArray(_))
+    ));
+}
+
+#[test]
+pub fn dynamic_null_and_na() {
+    let schema = Schema::new_with_id(1, "dynamic", None, default_fields(), true, false);
+    let chunks = Chunks::new_dummy(1, CHUNK_SIZE);
+    chunks.list[0].meta.schemas.new_schema(schema.clone());
+    let id = Id::new(1, 1);
+    let mut data_map = OwnedMap::new();
+    data_map.insert(&String::from("id"), OwnedValue::I64(1));
+    data_map.insert(&String::from("score"), OwnedValue::U64(70));
+    data_map.insert(
+        &String::from("name"),
+        OwnedValue::String(String::from("Jack")),
+    );
+    data_map.insert(&String::from("explicit_null"), OwnedValue::Null);
+    data_map.insert(&String::from("unknown"), OwnedValue::NA);
+    data_map.insert(
+        &String::from("nested"),
+        OwnedValue::Array(vec![OwnedValue::Null, OwnedValue::NA]),
+    );
+    let mut cell = OwnedCell::new_with_id(schema.id, &id, OwnedValue::Map(data_map));
+    chunks.write_cell(&mut cell).unwrap();
+    let stored = chunks.read_cell(&id).unwrap().to_owned();
+    let map = stored.data.Map().unwrap();
+    assert_eq!(
+        map.map.get(&key_hash("explicit_null")),
+        Some(&OwnedValue::Null)
+    );
+    assert_eq!(map.map.get(&key_hash("unknown")), Some(&OwnedValue::NA));
+    assert_eq!(
+        map.map.get(&key_hash("nested")),
+        Some(&OwnedValue::Array(vec![OwnedValue::Null, OwnedValue::NA]))
+    );
+}
+
+#[test]
+pub fn header_layouts() {
+    let schema = Schema::new_with_id(1, "layouts", None, default_fields(), false, false);
+    let mut chunks = Chunks::new_dummy(1, CHUNK_SIZE);
+    Arc::get_mut(&mut chunks).unwrap().list[0].header_layout = HeaderLayout::V1;
+    chunks.list[0].meta.schemas.new_schema(schema.clone());
+    let id1 = Id::new(1, 1);
+    let id2 = Id::new(1, 2);
+    let mut cell = OwnedCell::new_with_id(
+        schema.id,
+        &id1,
+        data_map_value! {
+            id: 1 as i64,
+            score: 70 as u64,
+            name: "Jack"
+        },
+    );
+    chunks.write_cell(&mut cell).unwrap();
+    let entry_types = |chunks: &Chunks| {
+        let chunk = &chunks.list[0];
+        chunk
+            .segments()
+            .iter()
+            .flat_map(|seg| {
+                chunk
+                    .live_entries(seg)
+                    .map(|e| e.meta.entry_header.entry_type)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(entry_types(&chunks), vec![EntryType::CELL]);
+    // Cells in the legacy layout are still readable after switching layout
+    Arc::get_mut(&mut chunks).unwrap().list[0].header_layout = HeaderLayout::V2;
+    let stored = chunks.read_cell(&id1).unwrap().to_owned();
+    assert_eq!(stored.id(), id1);
+    assert_eq!(stored.version(), cell.version());
+    assert_eq!(stored.data["name"].string().unwrap(), "Jack");
+    assert_eq!(stored.data["score"].u64().unwrap(), &70);
+    // New cells are written in the new layout
+    let mut cell = OwnedCell::new_with_id(
+        schema.id,
+        &id2,
+        data_map_value! {
+            id: 2 as i64,
+            score: 80 as u64,
+            name: "John"
+        },
+    );
+    chunks.write_cell(&mut cell).unwrap();
+    let stored = chunks.read_cell(&id2).unwrap().to_owned();
+    assert_eq!(stored.id(), id2);
+    assert_eq!(stored.header.schema, schema.id);
+    assert_eq!(stored.version(), cell.version());
+    assert_eq!(stored.data["id"].i64().unwrap(), &2);
+    assert_eq!(stored.data["name"].string().unwrap(), "John");
+    assert_eq!(stored.data["score"].u64().unwrap(), &80);
+    let mut types = entry_types(&chunks);
+    types.sort_by_key(|t| t.bits());
+    assert_eq!(types, vec![EntryType::CELL, EntryType::VERSIONED_CELL]);
+    // Updating a legacy cell migrates it to the new layout
+    let mut cell = chunks.read_cell(&id1).unwrap().to_owned();
+    cell["score"] = OwnedValue::U64(75);
+    chunks.update_cell(&mut cell).unwrap();
+    let stored = chunks.read_cell(&id1).unwrap().to_owned();
+    assert_eq!(stored.data["score"].u64().unwrap(), &75);
+    assert!(entry_types(&chunks)
+        .iter()
+        .all(|t| *t == EntryType::VERSIONED_CELL));
+}
+
+#[test]
+pub fn interned_dynamic_names() {
+    let schema = Schema::new_with_id(1, "interned", None, default_fields(), true, false);
+    let dynamic_names = ["measurement_description", "measurement_location"];
+    let cell_of = |i: u64| {
+        let mut data_map = OwnedMap::new();
+        data_map.insert("id", OwnedValue::I64(i as i64));
+        data_map.insert("score", OwnedValue::U64(i));
+        data_map.insert("name", OwnedValue::String(format!("cell {}", i)));
+        data_map.insert(dynamic_names[0], OwnedValue::U32(i as u32));
+        data_map.insert(dynamic_names[1], OwnedValue::String(String::from("lab")));
+        OwnedCell::new_with_id(schema.id, &Id::new(1, i), OwnedValue::Map(data_map))
+    };
+    let used_space = |chunks: &Chunks| {
+        let chunk = &chunks.list[0];
+        chunk
+            .segments()
+            .iter()
+            .map(|seg| seg.used_spaces() as usize)
+            .sum::<usize>()
+    };
+    let mut plain_chunks = Chunks::new_dummy(1, CHUNK_SIZE);
+    let mut interned_chunks = Chunks::new_dummy(1, CHUNK_SIZE);
+    Arc::get_mut(&mut plain_chunks).unwrap().list[0].intern_dynamic_names = false;
+    Arc::get_mut(&mut interned_chunks).unwrap().list[0].intern_dynamic_names = false;
+    for chunks in &[&plain_chunks, &interned_chunks] {
+        chunks.list[0].meta.schemas.new_schema(schema.clone());
+        chunks.write_cell(&mut cell_of(0)).unwrap();
+    }
+    // Cells written before interning is on are still readable after
+    Arc::get_mut(&mut interned_chunks).unwrap().list[0].intern_dynamic_names = true;
+    for i in 1..64 {
+        plain_chunks.write_cell(&mut cell_of(i)).unwrap();
+        interned_chunks.write_cell(&mut cell_of(i)).unwrap();
+    }
+    assert!(plain_chunks.list[0].dynamic_names.is_empty());
+    assert_eq!(
+        interned_chunks.list[0].dynamic_names.len(),
+        dynamic_names.len()
+    );
+    assert!(used_space(&interned_chunks) < used_space(&plain_chunks));
+    for i in 0..64 {
+        let stored = interned_chunks
+            .read_cell(&Id::new(1, i))
+            .unwrap()
+            .to_owned();
+        assert_eq!(
+            stored.data["name"].string().unwrap(),
+            &format!("cell {}", i)
+        );
+        assert_eq!(stored.data[dynamic_names[0]].u32().unwrap(), &(i as u32));
+        assert_eq!(stored.data[dynamic_names[1]].string().unwrap(), "lab");
+    }
+    // Interned names missing from the dictionary are errors, cells written before stay readable
+    Arc::get_mut(&mut interned_chunks).unwrap().list[0].dynamic_names = Default::default();
+    assert!(interned_chunks.read_cell(&Id::new(1, 0)).is_ok());
+    assert!(matches!(
+        interned_chunks.read_cell(&Id::new(1, 1)),
+        Err(ReadError::UnknownDynamicFieldName(_))
+    ));
+}
+
+#[test]
+pub fn enum_fields() {
+    let statuses = ["pending", "active", "closed"];
+    let fields = Field::new(
+        "*",
+        Type::Map,
+        false,
+        false,
+        Some(vec![
+            Field::new("id", Type::I64, false, false, None, vec![]),
+            Field::new("status", Type::String, false, false, None, vec![])
+                .with_enum_values(&statuses),
+            Field::new("history", Type::String, false, true, None, vec![])
+                .with_enum_values(&statuses),
+        ]),
+        vec![],
+    );
+    let schema = Schema::new_with_id(1, "enums", None, fields, false, false);
+    let chunks = Chunks::new_dummy(1, CHUNK_SIZE);
+    chunks.list[0].meta.schemas.new_schema(schema.clone());
+    let history = || {
+        OwnedValue::Array(vec![
+            OwnedValue::String(String::from("pending")),
+            OwnedValue::String(String::from("active")),
+        ])
+    };
+    let id = Id::new(1, 1);
+    let mut cell = OwnedCell::new_with_id(
+        schema.id,
+        &id,
+        data_map_value! {
+            id: 1 as i64,
+            status: "active",
+            history: history()
+        },
+    );
+    chunks.write_cell(&mut cell).unwrap();
+    let stored = chunks.read_cell(&id).unwrap().to_owned();
+    assert_eq!(stored.data["status"].string().unwrap(), "active");
+    assert_eq!(stored.data["history"], history());
+    // Cells read keep the names of their values after the schema is replaced
+    {
+        let read = chunks.read_cell(&id).unwrap();
+        let replacement = Schema::new_with_id(1, "enums", None, default_fields(), false, false);
+        chunks.list[0].meta.schemas.new_schema(replacement);
+        assert_eq!(read.data["status"].string().unwrap(), "active");
+        chunks.list[0].meta.schemas.new_schema(schema.clone());
+    }
+    // So are values selected
+    {
+        let (selected, _schema) = chunks.read_selected(&id, &[key_hash("status")]).unwrap();
+        let replacement = Schema::new_with_id(1, "enums", None, default_fields(), false, false);
+        chunks.list[0].meta.schemas.new_schema(replacement);
+        assert_eq!(selected.string().unwrap(), "active");
+        chunks.list[0].meta.schemas.new_schema(schema.clone());
+    }
+    // Ordinals of enum arrays are not iterated as they are stored
+    assert!(matches!(
+        chunks.array_field_iter(&id, key_hash("history")),
+        Err(ReadError::FieldIsNotPrimArray(_))
+    ));
+    // Values are stored as u16 ordinals
+    let plain_fields = Field::new(
+        "*",
+        Type::Map,
+        false,
+        false,
+        Some(vec![
+            Field::new("id", Type::I64, false, false, None, vec![]),
+            Field::new("status", Type::U16, false, false, None, vec![]),
+        ]),
+        vec![],
+    );
+    let plain_schema = Schema::new_with_id(1, "enums", None, plain_fields, false, false);
+    chunks.list[0].meta.schemas.new_schema(plain_schema);
+    let stored = chunks.read_cell(&id).unwrap().to_owned();
+    assert_eq!(stored.data["status"].u16().unwrap(), &1);
+    chunks.list[0].meta.schemas.new_schema(schema.clone());
+    // Values not declared are rejected
+    let mut cell = OwnedCell::new_with_id(
+        schema.id,
+        &Id::new(1, 2),
+        data_map_value! {
+            id: 2 as i64,
+            status: "archived",
+            history: history()
+        },
+    );
+    assert!(matches!(
+        chunks.write_cell(&mut cell),
+        Err(WriteError::UnknownEnumValue(_, _))
+    ));
+    let mut cell = OwnedCell::new_with_id(
+        schema.id,
+        &Id::new(1, 3),
+        data_map_value! {
+            id: 3 as i64,
+            status: 1 as u16,
+            history: history()
+        },
+    );
+    assert!(matches!(
+        chunks.write_cell(&mut cell),
+        Err(WriteError::TypeMismatch {
+            expected: Type::String,
+            ..
+        })
+    ));
+}
+
+#[test]
+pub fn write_validation_errors() {
+    let fields = Field::new(
+        "*",
+        Type::Map,
+        false,
+        false,
+        Some(vec![
+            Field::new("id", Type::I64, false, false, None, vec![]),
+            Field::new("name", Type::String, true, false, None, vec![]),
+            Field::new("tags", Type::String, false, true, None, vec![]),
+        ]),
+        vec![],
+    );
+    let schema = Schema::new_with_id(1, "validations", None, fields, false, false);
+    let chunks = Chunks::new_dummy(1, CHUNK_SIZE);
+    chunks.list[0].meta.schemas.new_schema(schema.clone());
+    let write = |data: OwnedValue| {
+        let mut cell = OwnedCell::new_with_id(schema.id, &Id::new(1, 1), data);
+        chunks.write_cell(&mut cell)
+    };
+    let tags = || OwnedValue::Array(vec![OwnedValue::String("a".to_string())]);
+    assert!(write(data_map_value! { id: 1 as i64, name: "a", tags: tags() }).is_ok());
+    match write(data_map_value! { id: 2 as u64, tags: tags() }) {
+        Err(WriteError::TypeMismatch {
+            field,
+            expected,
+            got,
+        }) => {
+            assert_eq!(field.name, "id");
+            assert_eq!(expected, Type::I64);
+            assert_eq!(got, OwnedValue::U64(2));
+        }
+        res => panic!("Expected type mismatch, got {:?}", res),
+    }
+    match write(data_map_value! { name: "a", tags: tags() }) {
+        Err(WriteError::UnexpectedNull(field)) => assert_eq!(field.name, "id"),
+        res => panic!("Expected unexpected null, got {:?}", res),
+    }
+    match write(data_map_value! { id: 3 as i64, tags: "a" }) {
+        Err(WriteError::ExpectedArray(field)) => assert_eq!(field.name, "tags"),
+        res => panic!("Expected array, got {:?}", res),
+    }
+    match write(data_map_value! { id: 4 as i64, name: tags(), tags: tags() }) {
+        Err(WriteError::ExpectedScalar(field)) => assert_eq!(field.name, "name"),
+        res => panic!("Expected scalar, got {:?}", res),
+    }
+}
+
+#[test]
+pub fn added_fields() {
+    let schema = Schema::new_with_id(1, "evolving", None, default_fields(), false, false);
+    let chunks = Chunks::new_dummy(1, CHUNK_SIZE);
+    let schemas = &chunks.list[0].meta.schemas;
+    schemas.new_schema(schema.clone());
+    let mut old_cell = OwnedCell::new_with_id(
+        schema.id,
+        &Id::new(1, 1),
+        data_map_value! { id: 1 as i64, name: "Jack", score: 70 as u64 },
+    );
+    chunks.write_cell(&mut old_cell).unwrap();
+
+    let mut evolved = schema.clone();
+    evolved
+        .add_field(Field::new("age", Type::U32, true, false, None, vec![]))
+        .unwrap();
+    evolved
+        .add_field(Field::new("scores", Type::U64, true, true, None, vec![]))
+        .unwrap();
+    assert_eq!(evolved.static_bound, schema.static_bound);
+    schemas.new_schema(evolved.clone());
+    let scores = OwnedValue::PrimArray(OwnedPrimArray::U64(vec![80, 90]));
+    let mut new_cell = OwnedCell::new_with_id(
+        schema.id,
+        &Id::new(1, 2),
+        data_map_value! {
+            id: 2 as i64,
+            name: "John",
+            score: 80 as u64,
+            age: 30 as u32,
+            scores: scores.clone()
+        },
+    );
+    chunks.write_cell(&mut new_cell).unwrap();
+
+    // Cells written before read added fields as null
+    let stored = chunks.read_cell(&Id::new(1, 1)).unwrap().to_owned();
+    assert_eq!(stored.data["id"].i64().unwrap(), &1);
+    assert_eq!(stored.data["name"].string().unwrap(), "Jack");
+    assert_eq!(stored.data["score"].u64().unwrap(), &70);
+    assert_eq!(stored.data["age"], OwnedValue::Null);
+    assert_eq!(stored.data["scores"], OwnedValue::Null);
+    let stored = chunks.read_cell(&Id::new(1, 2)).unwrap().to_owned();
+    assert_eq!(stored.data["name"].string().unwrap(), "John");
+    assert_eq!(stored.data["score"].u64().unwrap(), &80);
+    assert_eq!(stored.data["age"].u32().unwrap(), &30);
+    assert_eq!(stored.data["scores"], scores);
+    let age_id = evolved.field_id("age");
+    let (selected, _) = chunks.read_selected(&Id::new(1, 2), &[age_id]).unwrap();
+    assert_eq!(selected.u32().unwrap(), &30);
+    let (selected, _) = chunks.read_selected(&Id::new(1, 1), &[age_id]).unwrap();
+    assert!(selected.u32().is_none());
+
+    let mut rejected = evolved.clone();
+    match rejected.add_field(Field::new("height", Type::U32, false, false, None, vec![])) {
+        Err(SchemaError::InvalidField { path, .. }) => assert_eq!(path, "height"),
+        res => panic!("Expected invalid field, got {:?}", res),
+    }
+    assert_eq!(
+        rejected.add_field(Field::new("name", Type::String, true, false, None, vec![])),
+        Err(SchemaError::FieldAlreadyExisted("name".to_string()))
+    );
+    let mut dynamic = Schema::new_with_id(2, "dynamic", None, default_fields(), true, false);
+    assert!(dynamic
+        .add_field(Field::new("age", Type::U32, true, false, None, vec![]))
+        .is_err());
+}
@@ -1,13 +1,15 @@
 use super::*;
 use crate::ram::cell::*;
-use crate::ram::chunk::Chunks;
+use crate::ram::chunk::{default_cell_index_capacity, AdmissionConfig, Chunks};
+use crate::ram::segs::{MmapAllocator, SEGMENT_SIZE};
 use crate::ram::schema::*;
 use crate::ram::types::*;
 use crate::server::ServerMeta;
 use bifrost_hasher::hash_str;
 use env_logger;
+use rayon::prelude::*;
 use std;
-use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
 use std::sync::Arc;
 
 pub const CHUNK_SIZE: usize = 1 * 8 * 1024 * 1024;
@@ -50,6 +52,7 @@ pub fn cell_rw() {
         None,
         None,
         None,
+        default_cell_index_capacity(),
     );
     let header = chunks.write_cell(&mut cell).unwrap();
     let cell_1_ptr = chunks.address_of(&Id::from_header(&header));
@@ -135,7 +138,8 @@ pub fn cell_rw() {
     {
         let sel_cell = chunks
             .read_selected(&id2, &[hash_str("score"), hash_str("name")])
-            .unwrap();
+            .unwrap()
+            .0;
         assert_eq!(sel_cell[0usize].u64().unwrap(), &100);
         assert_eq!(sel_cell[1usize].string().unwrap(), "John");
     }
@@ -163,6 +167,7 @@ pub fn simple_cell_rw() {
         None,
         None,
         None,
+        default_cell_index_capacity(),
     );
     chunks.write_cell(&mut cell).unwrap();
     {
@@ -171,6 +176,42 @@ pub fn simple_cell_rw() {
     }
 }
 
+#[test]
+pub fn burst_writes_are_contiguous() {
+    let _ = env_logger::try_init();
+    let fields = simple_fields();
+    let schema = Schema::new("burst", None, fields, false, false);
+    let schemas = LocalSchemasCache::new_local("");
+    schemas.new_schema(schema.clone());
+    let chunks = Chunks::new(
+        1,
+        CHUNK_SIZE,
+        Arc::new(ServerMeta { schemas }),
+        None,
+        None,
+        None,
+        default_cell_index_capacity(),
+    );
+    let num = 64;
+    for i in 0..num {
+        let mut cell = OwnedCell {
+            header: CellHeader::new(schema.id, &Id::new(1, i + 1)),
+            data: OwnedValue::U64(i),
+        };
+        chunks.write_cell(&mut cell).unwrap();
+    }
+    // all entries are appended to the head segment one after another
+    let chunk = &chunks.list[0];
+    assert_eq!(chunk.seg_count(), 1);
+    let addrs: Vec<_> = (0..num)
+        .map(|i| chunks.address_of(&Id::new(1, i + 1)))
+        .collect();
+    let entry_size = addrs[1] - addrs[0];
+    addrs.windows(2).for_each(|pair| {
+        assert_eq!(pair[1] - pair[0], entry_size);
+    });
+}
+
 #[test]
 pub fn array_dyn_map() {
     let _ = env_logger::try_init();
@@ -203,6 +244,7 @@ pub fn array_dyn_map() {
         None,
         None,
         None,
+        default_cell_index_capacity(),
     );
     let data = data_map_value!(
         fixed: OwnedValue::U32(42),
@@ -234,6 +276,7 @@ pub fn complex_cell_sel_read() {
         None,
         None,
         None,
+        default_cell_index_capacity(),
     );
     let data = data_map_value!(
         id: OwnedValue::I64(128),
@@ -280,7 +323,7 @@ pub fn complex_cell_sel_read() {
         let partial_cell = chunks.read_selected(&id1, &key_hashes(&vec![
             String::from("id"),
             String::from("num")
-        ])).unwrap().owned();
+        ])).unwrap().0.owned();
         assert_eq!(&partial_cell[0usize], &cell["id"]);
         assert_eq!(&partial_cell[1usize], &cell["num"]);
     }
@@ -288,42 +331,42 @@ pub fn complex_cell_sel_read() {
         // Selecting one in nested map
         let partial_cell = chunks.read_selected(&id1, &key_hashes(&vec![
             String::from("sub|sub1"),
-        ])).unwrap().owned();
+        ])).unwrap().0.owned();
         assert_eq!(&partial_cell, &cell["sub"]["sub1"]);
     }
     {
         // Selecting one array in nested map
         let partial_cell = chunks.read_selected(&id1, &key_hashes(&vec![
             String::from("sub|sub2"),
-        ])).unwrap().owned();
+        ])).unwrap().0.owned();
         assert_eq!(&partial_cell, &cell["sub"]["sub2"]);
     }
     {
         // Selecting one string in nested map
         let partial_cell = chunks.read_selected(&id1, &key_hashes(&vec![
             String::from("sub|sub3"),
-        ])).unwrap().owned();
+        ])).unwrap().0.owned();
         assert_eq!(&partial_cell, &cell["sub"]["sub3"]);
     }
     {
         // Selecting one map array in nested map
         let partial_cell = chunks.read_selected(&id1, &key_hashes(&vec![
             String::from("sub|sub4"),
-        ])).unwrap().owned();
+        ])).unwrap().0.owned();
         assert_eq!(&partial_cell, &cell["sub"]["sub4"]);
     }
     {
         // Selecting one deeper in nested map
         let partial_cell = chunks.read_selected(&id1, &key_hashes(&vec![
             String::from("sub|sub4|sub4sub1"),
-        ])).unwrap().owned();
+        ])).unwrap().0.owned();
         assert_eq!(&partial_cell, &cell["sub"]["sub4"]["sub4sub1"]);
     }
     {
         // Selecting one deeper nullable array in nested map
         let partial_cell = chunks.read_selected(&id1, &key_hashes(&vec![
             String::from("sub|sub4|sub4sub3"),
-        ])).unwrap().owned();
+        ])).unwrap().0.owned();
         assert_eq!(&partial_cell, &cell["sub"]["sub4"]["sub4sub3"]);
     }
     {
@@ -331,7 +374,7 @@ pub fn complex_cell_sel_read() {
         let partial_cell = chunks.read_selected(&id1, &key_hashes(&vec![
             String::from("sub|sub3"),
             String::from("sub|sub4|sub4sub3"),
-        ])).unwrap().owned();
+        ])).unwrap().0.owned();
         assert_eq!(&partial_cell, &OwnedValue::Array(vec![
             cell["sub"]["sub3"].clone(),
             cell["sub"]["sub4"]["sub4sub3"].clone()
@@ -339,6 +382,189 @@ pub fn complex_cell_sel_read() {
     }
 }
 
+#[test]
+pub fn parallel_scan() {
+    let _ = env_logger::try_init();
+    let chunks = Chunks::new_dummy(4, CHUNK_SIZE);
+    let schema = Schema::new_with_id(1, "scanning", None, default_fields(), false, false);
+    let other_schema = Schema::new_with_id(2, "other", None, default_fields(), false, false);
+    chunks.list[0].meta.schemas.new_schema(schema.clone());
+    chunks.list[0].meta.schemas.new_schema(other_schema.clone());
+    let num_cells = 1000;
+    for i in 0..num_cells {
+        // Every 5th cell belongs to the other schema, should not be scanned
+        let schema_id = if i % 5 == 0 { other_schema.id } else { schema.id };
+        let mut cell = OwnedCell::new_with_id(
+            schema_id,
+            &Id::new(i % 4, i),
+            data_map_value! {
+                id: i as i64,
+                score: i,
+                name: "Jack"
+            },
+        );
+        chunks.write_cell(&mut cell).unwrap();
+    }
+    let serial_count = chunks.cell_ids_of_schema(schema.id).len();
+    let serial_sum: u64 = (0..num_cells).filter(|i| i % 5 != 0).sum();
+    assert_eq!(serial_count, 800);
+    let parallel_count = AtomicUsize::new(0);
+    chunks.parallel_scan(schema.id, |cell| {
+        assert_eq!(cell.header.schema, schema.id);
+        parallel_count.fetch_add(1, Ordering::Relaxed);
+    });
+    assert_eq!(parallel_count.load(Ordering::Relaxed), serial_count);
+    let sum = chunks.map_reduce(
+        schema.id,
+        |cell| *cell.data["score"].u64().unwrap(),
+        |a, b| a + b,
+    );
+    assert_eq!(sum, Some(serial_sum));
+    let count = chunks.map_reduce(schema.id, |_| 1usize, |a, b| a + b);
+    assert_eq!(count, Some(serial_count));
+    assert_eq!(chunks.map_reduce(3, |_| 1usize, |a, b| a + b), None);
+}
+
+#[test]
+pub fn dedup_writes() {
+    let chunks = Chunks::new_dummy(4, CHUNK_SIZE);
+    let schema = Schema::new_with_id(1, "dedup", None, default_fields(), false, false);
+    chunks.list[0].meta.schemas.new_schema(schema.clone());
+    let data = data_map_value! {
+        id: 1 as i64,
+        score: 70 as u64,
+        name: "Jack"
+    };
+    let id1 = Id::new(1, 1);
+    let id2 = Id::new(2, 2);
+    let mut cell = OwnedCell::new_with_id(schema.id, &id1, data.clone());
+    let header = chunks.write_cell_dedup(&mut cell).unwrap();
+    assert_eq!(header.id(), id1);
+    // Identical content under another id, in another chunk, resolves to the first cell
+    let mut dup = OwnedCell::new_with_id(schema.id, &id2, data.clone());
+    let header = chunks.write_cell_dedup(&mut dup).unwrap();
+    assert_eq!(header.id(), id1);
+    assert_eq!(chunks.count(), 1);
+    assert!(chunks.read_cell(&id2).is_err());
+    // Different content is written
+    let id3 = Id::new(3, 3);
+    let mut other = OwnedCell::new_with_id(
+        schema.id,
+        &id3,
+        data_map_value! {
+            id: 1 as i64,
+            score: 80 as u64,
+            name: "Jack"
+        },
+    );
+    assert_eq!(chunks.write_cell_dedup(&mut other).unwrap().id(), id3);
+    assert_eq!(chunks.count(), 2);
+    // After the original is removed, the content is written again
+    chunks.remove_cell(&id1).unwrap();
+    let mut dup = OwnedCell::new_with_id(schema.id, &id2, data);
+    assert_eq!(chunks.write_cell_dedup(&mut dup).unwrap().id(), id2);
+    assert_eq!(chunks.count(), 2);
+}
+
+#[test]
+pub fn write_admission() {
+    let _ = env_logger::try_init();
+    let chunks = Chunks::new_dummy(1, 4 * SEGMENT_SIZE);
+    chunks.list[0].set_admission(AdmissionConfig {
+        space_rate: 0.9,
+        living_rate: 0.8,
+        max_cleaner_backlog: 16,
+    });
+    let schema = Schema::new_with_id(1, "admission", None, default_fields(), false, false);
+    chunks.list[0].meta.schemas.new_schema(schema.clone());
+    let name = (0..4096).map(|_| "x").collect::<String>();
+    let mut written = 0;
+    let err = loop {
+        let mut cell = OwnedCell::new_with_id(
+            schema.id,
+            &Id::new(1, written + 1),
+            data_map_value! {
+                id: written as i64,
+                score: written,
+                name: name.clone()
+            },
+        );
+        match chunks.write_cell(&mut cell) {
+            Ok(_) => written += 1,
+            Err(e) => break e,
+        }
+    };
+    // Writers are pushed back before the chunk runs out of space
+    assert_eq!(err, WriteError::Overloaded);
+    assert!(written > 0);
+    let chunk = &chunks.list[0];
+    let used: usize = chunk
+        .segments()
+        .iter()
+        .map(|seg| seg.used_spaces() as usize)
+        .sum();
+    assert!(used < chunk.capacity);
+    // Updates are not held back, growing the cells runs the chunk out of space afterwards
+    let name = (0..8192).map(|_| "y").collect::<String>();
+    let err = (0..written).find_map(|n| {
+        let mut cell = OwnedCell::new_with_id(
+            schema.id,
+            &Id::new(1, n + 1),
+            data_map_value! {
+                id: n as i64,
+                score: n,
+                name: name.clone()
+            },
+        );
+        chunks.update_cell(&mut cell).err()
+    });
+    assert_eq!(err, Some(WriteError::CannotAllocateSpace));
+}
+
+#[test]
+pub fn clear_chunk() {
+    let _ = env_logger::try_init();
+    let chunks = Chunks::new_dummy(1, CHUNK_SIZE);
+    let schema = Schema::new_with_id(1, "clearing", None, default_fields(), false, false);
+    chunks.list[0].meta.schemas.new_schema(schema.clone());
+    let cell_of = |n: u64| {
+        OwnedCell::new_with_id(
+            schema.id,
+            &Id::new(1, n + 1),
+            data_map_value! {
+                id: n as i64,
+                score: n,
+                name: "Jack"
+            },
+        )
+    };
+    for n in 0..100 {
+        chunks.write_cell(&mut cell_of(n)).unwrap();
+    }
+    chunks.remove_cell(&Id::new(1, 1)).unwrap();
+    let chunk = &chunks.list[0];
+    let num_segs = chunk.seg_count();
+    assert_eq!(chunk.count(), 99);
+    chunk.clear();
+    assert_eq!(chunk.count(), 0);
+    assert!(chunk
+        .segments()
+        .iter()
+        .all(|seg| seg.used_spaces() == 0 && seg.entry_iter().count() == 0));
+    assert_eq!(
+        chunks.read_cell(&Id::new(1, 2)).err(),
+        Some(ReadError::CellDoesNotExisted)
+    );
+    // Cleared segments are reused for new cells
+    for n in 0..100 {
+        chunks.write_cell(&mut cell_of(n)).unwrap();
+    }
+    assert_eq!(chunk.count(), 100);
+    assert_eq!(chunk.seg_count(), num_segs);
+    let stored = chunks.read_cell(&Id::new(1, 1)).unwrap().to_owned();
+    assert_eq!(stored.data["score"].u64().unwrap(), &0);
+}
+
 fn dyn_map_value() -> OwnedValue {
     OwnedValue::Array(vec![
         data_map_value!(
@@ -363,3 +589,494 @@ fn dyn_map_value() -> OwnedValue {
         ),
     ])
 }
+
+#[test]
+pub fn mmap_allocator() {
+    let _ = env_logger::try_init();
+    let dir = std::env::temp_dir().join("neb-mmap-allocator-test");
+    let _ = std::fs::remove_dir_all(&dir);
+    let allocator = MmapAllocator::new(dir.to_str().unwrap());
+    let schema = Schema::new("mmap", None, default_fields(), false, false);
+    let schemas = LocalSchemasCache::new_local("");
+    schemas.new_schema(schema.clone());
+    let chunks = Chunks::new_with_allocator(
+        1,
+        CHUNK_SIZE,
+        Arc::new(ServerMeta { schemas }),
+        None,
+        None,
+        None,
+        default_cell_index_capacity(),
+        &allocator,
+    );
+    let file_len = std::fs::metadata(allocator.file_name(0)).unwrap().len();
+    assert!(file_len >= CHUNK_SIZE as u64);
+    for i in 0..100 {
+        let id = Id::new(1, i);
+        let mut cell = OwnedCell::new_with_id(
+            schema.id,
+            &id,
+            data_map_value! {
+                id: i as i64,
+                score: 70 as u64,
+                name: "Jack"
+            },
+        );
+        chunks.write_cell(&mut cell).unwrap();
+    }
+    for i in 0..100 {
+        let stored_cell = chunks.read_cell(&Id::new(1, i)).unwrap();
+        assert_eq!(stored_cell.data["id"].i64().unwrap(), &(i as i64));
+        assert_eq!(stored_cell.data["name"].string().unwrap(), "Jack");
+    }
+    let mut cell = OwnedCell::new_with_id(
+        schema.id,
+        &Id::new(1, 1),
+        data_map_value! {
+            id: 1 as i64,
+            score: 80 as u64,
+            name: "John"
+        },
+    );
+    chunks.update_cell(&mut cell).unwrap();
+    let stored_cell = chunks.read_cell(&Id::new(1, 1)).unwrap();
+    assert_eq!(stored_cell.data["score"].u64().unwrap(), &80);
+    assert_eq!(stored_cell.data["name"].string().unwrap(), "John");
+}
+
+#[test]
+pub fn key_field_enforcement() {
+    let _ = env_logger::try_init();
+    let schema = Schema::new_with_id(
+        1,
+        "keyed",
+        Some(vec!["name".to_string()]),
+        default_fields(),
+        false,
+        false,
+    );
+    let chunks = Chunks::new_dummy(1, CHUNK_SIZE);
+    chunks.list[0].meta.schemas.new_schema(schema.clone());
+    let data = data_map_value! {
+        id: 1 as i64,
+        score: 70 as u64,
+        name: "Jack"
+    };
+    let expected_id = OwnedCell::new(&schema, data.clone()).unwrap().id();
+    // Cells with ids not derived from the key are rejected
+    let mut cell = OwnedCell::new_with_id(schema.id, &Id::new(1, 1), data.clone());
+    assert!(matches!(
+        chunks.write_cell(&mut cell),
+        Err(WriteError::CellIdMismatchKey(id)) if id == expected_id
+    ));
+    let mut cell = OwnedCell::new_with_id(
+        schema.id,
+        &expected_id,
+        data_map_value! {
+            id: 1 as i64,
+            score: 70 as u64
+        },
+    );
+    assert!(matches!(
+        chunks.write_cell(&mut cell),
+        Err(WriteError::KeyFieldMissing)
+    ));
+    assert!(chunks.read_cell(&Id::new(1, 1)).is_err());
+    // Upserts by natural key update the same cell
+    let mut cell = OwnedCell::new(&schema, data).unwrap();
+    chunks.upsert_cell(&mut cell).unwrap();
+    let mut cell = OwnedCell::new(
+        &schema,
+        data_map_value! {
+            id: 1 as i64,
+            score: 80 as u64,
+            name: "Jack"
+        },
+    )
+    .unwrap();
+    assert_eq!(cell.id(), expected_id);
+    chunks.upsert_cell(&mut cell).unwrap();
+    let stored_cell = chunks.read_cell(&expected_id).unwrap();
+    assert_eq!(stored_cell.data["score"].u64().unwrap(), &80);
+    assert_eq!(chunks.count(), 1);
+}
+
+#[test]
+pub fn write_timestamps() {
+    let _ = env_logger::try_init();
+    let schema = Schema::new_with_id(1, "timestamps", None, default_fields(), false, false);
+    let chunks = Chunks::new_dummy(1, CHUNK_SIZE);
+    chunks.list[0].meta.schemas.new_schema(schema.clone());
+    let id1 = Id::new(1, 1);
+    let id2 = Id::new(1, 2);
+    for id in &[id1, id2] {
+        let mut cell = OwnedCell::new_with_id(
+            schema.id,
+            id,
+            data_map_value! {
+                id: id.lower as i64,
+                score: 70 as u64,
+                name: "Jack"
+            },
+        );
+        chunks.write_cell(&mut cell).unwrap();
+    }
+    let timestamp_of = |id: &Id| chunks.read_cell(id).unwrap().header.timestamp;
+    let original = chunks.read_cell(&id1).unwrap().to_owned();
+    let untouched = timestamp_of(&id2);
+    // The clock ticks in seconds
+    std::thread::sleep(std::time::Duration::from_millis(2100));
+    let mut cell = original.clone();
+    cell.data = data_map_value! {
+        id: 1 as i64,
+        score: 80 as u64,
+        name: "Jack"
+    };
+    let header = chunks.update_cell(&mut cell).unwrap();
+    assert!(header.timestamp > original.timestamp());
+    assert_eq!(timestamp_of(&id1), header.timestamp);
+    // Cells moved by the cleaner keep their timestamps
+    let chunk = &chunks.list[0];
+    chunk.segments().into_iter().for_each(|seg| {
+        crate::ram::cleaner::compact::CompactCleaner::clean_segment(chunk, &seg);
+    });
+    assert_eq!(timestamp_of(&id1), header.timestamp);
+    assert_eq!(timestamp_of(&id2), untouched);
+}
+
+#[test]
+pub fn auto_archive_sealed_segments() {
+    let _ = env_logger::try_init();
+    let dir = std::env::temp_dir().join("neb-auto-archive-test");
+    let _ = std::fs::remove_dir_all(&dir);
+    let schema = Schema::new("archive", None, default_fields(), false, false);
+    let schemas = LocalSchemasCache::new_local("");
+    schemas.new_schema(schema.clone());
+    let chunks = Chunks::new(
+        1,
+        4 * SEGMENT_SIZE,
+        Arc::new(ServerMeta { schemas }),
+        None,
+        Some(dir.to_str().unwrap().to_string()),
+        None,
+        default_cell_index_capacity(),
+    );
+    let chunk = &chunks.list[0];
+    let first_seg_id = chunk.segment_ids()[0];
+    let name = "N".repeat(64 * 1024);
+    let mut i = 0;
+    // Fill the first segment until the head moves on
+    while chunk.seg_count() < 2 {
+        i += 1;
+        let mut cell = OwnedCell::new_with_id(
+            schema.id,
+            &Id::new(1, i),
+            data_map_value! {
+                id: i as i64,
+                score: 70 as u64,
+                name: name.clone()
+            },
+        );
+        chunks.write_cell(&mut cell).unwrap();
+    }
+    let backup_file = dir
+        .join("chunk-bk-0")
+        .join(format!("{}.backup", first_seg_id));
+    let start = std::time::Instant::now();
+    while !backup_file.exists() {
+        assert!(
+            start.elapsed() < std::time::Duration::from_secs(10),
+            "Sealed segment is not archived"
+        );
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    let first_seg = chunk.segs.get(&first_seg_id).unwrap();
+    assert!(first_seg.archived.load(Ordering::Relaxed));
+    // The head is not sealed yet
+    let head_seg = chunk.segs.get(&chunk.segment_ids()[1]).unwrap();
+    assert!(!head_seg.archived.load(Ordering::Relaxed));
+}
+
+#[test]
+pub fn restore_from_backup() {
+    let _ = env_logger::try_init();
+    let dir = std::env::temp_dir().join("neb-restore-backup-test");
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut schema = Schema::new("restore", None, default_fields(), false, false);
+    schema
+        .add_index(schema.field_id("score"), IndexType::Ranged)
+        .unwrap();
+    let plain_schema = Schema::new_with_id(2, "restore_plain", None, default_fields(), false, false);
+    let new_chunks = || {
+        let schemas = LocalSchemasCache::new_local("");
+        schemas.new_schema(schema.clone());
+        schemas.new_schema(plain_schema.clone());
+        Chunks::new(
+            1,
+            4 * SEGMENT_SIZE,
+            Arc::new(ServerMeta { schemas }),
+            None,
+            Some(dir.to_str().unwrap().to_string()),
+            None,
+            default_cell_index_capacity(),
+        )
+    };
+    let plain_cell = |i: u64| {
+        OwnedCell::new_with_id(
+            plain_schema.id,
+            &Id::new(1, i),
+            data_map_value! {
+                id: 0 as i64,
+                score: 70 as u64,
+                name: "Jack"
+            },
+        )
+    };
+    let write = |chunks: &Arc<Chunks>, i: u64, score: u64| {
+        let mut cell = OwnedCell::new_with_id(
+            schema.id,
+            &Id::new(1, i),
+            data_map_value! {
+                id: i as i64,
+                score: score,
+                name: "Jack"
+            },
+        );
+        chunks.upsert_cell(&mut cell).unwrap();
+    };
+    {
+        let chunks = new_chunks();
+        for i in 0..10 {
+            write(&chunks, i, 70);
+        }
+        write(&chunks, 1, 80);
+        chunks.remove_cell(&Id::new(1, 2)).unwrap();
+        // Removed and written again
+        chunks.remove_cell(&Id::new(1, 3)).unwrap();
+        write(&chunks, 3, 90);
+        chunks.write_cell_dedup(&mut plain_cell(50)).unwrap();
+        let chunk = &chunks.list[0];
+        for seg in chunk.segments() {
+            seg.archive().unwrap();
+        }
+    }
+    let chunks = new_chunks();
+    assert_eq!(chunks.restore_from_backup().unwrap(), 10);
+    assert_eq!(chunks.list[0].cell_count(), 10);
+    for i in 0..10 {
+        let res = chunks.read_cell(&Id::new(1, i));
+        if i == 2 {
+            assert!(res.is_err());
+            continue;
+        }
+        let cell = res.unwrap();
+        let score = match i {
+            1 => 80,
+            3 => 90,
+            _ => 70,
+        };
+        assert_eq!(cell.data["id"].i64().unwrap(), &(i as i64));
+        assert_eq!(cell.data["score"].u64().unwrap(), &score);
+    }
+    // Dedup writes resolve to the restored cells of the same content
+    let mut dup = OwnedCell::new_with_id(
+        schema.id,
+        &Id::new(1, 100),
+        data_map_value! {
+            id: 0 as i64,
+            score: 70 as u64,
+            name: "Jack"
+        },
+    );
+    assert_eq!(chunks.write_cell_dedup(&mut dup).unwrap().id(), Id::new(1, 0));
+    assert_eq!(chunks.list[0].cell_count(), 10);
+    // Restored cells of schemas without indices are not indexed by their contents
+    let header = chunks.write_cell_dedup(&mut plain_cell(51)).unwrap();
+    assert_eq!(header.id(), Id::new(1, 51));
+    assert_eq!(chunks.list[0].cell_count(), 11);
+    // Backups are consumed, and kept until the cells restored to the head are archived
+    let files_of = |ext: &str| {
+        std::fs::read_dir(&dir)
+            .unwrap()
+            .filter(|entry| {
+                let path = entry.as_ref().unwrap().path();
+                path.extension().map_or(false, |e| e == ext)
+            })
+            .count()
+    };
+    assert_eq!(files_of("backup"), 0);
+    assert!(files_of("restoring") > 0);
+    assert_eq!(chunks.restore_from_backup().unwrap(), 0);
+    let chunk = &chunks.list[0];
+    chunk.sealed_segs.push(chunk.head_seg_id.load(Ordering::Relaxed));
+    assert_eq!(chunk.archive_sealed_segments(), 1);
+    assert_eq!(files_of("restoring"), 0);
+    assert_eq!(files_of("backup"), 1);
+}
+
+#[test]
+pub fn concurrent_writes_by_index_capacity() {
+    let _ = env_logger::try_init();
+    let num_cells = 64 * 1024;
+    // Capacities are rounded up to powers of 2
+    let default_capacity = default_cell_index_capacity();
+    for (capacity, index_capacity) in vec![
+        (0, 1),
+        (64, 64),
+        (1000, 1024),
+        (default_capacity * 16, (default_capacity * 16).next_power_of_two()),
+    ] {
+        let schema = Schema::new_with_id(1, "index_capacity", None, default_fields(), false, false);
+        let schemas = LocalSchemasCache::new_local("");
+        schemas.new_schema(schema.clone());
+        let chunks = Chunks::new(
+            1,
+            64 * 1024 * 1024,
+            Arc::new(ServerMeta { schemas }),
+            None,
+            None,
+            None,
+            capacity,
+        );
+        assert_eq!(chunks.list[0].cell_index_capacity, index_capacity);
+        let start = std::time::Instant::now();
+        (0..num_cells).into_par_iter().for_each(|n: u64| {
+            let mut cell = OwnedCell::new_with_id(
+                schema.id,
+                &Id::new(1, n + 1),
+                data_map_value! {
+                    id: n as i64,
+                    score: n,
+                    name: "Jack"
+                },
+            );
+            chunks.write_cell(&mut cell).unwrap();
+        });
+        debug!(
+            "Wrote {} cells concurrently with index capacity {} in {:?}",
+            num_cells,
+            capacity,
+            start.elapsed()
+        );
+        assert_eq!(chunks.count(), num_cells as usize);
+        for n in (0..num_cells).step_by(97) {
+            let cell = chunks.read_cell(&Id::new(1, n + 1)).unwrap().to_owned();
+            assert_eq!(cell.data["score"].u64().unwrap(), &n);
+        }
+    }
+}
+
+#[test]
+pub fn read_cell_raw() {
+    let _ = env_logger::try_init();
+    let chunks = Chunks::new_dummy(1, 8 * 1024 * 1024);
+    let schema = Schema::new_with_id(1, "raw", None, default_fields(), false, false);
+    chunks.list[0].meta.schemas.new_schema(schema.clone());
+    let id = Id::new(1, 1);
+    let mut cell = OwnedCell::new_with_id(
+        schema.id,
+        &id,
+        data_map_value! {
+            id: 1 as i64,
+            score: 70 as u64,
+            name: "Jack"
+        },
+    );
+    chunks.write_cell(&mut cell).unwrap();
+    let raw = chunks.read_cell_raw(&id).unwrap();
+    let (decoded, _) =
+        SharedCellData::from_chunk_raw(raw.as_ptr() as usize, &chunks.list[0]).unwrap();
+    let original = chunks.read_cell(&id).unwrap().to_owned();
+    let decoded = decoded.to_owned();
+    assert_eq!(decoded.id(), id);
+    assert_eq!(decoded.header.version, original.header.version);
+    assert_eq!(decoded.header.timestamp, original.header.timestamp);
+    assert_eq!(decoded.header.schema, schema.id);
+    assert_eq!(decoded.data["id"].i64().unwrap(), &1);
+    assert_eq!(decoded.data["score"].u64().unwrap(), &70);
+    assert_eq!(decoded.data["name"].string().unwrap(), "Jack");
+    assert!(matches!(
+        chunks.read_cell_raw(&Id::new(1, 2)),
+        Err(ReadError::CellDoesNotExisted)
+    ));
+}
+
+#[test]
+pub fn self_check() {
+    let _ = env_logger::try_init();
+    let chunks = Chunks::new_dummy(1, CHUNK_SIZE);
+    let chunk = &chunks.list[0];
+    let schema = Schema::new_with_id(1, "check", None, default_fields(), false, false);
+    chunk.meta.schemas.new_schema(schema.clone());
+    let cell_of = |n: u64| {
+        OwnedCell::new_with_id(
+            schema.id,
+            &Id::new(1, n),
+            data_map_value! {
+                id: n as i64,
+                score: n,
+                name: "Jack"
+            },
+        )
+    };
+    for n in 1..=10 {
+        chunks.write_cell(&mut cell_of(n)).unwrap();
+    }
+    // Updated and removed cells leave their old entries in segments
+    chunks.update_cell(&mut cell_of(1)).unwrap();
+    chunks.remove_cell(&Id::new(1, 2)).unwrap();
+    assert!(chunk.self_check().is_consistent());
+    // Desync the index
+    chunk.cell_index.remove(&3);
+    let addr_5 = chunks.address_of(&Id::new(1, 5));
+    chunk.cell_index.insert(&4, addr_5);
+    chunk.cell_index.insert(&42, addr_5 + 1);
+    let report = chunk.self_check();
+    assert!(!report.is_consistent());
+    assert_eq!(report.chunk_id, 0);
+    assert_eq!(report.unindexed, vec![3]);
+    assert_eq!(report.mismatched, vec![(4, 5)]);
+    assert_eq!(report.dangling, vec![(42, addr_5 + 1)]);
+    assert!(report.corrupted.is_empty());
+    assert_eq!(chunks.self_check(), vec![report]);
+}
+
+#[test]
+pub fn fragmentation_report() {
+    let _ = env_logger::try_init();
+    let chunks = Chunks::new_dummy(1, CHUNK_SIZE);
+    let chunk = &chunks.list[0];
+    let schema = Schema::new_with_id(1, "fragmentation", None, default_fields(), false, false);
+    chunk.meta.schemas.new_schema(schema.clone());
+    let cell_of = |n: u64| {
+        OwnedCell::new_with_id(
+            schema.id,
+            &Id::new(1, n),
+            data_map_value! {
+                id: n as i64,
+                score: n,
+                name: "Jack"
+            },
+        )
+    };
+    for n in 1..=10 {
+        chunks.write_cell(&mut cell_of(n)).unwrap();
+    }
+    let report = chunk.fragmentation_report();
+    assert_eq!(report.len(), chunk.seg_count());
+    assert!(report
+        .iter()
+        .all(|stat| stat.dead == 0 && stat.living_rate == 1.0));
+    let used = report.iter().map(|stat| stat.used).sum::<u32>();
+    assert!(used > 0);
+    // Old entries of updated and removed cells are dead
+    chunks.update_cell(&mut cell_of(1)).unwrap();
+    chunks.remove_cell(&Id::new(1, 2)).unwrap();
+    let report = chunk.fragmentation_report();
+    let stat = &report[0];
+    assert_eq!(stat.chunk_id, 0);
+    assert!(stat.used > used);
+    assert!(stat.dead > 0 && stat.dead < stat.used);
+    assert!(stat.living_rate < 1.0);
+    assert_eq!(chunks.fragmentation_report(), report);
+}
@@ -1,5 +1,7 @@
 mod cell;
 mod chunk;
+mod schema;
+mod segs;
 mod types;
 
 use std::collections::vec_deque;
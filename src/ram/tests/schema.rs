@@ -0,0 +1,528 @@
+use crate::ram::cell::*;
+use crate::ram::chunk::Chunks;
+use crate::ram::schema::builder::SchemaBuilder;
+use crate::ram::schema::*;
+use crate::ram::types::*;
+use bifrost_hasher::hash_str;
+
+use super::*;
+
+#[test]
+pub fn leaf_fields() {
+    let schema = Schema::new_with_id(1, "leaves", None, default_fields(), false, false);
+    let leaves = schema.leaf_fields();
+    assert_eq!(
+        leaves,
+        vec![
+            LeafField {
+                name_id: hash_str("id"),
+                name_path: "id".to_string(),
+                offset: 0,
+                data_type: Type::I64,
+                is_array: false,
+                nullable: false,
+            },
+            LeafField {
+                name_id: hash_str("name"),
+                name_path: "name".to_string(),
+                offset: 8,
+                data_type: Type::String,
+                is_array: false,
+                nullable: false,
+            },
+            LeafField {
+                name_id: hash_str("score"),
+                name_path: "score".to_string(),
+                offset: 12,
+                data_type: Type::U64,
+                is_array: false,
+                nullable: false,
+            },
+        ]
+    );
+    for leaf in &leaves {
+        assert!(schema.field_index.contains_key(&leaf.name_id));
+    }
+}
+
+#[test]
+pub fn leaf_fields_nested() {
+    let schema = Schema::new_with_id(1, "nested_leaves", None, complex_fields(), false, false);
+    let leaves = schema.leaf_fields();
+    let paths: Vec<_> = leaves.iter().map(|l| l.name_path.as_str()).collect();
+    assert_eq!(
+        paths,
+        vec![
+            "id",
+            "strings",
+            "num",
+            "nums",
+            "sub|sub1",
+            "sub|sub2",
+            "sub|sub3",
+            "sub|sub4|sub4sub1",
+            "sub|sub4|sub4sub2",
+            "sub|sub4|sub4sub3",
+            "sub|sub4|sub4sub4",
+            "sub|sub5",
+            "sub|subend",
+        ]
+    );
+    // array of maps is a leaf
+    let sub5 = &leaves[11];
+    assert!(sub5.is_array);
+    assert_eq!(sub5.data_type, Type::Map);
+    // nullable array
+    let sub4sub3 = &leaves[9];
+    assert!(sub4sub3.is_array);
+    assert!(sub4sub3.nullable);
+    // offsets follow the layout order and stay within static bound
+    leaves.windows(2).for_each(|pair| {
+        assert!(pair[0].offset < pair[1].offset);
+    });
+    assert!(leaves.last().unwrap().offset < schema.static_bound);
+    for leaf in &leaves {
+        assert!(schema.field_index.contains_key(&leaf.name_id));
+    }
+}
+
+#[test]
+pub fn cell_too_large() {
+    let schema = Schema::new_checked(
+        "small",
+        None,
+        default_fields(),
+        false,
+        false,
+        DEFAULT_MAX_CELL_SIZE,
+    )
+    .unwrap();
+    assert!(schema.min_cell_size() > schema.static_bound);
+    // 128k fixed u64 fields take 1MB in the static part alone
+    let huge_fields = Field::new(
+        "*",
+        Type::Map,
+        false,
+        false,
+        Some(
+            (0..128 * 1024)
+                .map(|i| Field::new(&format!("f{}", i), Type::U64, false, false, None, vec![]))
+                .collect(),
+        ),
+        vec![],
+    );
+    let res = Schema::new_checked(
+        "huge",
+        None,
+        huge_fields,
+        false,
+        false,
+        DEFAULT_MAX_CELL_SIZE,
+    );
+    match res {
+        Err(SchemaError::CellTooLarge { estimated, max }) => {
+            assert!(estimated > 128 * 1024 * 8);
+            assert_eq!(max, DEFAULT_MAX_CELL_SIZE);
+        }
+        _ => panic!("Schema with huge static part should be rejected"),
+    }
+    // A custom limit is also respected
+    assert!(Schema::new_checked("small", None, default_fields(), false, false, 16).is_err());
+    // Headers of later layouts lead with the layout byte
+    assert_eq!(
+        schema.min_cell_size_in(HeaderLayout::V2),
+        schema.min_cell_size_in(HeaderLayout::V1) + 1
+    );
+    // Checked by the largest header whatever layout the chunks write in
+    assert_eq!(
+        schema.min_cell_size(),
+        schema.min_cell_size_in(HeaderLayout::LARGEST)
+    );
+}
+
+#[test]
+pub fn invalid_fields() {
+    let check = |subs: Vec<Field>| {
+        let fields = Field::new("*", Type::Map, false, false, Some(subs), vec![]);
+        Schema::new_checked("invalid", None, fields, false, false, DEFAULT_MAX_CELL_SIZE)
+    };
+    assert!(check(vec![]).is_ok());
+    assert!(complex_fields().validate().is_ok());
+    let reject = |subs: Vec<Field>, expected_path: &str| match check(subs) {
+        Err(SchemaError::InvalidField { path, .. }) => assert_eq!(path, expected_path),
+        _ => panic!("Expecting invalid field {}", expected_path),
+    };
+    reject(
+        vec![
+            Field::new("id", Type::I64, false, false, None, vec![]),
+            Field::new("id", Type::U64, false, false, None, vec![]),
+        ],
+        "id",
+    );
+    reject(
+        vec![Field::new(
+            "sub",
+            Type::Map,
+            false,
+            false,
+            Some(vec![Field::new(
+                "empty",
+                Type::Map,
+                false,
+                true,
+                Some(vec![]),
+                vec![],
+            )]),
+            vec![],
+        )],
+        "sub|empty",
+    );
+    reject(
+        vec![Field::new(
+            "num",
+            Type::U64,
+            false,
+            false,
+            Some(vec![Field::new(
+                "id",
+                Type::I64,
+                false,
+                false,
+                None,
+                vec![],
+            )]),
+            vec![],
+        )],
+        "num",
+    );
+    // Schemas registered to the cluster are checked the same
+    let duplicated = Field::new(
+        "*",
+        Type::Map,
+        false,
+        false,
+        Some(vec![
+            Field::new("id", Type::I64, false, false, None, vec![]),
+            Field::new("id", Type::U64, false, false, None, vec![]),
+        ]),
+        vec![],
+    );
+    let schema = Schema::new("invalid", None, duplicated, false, false);
+    assert!(matches!(
+        schema.check(),
+        Err(SchemaError::InvalidField { .. })
+    ));
+}
+
+#[test]
+pub fn rename_field() {
+    let mut schema = Schema::new_with_id(1, "renaming", None, default_fields(), false, false);
+    let chunks = Chunks::new_dummy(1, 8 * 1024 * 1024);
+    chunks.list[0].meta.schemas.new_schema(schema.clone());
+    let id1 = Id::new(1, 1);
+    let mut cell = OwnedCell::new_with_id(
+        schema.id,
+        &id1,
+        data_map_value! {
+            id: 1 as i64,
+            score: 70 as u64,
+            name: "Jack"
+        },
+    );
+    chunks.write_cell(&mut cell).unwrap();
+    schema.rename_field("score", "points").unwrap();
+    assert_eq!(schema.field_id("score"), schema.field_id("points"));
+    assert_eq!(schema.field_id("name"), hash_str("name"));
+    assert!(schema.field_index.contains_key(&hash_str("points")));
+    assert!(matches!(
+        schema.clone().rename_field("score", "points"),
+        Err(SchemaError::FieldAlreadyExisted(_))
+    ));
+    assert!(matches!(
+        schema.clone().rename_field("missing", "other"),
+        Err(SchemaError::FieldDoesNotExisted(_))
+    ));
+    chunks.list[0].meta.schemas.new_schema(schema.clone());
+    // Existing cell is readable by both names
+    let stored = chunks.read_cell(&id1).unwrap().to_owned();
+    let map = stored.data.Map().unwrap();
+    assert_eq!(
+        map.get_by_key_id(schema.field_id("score")).u64().unwrap(),
+        &70
+    );
+    assert_eq!(
+        map.get_by_key_id(schema.field_id("points")).u64().unwrap(),
+        &70
+    );
+    // New cells can be written with the new name
+    let id2 = Id::new(1, 2);
+    let mut cell = OwnedCell::new_with_id(
+        schema.id,
+        &id2,
+        data_map_value! {
+            id: 2 as i64,
+            points: 80 as u64,
+            name: "John"
+        },
+    );
+    chunks.write_cell(&mut cell).unwrap();
+    let stored = chunks.read_cell(&id2).unwrap().to_owned();
+    let map = stored.data.Map().unwrap();
+    assert_eq!(
+        map.get_by_key_id(schema.field_id("score")).u64().unwrap(),
+        &80
+    );
+    assert_eq!(
+        map.get_by_key_id(schema.field_id("points")).u64().unwrap(),
+        &80
+    );
+}
+
+#[test]
+pub fn field_paths() {
+    let mut schema = Schema::new_with_id(1, "paths", None, complex_fields(), false, false);
+    for path in &[
+        vec!["id"],
+        vec!["sub"],
+        vec!["sub", "sub1"],
+        vec!["sub", "sub4", "sub4sub3"],
+        vec!["sub", "sub5"],
+    ] {
+        let field_id = schema.field_id_for_path(path).unwrap();
+        assert_eq!(field_id, hash_str(&path.join("|")));
+        assert_eq!(schema.path_for_field_id(field_id).unwrap(), *path);
+        assert!(schema.field_index.contains_key(&field_id));
+    }
+    assert!(schema.field_id_for_path(&["sub", "missing"]).is_none());
+    assert!(schema.field_id_for_path(&["sub4", "sub4sub3"]).is_none());
+    assert!(schema.field_id_for_path(&[]).is_none());
+    assert!(schema.path_for_field_id(hash_str("missing")).is_none());
+    // Renamed fields resolve by both names to the same id and the current name
+    schema.rename_field("num", "number").unwrap();
+    let field_id = schema.field_id_for_path(&["num"]).unwrap();
+    assert_eq!(schema.field_id_for_path(&["number"]), Some(field_id));
+    assert_eq!(schema.path_for_field_id(field_id).unwrap(), vec!["number"]);
+}
+
+#[test]
+pub fn schema_builder() {
+    let built = SchemaBuilder::new()
+        .i64_field("id", false)
+        .string_field("name", false)
+        .u64_field("score", false)
+        .fields();
+    assert_eq!(built, default_fields());
+    let built = SchemaBuilder::new()
+        .i64_field("id", false)
+        .array_of("strings", Type::String, false)
+        .u64_field("num", false)
+        .array_of("nums", Type::U64, true)
+        .nested("sub", false, |sub| {
+            sub.u32_field("sub1", false)
+                .array_of("sub2", Type::U32, false)
+                .u32_field("sub3", false)
+                .nested("sub4", false, |sub4| {
+                    sub4.u32_field("sub4sub1", false)
+                        .array_of("sub4sub2", Type::U32, false)
+                        .array_of("sub4sub3", Type::U64, true)
+                        .u16_field("sub4sub4", false)
+                })
+                .nested_array("sub5", false, |sub5| {
+                    sub5.u32_field("sub5sub1", false)
+                        .array_of("sub5sub2", Type::U32, false)
+                        .array_of("sub5sub3", Type::U64, true)
+                        .u16_field("sub5sub4", false)
+                })
+                .u32_field("subend", false)
+        })
+        .fields();
+    assert_eq!(built, complex_fields());
+    // Same offsets once made into schemas
+    let schema = SchemaBuilder::new()
+        .i64_field("id", false)
+        .string_field("name", false)
+        .u64_field("score", false)
+        .indexed(IndexType::Ranged)
+        .build("built", None, false, false);
+    let expected = Schema::new(
+        "built",
+        None,
+        Field::new(
+            "*",
+            Type::Map,
+            false,
+            false,
+            Some(vec![
+                Field::new("id", Type::I64, false, false, None, vec![]),
+                Field::new("name", Type::String, false, false, None, vec![]),
+                Field::new(
+                    "score",
+                    Type::U64,
+                    false,
+                    false,
+                    None,
+                    vec![IndexType::Ranged],
+                ),
+            ]),
+            vec![],
+        ),
+        false,
+        false,
+    );
+    assert_eq!(schema.fields, expected.fields);
+    assert_eq!(schema.static_bound, expected.static_bound);
+    assert_eq!(schema.leaf_fields(), expected.leaf_fields());
+    assert_eq!(
+        schema.index_fields.get(&hash_str("score")),
+        Some(&vec![IndexType::Ranged])
+    );
+}
+
+#[test]
+pub fn index_type_encoding() {
+    // Index types are persisted and replicated with schemas by their indices
+    let encoded = [
+        IndexType::Ranged,
+        IndexType::Hashed,
+        IndexType::Vectorized,
+        IndexType::Statistics,
+        IndexType::RangedDesc,
+    ]
+    .iter()
+    .map(|index| bincode::deserialize::<u32>(&bincode::serialize(index).unwrap()).unwrap())
+    .collect::<Vec<_>>();
+    assert_eq!(encoded, vec![0, 1, 2, 3, 4]);
+}
+
+#[test]
+pub fn schema_encoding() {
+    use std::collections::HashMap;
+    // Layout of schemas and fields encoded before versioning
+    #[derive(Serialize)]
+    struct FieldV0 {
+        data_type: Type,
+        nullable: bool,
+        is_array: bool,
+        sub_fields: Option<Vec<FieldV0>>,
+        name: String,
+        name_id: u64,
+        indices: Vec<IndexType>,
+        offset: Option<usize>,
+    }
+    #[derive(Serialize)]
+    struct SchemaV0 {
+        id: u32,
+        name: String,
+        key_field: Option<Vec<u64>>,
+        str_key_field: Option<Vec<String>>,
+        field_index: HashMap<u64, Vec<usize>>,
+        id_index: HashMap<u64, Vec<u64>>,
+        index_fields: HashMap<u64, Vec<IndexType>>,
+        fields: FieldV0,
+        static_bound: usize,
+        is_dynamic: bool,
+        is_scannable: bool,
+    }
+    fn field_v0(field: &Field) -> FieldV0 {
+        FieldV0 {
+            data_type: field.data_type,
+            nullable: field.nullable,
+            is_array: field.is_array,
+            sub_fields: field
+                .sub_fields
+                .as_ref()
+                .map(|subs| subs.iter().map(field_v0).collect()),
+            name: field.name.clone(),
+            name_id: field.name_id,
+            indices: field.indices.clone(),
+            offset: field.offset,
+        }
+    }
+    let schema = Schema::new_with_id(1, "old", None, default_fields(), false, false);
+    let old = SchemaV0 {
+        id: schema.id,
+        name: schema.name.clone(),
+        key_field: schema.key_field.clone(),
+        str_key_field: schema.str_key_field.clone(),
+        field_index: schema.field_index.clone(),
+        id_index: schema.id_index.clone(),
+        index_fields: schema.index_fields.clone(),
+        fields: field_v0(&schema.fields),
+        static_bound: schema.static_bound,
+        is_dynamic: schema.is_dynamic,
+        is_scannable: schema.is_scannable,
+    };
+    // Followed by other values, like in state machine snapshots
+    let encoded = bincode::serialize(&(vec![old], 7u32)).unwrap();
+    let (decoded, next): (Vec<Schema>, u32) = bincode::deserialize(&encoded).unwrap();
+    assert_eq!(next, 7);
+    assert_eq!(decoded[0].id, 1);
+    assert_eq!(decoded[0].name, "old");
+    assert_eq!(decoded[0].fields, schema.fields);
+    assert_eq!(decoded[0].static_bound, schema.static_bound);
+    assert_eq!(decoded[0].ttl_secs, None);
+    // Fields added since round trip
+    let fields = Field::new(
+        "*",
+        Type::Map,
+        false,
+        false,
+        Some(vec![
+            Field::new("id", Type::I64, false, false, None, vec![]),
+            Field::new("status", Type::String, false, false, None, vec![])
+                .with_enum_values(&["pending", "active"]),
+            Field::new("score", Type::U64, false, false, None, vec![]).with_ttl(10),
+        ]),
+        vec![],
+    );
+    let schema = Schema::new_with_id(2, "new", None, fields, false, false)
+        .with_ttl(60)
+        .with_composite_index(&["status", "score"])
+        .unwrap();
+    let encoded = bincode::serialize(&(vec![schema.clone()], 7u32)).unwrap();
+    let (decoded, next): (Vec<Schema>, u32) = bincode::deserialize(&encoded).unwrap();
+    assert_eq!(next, 7);
+    assert_eq!(decoded[0].fields, schema.fields);
+    assert_eq!(decoded[0].ttl_secs, Some(60));
+    assert_eq!(decoded[0].composite_indices, schema.composite_indices);
+    let mut reserved = schema.clone();
+    reserved.id = encoding::SCHEMA_ENCODING_MARKER;
+    assert!(matches!(reserved.check(), Err(SchemaError::ReservedId(_))));
+}
+
+#[test]
+pub fn composite_index_fields() {
+    let fields = Field::new(
+        "*",
+        Type::Map,
+        false,
+        false,
+        Some(vec![
+            Field::new("country", Type::String, false, false, None, vec![]),
+            Field::new("city", Type::String, false, false, None, vec![]),
+            Field::new("tags", Type::String, false, true, None, vec![]),
+        ]),
+        vec![],
+    );
+    let schema = Schema::new_with_id(1, "composite", None, fields, false, false);
+    let composite = schema
+        .clone()
+        .with_composite_index(&["country", "city"])
+        .unwrap();
+    assert_eq!(
+        composite.composite_indices,
+        vec![vec![hash_str("country"), hash_str("city")]]
+    );
+    assert!(matches!(
+        schema.clone().with_composite_index(&["country", "zip"]),
+        Err(SchemaError::FieldDoesNotExisted(ref name)) if name == "zip"
+    ));
+    assert!(matches!(
+        schema.clone().with_composite_index(&["country", "tags"]),
+        Err(SchemaError::InvalidField { ref path, .. }) if path == "tags"
+    ));
+    assert!(matches!(
+        schema.with_composite_index(&["country"]),
+        Err(SchemaError::InvalidField { .. })
+    ));
+}
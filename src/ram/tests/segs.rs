@@ -0,0 +1,121 @@
+use crate::ram::entry::{Entry, EntryType};
+use crate::ram::segs::{self, ScanError, Segment, SEGMENT_SIZE};
+
+#[test]
+pub fn acquire_oversized() {
+    // Segments never touch the buffer on acquisition, the address is only used for bookkeeping
+    let seg = Segment::new(0, 4096, &None, &None);
+    assert!(seg.try_acquire(u32::MAX).is_none());
+    assert!(seg.try_acquire(SEGMENT_SIZE as u32 + 1).is_none());
+    assert_eq!(seg.try_acquire(SEGMENT_SIZE as u32), Some(4096));
+    assert!(seg.try_acquire(1).is_none());
+}
+
+#[test]
+pub fn acquire_overflow() {
+    // The bound of this segment is the end of the address space
+    let addr = usize::MAX - SEGMENT_SIZE;
+    let seg = Segment::new(0, addr, &None, &None);
+    let filled = SEGMENT_SIZE as u32 - 8;
+    assert_eq!(seg.try_acquire(filled), Some(addr));
+    assert!(seg.try_acquire(u32::MAX).is_none());
+    // Passes the size bound but overflows from the current append header
+    assert!(seg.try_acquire(SEGMENT_SIZE as u32).is_none());
+    assert_eq!(seg.try_acquire(8), Some(addr + filled as usize));
+    assert!(seg.try_acquire(1).is_none());
+}
+
+#[test]
+pub fn scan_corrupt_entry() {
+    let mut buffer = vec![0u8; 4096];
+    let seg = Segment::new(0, buffer.as_mut_ptr() as usize, &None, &None);
+    let entry_size = Entry::size(1, 8);
+    let entry_pos = (0..4)
+        .map(|_| {
+            let pos = seg.try_acquire(entry_size).unwrap();
+            Entry::encode_to(pos, EntryType::CELL, 8, 1, |_| {});
+            pos
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(seg.entry_iter().count(), 4);
+    let corrupt = |pos: usize, byte: u8| unsafe { *(pos as *mut u8) = byte };
+    // Content length running past the bound
+    corrupt(entry_pos[2] + 1, u8::MAX);
+    let mut iter = seg.entry_iter();
+    let entries = iter.by_ref().collect::<Vec<_>>();
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[1].entry_pos, entry_pos[1]);
+    assert_eq!(
+        iter.error(),
+        Some(ScanError::CorruptEntry {
+            offset: entry_size as usize * 2
+        })
+    );
+    assert!(iter.next().is_none());
+    // Length bytes beyond a u32
+    corrupt(entry_pos[1], EntryType::CELL.bits() | 0b1111);
+    let mut iter = seg.entry_iter();
+    assert_eq!(iter.by_ref().count(), 1);
+    assert_eq!(
+        iter.error(),
+        Some(ScanError::CorruptEntry {
+            offset: entry_size as usize
+        })
+    );
+    // Unknown entry type
+    corrupt(entry_pos[0], 0b1000_0001);
+    let mut iter = seg.entry_iter();
+    assert_eq!(iter.by_ref().count(), 0);
+    assert_eq!(iter.error(), Some(ScanError::CorruptEntry { offset: 0 }));
+}
+
+#[test]
+pub fn scan_streamed_entries() {
+    let mut buffer = vec![0u8; 4096];
+    let seg = Segment::new(0, buffer.as_mut_ptr() as usize, &None, &None);
+    let entry_size = Entry::size(1, 8);
+    for i in 0..4 {
+        let pos = seg.try_acquire(entry_size).unwrap();
+        Entry::encode_to(pos, EntryType::CELL, 8, 1, |body_pos| unsafe {
+            *(body_pos as *mut u8) = i
+        });
+    }
+    let content = buffer[..entry_size as usize * 4].to_vec();
+    let scan = |content: &[u8]| {
+        let mut entries = vec![];
+        let error = segs::scan_entries(content, |offset, entry_meta| {
+            let first_byte = unsafe { *(entry_meta.body_pos as *const u8) };
+            entries.push((offset, entry_meta.entry_size, first_byte));
+        })
+        .unwrap();
+        (entries, error)
+    };
+    let (entries, error) = scan(&content);
+    assert!(error.is_none());
+    assert_eq!(
+        entries,
+        (0..4)
+            .map(|i| (i * entry_size as usize, entry_size as usize, i as u8))
+            .collect::<Vec<_>>()
+    );
+    // Content cut short in the last entry
+    let (entries, error) = scan(&content[..content.len() - 1]);
+    assert_eq!(entries.len(), 3);
+    assert_eq!(
+        error,
+        Some(ScanError::CorruptEntry {
+            offset: entry_size as usize * 3
+        })
+    );
+    // Unknown entry type
+    let mut corrupted = content.clone();
+    corrupted[entry_size as usize] = 0b1000_0001;
+    let (entries, error) = scan(&corrupted);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(
+        error,
+        Some(ScanError::CorruptEntry {
+            offset: entry_size as usize
+        })
+    );
+}
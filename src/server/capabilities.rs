@@ -0,0 +1,33 @@
+use super::Service as NebService;
+use bifrost::rpc::*;
+use bifrost_plugins::hash_ident;
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use std::sync::Arc;
+
+pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(NEB_CAPABILITIES_RPC_SERVICE) as u64;
+
+// Registered on every server, for clients to tell services not offered from failures of them
+service! {
+    rpc capabilities() -> Vec<NebService>;
+}
+
+pub struct CapabilitiesService {
+    services: Vec<NebService>,
+}
+
+impl Service for CapabilitiesService {
+    fn capabilities(&self) -> BoxFuture<Vec<NebService>> {
+        future::ready(self.services.clone()).boxed()
+    }
+}
+
+dispatch_rpc_service_functions!(CapabilitiesService);
+
+impl CapabilitiesService {
+    pub fn new(services: &Vec<NebService>) -> Arc<Self> {
+        Arc::new(Self {
+            services: services.clone(),
+        })
+    }
+}
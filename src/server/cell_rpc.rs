@@ -1,12 +1,17 @@
-use crate::ram::types::Id;
+use crate::ram::types::{Id, OwnedValue, SharedValue};
 use crate::server::NebServer;
 use crate::{
     index::builder::IndexBuilder,
+    ram::aggregate::{self, Aggregation, GroupedAggResult},
     ram::cell::{CellHeader, OwnedCell, ReadError, WriteError},
+    ram::schema::{IndexType, Schema},
 };
 use bifrost::rpc::*;
 use futures::future::BoxFuture;
 use futures::prelude::*;
+use linked_hash_map::LinkedHashMap;
+use parking_lot::Mutex;
+use std::time::{Duration, Instant};
 
 use bifrost_plugins::hash_ident;
 
@@ -15,15 +20,125 @@ pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(NEB_CELL_RPC_SERVICE) as u64;
 service! {
     rpc read_cell(key: Id) -> Result<OwnedCell, ReadError>;
     rpc read_all_cells(keys: Vec<Id>) -> Vec<Result<OwnedCell, ReadError>>;
+    rpc read_cell_raw(key: Id) -> Result<Vec<u8>, ReadError>;
+    rpc read_cell_selected(key: Id, fields: Vec<u64>) -> Result<Vec<OwnedValue>, ReadError>;
+    rpc read_cell_frame(key: Id, offset: u64, frame_size: u32) -> Result<CellFrame, ReadError>;
     rpc write_cell(cell:OwnedCell) -> Result<CellHeader, WriteError>;
+    rpc write_cell_dedup(cell: OwnedCell) -> Result<CellHeader, WriteError>;
     rpc update_cell(cell: OwnedCell) -> Result<CellHeader, WriteError>;
     rpc upsert_cell(cell: OwnedCell) -> Result<CellHeader, WriteError>;
+    rpc cas_cell(cell: OwnedCell, expected_version: u64) -> Result<CellHeader, WriteError>;
     rpc remove_cell(key: Id) -> Result<(), WriteError>;
+    rpc remove_cell_if_version(key: Id, expected_version: u64) -> Result<(), WriteError>;
+    rpc write_all_cells(cells: Vec<OwnedCell>) -> Vec<Result<CellHeader, WriteError>>;
+    rpc upsert_all_cells(cells: Vec<OwnedCell>) -> Vec<Result<CellHeader, WriteError>>;
+    rpc remove_all_cells(keys: Vec<Id>) -> Vec<Result<(), WriteError>>;
     rpc count() -> u64;
+    rpc cell_ids_of_schema(schema_id: u32) -> Vec<Id>;
+    rpc chunk_id_for(key: Id) -> u64;
+    rpc aggregate_grouped(schema_id: u32, group_by: u64, agg_field: u64, agg: Aggregation, max_groups: u32) -> GroupedAggResult;
+    rpc backfill_index(schema: Schema, field_id: u64, index: IndexType) -> u64;
+    rpc track_dependent(source: Id, dependent: Id);
+    rpc refresh_derived(key: Id, source: Id) -> Result<bool, WriteError>;
+    rpc migrate_unowned_cells() -> u64;
+}
+
+// Large cells are read in frames of their serialized form, fetched by offsets
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum CellFrame {
+    // The cell fits in a single frame
+    Whole(OwnedCell),
+    Part {
+        version: u64,
+        total: u64,
+        data: Vec<u8>,
+    },
+}
+
+const FRAMED_READS_MAX_BYTES: usize = 64 * 1024 * 1024;
+const FRAMED_READS_MAX_IDLE: Duration = Duration::from_secs(30);
+
+// Serialized cells of the reads in frames in progress. Reads abandoned by their clients are
+// dropped once idle for a while, and the least recently read ones are dropped first to keep the
+// cached bytes bounded. Readers of dropped cells get them serialized again.
+pub struct FramedReads {
+    reads: LinkedHashMap<Id, FramedRead>,
+    bytes: usize,
+    max_bytes: usize,
+    max_idle: Duration,
+}
+
+struct FramedRead {
+    version: u64,
+    data: Arc<Vec<u8>>,
+    accessed: Instant,
+}
+
+impl FramedReads {
+    pub fn new(max_bytes: usize, max_idle: Duration) -> Self {
+        Self {
+            reads: LinkedHashMap::new(),
+            bytes: 0,
+            max_bytes,
+            max_idle,
+        }
+    }
+    pub fn get(&mut self, key: &Id, version: u64) -> Option<Arc<Vec<u8>>> {
+        self.expire();
+        let read = self.reads.get_refresh(key)?;
+        if read.version != version {
+            return None;
+        }
+        read.accessed = Instant::now();
+        Some(read.data.clone())
+    }
+    pub fn insert(&mut self, key: Id, version: u64, data: Arc<Vec<u8>>) {
+        self.remove(&key);
+        self.expire();
+        if data.len() > self.max_bytes {
+            return;
+        }
+        while self.bytes + data.len() > self.max_bytes {
+            self.pop_front();
+        }
+        self.bytes += data.len();
+        let accessed = Instant::now();
+        self.reads.insert(
+            key,
+            FramedRead {
+                version,
+                data,
+                accessed,
+            },
+        );
+    }
+    pub fn remove(&mut self, key: &Id) {
+        if let Some(read) = self.reads.remove(key) {
+            self.bytes -= read.data.len();
+        }
+    }
+    pub fn cached_bytes(&self) -> usize {
+        self.bytes
+    }
+    // Reads are ordered by their last access
+    fn expire(&mut self) {
+        while let Some((_, read)) = self.reads.front() {
+            if read.accessed.elapsed() < self.max_idle {
+                break;
+            }
+            self.pop_front();
+        }
+    }
+    fn pop_front(&mut self) {
+        if let Some((_, read)) = self.reads.pop_front() {
+            self.bytes -= read.data.len();
+        }
+    }
 }
 
 pub struct NebRPCService {
     server: Arc<NebServer>,
+    framed_reads: Mutex<FramedReads>,
 }
 
 impl Service for NebRPCService {
@@ -38,30 +153,281 @@ impl Service for NebRPCService {
         )
         .boxed()
     }
+    fn read_cell_raw(&self, key: Id) -> BoxFuture<Result<Vec<u8>, ReadError>> {
+        future::ready(self.server.chunks.read_cell_raw(&key)).boxed()
+    }
+    fn read_cell_selected(
+        &self,
+        key: Id,
+        fields: Vec<u64>,
+    ) -> BoxFuture<Result<Vec<OwnedValue>, ReadError>> {
+        let res = self
+            .server
+            .chunks
+            .read_selected(&key, &fields)
+            .map(|(value, _schema)| match (fields.len(), value) {
+                // A single field is read as its value, more fields as an array of values
+                (1, value) => vec![value.owned()],
+                (_, SharedValue::Array(values)) => values.iter().map(|v| v.owned()).collect(),
+                _ => vec![],
+            });
+        future::ready(res).boxed()
+    }
+    fn read_cell_frame(
+        &self,
+        key: Id,
+        offset: u64,
+        frame_size: u32,
+    ) -> BoxFuture<Result<CellFrame, ReadError>> {
+        future::ready(self.cell_frame(key, offset, frame_size as usize)).boxed()
+    }
     fn write_cell(&self, mut cell: OwnedCell) -> BoxFuture<Result<CellHeader, WriteError>> {
-        self.with_indices_ensured(self.server.chunks.write_cell(&mut cell))
+        async move {
+            let sources = self.server.resolve_derived_fields(&mut cell).await?;
+            let res = self.server.chunks.write_cell(&mut cell);
+            if res.is_ok() {
+                self.server.derived_cell_written(cell.id(), sources).await;
+            }
+            self.with_indices_ensured(res).await
+        }
+        .boxed()
+    }
+    fn write_cell_dedup(&self, mut cell: OwnedCell) -> BoxFuture<Result<CellHeader, WriteError>> {
+        async move {
+            let sources = self.server.resolve_derived_fields(&mut cell).await?;
+            let res = self.server.chunks.write_cell_dedup(&mut cell);
+            match &res {
+                // Only track the sources when the cell is actually written
+                Ok(header) if header.id() == cell.id() => {
+                    self.server.derived_cell_written(cell.id(), sources).await
+                }
+                _ => {}
+            }
+            self.with_indices_ensured(res).await
+        }
+        .boxed()
     }
 
     fn update_cell(&self, mut cell: OwnedCell) -> BoxFuture<Result<CellHeader, WriteError>> {
-        self.with_indices_ensured(self.server.chunks.update_cell(&mut cell))
+        async move {
+            let sources = self.server.resolve_derived_fields(&mut cell).await?;
+            let res = self.server.chunks.update_cell(&mut cell);
+            if res.is_ok() {
+                self.server.derived_cell_written(cell.id(), sources).await;
+            }
+            self.with_indices_ensured(res).await
+        }
+        .boxed()
     }
     fn remove_cell(&self, key: Id) -> BoxFuture<Result<(), WriteError>> {
-        self.with_indices_ensured(self.server.chunks.remove_cell(&key))
+        let res = self.server.chunks.remove_cell(&key);
+        if res.is_ok() {
+            self.server.dependents.remove_source(&key);
+        }
+        self.with_indices_ensured(res)
+    }
+    fn remove_cell_if_version(
+        &self,
+        key: Id,
+        expected_version: u64,
+    ) -> BoxFuture<Result<(), WriteError>> {
+        let res = self
+            .server
+            .chunks
+            .remove_cell_if_version(&key, expected_version);
+        if res.is_ok() {
+            self.server.dependents.remove_source(&key);
+        }
+        self.with_indices_ensured(res)
     }
     fn upsert_cell(&self, mut cell: OwnedCell) -> BoxFuture<Result<CellHeader, WriteError>> {
-        self.with_indices_ensured(self.server.chunks.upsert_cell(&mut cell))
+        async move {
+            let sources = self.server.resolve_derived_fields(&mut cell).await?;
+            let res = self.server.chunks.upsert_cell(&mut cell);
+            if res.is_ok() {
+                self.server.derived_cell_written(cell.id(), sources).await;
+            }
+            self.with_indices_ensured(res).await
+        }
+        .boxed()
+    }
+    fn cas_cell(
+        &self,
+        mut cell: OwnedCell,
+        expected_version: u64,
+    ) -> BoxFuture<Result<CellHeader, WriteError>> {
+        async move {
+            let sources = self.server.resolve_derived_fields(&mut cell).await?;
+            let res = self.server.chunks.cas_cell(&mut cell, expected_version);
+            if res.is_ok() {
+                self.server.derived_cell_written(cell.id(), sources).await;
+            }
+            self.with_indices_ensured(res).await
+        }
+        .boxed()
+    }
+    fn write_all_cells(
+        &self,
+        cells: Vec<OwnedCell>,
+    ) -> BoxFuture<Vec<Result<CellHeader, WriteError>>> {
+        async move {
+            let mut res = Vec::with_capacity(cells.len());
+            for cell in cells {
+                res.push(self.write_cell(cell).await);
+            }
+            res
+        }
+        .boxed()
+    }
+    fn upsert_all_cells(
+        &self,
+        cells: Vec<OwnedCell>,
+    ) -> BoxFuture<Vec<Result<CellHeader, WriteError>>> {
+        async move {
+            let mut res = Vec::with_capacity(cells.len());
+            for cell in cells {
+                res.push(self.upsert_cell(cell).await);
+            }
+            res
+        }
+        .boxed()
+    }
+    fn remove_all_cells(&self, keys: Vec<Id>) -> BoxFuture<Vec<Result<(), WriteError>>> {
+        async move {
+            let mut res = Vec::with_capacity(keys.len());
+            for key in keys {
+                res.push(self.remove_cell(key).await);
+            }
+            res
+        }
+        .boxed()
     }
     fn count(&self) -> BoxFuture<u64> {
         future::ready(self.server.chunks.count() as u64).boxed()
     }
+    fn cell_ids_of_schema(&self, schema_id: u32) -> BoxFuture<Vec<Id>> {
+        future::ready(self.server.chunks.cell_ids_of_schema(schema_id)).boxed()
+    }
+    fn chunk_id_for(&self, key: Id) -> BoxFuture<u64> {
+        future::ready(self.server.chunks.chunk_id_for(&key) as u64).boxed()
+    }
+    fn aggregate_grouped(
+        &self,
+        schema_id: u32,
+        group_by: u64,
+        agg_field: u64,
+        agg: Aggregation,
+        max_groups: u32,
+    ) -> BoxFuture<GroupedAggResult> {
+        future::ready(aggregate::aggregate_grouped(
+            &self.server.chunks,
+            schema_id,
+            group_by,
+            agg_field,
+            agg,
+            max_groups as usize,
+        ))
+        .boxed()
+    }
+    // Build the index of the field for cells already in this server, returns the number of cells
+    fn backfill_index(&self, schema: Schema, field_id: u64, index: IndexType) -> BoxFuture<u64> {
+        // Cells written from now on are indexed on write
+        self.server.meta.schemas.new_schema(schema.clone());
+        let indexer = match self.server.indexer {
+            Some(ref indexer) => indexer,
+            None => return future::ready(0).boxed(),
+        };
+        // Only the new index is missing from the existing cells
+        let mut index_schema = schema;
+        index_schema.is_scannable = false;
+        index_schema.index_fields = vec![(field_id, vec![index])].into_iter().collect();
+        index_schema.composite_indices.clear();
+        let mut num_cells = 0;
+        for id in self.server.chunks.cell_ids_of_schema(index_schema.id) {
+            // Removed cells are skipped
+            if let Ok(cell) = self.server.chunks.read_cell(&id) {
+                indexer.ensure_indices(&cell.to_owned(), &index_schema, None);
+                num_cells += 1;
+            }
+        }
+        IndexBuilder::await_indices()
+            .map(move |_| num_cells)
+            .boxed()
+    }
+    fn track_dependent(&self, source: Id, dependent: Id) -> BoxFuture<()> {
+        self.server.dependents.add(source, dependent);
+        future::ready(()).boxed()
+    }
+    fn refresh_derived(&self, key: Id, source: Id) -> BoxFuture<Result<bool, WriteError>> {
+        self.server.refresh_derived(key, Some(source))
+    }
+    // Move cells of this server remapped to other servers, after the table changed elsewhere
+    fn migrate_unowned_cells(&self) -> BoxFuture<u64> {
+        async move {
+            if !self.server.consh.init_table().await.is_ok() {
+                error!("Cannot rebuild member table to migrate cells");
+                return 0;
+            }
+            self.server.migrate_unowned_cells().await as u64
+        }
+        .boxed()
+    }
 }
 
 dispatch_rpc_service_functions!(NebRPCService);
 
 impl NebRPCService {
     pub fn new(server: &Arc<NebServer>) -> Arc<NebRPCService> {
-        Arc::new(NebRPCService {
+        let service = NebRPCService {
             server: server.clone(),
+            framed_reads: Mutex::new(FramedReads::new(
+                FRAMED_READS_MAX_BYTES,
+                FRAMED_READS_MAX_IDLE,
+            )),
+        };
+        Arc::new(service)
+    }
+    fn cell_frame(&self, key: Id, offset: u64, frame_size: usize) -> Result<CellFrame, ReadError> {
+        // Empty frames never advance the reader
+        if frame_size == 0 {
+            return Err(ReadError::InvalidFrameSize);
+        }
+        let chunks = &self.server.chunks;
+        // Serialize once per version, readers seeing a new version start over
+        let cached = if offset == 0 {
+            None
+        } else {
+            let version = chunks.head_cell(&key)?.version;
+            self.framed_reads
+                .lock()
+                .get(&key, version)
+                .map(|data| (version, data))
+        };
+        let (version, data) = match cached {
+            Some(cached) => cached,
+            None => {
+                let cell = chunks.read_cell(&key)?.to_owned();
+                // Cells fitting in a frame are sent as they are, only sized here
+                if offset == 0 && bincode::serialized_size(&cell).unwrap() as usize <= frame_size {
+                    return Ok(CellFrame::Whole(cell));
+                }
+                let data = Arc::new(bincode::serialize(&cell).unwrap());
+                self.framed_reads
+                    .lock()
+                    .insert(key, cell.header.version, data.clone());
+                (cell.header.version, data)
+            }
+        };
+        let total = data.len();
+        let start = (offset as usize).min(total);
+        let end = (start + frame_size).min(total);
+        if end == total {
+            self.framed_reads.lock().remove(&key);
+        }
+        Ok(CellFrame::Part {
+            version,
+            total: total as u64,
+            data: data[start..end].to_vec(),
         })
     }
     fn with_indices_ensured<'a, R>(&'a self, res: R) -> BoxFuture<R>
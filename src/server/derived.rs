@@ -0,0 +1,239 @@
+use super::cell_rpc::{AsyncServiceClient, DEFAULT_SERVICE_ID};
+use super::{rpc_client_by_id, NebServer};
+use crate::index::builder::IndexBuilder;
+use crate::ram::cell::{OwnedCell, ReadError, WriteError};
+use crate::ram::types::{Id, OwnedValue};
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+const DEPENDENT_SHARDS: usize = 64;
+
+// source cell id -> cells with fields derived from it, sharded by the source ids.
+// Kept by the server owning the sources. Servers register the derived cells they store with the
+// owners of the sources on write, and from the stored cells on start.
+pub struct Dependents {
+    shards: Vec<Mutex<HashMap<Id, HashSet<Id>>>>,
+}
+
+impl Dependents {
+    pub fn new() -> Dependents {
+        Dependents {
+            shards: (0..DEPENDENT_SHARDS)
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+        }
+    }
+    fn shard(&self, source: &Id) -> &Mutex<HashMap<Id, HashSet<Id>>> {
+        &self.shards[(source.higher ^ source.lower) as usize % DEPENDENT_SHARDS]
+    }
+    pub fn add(&self, source: Id, dependent: Id) {
+        self.shard(&source)
+            .lock()
+            .entry(source)
+            .or_insert_with(|| HashSet::new())
+            .insert(dependent);
+    }
+    pub fn remove(&self, source: &Id, dependent: &Id) {
+        if let Some(ids) = self.shard(source).lock().get_mut(source) {
+            ids.remove(dependent);
+        }
+    }
+    // Cells derived from the removed source will keep their last values
+    pub fn remove_source(&self, source: &Id) {
+        self.shard(source).lock().remove(source);
+    }
+    // Cells in the cluster with fields derived from the source
+    pub fn of(&self, source: &Id) -> Vec<Id> {
+        match self.shard(source).lock().get(source) {
+            Some(ids) => ids.iter().cloned().collect(),
+            None => vec![],
+        }
+    }
+}
+
+impl NebServer {
+    // Inline values of derived fields from their source cells, returns ids of the sources
+    pub async fn resolve_derived_fields(
+        self: &Arc<Self>,
+        cell: &mut OwnedCell,
+    ) -> Result<Vec<Id>, WriteError> {
+        let schema = match self.meta.schemas.get(&cell.header.schema) {
+            Some(schema) => schema,
+            None => return Ok(vec![]), // will be rejected by the chunk
+        };
+        let mut sources = vec![];
+        for field in schema.derived_fields() {
+            let derived = field.derived_from.as_ref().unwrap();
+            let source_id = match &cell.data[&*derived.id_field] {
+                &OwnedValue::Id(id) => id,
+                _ => continue,
+            };
+            let source = self.read_source_cell(source_id).await?;
+            cell.data[&*field.name] = source.data[&*derived.source_field].clone();
+            sources.push(source_id);
+        }
+        Ok(sources)
+    }
+
+    async fn read_source_cell(self: &Arc<Self>, id: Id) -> Result<OwnedCell, WriteError> {
+        if self.get_server_id_by_id(&id) == Some(self.server_id) {
+            return self
+                .chunks
+                .read_cell(&id)
+                .map(|c| c.to_owned())
+                .map_err(|e| WriteError::ReadError(e));
+        }
+        let client = rpc_client_by_id(&id, self)
+            .await
+            .map_err(|_| WriteError::NetworkingError)?;
+        AsyncServiceClient::new(DEFAULT_SERVICE_ID, &client)
+            .read_cell(id)
+            .await
+            .map_err(|_| WriteError::NetworkingError)?
+            .map_err(|e| WriteError::ReadError(e))
+    }
+
+    // Ids of the cells the derived fields of the cell are looked up from
+    fn derived_sources(&self, cell: &OwnedCell) -> Vec<Id> {
+        let schema = match self.meta.schemas.get(&cell.header.schema) {
+            Some(schema) => schema,
+            None => return vec![],
+        };
+        schema
+            .derived_fields()
+            .into_iter()
+            .filter_map(|field| {
+                let derived = field.derived_from.as_ref().unwrap();
+                match &cell.data[&*derived.id_field] {
+                    &OwnedValue::Id(id) => Some(id),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    // Register a derived cell with the servers owning its sources
+    pub async fn track_sources(self: &Arc<Self>, dependent: Id, sources: Vec<Id>) {
+        for source in sources {
+            if self.get_server_id_by_id(&source) == Some(self.server_id) {
+                self.dependents.add(source, dependent);
+                continue;
+            }
+            let res = match rpc_client_by_id(&source, self).await {
+                Ok(client) => {
+                    AsyncServiceClient::new(DEFAULT_SERVICE_ID, &client)
+                        .track_dependent(source, dependent)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = res {
+                warn!(
+                    "Cannot track cell {:?} derived from {:?}, {:?}",
+                    dependent, source, e
+                );
+            }
+        }
+    }
+
+    // Track the sources of a cell just written and refresh the cells derived from it
+    pub async fn derived_cell_written(self: &Arc<Self>, id: Id, sources: Vec<Id>) {
+        self.track_sources(id, sources).await;
+        self.refresh_dependents(id).await;
+    }
+
+    // Refresh the cells derived from the source on the servers owning them
+    pub fn refresh_dependents(self: &Arc<Self>, source: Id) -> BoxFuture<'_, ()> {
+        async move {
+            for dependent in self.dependents.of(&source) {
+                let res = if self.get_server_id_by_id(&dependent) == Some(self.server_id) {
+                    self.refresh_derived(dependent, Some(source)).await
+                } else {
+                    match rpc_client_by_id(&dependent, self).await {
+                        Ok(client) => AsyncServiceClient::new(DEFAULT_SERVICE_ID, &client)
+                            .refresh_derived(dependent, source)
+                            .await
+                            .unwrap_or(Err(WriteError::NetworkingError)),
+                        Err(_) => Err(WriteError::NetworkingError),
+                    }
+                };
+                match res {
+                    Ok(true) => {}
+                    // dependent have been removed or no longer derived from the source
+                    Ok(false) | Err(WriteError::CellDoesNotExisted) => {
+                        self.dependents.remove(&source, &dependent)
+                    }
+                    Err(e) => warn!("Cannot refresh derived fields of {:?}, {:?}", dependent, e),
+                }
+            }
+        }
+        .boxed()
+    }
+
+    // Resolve the derived fields of a stored cell again and write it like any other update when
+    // they changed. Without a source, the cell was committed by a transaction that does not
+    // resolve derived fields, its sources are tracked and its dependents refreshed regardless.
+    // Returns false when the cell is not derived from the source.
+    pub fn refresh_derived(
+        self: &Arc<Self>,
+        id: Id,
+        source: Option<Id>,
+    ) -> BoxFuture<'_, Result<bool, WriteError>> {
+        async move {
+            let stored = match self.chunks.read_cell(&id) {
+                Ok(cell) => cell.to_owned(),
+                Err(ReadError::CellDoesNotExisted) => return Err(WriteError::CellDoesNotExisted),
+                Err(e) => return Err(WriteError::ReadError(e)),
+            };
+            if let Some(source) = source {
+                if !self.derived_sources(&stored).contains(&source) {
+                    return Ok(false);
+                }
+            }
+            let mut cell = stored.clone();
+            let sources = self.resolve_derived_fields(&mut cell).await?;
+            if source.is_none() {
+                self.track_sources(id, sources).await;
+            }
+            // Unchanged values stop the refresh, also for cells derived from each other
+            let changed = cell.data != stored.data;
+            if changed {
+                match self.chunks.cas_cell(&mut cell, stored.header.version) {
+                    Ok(_) => {
+                        if self.indexer.is_some() {
+                            IndexBuilder::await_indices().await;
+                        }
+                    }
+                    // Written meanwhile, the writer have resolved the derived fields by itself
+                    Err(WriteError::CellVersionMismatch(_)) => return Ok(true),
+                    Err(e) => return Err(e),
+                }
+            }
+            if changed || source.is_none() {
+                self.refresh_dependents(id).await;
+            }
+            Ok(true)
+        }
+        .boxed()
+    }
+
+    // Track the sources of derived cells already in the chunks, like the ones restored from backups
+    pub async fn track_stored_dependents(self: &Arc<Self>) {
+        for schema in self.meta.schemas.get_all() {
+            if schema.derived_fields().is_empty() {
+                continue;
+            }
+            for id in self.chunks.cell_ids_of_schema(schema.id) {
+                let cell = match self.chunks.read_cell(&id) {
+                    Ok(cell) => cell.to_owned(),
+                    Err(_) => continue,
+                };
+                let sources = self.derived_sources(&cell);
+                self.track_sources(id, sources).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use crate::ram::cell::{CellHeader, OwnedCell, ReadError, WriteError};
+use crate::ram::types::Id;
+use crate::server::cell_rpc::{AsyncServiceClient, DEFAULT_SERVICE_ID};
+use crate::server::NebServer;
+use bifrost::rpc::RPCError;
+use std::collections::HashMap;
+
+#[derive(Debug)]
+pub enum MigrationError {
+    TargetIsSource,
+    ReadError(ReadError),
+    // Cannot write the cell to the target, it stays on the source
+    TargetWriteError(WriteError),
+    // The target already has a cell of the id, both are left as they are
+    TargetHasCell,
+    // The cell changed or is gone on the source during the migration, the copy on the target
+    // has been removed
+    SourceChanged(WriteError),
+    RPCError(RPCError),
+}
+
+impl NebServer {
+    // Move a cell of this server to the target server, for rebalancing.
+    // The cell is written to the target before it is removed here, and removed from the target
+    // again if it changed here in between. It can be found on both servers during the move.
+    // Cells of the target are never overwritten or removed.
+    pub async fn migrate_cell(&self, id: Id, target: u64) -> Result<CellHeader, MigrationError> {
+        self.migrate_cells(vec![id], target).await?.pop().unwrap().1
+    }
+
+    // Move cells of this server with ids in [start, end) to the target server in one batch
+    pub async fn migrate_range(
+        &self,
+        start: &Id,
+        end: &Id,
+        target: u64,
+    ) -> Result<Vec<(Id, Result<CellHeader, MigrationError>)>, MigrationError> {
+        let ids = self.chunks.cell_ids_in_range(start, end);
+        debug!(
+            "Migrating {} cells in {:?} to {:?} to server {}",
+            ids.len(),
+            start,
+            end,
+            target
+        );
+        self.migrate_cells(ids, target).await
+    }
+
+    // Move the cells of this server owned by other servers in the consistent hash table, like
+    // after reweighting, in one batch per owner. Returns the number of cells moved.
+    pub async fn migrate_unowned_cells(&self) -> usize {
+        let mut by_owner: HashMap<u64, Vec<Id>> = HashMap::new();
+        for id in self.chunks.cell_ids() {
+            match self.get_server_id_by_id(&id) {
+                Some(owner) if owner != self.server_id => {
+                    by_owner.entry(owner).or_insert_with(|| vec![]).push(id)
+                }
+                _ => {}
+            }
+        }
+        let mut moved = 0;
+        for (owner, ids) in by_owner {
+            match self.migrate_cells(ids, owner).await {
+                Ok(results) => {
+                    for (id, res) in results {
+                        match res {
+                            Ok(_) => moved += 1,
+                            Err(e) => warn!("Cannot move cell {:?} to {}, {:?}", id, owner, e),
+                        }
+                    }
+                }
+                Err(e) => error!("Cannot move cells to server {}, {:?}", owner, e),
+            }
+        }
+        moved
+    }
+
+    // Fails as a whole only when the batch cannot be sent to the target, nothing is moved then
+    pub async fn migrate_cells(
+        &self,
+        ids: Vec<Id>,
+        target: u64,
+    ) -> Result<Vec<(Id, Result<CellHeader, MigrationError>)>, MigrationError> {
+        if target == self.server_id {
+            return Err(MigrationError::TargetIsSource);
+        }
+        let client = self
+            .get_member_by_server_id(target)
+            .await
+            .map_err(|e| MigrationError::RPCError(RPCError::IOError(e)))?;
+        let client = AsyncServiceClient::new(DEFAULT_SERVICE_ID, &client);
+        let mut results = Vec::with_capacity(ids.len());
+        let mut cells = vec![];
+        let mut versions = vec![];
+        for id in ids {
+            match self.chunks.read_cell(&id) {
+                Ok(cell) => {
+                    let cell: OwnedCell = cell.to_owned();
+                    versions.push((id, cell.header.version));
+                    cells.push(cell);
+                }
+                Err(e) => results.push((id, Err(MigrationError::ReadError(e)))),
+            }
+        }
+        if cells.is_empty() {
+            return Ok(results);
+        }
+        let written = client
+            .write_all_cells(cells)
+            .await
+            .map_err(MigrationError::RPCError)?;
+        let mut rollbacks = vec![];
+        for ((id, version), res) in versions.into_iter().zip(written) {
+            let header = match res {
+                Ok(header) => header,
+                Err(WriteError::CellAlreadyExisted) => {
+                    warn!("Cell {:?} exists on target server {}", id, target);
+                    results.push((id, Err(MigrationError::TargetHasCell)));
+                    continue;
+                }
+                Err(e) => {
+                    results.push((id, Err(MigrationError::TargetWriteError(e))));
+                    continue;
+                }
+            };
+            match self
+                .chunks
+                .remove_cell_by(&id, |cell| cell.header.version == version)
+            {
+                Ok(()) => results.push((id, Ok(header))),
+                Err(e) => {
+                    warn!("Cell {:?} changed during migration, rolling back", id);
+                    rollbacks.push(id);
+                    results.push((id, Err(MigrationError::SourceChanged(e))));
+                }
+            }
+        }
+        // Only cells created on the target by this migration are rolled back
+        if !rollbacks.is_empty() {
+            match client.remove_all_cells(rollbacks.clone()).await {
+                Ok(removed) => {
+                    for (id, res) in rollbacks.iter().zip(removed) {
+                        if let Err(e) = res {
+                            error!("Cannot roll back migrated cell {:?}, {:?}", id, e);
+                        }
+                    }
+                }
+                Err(e) => error!(
+                    "Cannot roll back {} migrated cells, {:?}",
+                    rollbacks.len(),
+                    e
+                ),
+            }
+        }
+        Ok(results)
+    }
+}
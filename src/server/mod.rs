@@ -15,20 +15,29 @@ use bifrost::vector_clock::ServerVectorClock;
 use bifrost_plugins::hash_ident;
 // use crate::index::lsmtree;
 use crate::index::ranged;
-use crate::ram::chunk::Chunks;
+use crate::query::statistics::manager::{StatisticsConfig, StatisticsManager};
+use crate::ram::chunk::{self, Chunks};
+pub use crate::ram::chunk::{default_cell_index_capacity, AdmissionConfig};
 use crate::ram::cleaner::Cleaner;
+pub use crate::ram::cleaner::DEFAULT_LIVING_RATE_THRESHOLD;
 use crate::ram::schema::sm as schema_sm;
 use crate::ram::schema::LocalSchemasCache;
 use crate::ram::types::Id;
 use std::io;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+pub mod capabilities;
 pub mod cell_rpc;
+pub mod derived;
+pub mod migration;
 #[cfg(test)]
 mod tests;
 pub mod transactions;
 
 pub static CONS_HASH_ID: u64 = hash_ident!(NEB_CONSHASH_MEM_WEIGHTS) as u64;
+pub const DEFAULT_READY_TIMEOUT: Duration = Duration::from_secs(30);
+const READY_POLL_INTERVAL_MS: u64 = 50;
 
 #[derive(Debug)]
 pub enum ServerError {
@@ -36,10 +45,14 @@ pub enum ServerError {
     CannotJoinClusterGroup(sm_master::ExecError),
     CannotInitMemberTable,
     CannotSetServerWeight,
+    InvalidServerWeight,
+    // Some members cannot move their cells remapped by a changed table
+    CannotMigrateCells,
     CannotInitConsistentHashTable,
     CannotLoadMetaClient,
     CannotInitializeSchemaServer(sm_master::ExecError),
     StandaloneMustAlsoBeMetaServer,
+    NotReady,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -50,9 +63,17 @@ pub struct ServerOptions {
     pub wal_storage: Option<String>,
     pub services: Vec<Service>,
     pub index_enabled: bool,
+    // Segments living above this rate will be skipped by the compact cleaner until a full pass
+    pub cleaner_living_rate_threshold: f32,
+    // Creating the server fails if it cannot serve requests within this time
+    pub ready_timeout: Duration,
+    // Thresholds for chunks to reject new cells as overloaded
+    pub admission: AdmissionConfig,
+    // Initial slots of the cell index of each chunk, rounded up to a power of 2
+    pub cell_index_capacity: usize,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Service {
     Cell,
     Transaction,
@@ -75,7 +96,10 @@ pub struct NebServer {
     pub raft_client: Arc<RaftClient>,
     pub server_id: u64,
     pub cleaner: Cleaner,
+    // Statistics of the cells in the chunks, refreshed in the background
+    pub statistics: Arc<StatisticsManager>,
     pub indexer: Option<Arc<IndexBuilder>>,
+    pub dependents: derived::Dependents,
 }
 
 pub async fn init_conshash(
@@ -151,11 +175,30 @@ impl NebServer {
             index_builder.clone(),
             opts.backup_storage.clone(),
             opts.wal_storage.clone(),
+            opts.cell_index_capacity,
         );
-        let cleaner = Cleaner::new_and_start(chunks.clone());
+        for chunk in &chunks.list {
+            chunk.set_admission(opts.admission.clone());
+        }
+        if chunk::restore_on_startup() {
+            match chunks.restore_from_backup() {
+                Ok(restored) => info!("Restored {} cells from backups", restored),
+                Err(e) => error!("Cannot restore cells from backups, {:?}", e),
+            }
+        }
+        if chunk::self_check_on_startup() {
+            for report in chunks.self_check() {
+                if !report.is_consistent() {
+                    error!("Chunk {} is inconsistent, {:?}", report.chunk_id, report);
+                }
+            }
+        }
+        let cleaner = Cleaner::new_and_start(chunks.clone(), opts.cleaner_living_rate_threshold);
+        let statistics = StatisticsManager::start(&chunks, StatisticsConfig::default());
         let server = Arc::new(NebServer {
             chunks,
             cleaner,
+            statistics,
             meta: meta_rc,
             rpc: rpc_server.clone(),
             consh: conshasing.clone(),
@@ -166,7 +209,9 @@ impl NebServer {
             raft_client: raft_client.clone(),
             server_id: rpc_server.server_id,
             indexer: index_builder,
+            dependents: derived::Dependents::new(),
         });
+        init_capabilities_service(rpc_server, &opts.services).await;
         for service in &opts.services {
             match service {
                 &Service::Cell => init_cell_rpc_service(rpc_server, &server).await,
@@ -183,10 +228,45 @@ impl NebServer {
                 }
             }
         }
-
+        server.wait_ready(group_name, opts.ready_timeout).await?;
+        if opts.services.contains(&Service::Cell) {
+            server.track_stored_dependents().await;
+        }
         Ok(server)
     }
 
+    // Wait until the consistent hash table is built and the local schema cache has caught up
+    // with the schemas in the cluster, so requests right after startup can be served
+    async fn wait_ready(&self, group_name: &String, timeout: Duration) -> Result<(), ServerError> {
+        let schemas_sm = schema_sm::client::SMClient::new(
+            schema_sm::generate_sm_id(group_name),
+            &self.raft_client,
+        );
+        let deadline = Instant::now() + timeout;
+        loop {
+            let table_ready =
+                self.consh.init_table().await.is_ok() && self.consh.rand_server().is_some();
+            let schemas_ready = match schemas_sm.get_all().await {
+                Ok(schemas) => schemas
+                    .iter()
+                    .all(|schema| self.meta.schemas.get(&schema.id).is_some()),
+                Err(_) => false,
+            };
+            if table_ready && schemas_ready {
+                debug!("Server {} is ready", self.rpc.address);
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                error!(
+                    "Server {} is not ready in {:?}, member table {}, schemas {}",
+                    self.rpc.address, timeout, table_ready, schemas_ready
+                );
+                return Err(ServerError::NotReady);
+            }
+            tokio::time::sleep(Duration::from_millis(READY_POLL_INTERVAL_MS)).await;
+        }
+    }
+
     pub async fn new_from_opts<'a>(
         opts: &ServerOptions,
         server_addr: &'a str,
@@ -296,6 +376,56 @@ impl NebServer {
     pub fn conshash(&self) -> &ConsistentHashing {
         &*self.consh
     }
+    // Reweight this server in the consistent hash table, for nodes with changed capacity.
+    // Keys are remapped across the group once the table is rebuilt. Every member then migrates
+    // its cells remapped to other servers, so cells are found where the new table routes reads.
+    pub async fn set_weight(&self, weight: u64) -> Result<(), ServerError> {
+        if weight == 0 {
+            return Err(ServerError::InvalidServerWeight);
+        }
+        if self.consh.set_weight(&self.rpc.address, weight).await.is_err() {
+            error!("Cannot set server weight to {}", weight);
+            return Err(ServerError::CannotSetServerWeight);
+        }
+        if !self.consh.init_table().await.is_ok() {
+            error!("Cannot rebuild member table after reweighting");
+            return Err(ServerError::CannotInitMemberTable);
+        }
+        let moved = self.migrate_unowned_cells().await;
+        info!("Moved {} cells to other servers after reweighting", moved);
+        let members = match self.consh.membership().all_members(true).await {
+            Ok((members, _)) => members,
+            Err(e) => {
+                error!("Cannot list members to migrate after reweighting, {:?}", e);
+                return Err(ServerError::CannotMigrateCells);
+            }
+        };
+        let mut res = Ok(());
+        for member in members.into_iter().filter(|m| m.id != self.server_id) {
+            let moved = match self.get_member_by_server_id(member.id).await {
+                Ok(client) => {
+                    cell_rpc::AsyncServiceClient::new(cell_rpc::DEFAULT_SERVICE_ID, &client)
+                        .migrate_unowned_cells()
+                        .await
+                }
+                Err(e) => Err(RPCError::IOError(e)),
+            };
+            match moved {
+                Ok(moved) => info!(
+                    "Server {} moved {} cells after reweighting",
+                    member.id, moved
+                ),
+                Err(e) => {
+                    error!(
+                        "Server {} cannot migrate cells after reweighting, {:?}",
+                        member.id, e
+                    );
+                    res = Err(ServerError::CannotMigrateCells);
+                }
+            }
+        }
+        res
+    }
 }
 
 pub async fn rpc_client_by_id(id: &Id, neb: &Arc<NebServer>) -> Result<Arc<RPCClient>, RPCError> {
@@ -320,6 +450,15 @@ impl Peer {
     }
 }
 
+pub async fn init_capabilities_service(rpc_server: &Arc<Server>, services: &Vec<Service>) {
+    rpc_server
+        .register_service(
+            capabilities::DEFAULT_SERVICE_ID,
+            &capabilities::CapabilitiesService::new(services),
+        )
+        .await;
+}
+
 pub async fn init_cell_rpc_service(rpc_server: &Arc<Server>, neb_server: &Arc<NebServer>) {
     rpc_server
         .register_service(
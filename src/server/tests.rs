@@ -44,6 +44,10 @@ pub async fn init() {
             backup_storage: None,
             wal_storage: None,
             index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
             services: vec![],
         },
         &String::from("127.0.0.1:5100"),
@@ -69,6 +73,10 @@ pub async fn smoke_test() {
             backup_storage: None,
             wal_storage: None,
             index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
             services: vec![Service::Cell],
         },
         &server_addr,
@@ -140,6 +148,16 @@ pub async fn smoke_test() {
         let read_cell = client.read_cell(id).await.unwrap().unwrap();
         assert_eq!(*(read_cell.data[DATA].u64().unwrap()), i * 2);
     }
+
+    // Statistics of the server cover the cells
+    server.statistics.refresh_now(schema_id);
+    let counted: usize = server
+        .statistics
+        .schema_statistics(schema_id)
+        .iter()
+        .map(|stat| stat.count)
+        .sum();
+    assert_eq!(counted, num as usize);
 }
 
 #[tokio::test(flavor = "multi_thread")]
@@ -160,6 +178,10 @@ pub async fn smoke_test_parallel() {
             backup_storage: None,
             wal_storage: None,
             index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
             services: vec![Service::Cell],
         },
         &server_addr,
@@ -262,6 +284,10 @@ pub async fn txn() {
             backup_storage: None,
             wal_storage: None,
             index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
             services: vec![Service::Cell, Service::Transaction],
         },
         &server_addr,
@@ -317,3 +343,694 @@ pub async fn txn() {
             .unwrap();
     }
 }
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn txn_attempts() {
+    let _ = env_logger::try_init();
+    let server_addr = String::from("127.0.0.1:5725");
+    let server_group = String::from("txn_attempts_test");
+    let server = NebServer::new_from_opts(
+        &ServerOptions {
+            chunk_count: 1,
+            memory_size: 16 * 1024 * 1024,
+            backup_storage: None,
+            wal_storage: None,
+            index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
+            services: vec![Service::Cell, Service::Transaction],
+        },
+        &server_addr,
+        &server_group,
+    )
+    .await;
+    let client = client::AsyncClient::new(
+        &server.rpc,
+        &server.membership,
+        &vec![server_addr],
+        &server_group,
+    )
+    .await
+    .unwrap();
+    let attempts = parking_lot::Mutex::new(vec![]);
+    let attempts_ref = &attempts;
+    let res = client
+        .transaction(async move |txn| {
+            attempts_ref.lock().push(txn.attempt);
+            if txn.attempt < 2 {
+                return Err(client::transaction::TxnError::NotRealizable);
+            }
+            Ok(txn.attempt)
+        })
+        .await
+        .unwrap();
+    assert_eq!(res, 2);
+    assert_eq!(*attempts.lock(), vec![0, 1, 2]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn derived_fields() {
+    let _ = env_logger::try_init();
+    const NAME: &'static str = "name";
+    const CUSTOMER: &'static str = "customer";
+    const CUSTOMER_NAME: &'static str = "customer_name";
+    let server_addr = String::from("127.0.0.1:5501");
+    let server_group = String::from("derived_fields_test");
+    let server = NebServer::new_from_opts(
+        &ServerOptions {
+            chunk_count: 1,
+            memory_size: 16 * 1024 * 1024,
+            backup_storage: None,
+            wal_storage: None,
+            index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
+            services: vec![Service::Cell],
+        },
+        &server_addr,
+        &server_group,
+    )
+    .await;
+    let customer_schema = Schema::new_with_id(
+        1,
+        &String::from("customer"),
+        None,
+        Field::new(
+            "*",
+            Type::Map,
+            false,
+            false,
+            Some(vec![Field::new(
+                NAME,
+                Type::String,
+                false,
+                false,
+                None,
+                vec![],
+            )]),
+            vec![],
+        ),
+        false,
+        false,
+    );
+    let order_schema = Schema::new_with_id(
+        2,
+        &String::from("order"),
+        None,
+        Field::new(
+            "*",
+            Type::Map,
+            false,
+            false,
+            Some(vec![
+                Field::new(CUSTOMER, Type::Id, false, false, None, vec![]),
+                Field::new(CUSTOMER_NAME, Type::String, false, false, None, vec![])
+                    .derive_from(CUSTOMER, NAME),
+            ]),
+            vec![],
+        ),
+        false,
+        false,
+    );
+    let client = Arc::new(
+        client::AsyncClient::new(
+            &server.rpc,
+            &server.membership,
+            &vec![server_addr],
+            &server_group,
+        )
+        .await
+        .unwrap(),
+    );
+    client
+        .new_schema_with_id(customer_schema)
+        .await
+        .unwrap()
+        .unwrap();
+    client
+        .new_schema_with_id(order_schema)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let customer_id = Id::new(1, 1);
+    let mut customer = OwnedValue::Map(OwnedMap::new());
+    customer[NAME] = OwnedValue::String("Jack".to_string());
+    client
+        .write_cell(OwnedCell::new_with_id(1, &customer_id, customer))
+        .await
+        .unwrap()
+        .unwrap();
+
+    // customer name will be filled by the server
+    let order_id = Id::new(1, 2);
+    let mut order = OwnedValue::Map(OwnedMap::new());
+    order[CUSTOMER] = OwnedValue::Id(customer_id);
+    client
+        .write_cell(OwnedCell::new_with_id(2, &order_id, order))
+        .await
+        .unwrap()
+        .unwrap();
+    let order_cell = client.read_cell(order_id).await.unwrap().unwrap();
+    assert_eq!(order_cell.data[CUSTOMER_NAME].string().unwrap(), "Jack");
+
+    // changing the customer will refresh the order
+    let mut customer = OwnedValue::Map(OwnedMap::new());
+    customer[NAME] = OwnedValue::String("John".to_string());
+    client
+        .update_cell(OwnedCell::new_with_id(1, &customer_id, customer))
+        .await
+        .unwrap()
+        .unwrap();
+    let order_cell = client.read_cell(order_id).await.unwrap().unwrap();
+    assert_eq!(order_cell.data[CUSTOMER_NAME].string().unwrap(), "John");
+
+    // dependents are tracked from the stored cells, like after restarts
+    server.dependents.remove_source(&customer_id);
+    server.track_stored_dependents().await;
+    assert_eq!(server.dependents.of(&customer_id), vec![order_id]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn derived_fields_across_servers() {
+    let _ = env_logger::try_init();
+    const NAME: &'static str = "name";
+    const CUSTOMER: &'static str = "customer";
+    const CUSTOMER_NAME: &'static str = "customer_name";
+    let server_addr_1 = String::from("127.0.0.1:5508");
+    let server_addr_2 = String::from("127.0.0.1:5509");
+    let server_group = String::from("derived_fields_servers_test");
+    let opts = ServerOptions {
+        chunk_count: 1,
+        memory_size: 16 * 1024 * 1024,
+        backup_storage: None,
+        wal_storage: None,
+        index_enabled: false,
+        cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+        ready_timeout: DEFAULT_READY_TIMEOUT,
+        admission: AdmissionConfig::default(),
+        cell_index_capacity: default_cell_index_capacity(),
+        services: vec![Service::Cell, Service::Transaction],
+    };
+    let server_1 = NebServer::new_from_opts(&opts, &server_addr_1, &server_group).await;
+    let server_2 = NebServer::new_cluster_from_opts(
+        &opts,
+        &server_addr_2,
+        &vec![server_addr_1.clone()],
+        &server_group,
+    )
+    .await;
+    let customer_schema = Schema::new_with_id(
+        1,
+        &String::from("customer"),
+        None,
+        Field::new(
+            "*",
+            Type::Map,
+            false,
+            false,
+            Some(vec![Field::new(
+                NAME,
+                Type::String,
+                false,
+                false,
+                None,
+                vec![],
+            )]),
+            vec![],
+        ),
+        false,
+        false,
+    );
+    let order_schema = Schema::new_with_id(
+        2,
+        &String::from("order"),
+        None,
+        Field::new(
+            "*",
+            Type::Map,
+            false,
+            false,
+            Some(vec![
+                Field::new(CUSTOMER, Type::Id, false, false, None, vec![]),
+                Field::new(CUSTOMER_NAME, Type::String, false, false, None, vec![])
+                    .derive_from(CUSTOMER, NAME),
+            ]),
+            vec![],
+        ),
+        false,
+        false,
+    );
+    let client = client::AsyncClient::new(
+        &server_1.rpc,
+        &server_1.membership,
+        &vec![server_addr_1.clone()],
+        &server_group,
+    )
+    .await
+    .unwrap();
+    client
+        .new_schema_with_id(customer_schema)
+        .await
+        .unwrap()
+        .unwrap();
+    client
+        .new_schema_with_id(order_schema)
+        .await
+        .unwrap()
+        .unwrap();
+    let id_owned_by = |server: &Arc<NebServer>| loop {
+        let id = Id::rand();
+        if server.get_server_id_by_id(&id) == Some(server.server_id) {
+            return id;
+        }
+    };
+    let customer_of = |name: &str| {
+        let mut customer = OwnedValue::Map(OwnedMap::new());
+        customer[NAME] = OwnedValue::String(name.to_string());
+        customer
+    };
+    let customer_id = id_owned_by(&server_1);
+    client
+        .write_cell(OwnedCell::new_with_id(1, &customer_id, customer_of("Jack")))
+        .await
+        .unwrap()
+        .unwrap();
+
+    // The order is kept by another server than the customer
+    let order_id = id_owned_by(&server_2);
+    let mut order = OwnedValue::Map(OwnedMap::new());
+    order[CUSTOMER] = OwnedValue::Id(customer_id);
+    client
+        .write_cell(OwnedCell::new_with_id(2, &order_id, order.clone()))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(server_1.dependents.of(&customer_id), vec![order_id]);
+    let order_cell = client.read_cell(order_id).await.unwrap().unwrap();
+    assert_eq!(order_cell.data[CUSTOMER_NAME].string().unwrap(), "Jack");
+
+    client
+        .update_cell(OwnedCell::new_with_id(1, &customer_id, customer_of("John")))
+        .await
+        .unwrap()
+        .unwrap();
+    let order_cell = client.read_cell(order_id).await.unwrap().unwrap();
+    assert_eq!(order_cell.data[CUSTOMER_NAME].string().unwrap(), "John");
+
+    // Customers changed by transactions refresh their orders as well
+    client
+        .transaction(async move |txn| {
+            txn.update(OwnedCell::new_with_id(1, &customer_id, customer_of("Jill")))
+                .await
+        })
+        .await
+        .unwrap();
+    let order_cell = client.read_cell(order_id).await.unwrap().unwrap();
+    assert_eq!(order_cell.data[CUSTOMER_NAME].string().unwrap(), "Jill");
+
+    // Orders written by transactions are resolved once committed
+    let txn_order_id = id_owned_by(&server_2);
+    order[CUSTOMER_NAME] = OwnedValue::String(String::new());
+    client
+        .transaction(async move |txn| {
+            txn.write(OwnedCell::new_with_id(2, &txn_order_id, order.clone()))
+                .await
+        })
+        .await
+        .unwrap();
+    let order_cell = client.read_cell(txn_order_id).await.unwrap().unwrap();
+    assert_eq!(order_cell.data[CUSTOMER_NAME].string().unwrap(), "Jill");
+    let mut dependents = server_1.dependents.of(&customer_id);
+    dependents.sort();
+    let mut expected = vec![order_id, txn_order_id];
+    expected.sort();
+    assert_eq!(dependents, expected);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn reweight() {
+    let _ = env_logger::try_init();
+    let server_addr_1 = String::from("127.0.0.1:5502");
+    let server_addr_2 = String::from("127.0.0.1:5503");
+    let server_group = String::from("reweight_test");
+    let opts = ServerOptions {
+        chunk_count: 1,
+        memory_size: 16 * 1024 * 1024,
+        backup_storage: None,
+        wal_storage: None,
+        index_enabled: false,
+        cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+        ready_timeout: DEFAULT_READY_TIMEOUT,
+        admission: AdmissionConfig::default(),
+        cell_index_capacity: default_cell_index_capacity(),
+        services: vec![Service::Cell],
+    };
+    let server_1 = NebServer::new_from_opts(&opts, &server_addr_1, &server_group).await;
+    let server_2 = NebServer::new_cluster_from_opts(
+        &opts,
+        &server_addr_2,
+        &vec![server_addr_1.clone()],
+        &server_group,
+    )
+    .await;
+    let partitions = (0..4096).map(|_| Id::rand().higher).collect::<Vec<_>>();
+    let owned_by_server_2 = || {
+        partitions
+            .iter()
+            .filter(|p| server_2.get_server_id_by_id(&Id::new(**p, 0)) == Some(server_2.server_id))
+            .count()
+    };
+    let before = owned_by_server_2();
+    assert!(before > 0);
+    assert!(before < partitions.len());
+    assert!(matches!(
+        server_2.set_weight(0).await,
+        Err(ServerError::InvalidServerWeight)
+    ));
+    assert_eq!(owned_by_server_2(), before);
+    server_2
+        .set_weight(opts.memory_size as u64 * 8)
+        .await
+        .unwrap();
+    let after = owned_by_server_2();
+    assert!(
+        after > before,
+        "Server 2 should own more keys after reweight, before {}, after {}",
+        before,
+        after
+    );
+
+    // Cells remapped to other servers are moved to them
+    const DATA: &'static str = "DATA";
+    let schema = Schema::new_with_id(
+        1,
+        &String::from("reweight"),
+        None,
+        Field::new(
+            "*",
+            Type::Map,
+            false,
+            false,
+            Some(vec![Field::new(
+                DATA,
+                Type::U64,
+                false,
+                false,
+                None,
+                vec![],
+            )]),
+            vec![],
+        ),
+        false,
+        false,
+    );
+    let client = client::AsyncClient::new(
+        &server_2.rpc,
+        &server_2.membership,
+        &vec![server_addr_1.clone()],
+        &server_group,
+    )
+    .await
+    .unwrap();
+    client.new_schema_with_id(schema).await.unwrap().unwrap();
+    let num_cells = 64;
+    for i in 0..num_cells {
+        let mut value = OwnedValue::Map(OwnedMap::new());
+        value[DATA] = OwnedValue::U64(i);
+        let id = Id::new(partitions[i as usize], i);
+        client
+            .write_cell(OwnedCell::new_with_id(1, &id, value))
+            .await
+            .unwrap()
+            .unwrap();
+    }
+    assert!(server_2.chunks.count() > 0);
+    server_2.set_weight(1).await.unwrap();
+    for id in server_2.chunks.cell_ids() {
+        assert_eq!(server_2.get_server_id_by_id(&id), Some(server_2.server_id));
+    }
+    assert_eq!(
+        server_1.chunks.count() + server_2.chunks.count(),
+        num_cells as usize
+    );
+
+    // Cells remapped to the reweighted server are moved to it by the other servers
+    let server_2_cells = server_2.chunks.count();
+    server_2
+        .set_weight(opts.memory_size as u64 * 8)
+        .await
+        .unwrap();
+    assert!(server_2.chunks.count() > server_2_cells);
+    for server in &[&server_1, &server_2] {
+        for id in server.chunks.cell_ids() {
+            assert_eq!(server.get_server_id_by_id(&id), Some(server.server_id));
+        }
+    }
+    assert_eq!(
+        server_1.chunks.count() + server_2.chunks.count(),
+        num_cells as usize
+    );
+    // Reads routed by the new table find all of the cells
+    for i in 0..num_cells {
+        let id = Id::new(partitions[i as usize], i);
+        let owner = if server_1.get_server_id_by_id(&id) == Some(server_1.server_id) {
+            &server_1
+        } else {
+            &server_2
+        };
+        let cell = owner.chunks.read_cell(&id).unwrap().to_owned();
+        assert_eq!(*(cell.data[DATA].u64().unwrap()), i);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn ready_on_return() {
+    let _ = env_logger::try_init();
+    const DATA: &'static str = "DATA";
+    let server_addr_1 = String::from("127.0.0.1:5504");
+    let server_addr_2 = String::from("127.0.0.1:5505");
+    let server_group = String::from("ready_test");
+    let opts = ServerOptions {
+        chunk_count: 1,
+        memory_size: 16 * 1024 * 1024,
+        backup_storage: None,
+        wal_storage: None,
+        index_enabled: false,
+        cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+        ready_timeout: DEFAULT_READY_TIMEOUT,
+        admission: AdmissionConfig::default(),
+        cell_index_capacity: default_cell_index_capacity(),
+        services: vec![Service::Cell],
+    };
+    let schema_id = 123;
+    let schema = Schema::new_with_id(
+        schema_id,
+        &String::from("schema"),
+        None,
+        Field::new(
+            "*",
+            Type::Map,
+            false,
+            false,
+            Some(vec![Field::new(
+                DATA,
+                Type::U64,
+                false,
+                false,
+                None,
+                vec![],
+            )]),
+            vec![],
+        ),
+        false,
+        false,
+    );
+    let server_1 = NebServer::new_from_opts(&opts, &server_addr_1, &server_group).await;
+    let meta_servers = vec![server_addr_1.clone()];
+    let client_1 = client::AsyncClient::new(
+        &server_1.rpc,
+        &server_1.membership,
+        &meta_servers,
+        &server_group,
+    )
+    .await
+    .unwrap();
+    client_1.new_schema_with_id(schema).await.unwrap().unwrap();
+    let id = Id::new(1, 1);
+    let mut value = OwnedValue::Map(OwnedMap::new());
+    value[DATA] = OwnedValue::U64(1);
+    let cell = OwnedCell::new_with_id(schema_id, &id, value);
+    client_1.upsert_cell(cell).await.unwrap().unwrap();
+    // No sleep in between, the joining server is ready when it returns
+    let server_2 =
+        NebServer::new_cluster_from_opts(&opts, &server_addr_2, &meta_servers, &server_group).await;
+    assert!(server_2.meta.schemas.get(&schema_id).is_some());
+    let client_2 = client::AsyncClient::new(
+        &server_2.rpc,
+        &server_2.membership,
+        &meta_servers,
+        &server_group,
+    )
+    .await
+    .unwrap();
+    let read_cell = client_2.read_cell(id).await.unwrap().unwrap();
+    assert_eq!(*read_cell.data[DATA].u64().unwrap(), 1);
+    for i in 2..64 {
+        let id = Id::new(1, i);
+        let mut value = OwnedValue::Map(OwnedMap::new());
+        value[DATA] = OwnedValue::U64(i);
+        let cell = OwnedCell::new_with_id(schema_id, &id, value);
+        client_2.upsert_cell(cell).await.unwrap().unwrap();
+        let read_cell = client_1.read_cell(id).await.unwrap().unwrap();
+        assert_eq!(*read_cell.data[DATA].u64().unwrap(), i);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn migrate_cells() {
+    let _ = env_logger::try_init();
+    const DATA: &'static str = "DATA";
+    let server_addr_1 = String::from("127.0.0.1:5506");
+    let server_addr_2 = String::from("127.0.0.1:5507");
+    let server_group = String::from("migration_test");
+    let opts = ServerOptions {
+        chunk_count: 1,
+        memory_size: 16 * 1024 * 1024,
+        backup_storage: None,
+        wal_storage: None,
+        index_enabled: false,
+        cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+        ready_timeout: DEFAULT_READY_TIMEOUT,
+        admission: AdmissionConfig::default(),
+        cell_index_capacity: default_cell_index_capacity(),
+        services: vec![Service::Cell],
+    };
+    let server_1 = NebServer::new_from_opts(&opts, &server_addr_1, &server_group).await;
+    let meta_servers = vec![server_addr_1.clone()];
+    let server_2 =
+        NebServer::new_cluster_from_opts(&opts, &server_addr_2, &meta_servers, &server_group).await;
+    let schema_id = 123;
+    let schema = Schema::new_with_id(
+        schema_id,
+        &String::from("schema"),
+        None,
+        Field::new(
+            "*",
+            Type::Map,
+            false,
+            false,
+            Some(vec![Field::new(
+                DATA,
+                Type::U64,
+                false,
+                false,
+                None,
+                vec![],
+            )]),
+            vec![],
+        ),
+        false,
+        false,
+    );
+    let client = client::AsyncClient::new(
+        &server_1.rpc,
+        &server_1.membership,
+        &meta_servers,
+        &server_group,
+    )
+    .await
+    .unwrap();
+    client.new_schema_with_id(schema).await.unwrap().unwrap();
+    // A partition owned by the first server
+    let partition = (1..)
+        .find(|p| server_1.get_server_id_by_id(&Id::new(*p, 0)) == Some(server_1.server_id))
+        .unwrap();
+    let num = 100;
+    for i in 0..num {
+        let mut value = OwnedValue::Map(OwnedMap::new());
+        value[DATA] = OwnedValue::U64(i);
+        let cell = OwnedCell::new_with_id(schema_id, &Id::new(partition, i), value);
+        client.write_cell(cell).await.unwrap().unwrap();
+    }
+    let target = server_2.server_id;
+    assert!(matches!(
+        server_1
+            .migrate_cell(Id::new(partition, 0), server_1.server_id)
+            .await,
+        Err(migration::MigrationError::TargetIsSource)
+    ));
+    server_1
+        .migrate_cell(Id::new(partition, 0), target)
+        .await
+        .unwrap();
+    let moved = server_1
+        .migrate_range(&Id::new(partition, 1), &Id::new(partition, num / 2), target)
+        .await
+        .unwrap();
+    assert_eq!(moved.len() as u64, num / 2 - 1);
+    assert!(moved.iter().all(|(_, res)| res.is_ok()));
+    for i in 0..num {
+        let id = Id::new(partition, i);
+        let on_source = server_1.chunks.read_cell(&id).map(|c| c.to_owned());
+        let on_target = server_2.chunks.read_cell(&id).map(|c| c.to_owned());
+        let (moved, stayed) = if i < num / 2 {
+            (on_target, on_source)
+        } else {
+            (on_source, on_target)
+        };
+        assert_eq!(*moved.unwrap().data[DATA].u64().unwrap(), i);
+        assert!(stayed.is_err());
+    }
+    // Cells not on the server cannot be moved
+    assert!(matches!(
+        server_1.migrate_cell(Id::new(partition, 0), target).await,
+        Err(migration::MigrationError::ReadError(_))
+    ));
+    // Cells of the target are kept, so is the one on the source
+    let id = Id::new(partition, num - 1);
+    let mut value = OwnedValue::Map(OwnedMap::new());
+    value[DATA] = OwnedValue::U64(num);
+    let mut cell = OwnedCell::new_with_id(schema_id, &id, value);
+    server_2.chunks.write_cell(&mut cell).unwrap();
+    assert!(matches!(
+        server_1.migrate_cell(id, target).await,
+        Err(migration::MigrationError::TargetHasCell)
+    ));
+    let on_target = server_2.chunks.read_cell(&id).unwrap().to_owned();
+    assert_eq!(*on_target.data[DATA].u64().unwrap(), num);
+    let on_source = server_1.chunks.read_cell(&id).unwrap().to_owned();
+    assert_eq!(*on_source.data[DATA].u64().unwrap(), num - 1);
+}
+
+#[test]
+pub fn framed_reads_bounded() {
+    use crate::server::cell_rpc::FramedReads;
+    use std::time::Duration;
+    let (id1, id2, id3) = (Id::new(1, 1), Id::new(1, 2), Id::new(1, 3));
+    // Least recently read cells make room for new ones
+    let mut reads = FramedReads::new(100, Duration::from_secs(60));
+    reads.insert(id1, 1, Arc::new(vec![0; 40]));
+    reads.insert(id2, 1, Arc::new(vec![0; 40]));
+    assert!(reads.get(&id1, 1).is_some());
+    reads.insert(id3, 1, Arc::new(vec![0; 40]));
+    assert!(reads.get(&id2, 1).is_none());
+    assert!(reads.get(&id1, 1).is_some());
+    assert!(reads.get(&id3, 1).is_some());
+    assert!(reads.get(&id3, 2).is_none());
+    assert_eq!(reads.cached_bytes(), 80);
+    // Too large to cache at all
+    reads.insert(id2, 1, Arc::new(vec![0; 200]));
+    assert!(reads.get(&id2, 1).is_none());
+    assert_eq!(reads.cached_bytes(), 80);
+    // Abandoned reads expire
+    let mut reads = FramedReads::new(100, Duration::from_millis(50));
+    reads.insert(id1, 1, Arc::new(vec![0; 40]));
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(reads.get(&id1, 1).is_none());
+    assert_eq!(reads.cached_bytes(), 0);
+}
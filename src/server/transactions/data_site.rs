@@ -24,6 +24,9 @@ type CommitHistory = BTreeMap<Id, CellHistory>;
 type CellMetaMutex = Arc<Mutex<CellMeta>>;
 type TxnMutex = Arc<Mutex<Transaction>>;
 
+// Max number of cells tracked for write conflicts, least recently conflicted cells are evicted
+const MAX_CONFLICT_TRACKED_CELLS: usize = 1024;
+
 #[derive(Debug)]
 pub struct CellMeta {
     read: TxnId,
@@ -57,6 +60,7 @@ impl CellHistory {
 pub struct DataManager {
     cells: LFMap<Id, Arc<Mutex<CellMeta>>>,
     cell_lru: Mutex<LinkedHashMap<Id, i64>>,
+    conflicts: Mutex<LinkedHashMap<Id, u64>>,
     txns: LFMap<TxnId, Arc<Mutex<Transaction>>>,
     txns_sorted: Mutex<BTreeSet<TxnId>>,
     managers: ObjectMap<Arc<manager::AsyncServiceClient>>,
@@ -79,6 +83,9 @@ service! {
 
     // there also should be a 'end' from transaction manager to inform data manager to clean up and release cell locks
     rpc end(clock :StandardVectorClock, tid: TxnId) -> DataSiteResponse<EndResult>;
+
+    // cells with most write conflicts, for diagnosing contentions that cause frequent aborts
+    rpc hot_cells(n: usize) -> Vec<(Id, u64)>;
 }
 
 dispatch_rpc_service_functions!(DataManager);
@@ -89,6 +96,7 @@ impl DataManager {
         let manager = Arc::new(Self {
             cells: LFMap::with_capacity(256),
             cell_lru: Mutex::new(LinkedHashMap::new()),
+            conflicts: Mutex::new(LinkedHashMap::new()),
             txns: LFMap::with_capacity(128),
             txns_sorted: Mutex::new(BTreeSet::new()),
             managers: ObjectMap::with_capacity(16),
@@ -210,6 +218,14 @@ impl DataManager {
             }
         }
     }
+    fn record_conflict(&self, cell_id: &Id) {
+        let mut conflicts = self.conflicts.lock();
+        *conflicts.entry(cell_id.clone()).or_insert(0) += 1;
+        conflicts.get_refresh(cell_id);
+        while conflicts.len() > MAX_CONFLICT_TRACKED_CELLS {
+            conflicts.pop_front();
+        }
+    }
     fn wipe_out_transaction(&self, tid: &TxnId) {
         if let Some(txn) = self.txns.write(tid) {
             txn.remove();
@@ -323,7 +339,7 @@ impl Service for DataManager {
             return r;
         }
         match self.server.chunks.read_selected(&id, &fields[..]) {
-            Ok(values) => self.response_with(TxnExecResult::Accepted(values.owned())),
+            Ok((values, _schema)) => self.response_with(TxnExecResult::Accepted(values.owned())),
             Err(read_error) => self.response_with(TxnExecResult::Error(read_error)),
         }
     }
@@ -398,6 +414,7 @@ impl Service for DataManager {
             cell_guards.push(meta);
         }
         if cell_guards.len() != cell_ids.len() {
+            self.record_conflict(&cell_ids[cell_guards.len()]);
             return self.response_with(DMPrepareResult::NotRealizable); // need retry
         } else {
             for mut meta in cell_guards {
@@ -442,6 +459,9 @@ impl Service for DataManager {
             ));
         }
         let mut write_error: Option<(Id, WriteError)> = None;
+        // Cells written and removed by the transaction, for their derived fields and dependents
+        let mut written = vec![];
+        let mut removed = vec![];
         {
             let commit_history = &mut txn.history; // for rollback in case of write error
             for cell_op in cells {
@@ -455,6 +475,7 @@ impl Service for DataManager {
                                 commit_history
                                     .insert(cell.id(), CellHistory::new(None, header.version));
                                 self.update_cell_write(&cell.id(), &tid);
+                                written.push(cell.id());
                             }
                             Err(error) => {
                                 write_error = Some((cell.id(), error));
@@ -481,6 +502,7 @@ impl Service for DataManager {
                                 commit_history
                                     .insert(*cell_id, CellHistory::new(Some(original_cell), 0));
                                 self.update_cell_write(cell_id, &tid);
+                                removed.push(*cell_id);
                             }
                             Err(error) => {
                                 write_error = Some((*cell_id, error));
@@ -517,6 +539,7 @@ impl Service for DataManager {
                                     CellHistory::new(Some(original_cell), cell.header.version),
                                 );
                                 self.update_cell_write(&cell_id, &tid);
+                                written.push(cell_id);
                             }
                             Err(error) => {
                                 write_error = Some((cell_id, error));
@@ -546,9 +569,24 @@ impl Service for DataManager {
         } else {
             // all set, able to commit
             txn.state = TxnState::Committed;
-            // Commit all indices
             let response = DataSiteResponse::new(&self.server.txn_peer, DMCommitResult::Success);
-            return IndexBuilder::await_indices().map(|_| response).boxed();
+            return async move {
+                for id in removed {
+                    self.server.dependents.remove_source(&id);
+                }
+                for id in written {
+                    if let Err(e) = self.server.refresh_derived(id, None).await {
+                        warn!(
+                            "Cannot resolve derived fields of committed cell {:?}, {:?}",
+                            id, e
+                        );
+                    }
+                }
+                // Commit all indices
+                IndexBuilder::await_indices().await;
+                response
+            }
+            .boxed();
         }
     }
     fn abort(
@@ -671,4 +709,15 @@ impl Service for DataManager {
         }
         .boxed()
     }
+    fn hot_cells(&self, n: usize) -> BoxFuture<Vec<(Id, u64)>> {
+        let mut cells: Vec<_> = self
+            .conflicts
+            .lock()
+            .iter()
+            .map(|(id, count)| (*id, *count))
+            .collect();
+        cells.sort_by(|(_, c1), (_, c2)| c2.cmp(c1));
+        cells.truncate(n);
+        future::ready(cells).boxed()
+    }
 }
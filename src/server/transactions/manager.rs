@@ -8,6 +8,7 @@ use bifrost_plugins::hash_ident;
 use itertools::Itertools;
 use lightning::map::{HashMap as LFMap, Map, ObjectMap};
 use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::Duration;
 // Use async mutex because this module is a distributed coordinator
 use async_std::sync::{Mutex, MutexGuard};
 use futures::future::BoxFuture;
@@ -20,6 +21,7 @@ type AffectedObjs = BTreeMap<u64, BTreeMap<Id, DataObject>>; // server_id as key
 type DataSitesMap = HashMap<u64, Arc<data_site::AsyncServiceClient>>;
 
 pub static DEFAULT_SERVICE_ID: u64 = hash_ident!(TXN_MANAGER_RPC_SERVICE) as u64;
+const BARRIER_POLL_INTERVAL_MS: u64 = 10;
 
 #[derive(Clone, Debug)]
 struct DataObject {
@@ -36,6 +38,14 @@ struct Transaction {
     state: TxnState,
 }
 
+// Transactions are stamped with an epoch on begin, so a barrier only waits for transactions that
+// have begun before it.
+#[derive(Default)]
+struct TxnEpochs {
+    next: u64,
+    active: BTreeMap<TxnId, u64>,
+}
+
 service! {
     rpc begin() -> Result<TxnId, TMError>;
     rpc read(tid: TxnId, id: Id) -> Result<TxnExecResult<OwnedCell, ReadError>, TMError>;
@@ -50,6 +60,7 @@ service! {
     rpc abort(tid: TxnId) -> Result<AbortResult, TMError>;
 
     rpc go_ahead(tids: BTreeSet<TxnId>, server_id: u64); // invoked by data site to continue on it's transaction in case of waiting
+    rpc barrier(); // returns when all transactions begun before have ended
 }
 
 dispatch_rpc_service_functions!(TransactionManager);
@@ -59,6 +70,7 @@ pub struct TransactionManager {
     transactions: LFMap<TxnId, TxnMutex>,
     data_sites: ObjectMap<Arc<data_site::AsyncServiceClient>>,
     await_manager: AwaitManager,
+    epochs: parking_lot::Mutex<TxnEpochs>,
 }
 
 impl TransactionManager {
@@ -68,6 +80,7 @@ impl TransactionManager {
             transactions: LFMap::with_capacity(128),
             data_sites: ObjectMap::with_capacity(8),
             await_manager: AwaitManager::new(),
+            epochs: parking_lot::Mutex::new(TxnEpochs::default()),
         })
     }
 }
@@ -284,6 +297,10 @@ impl Service for TransactionManager {
         {
             future::ready(Err(TMError::TransactionIdExisted)).boxed()
         } else {
+            let mut epochs = self.epochs.lock();
+            let epoch = epochs.next;
+            epochs.next += 1;
+            epochs.active.insert(id.clone(), epoch);
             future::ready(Ok(id)).boxed()
         }
     }
@@ -430,6 +447,17 @@ impl Service for TransactionManager {
         }
         .boxed()
     }
+    // Transactions abandoned by their clients without commit or abort will hold the barrier
+    fn barrier(&self) -> BoxFuture<()> {
+        let epoch = self.epochs.lock().next;
+        debug!("TM BARRIER AT EPOCH {}", epoch);
+        async move {
+            while self.has_active_before(epoch) {
+                tokio::time::sleep(Duration::from_millis(BARRIER_POLL_INTERVAL_MS)).await;
+            }
+        }
+        .boxed()
+    }
 }
 
 impl TransactionManager {
@@ -824,6 +852,10 @@ impl TransactionManager {
     }
     fn cleanup_transaction(&self, tid: &TxnId) {
         self.transactions.write(tid).map(|g| g.remove());
+        self.epochs.lock().active.remove(tid);
+    }
+    fn has_active_before(&self, epoch: u64) -> bool {
+        self.epochs.lock().active.values().any(|e| *e < epoch)
     }
 }
 
@@ -18,6 +18,10 @@ pub async fn workspace_wr() {
             backup_storage: None,
             wal_storage: None,
             index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
             services: vec![Service::Cell, Service::Transaction],
         },
         &server_addr,
@@ -156,6 +160,10 @@ pub async fn data_site_wr() {
             backup_storage: None,
             wal_storage: None,
             index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
             services: vec![Service::Cell, Service::Transaction],
         },
         &server_addr,
@@ -251,6 +259,10 @@ pub async fn multi_transaction() {
             backup_storage: None,
             wal_storage: None,
             index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
             services: vec![Service::Cell, Service::Transaction],
         },
         &server_addr,
@@ -357,6 +369,10 @@ pub async fn smoke_rw() {
             backup_storage: None,
             wal_storage: None,
             index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
             services: vec![Service::Cell, Service::Transaction],
         },
         &server_addr,
@@ -419,3 +435,68 @@ pub async fn smoke_rw() {
         f.await.unwrap();
     }
 }
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn hot_cells() {
+    let _ = env_logger::try_init();
+    let server_addr = String::from("127.0.0.1:5204");
+    let server = NebServer::new_from_opts(
+        &ServerOptions {
+            chunk_count: 1,
+            memory_size: 16 * 1024 * 1024,
+            backup_storage: None,
+            wal_storage: None,
+            index_enabled: false,
+            cleaner_living_rate_threshold: DEFAULT_LIVING_RATE_THRESHOLD,
+            ready_timeout: DEFAULT_READY_TIMEOUT,
+            admission: AdmissionConfig::default(),
+            cell_index_capacity: default_cell_index_capacity(),
+            services: vec![Service::Cell, Service::Transaction],
+        },
+        &server_addr,
+        "test",
+    )
+    .await;
+    let schema = Schema::new_with_id(
+        1,
+        &String::from("test"),
+        None,
+        default_fields(),
+        false,
+        false,
+    );
+    server.meta.schemas.new_schema(schema.clone());
+    let txn = transactions::new_async_client(&server_addr).await.unwrap();
+    let mut data_map = OwnedMap::new();
+    data_map.insert(&String::from("id"), OwnedValue::I64(100));
+    data_map.insert(&String::from("score"), OwnedValue::U64(0));
+    data_map.insert(
+        &String::from("name"),
+        OwnedValue::String(String::from("Jack")),
+    );
+    let mut hot_cell =
+        OwnedCell::new_with_id(schema.id, &Id::rand(), OwnedValue::Map(data_map.clone()));
+    server.chunks.write_cell(&mut hot_cell).unwrap();
+    let conflicts = 10;
+    for _ in 0..conflicts {
+        // later transaction reads the cell, earlier transaction writes it too late
+        let txn_1_id = txn.begin().await.unwrap().unwrap();
+        let txn_2_id = txn.begin().await.unwrap().unwrap();
+        txn.read(txn_2_id.to_owned(), hot_cell.id())
+            .await
+            .unwrap()
+            .unwrap();
+        txn.update(txn_1_id.to_owned(), hot_cell.to_owned())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            txn.prepare(txn_1_id.to_owned()).await.unwrap().unwrap(),
+            TMPrepareResult::DMPrepareError(DMPrepareResult::NotRealizable)
+        );
+    }
+    let rpc_client = DEFAULT_CLIENT_POOL.get(&server_addr).await.unwrap();
+    let data_site = data_site::AsyncServiceClient::new(data_site::DEFAULT_SERVICE_ID, &rpc_client);
+    let hot_cells = data_site.hot_cells(5).await.unwrap();
+    assert_eq!(hot_cells, vec![(hot_cell.id(), conflicts)]);
+}
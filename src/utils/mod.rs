@@ -1,8 +1,13 @@
 #[macro_use]
 pub mod ring_buffer;
+pub mod hyperloglog;
 pub mod lru_cache;
 pub mod raii_mutex_table;
 
+use std::env;
+use std::fmt::{Debug, Display};
+use std::str::FromStr;
+
 pub fn upper_power_of_2(mut v: usize) -> usize {
     debug_assert!(v > 0);
     v -= 1;
@@ -14,3 +19,25 @@ pub fn upper_power_of_2(mut v: usize) -> usize {
     v += 1;
     return v;
 }
+
+// Setting from the environment variable, or the default when it is unset. Invalid values are
+// taken as unset with a warning.
+pub fn env_or<T>(name: &str, default: T) -> T
+where
+    T: FromStr + Display,
+    T::Err: Debug,
+{
+    match env::var(name) {
+        Ok(value) => match value.parse() {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                warn!(
+                    "Invalid {} {:?}, using the default {}, {:?}",
+                    name, value, default, e
+                );
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}